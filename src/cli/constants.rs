@@ -11,11 +11,35 @@ use std::sync::LazyLock;
 
 use regex::Regex;
 
-pub const DEFAULT_CHUNK_SIZE: usize = 512;
+pub const DEFAULT_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+/// Minimum size of an S3 multipart upload part, except for the last part.
+pub const S3_MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+
+/// Free space a backup leaves on the spool filesystem by default.
+pub const DEFAULT_MIN_FREE_SPACE: u64 = 64 * 1024 * 1024;
+
+/// Failed backups kept in the spool for inspection by default.
+pub const DEFAULT_KEEP_FAILED: usize = 3;
+
+pub const DEFAULT_COMPRESSION_BUFFERS: usize = 1;
+
+pub const DEFAULT_FREEZE_WORKERS: usize = 1;
+
+pub const MAX_PREFETCH_CHUNKS: usize = 2;
 
 pub static DEFAULT_SPOOL_PATH: &str = "/var/spool/cryophile";
 
 pub static DEFAULT_CONFIG_PATH: &str = "/etc/cryophile/cryophile.toml";
 
+/// Prefix argument that is read from the first line of stdin when the command runs.
+pub static STDIN_PREFIX: &str = "-";
+
 pub static UNSAFE_PREFIX: LazyLock<Regex> =
     LazyLock::new(|| Regex::new(r#"[^a-zA-Z0-9[/()!'*._-]]+"#).expect("broken regex"));
+
+/// Amazon Resource Name of an IAM role, in any partition.
+pub static ROLE_ARN: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^arn:aws[a-z-]*:iam::[0-9]{12}:role/[a-zA-Z0-9+=,.@_/-]{1,512}$")
+        .expect("broken regex")
+});
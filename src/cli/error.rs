@@ -10,6 +10,7 @@
 use std::{env, fmt, io};
 
 use crate::config::ParseConfigError;
+use crate::crypto::openpgp::is_crypto_error;
 
 use super::CliResult;
 
@@ -17,20 +18,31 @@ use super::CliResult;
 pub enum CliError {
     #[error("BaseDirError: {0} {1}")]
     BaseDirError(xdg::BaseDirectoriesError, CliResult),
+    #[error("ChunkSizeError: {0} {1}")]
+    ChunkSizeError(String, CliResult),
     #[error("ConfigurationError: {0} {1}")]
     ConfigurationError(ParseConfigError, CliResult),
+    #[error("CryptoError: {0} {1}")]
+    CryptoError(io::Error, CliResult),
     #[error("EnvError: {0} {1}")]
     EnvError(env::VarError, CliResult),
     #[error("IoError: {0} {1}")]
     IoError(io::Error, CliResult),
     #[error("LogError: Cannot call set_logger more than once {1}")]
     LogError(log::SetLoggerError, CliResult),
+    #[error("PrefixError: {0} {1}")]
+    PrefixError(String, CliResult),
+    #[error("SpoolError: {0} {1}")]
+    SpoolError(String, CliResult),
 }
 
 impl From<ParseConfigError> for CliError {
     fn from(error: ParseConfigError) -> Self {
         match error {
-            ParseConfigError::TomlDeError(_) => {
+            ParseConfigError::TomlDeError(_)
+            | ParseConfigError::EnvExpansion(_)
+            | ParseConfigError::Invalid(_)
+            | ParseConfigError::DuplicateVault(_) => {
                 CliError::ConfigurationError(error, CliResult::ConfigError)
             }
             ParseConfigError::IoError(err) => CliError::IoError(err, CliResult::IoError),
@@ -40,6 +52,9 @@ impl From<ParseConfigError> for CliError {
 
 impl From<io::Error> for CliError {
     fn from(error: io::Error) -> Self {
+        if is_crypto_error(&error) {
+            return CliError::CryptoError(error, CliResult::CryptoError);
+        }
         CliError::IoError(error, CliResult::IoError)
     }
 }
@@ -13,16 +13,22 @@ pub mod parse;
 pub mod result;
 mod subcommand;
 
+use crate::core::aws::CredentialSource;
 use clap::Parser;
+use log::LevelFilter;
 use std::path::PathBuf;
+use std::time::Duration;
 
 pub use self::constants::{
-    DEFAULT_CHUNK_SIZE, DEFAULT_CONFIG_PATH, DEFAULT_SPOOL_PATH, UNSAFE_PREFIX,
+    DEFAULT_CHUNK_SIZE, DEFAULT_CONFIG_PATH, DEFAULT_SPOOL_PATH, S3_MIN_PART_SIZE, STDIN_PREFIX,
+    UNSAFE_PREFIX,
 };
 pub use self::error::CliError;
-use self::parse::{parse_config, parse_spool};
+use self::parse::{parse_config, parse_duration, parse_log_level, parse_role_arn, parse_spool};
 pub use self::result::CliResult;
-pub use self::subcommand::{Backup, Command, Freeze, Restore, Thaw};
+pub use self::subcommand::{
+    Backup, Command, ConfigCheck, Freeze, OutputFormat, Rechunk, Restore, Thaw, Version,
+};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = clap::crate_description!())]
@@ -36,11 +42,19 @@ pub struct Cli {
     /// Spool directory containing all backup and restore queues
     #[arg(
         short = 'S', long, value_parser = parse_spool,
-        default_value_os_t = PathBuf::from(DEFAULT_SPOOL_PATH),
         value_name = "DIRECTORY",
-        help = "Spool directory containing all backup and restore queues",
+        help = "Spool directory containing all backup and restore queues \
+                [default: /var/spool/cryophile for root, $XDG_STATE_HOME/cryophile/spool otherwise]",
+    )]
+    pub spool: Option<PathBuf>,
+
+    /// Root of the freeze queue, if not below the spool
+    #[arg(
+        long, value_parser = parse_spool,
+        value_name = "DIRECTORY",
+        help = "Root of the freeze queue instead of the spool, e.g., on other storage",
     )]
-    pub spool: PathBuf,
+    pub freeze_spool: Option<PathBuf>,
 
     /// Configuration file
     #[arg(
@@ -51,6 +65,72 @@ pub struct Cli {
     )]
     pub config: PathBuf,
 
+    /// Allow prefix segments that look like a vault uuid or backup ulid
+    #[arg(
+        long,
+        help = "Allow prefix segments that look like a vault uuid or backup ulid"
+    )]
+    pub allow_ambiguous_prefix: bool,
+
+    /// Source of AWS credentials
+    #[arg(
+        long,
+        value_enum,
+        value_name = "SOURCE",
+        help = "Source of AWS credentials [default: default]"
+    )]
+    pub aws_credentials: Option<CredentialSource>,
+
+    /// IAM role to assume with the AWS credentials, e.g., in another account
+    #[arg(
+        long, value_parser = parse_role_arn,
+        value_name = "ARN",
+        help = "IAM role to assume with the AWS credentials"
+    )]
+    pub assume_role_arn: Option<String>,
+
+    /// External id required by the trust policy of the assumed role
+    #[arg(
+        long,
+        requires = "assume_role_arn",
+        value_name = "ID",
+        help = "External id for the assumed role"
+    )]
+    pub external_id: Option<String>,
+
+    /// Session name of the assumed role
+    #[arg(
+        long,
+        requires = "assume_role_arn",
+        value_name = "NAME",
+        help = "Session name for the assumed role"
+    )]
+    pub role_session_name: Option<String>,
+
+    /// Use the S3 Transfer Acceleration endpoint
+    #[arg(long, help = "Use the S3 Transfer Acceleration endpoint")]
+    pub s3_accelerate: bool,
+
+    /// Use the dual-stack (IPv4 and IPv6) S3 endpoint
+    #[arg(long, help = "Use the dual-stack (IPv4 and IPv6) S3 endpoint")]
+    pub s3_dualstack: bool,
+
+    /// Poll the spool at interval instead of using inotify
+    #[arg(
+        long, value_parser = parse_duration,
+        value_name = "INTERVAL",
+        help = "Poll the spool at interval (e.g., 2s) instead of using inotify",
+    )]
+    pub watch_poll: Option<Duration>,
+
+    /// Drop repeated identical spool events within duration
+    #[arg(
+        long, value_parser = parse_duration,
+        value_name = "DURATION",
+        help = "Drop repeated identical spool events within duration (e.g., 500ms)",
+    )]
+    pub watch_debounce: Option<Duration>,
+
     /// Print debug information verbosely
     #[arg(
         short,
@@ -63,4 +143,12 @@ pub struct Cli {
     /// Quiet mode
     #[arg(short, long, help = "Quiet mode")]
     pub quiet: bool,
+
+    /// Log level, overrides --quiet and --debug
+    #[arg(
+        long, value_parser = parse_log_level,
+        value_name = "LEVEL",
+        help = "Log level (off, error, warn, info, debug, trace), overrides --quiet and --debug",
+    )]
+    pub log_level: Option<LevelFilter>,
 }
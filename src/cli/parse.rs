@@ -7,20 +7,25 @@
 // This file may not be copied, modified, or distributed except according
 // to those terms.
 
-use std::path::PathBuf;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 
 #[cfg(feature = "age")]
 use crate::crypto::age::RecipientSpec;
 
-use crate::crypto::openpgp::openpgp_error;
+use crate::crypto::openpgp::{openpgp_error, AllowedAlgorithm};
 use chrono::{DateTime, FixedOffset};
+use log::LevelFilter;
 use sequoia_openpgp::cert::CertParser;
 use sequoia_openpgp::parse::Parse;
 use sequoia_openpgp::Cert;
 use ulid::Ulid;
 
-use super::UNSAFE_PREFIX;
+use super::constants::{MAX_PREFETCH_CHUNKS, ROLE_ARN};
+use super::{STDIN_PREFIX, UNSAFE_PREFIX};
 
 pub(crate) fn parse_chunk_size(s: &str) -> Result<usize, String> {
     let parse_config = parse_size::Config::new()
@@ -31,9 +36,39 @@ pub(crate) fn parse_chunk_size(s: &str) -> Result<usize, String> {
         .map_err(|e| format!("Cannot parse chunk size: {e}"))?;
     let chunk_size = usize::try_from(parse_size_result)
         .map_err(|e| format!("Cannot parse chunk size (size exceeds usize): {e}"))?;
+    if chunk_size == 0 {
+        return Err("chunk size must be at least 1 byte".to_string());
+    }
     Ok(chunk_size)
 }
 
+pub(crate) fn parse_byte_size(s: &str) -> Result<u64, String> {
+    let parse_config = parse_size::Config::new()
+        .with_binary()
+        .with_byte_suffix(parse_size::ByteSuffix::Deny);
+    parse_config
+        .parse_size(s)
+        .map_err(|e| format!("Cannot parse size: {e}"))
+}
+
+pub(crate) fn parse_nonzero(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>().map_err(|e| e.to_string())? {
+        0 => Err("value must be at least 1".to_string()),
+        n => Ok(n),
+    }
+}
+
+pub(crate) fn parse_prefetch(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>().map_err(|e| e.to_string())? {
+        n if n > MAX_PREFETCH_CHUNKS => Err(format!("value must be at most {MAX_PREFETCH_CHUNKS}")),
+        n => Ok(n),
+    }
+}
+
+pub(crate) fn parse_duration(s: &str) -> Result<Duration, String> {
+    humantime::parse_duration(s).map_err(|e| format!("Cannot parse duration: {e}"))
+}
+
 pub(crate) fn parse_uuid(s: &str) -> Result<uuid::Uuid, String> {
     let uuid = uuid::Uuid::parse_str(s).map_err(|e| format!("Cannot parse uuid: {e}"))?;
     Ok(uuid)
@@ -47,9 +82,37 @@ pub(crate) fn parse_recipient(s: &str) -> Result<RecipientSpec, String> {
     Ok(recipient)
 }
 
+/// Read the certificates of a keyring file, or of all files in a keyring
+/// directory in file name order.
 pub(crate) fn parse_keyring(s: &str) -> Result<Vec<Cert>, String> {
+    let path = Path::new(s);
+    if !path.is_dir() {
+        let parser = CertParser::from_file(s).map_err(|e| openpgp_error(e).to_string())?;
+        return collect_keyring(parser, s);
+    }
+    let mut files = fs::read_dir(path)
+        .and_then(|entries| {
+            entries
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<io::Result<Vec<_>>>()
+        })
+        .map_err(|e| format!("Cannot read keyring directory {s}: {e}"))?;
+    files.retain(|file| file.is_file());
+    files.sort();
+    let mut cert_list = Vec::new();
+    for file in files {
+        let parser = CertParser::from_file(&file)
+            .map_err(|e| format!("{file:?}: {e}", e = openpgp_error(e)))?;
+        cert_list.extend(collect_keyring(parser, &file.to_string_lossy())?);
+    }
+    if cert_list.is_empty() {
+        return Err(format!("Keyring directory {s} is empty"));
+    }
+    Ok(cert_list)
+}
+
+fn collect_keyring(parser: CertParser, s: &str) -> Result<Vec<Cert>, String> {
     let mut cert_list: Vec<Cert> = Vec::new();
-    let parser = CertParser::from_file(s).map_err(|e| openpgp_error(e).to_string())?;
     for parsed_cert in parser {
         if let Err(err) = parsed_cert {
             return Err(openpgp_error(err).to_string());
@@ -64,6 +127,23 @@ pub(crate) fn parse_keyring(s: &str) -> Result<Vec<Cert>, String> {
     Ok(cert_list)
 }
 
+pub(crate) fn parse_fingerprint(s: &str) -> Result<String, String> {
+    let hex = s.strip_prefix("0x").unwrap_or(s).replace(' ', "");
+    if !(8..=64).contains(&hex.len()) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err("fingerprint must be 8 to 64 hex digits".to_string());
+    }
+    Ok(hex.to_ascii_uppercase())
+}
+
+pub(crate) fn parse_role_arn(s: &str) -> Result<String, String> {
+    if !ROLE_ARN.is_match(s) {
+        return Err(format!(
+            "{s} is not an IAM role ARN like arn:aws:iam::123456789012:role/NAME"
+        ));
+    }
+    Ok(s.to_owned())
+}
+
 pub(crate) fn parse_fd(s: &str) -> Result<i32, String> {
     let raw_fd = s.parse::<i32>().map_err(|e| e.to_string())?;
     if raw_fd < 0 {
@@ -79,12 +159,49 @@ pub(crate) fn parse_timestamp_for_ulid(s: &str) -> Result<Ulid, String> {
     Ok(Ulid::from_datetime(timestamp.into()))
 }
 
+/// Parse an RFC 3339 time, as for `--policy-time` and `--at`.
+pub(crate) fn parse_time(s: &str) -> Result<SystemTime, String> {
+    let timestamp = s
+        .parse::<DateTime<FixedOffset>>()
+        .map_err(|e| format!("Cannot parse time: {e}"))?;
+    Ok(timestamp.into())
+}
+
+pub(crate) fn parse_allowed_algo(s: &str) -> Result<AllowedAlgorithm, String> {
+    s.parse::<AllowedAlgorithm>()
+        .map_err(|e| format!("Cannot parse algorithm: {e}"))
+}
+
 pub(crate) fn parse_ulid(s: &str) -> Result<Ulid, String> {
     let ulid = Ulid::from_string(s).map_err(|e| format!("Cannot parse ulid: {e}"))?;
     Ok(ulid)
 }
 
+/// Parse a prefix given inline, or as `@FILE` to read it from the first line
+/// of FILE. The prefix `-` is kept as is, it is read from stdin with
+/// [`read_prefix`] when the command runs, not while parsing arguments.
 pub(crate) fn parse_prefix(s: &str) -> Result<PathBuf, String> {
+    if s == STDIN_PREFIX {
+        return Ok(PathBuf::from(s));
+    }
+    if let Some(file) = s.strip_prefix('@') {
+        let f =
+            fs::File::open(file).map_err(|e| format!("cannot read prefix from {file:?}: {e}"))?;
+        return read_prefix(io::BufReader::new(f));
+    }
+    parse_inline_prefix(s)
+}
+
+/// Read the prefix from the first line of `reader`, the rest is left unread.
+pub(crate) fn read_prefix(mut reader: impl io::BufRead) -> Result<PathBuf, String> {
+    let mut line = String::new();
+    reader
+        .read_line(&mut line)
+        .map_err(|e| format!("cannot read prefix: {e}"))?;
+    parse_inline_prefix(line.trim_end_matches(['\n', '\r']))
+}
+
+fn parse_inline_prefix(s: &str) -> Result<PathBuf, String> {
     if s.is_empty() {
         return Err("prefix cannot be empty".to_string());
     }
@@ -105,20 +222,34 @@ pub(crate) fn parse_prefix(s: &str) -> Result<PathBuf, String> {
     Ok(path)
 }
 
+/// The prefix `prefix` below `default_prefix`, or `default_prefix` alone,
+/// validated like an inline prefix.
+pub(crate) fn compose_prefix(
+    default_prefix: &str,
+    prefix: Option<&Path>,
+) -> Result<PathBuf, String> {
+    let mut composed: PathBuf = Path::new(default_prefix).components().collect();
+    composed.extend(prefix);
+    let composed = composed
+        .to_str()
+        .ok_or_else(|| format!("prefix {composed:?} is not valid UTF-8"))?;
+    parse_inline_prefix(composed)
+}
+
 pub(crate) fn parse_spool(s: &str) -> Result<PathBuf, String> {
     if s.is_empty() {
         return Err("spool cannot be empty".to_string());
     }
+    // the spool directory itself is checked before it is used, see check_spool
     let path = PathBuf::from_str(s).map_err(|e| e.to_string())?;
-    if path.is_symlink() {
-        return Err("spool cannot be a symlink".to_string());
-    }
-    if !path.is_dir() {
-        return Err("spool must be a directory".to_string());
-    }
     Ok(path)
 }
 
+pub(crate) fn parse_log_level(s: &str) -> Result<LevelFilter, String> {
+    LevelFilter::from_str(s)
+        .map_err(|_| "log level must be off, error, warn, info, debug or trace".to_string())
+}
+
 pub(crate) fn parse_config(s: &str) -> Result<PathBuf, String> {
     if s.is_empty() {
         return Err("config cannot be empty".to_string());
@@ -19,6 +19,7 @@ use super::CliError;
 pub enum CliResult {
     Ok = 0,
     IoError = 42,
+    CryptoError = 43,
     Usage = 64,
     LogError = 65,
     ConfigError = 78,
@@ -47,9 +48,13 @@ impl From<CliError> for CliResult {
         match error {
             CliError::BaseDirError(_, code) => code,
             CliError::ConfigurationError(_, code) => code,
+            CliError::CryptoError(_, code) => code,
             CliError::EnvError(_, code) => code,
             CliError::IoError(_, code) => code,
             CliError::LogError(_, code) => code,
+            CliError::PrefixError(_, code) => code,
+            CliError::ChunkSizeError(_, code) => code,
+            CliError::SpoolError(_, code) => code,
         }
     }
 }
@@ -7,22 +7,32 @@
 // This file may not be copied, modified, or distributed except according
 // to those terms.
 
-use super::constants::DEFAULT_CHUNK_SIZE;
+use super::constants::{
+    DEFAULT_CHUNK_SIZE, DEFAULT_COMPRESSION_BUFFERS, DEFAULT_FREEZE_WORKERS, DEFAULT_KEEP_FAILED,
+    DEFAULT_MIN_FREE_SPACE,
+};
 use super::parse::{
-    parse_chunk_size, parse_fd, parse_keyring, parse_prefix, parse_timestamp_for_ulid, parse_ulid,
-    parse_uuid,
+    parse_allowed_algo, parse_byte_size, parse_chunk_size, parse_duration, parse_fd,
+    parse_fingerprint, parse_keyring, parse_nonzero, parse_prefetch, parse_prefix, parse_time,
+    parse_timestamp_for_ulid, parse_ulid, parse_uuid,
 };
 
 #[cfg(feature = "age")]
 use super::parse::parse_recipient;
 #[cfg(feature = "age")]
 use crate::crypto::age::RecipientSpec;
+#[cfg(feature = "gnupg")]
+use crate::crypto::gnupg;
 
 use crate::compression::CompressionType;
-use clap::{value_parser, Parser, Subcommand};
+use crate::core::SyncPolicy;
+use crate::crypto::openpgp::{AllowedAlgorithm, CipherSelection, EncryptionUsage};
+use clap::{value_parser, Parser, Subcommand, ValueEnum};
 use sequoia_openpgp::Cert;
 use std::fmt;
+use std::io;
 use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
 use ulid::Ulid;
 
 #[derive(Subcommand, Debug)]
@@ -39,6 +49,15 @@ pub enum Command {
     /// Decrypt, uncompress downloaded backup files
     #[command(arg_required_else_help = false)]
     Restore(Restore),
+    /// Split a queued backup into chunks of a different size
+    #[command(arg_required_else_help = false)]
+    Rechunk(Rechunk),
+    /// Validate and print the effective configuration
+    #[command(arg_required_else_help = false)]
+    ConfigCheck(ConfigCheck),
+    /// Print version information
+    #[command(arg_required_else_help = false)]
+    Version(Version),
 }
 
 impl fmt::Display for Command {
@@ -48,35 +67,207 @@ impl fmt::Display for Command {
             Command::Freeze(_) => "freeze",
             Command::Thaw(_) => "thaw",
             Command::Restore(_) => "restore",
+            Command::Rechunk(_) => "rechunk",
+            Command::ConfigCheck(_) => "config-check",
+            Command::Version(_) => "version",
         };
         write!(f, "{command_name}")
     }
 }
 
+impl Backup {
+    /// All recipient certificates, including those exported from GnuPG.
+    pub fn certs(&self) -> io::Result<Vec<Cert>> {
+        #[cfg_attr(not(feature = "gnupg"), allow(unused_mut))]
+        let mut certs: Vec<Cert> = self.keyring.iter().flatten().cloned().collect();
+        #[cfg(feature = "gnupg")]
+        for key_id in &self.keyring_from_gpg_agent {
+            certs.extend(gnupg::export_certs(key_id)?);
+        }
+        Ok(certs)
+    }
+}
+
+impl Restore {
+    /// Compression type to decompress with, or `None` to detect it.
+    pub fn forced_compression(&self) -> Option<CompressionType> {
+        if self.no_compression_detection {
+            return Some(CompressionType::None);
+        }
+        self.compression
+    }
+
+    /// Whether the stream is passed through verbatim without decompressing it.
+    pub fn passes_through(&self) -> bool {
+        self.forced_compression() == Some(CompressionType::None)
+    }
+
+    /// All secret keys, including those exported from GnuPG.
+    pub fn certs(&self) -> io::Result<Vec<Cert>> {
+        #[cfg_attr(not(feature = "gnupg"), allow(unused_mut))]
+        let mut certs: Vec<Cert> = self.keyring.iter().flatten().cloned().collect();
+        #[cfg(feature = "gnupg")]
+        for key_id in &self.keyring_from_gpg_agent {
+            certs.extend(gnupg::export_secret_keys(key_id)?);
+        }
+        Ok(certs)
+    }
+}
+
+impl Command {
+    /// Chunk size of a command writing chunks, and whether chunks smaller
+    /// than the S3 minimum part size are allowed.
+    pub fn chunk_size(&self) -> Option<(usize, bool)> {
+        match self {
+            Command::Backup(backup) => Some((backup.size, backup.allow_small_chunks)),
+            Command::Rechunk(rechunk) => Some((rechunk.size, rechunk.allow_small_chunks)),
+            _ => None,
+        }
+    }
+
+    pub fn prefix(&self) -> Option<&PathBuf> {
+        match self {
+            Command::Backup(backup) => backup.prefix.as_ref(),
+            Command::Freeze(freeze) => freeze.prefix.as_ref(),
+            Command::Thaw(_) => None,
+            Command::Restore(restore) => restore.prefix.as_ref(),
+            Command::Rechunk(rechunk) => rechunk.prefix.as_ref(),
+            Command::ConfigCheck(_) => None,
+            Command::Version(_) => None,
+        }
+    }
+
+    pub fn prefix_mut(&mut self) -> Option<&mut Option<PathBuf>> {
+        match self {
+            Command::Backup(backup) => Some(&mut backup.prefix),
+            Command::Freeze(freeze) => Some(&mut freeze.prefix),
+            Command::Restore(restore) => Some(&mut restore.prefix),
+            Command::Rechunk(rechunk) => Some(&mut rechunk.prefix),
+            Command::Thaw(_) | Command::ConfigCheck(_) | Command::Version(_) => None,
+        }
+    }
+
+    pub fn vault(&self) -> Option<uuid::Uuid> {
+        match self {
+            Command::Backup(backup) => Some(backup.vault),
+            Command::Freeze(freeze) => freeze.vault,
+            Command::Restore(restore) => Some(restore.vault),
+            Command::Rechunk(rechunk) => Some(rechunk.vault),
+            Command::Thaw(_) | Command::ConfigCheck(_) | Command::Version(_) => None,
+        }
+    }
+}
+
+#[cfg(not(feature = "age"))]
 #[derive(Parser, Debug)]
 #[command(about = "Not shown")]
 pub struct Backup {
+    #[arg(long, help = "abort if compression expands the input")]
+    pub abort_if_expanding: bool,
+
+    #[arg(
+        long,
+        help = "write ASCII-armored chunks, e.g., for small backups sent by mail"
+    )]
+    pub armor: bool,
+
+    #[arg(
+        long,
+        help = "write SHA256SUMS of the chunks to the backup directory and the freeze queue, to check them with sha256sum -c"
+    )]
+    pub checksum: bool,
+
     #[arg(short = 'C', long, help = "compression type", value_enum, default_value_t = CompressionType::default())]
     pub compression: CompressionType,
 
+    #[arg(long, help = "compressed buffers queued for the encryption writer thread (compression threads are set with --zstd-workers)", default_value_t = DEFAULT_COMPRESSION_BUFFERS, value_parser = parse_nonzero)]
+    pub compression_buffers: usize,
+
+    #[arg(short, long, help = "replace an unfinished backup with the same ulid")]
+    pub force: bool,
+
     #[arg(short, long, help = "input file", value_parser = value_parser!(PathBuf))]
     pub input: Option<PathBuf>,
 
-    #[arg(short, long, help = "keyring", action = clap::ArgAction::Append, required = true, value_parser = parse_keyring)]
+    #[arg(long, help = "read input from inherited file descriptor", conflicts_with = "input", value_parser = parse_fd)]
+    pub input_fd: Option<i32>,
+
+    #[arg(long, value_name = "DIR", help = "read input from the chunk files chunk.1, chunk.2, … of a complete chunk set in DIR", conflicts_with_all = ["input", "input_fd", "mmap"], value_parser = value_parser!(PathBuf))]
+    pub input_chunks: Option<PathBuf>,
+
+    #[arg(long, help = "memory-map a regular input file")]
+    pub mmap: bool,
+
+    #[arg(
+        long,
+        help = "compress in the main thread instead of a separate writer thread"
+    )]
+    pub no_threaded_compression: bool,
+
+    #[cfg_attr(not(feature = "gnupg"), arg(required = true))]
+    #[cfg_attr(
+        feature = "gnupg",
+        arg(required_unless_present = "keyring_from_gpg_agent")
+    )]
+    #[arg(short, long, help = "keyring", action = clap::ArgAction::Append, value_parser = parse_keyring)]
     pub keyring: Vec<Vec<Cert>>,
 
-    #[arg(short, long, help = "prefix path in vault", value_parser = parse_prefix)]
+    #[cfg(feature = "gnupg")]
+    #[arg(long, value_name = "KEYID", help = "keyring exported from GnuPG for key id (requires gpg on PATH)", action = clap::ArgAction::Append)]
+    pub keyring_from_gpg_agent: Vec<String>,
+
+    #[arg(short, long, help = "prefix path in vault, @FILE or - to read it from a file or stdin", value_parser = parse_prefix)]
     pub prefix: Option<PathBuf>,
 
+    #[cfg(feature = "fec")]
+    #[arg(long, help = "number of Reed-Solomon parity chunks", default_value_t = 0, value_parser = value_parser!(u64).range(0..256))]
+    pub parity: u64,
+
+    #[arg(long, help = "key usage flag of recipient encryption subkeys", value_enum, default_value_t = EncryptionUsage::default())]
+    pub encryption_usage: EncryptionUsage,
+
+    #[arg(long, help = "fail if any recipient certificate is unusable")]
+    pub require_all_recipients: bool,
+
+    #[arg(long, help = "symmetric algorithm of the session key", value_enum, default_value_t = CipherSelection::default())]
+    pub cipher: CipherSelection,
+
+    #[arg(long, help = "only encrypt to the keyring certificate with fingerprint or key id", action = clap::ArgAction::Append, value_parser = parse_fingerprint)]
+    pub recipient_fingerprint: Vec<String>,
+
     #[arg(group = "backup-ulid", short, long, help = "backup timestamp", value_parser = parse_timestamp_for_ulid)]
     pub timestamp: Option<Ulid>,
 
     #[arg(group = "backup-ulid", short, long, help = "backup ulid", value_parser = parse_ulid)]
     pub ulid: Option<Ulid>,
 
+    #[arg(long, help = "maximum number of chunks")]
+    pub max_chunks: Option<u64>,
+
+    #[arg(long, value_name = "N", help = "keep the chunks of the last N failed backups in the spool for inspection, 0 removes them", default_value_t = DEFAULT_KEEP_FAILED)]
+    pub keep_failed: usize,
+
+    #[arg(long, value_name = "SIZE", help = "abort before writing chunks if the spool filesystem has less free space, or less than the input file size", value_parser = parse_byte_size, default_value_t = DEFAULT_MIN_FREE_SPACE)]
+    pub min_free_space: u64,
+
     #[arg(short, long, help = "chunk size", value_parser = parse_chunk_size, default_value_t = DEFAULT_CHUNK_SIZE)]
     pub size: usize,
 
+    #[arg(
+        long,
+        help = "allow chunks smaller than the S3 minimum part size of 5 MiB"
+    )]
+    pub allow_small_chunks: bool,
+
+    #[arg(long, help = "sync chunks before publishing them for freeze", value_enum, default_value_t = SyncPolicy::default())]
+    pub sync_policy: SyncPolicy,
+
+    #[arg(long, help = "zstd long distance matching with window log", num_args = 0..=1, default_missing_value = "27", value_parser = value_parser!(u32).range(10..=31))]
+    pub zstd_long: Option<u32>,
+
+    #[arg(long, help = "number of zstd compression threads", default_value_t = 0)]
+    pub zstd_workers: u32,
+
     #[arg(short, long, help = "vault", value_parser = parse_uuid)]
     pub vault: uuid::Uuid,
 }
@@ -85,38 +276,159 @@ pub struct Backup {
 #[derive(Parser, Debug)]
 #[command(about = "Not shown")]
 pub struct Backup {
+    #[arg(long, help = "abort if compression expands the input")]
+    pub abort_if_expanding: bool,
+
+    #[arg(
+        long,
+        help = "write ASCII-armored chunks, e.g., for small backups sent by mail"
+    )]
+    pub armor: bool,
+
+    #[arg(
+        long,
+        help = "write SHA256SUMS of the chunks to the backup directory and the freeze queue, to check them with sha256sum -c"
+    )]
+    pub checksum: bool,
+
     #[arg(short = 'C', long, help = "compression type", value_enum, default_value_t = CompressionType::default())]
     pub compression: CompressionType,
 
+    #[arg(long, help = "compressed buffers queued for the encryption writer thread (compression threads are set with --zstd-workers)", default_value_t = DEFAULT_COMPRESSION_BUFFERS, value_parser = parse_nonzero)]
+    pub compression_buffers: usize,
+
+    #[arg(short, long, help = "replace an unfinished backup with the same ulid")]
+    pub force: bool,
+
     #[arg(short, long, help = "input file", value_parser = value_parser!(PathBuf))]
     pub input: Option<PathBuf>,
 
-    #[arg(short, long, help = "keyring", action = clap::ArgAction::Append, required = true, value_parser = parse_keyring)]
+    #[arg(long, help = "read input from inherited file descriptor", conflicts_with = "input", value_parser = parse_fd)]
+    pub input_fd: Option<i32>,
+
+    #[arg(long, value_name = "DIR", help = "read input from the chunk files chunk.1, chunk.2, … of a complete chunk set in DIR", conflicts_with_all = ["input", "input_fd", "mmap"], value_parser = value_parser!(PathBuf))]
+    pub input_chunks: Option<PathBuf>,
+
+    #[arg(long, help = "memory-map a regular input file")]
+    pub mmap: bool,
+
+    #[arg(
+        long,
+        help = "compress in the main thread instead of a separate writer thread"
+    )]
+    pub no_threaded_compression: bool,
+
+    #[cfg_attr(
+        not(feature = "gnupg"),
+        arg(required_unless_present_any = ["recipient", "age_passphrase"])
+    )]
+    #[cfg_attr(
+        feature = "gnupg",
+        arg(required_unless_present_any = ["keyring_from_gpg_agent", "recipient", "age_passphrase"])
+    )]
+    #[arg(short, long, help = "keyring", action = clap::ArgAction::Append, value_parser = parse_keyring)]
     pub keyring: Vec<Vec<Cert>>,
 
-    #[arg(short, long, help = "prefix path in vault", value_parser = parse_prefix)]
+    #[cfg(feature = "gnupg")]
+    #[arg(long, value_name = "KEYID", help = "keyring exported from GnuPG for key id (requires gpg on PATH)", action = clap::ArgAction::Append)]
+    pub keyring_from_gpg_agent: Vec<String>,
+
+    #[arg(short, long, help = "prefix path in vault, @FILE or - to read it from a file or stdin", value_parser = parse_prefix)]
     pub prefix: Option<PathBuf>,
 
+    #[cfg(feature = "fec")]
+    #[arg(long, help = "number of Reed-Solomon parity chunks", default_value_t = 0, value_parser = value_parser!(u64).range(0..256))]
+    pub parity: u64,
+
+    #[arg(long, help = "key usage flag of recipient encryption subkeys", value_enum, default_value_t = EncryptionUsage::default())]
+    pub encryption_usage: EncryptionUsage,
+
+    #[arg(long, help = "fail if any recipient certificate is unusable")]
+    pub require_all_recipients: bool,
+
+    #[arg(long, help = "symmetric algorithm of the session key", value_enum, default_value_t = CipherSelection::default())]
+    pub cipher: CipherSelection,
+
+    #[arg(long, help = "only encrypt to the keyring certificate with fingerprint or key id", action = clap::ArgAction::Append, value_parser = parse_fingerprint)]
+    pub recipient_fingerprint: Vec<String>,
+
     #[arg(group = "backup-ulid", short, long, help = "backup timestamp", value_parser = parse_timestamp_for_ulid)]
     pub timestamp: Option<Ulid>,
 
     #[arg(group = "backup-ulid", short, long, help = "backup ulid", value_parser = parse_ulid)]
     pub ulid: Option<Ulid>,
 
-    #[arg(short, long, help = "recipient", value_parser = parse_recipient)]
+    #[cfg_attr(feature = "gnupg", arg(conflicts_with = "keyring_from_gpg_agent"))]
+    #[arg(short, long, help = "encrypt an age stream to recipient instead of the keyring", conflicts_with_all = ["keyring", "armor"], value_parser = parse_recipient)]
     pub recipient: Option<Vec<RecipientSpec>>,
 
+    #[cfg_attr(feature = "gnupg", arg(conflicts_with = "keyring_from_gpg_agent"))]
+    #[arg(
+        long,
+        help = "encrypt an age stream with a passphrase (scrypt) instead of recipients or the keyring",
+        conflicts_with_all = ["recipient", "keyring", "armor"]
+    )]
+    pub age_passphrase: bool,
+
+    #[arg(short = 'P', long, help = "read age passphrase from file descriptor", requires = "age_passphrase", value_parser = parse_fd)]
+    pub pass_fd: Option<i32>,
+
+    #[arg(long, help = "maximum number of chunks")]
+    pub max_chunks: Option<u64>,
+
+    #[arg(long, value_name = "N", help = "keep the chunks of the last N failed backups in the spool for inspection, 0 removes them", default_value_t = DEFAULT_KEEP_FAILED)]
+    pub keep_failed: usize,
+
+    #[arg(long, value_name = "SIZE", help = "abort before writing chunks if the spool filesystem has less free space, or less than the input file size", value_parser = parse_byte_size, default_value_t = DEFAULT_MIN_FREE_SPACE)]
+    pub min_free_space: u64,
+
     #[arg(short, long, help = "chunk size", value_parser = parse_chunk_size, default_value_t = DEFAULT_CHUNK_SIZE)]
     pub size: usize,
 
-    #[arg(short, long, help = "vault", value_parser = parse_uuid, requires = "backup-ulid")]
+    #[arg(
+        long,
+        help = "allow chunks smaller than the S3 minimum part size of 5 MiB"
+    )]
+    pub allow_small_chunks: bool,
+
+    #[arg(long, help = "sync chunks before publishing them for freeze", value_enum, default_value_t = SyncPolicy::default())]
+    pub sync_policy: SyncPolicy,
+
+    #[arg(long, help = "zstd long distance matching with window log", num_args = 0..=1, default_missing_value = "27", value_parser = value_parser!(u32).range(10..=31))]
+    pub zstd_long: Option<u32>,
+
+    #[arg(long, help = "number of zstd compression threads", default_value_t = 0)]
+    pub zstd_workers: u32,
+
+    #[arg(short, long, help = "vault", value_parser = parse_uuid)]
     pub vault: uuid::Uuid,
 }
 
 #[derive(Parser, Debug)]
 #[command(about = "Not shown")]
 pub struct Freeze {
-    #[arg(requires = "ulid", short, long, help = "prefix path in vault", value_parser = parse_prefix)]
+    #[arg(
+        long,
+        help = "follow symlinks in the freeze queue instead of ignoring them"
+    )]
+    pub follow_symlinks: bool,
+
+    #[arg(long, help = "number of freeze worker threads", default_value_t = DEFAULT_FREEZE_WORKERS, value_parser = parse_nonzero)]
+    pub freeze_workers: usize,
+
+    #[arg(long, help = "capacity of the freeze event queue (default: unbounded)", value_parser = parse_nonzero)]
+    pub freeze_queue_depth: Option<usize>,
+
+    #[arg(long, help = "stop freezing after duration (e.g., 6h)", value_parser = parse_duration)]
+    pub max_runtime: Option<Duration>,
+
+    #[arg(long, help = "write Prometheus metrics to textfile", value_parser = value_parser!(PathBuf))]
+    pub metrics_file: Option<PathBuf>,
+
+    #[arg(long, help = "do not lock the spool against other freeze processes")]
+    pub no_lock: bool,
+
+    #[arg(requires = "ulid", short, long, help = "prefix path in vault, @FILE or - to read it from a file or stdin", value_parser = parse_prefix)]
     pub prefix: Option<PathBuf>,
 
     #[arg(requires = "vault", short, long, help = "backup ulid", value_parser = parse_ulid)]
@@ -133,24 +445,196 @@ pub struct Thaw {}
 #[derive(Parser, Debug)]
 #[command(about = "Not shown")]
 pub struct Restore {
-    #[arg(short = 'C', long, help = "compression type", value_enum)]
+    #[arg(
+        short = 'C',
+        long,
+        help = "decompress with compression type instead of detecting it from magic bytes (none passes the stream through verbatim)",
+        value_enum
+    )]
     pub compression: Option<CompressionType>,
 
-    #[arg(short, long, help = "keyring", action = clap::ArgAction::Append, required = true, value_parser = parse_keyring)]
+    #[arg(
+        long,
+        help = "pass the stream through verbatim without detecting compression, same as --compression none",
+        conflicts_with = "compression"
+    )]
+    pub no_compression_detection: bool,
+
+    #[cfg_attr(
+        all(not(feature = "gnupg"), not(feature = "age")),
+        arg(required_unless_present_any = ["inspect", "dump_packets"])
+    )]
+    #[cfg_attr(
+        all(feature = "gnupg", not(feature = "age")),
+        arg(required_unless_present_any = ["keyring_from_gpg_agent", "inspect", "dump_packets"])
+    )]
+    #[cfg_attr(
+        all(not(feature = "gnupg"), feature = "age"),
+        arg(required_unless_present_any = ["age_identity", "age_passphrase", "inspect", "dump_packets"])
+    )]
+    #[cfg_attr(
+        all(feature = "gnupg", feature = "age"),
+        arg(required_unless_present_any = ["keyring_from_gpg_agent", "age_identity", "age_passphrase", "inspect", "dump_packets"])
+    )]
+    #[arg(short, long, help = "keyring", action = clap::ArgAction::Append, value_parser = parse_keyring)]
     pub keyring: Vec<Vec<Cert>>,
 
+    #[cfg(feature = "age")]
+    #[arg(long, value_name = "FILE", help = "decrypt an age stream with the X25519 identities or SSH private key in FILE", action = clap::ArgAction::Append, value_parser = value_parser!(PathBuf))]
+    pub age_identity: Vec<PathBuf>,
+
+    #[cfg(feature = "age")]
+    #[arg(long, help = "decrypt an age stream encrypted with a passphrase")]
+    pub age_passphrase: bool,
+
+    #[cfg(feature = "age")]
+    #[arg(long, value_name = "FD", help = "read age passphrase from file descriptor", requires = "age_passphrase", value_parser = parse_fd)]
+    pub age_pass_fd: Option<i32>,
+
+    #[cfg(feature = "gnupg")]
+    #[arg(long, value_name = "KEYID", help = "secret keys exported from GnuPG for key id (requires gpg on PATH)", action = clap::ArgAction::Append)]
+    pub keyring_from_gpg_agent: Vec<String>,
+
     #[arg(short = 'P', long, help = "read password from file descriptor", value_parser = parse_fd)]
     pub pass_fd: Option<i32>,
 
-    #[arg(short, long, help = "output file", value_parser = value_parser!(PathBuf))]
-    pub output: Option<PathBuf>,
+    #[arg(long, help = "stream chunks directly from the vault bucket")]
+    pub from_bucket: bool,
+
+    #[arg(
+        long,
+        help = "follow symlinks in the restore queue instead of ignoring them"
+    )]
+    pub follow_symlinks: bool,
+
+    #[arg(
+        long,
+        help = "decrypt and verify the complete restore queue before writing any output",
+        conflicts_with = "from_bucket"
+    )]
+    pub scan_first: bool,
+
+    #[arg(
+        long,
+        help = "list the recipients of the backup and available secret keys without decrypting"
+    )]
+    pub inspect: bool,
+
+    #[arg(
+        long,
+        hide = true,
+        help = "print the OpenPGP packet tree of the backup to stderr, decrypted if a secret key matches",
+        conflicts_with = "inspect"
+    )]
+    pub dump_packets: bool,
+
+    #[arg(short, long, help = "overwrite existing output file")]
+    pub force: bool,
+
+    #[arg(
+        long,
+        help = "allow writing to an existing block or character device or FIFO output"
+    )]
+    pub allow_device: bool,
+
+    #[arg(short, long, help = "output file, repeat to write several copies", action = clap::ArgAction::Append, value_parser = value_parser!(PathBuf))]
+    pub output: Vec<PathBuf>,
+
+    #[arg(long, help = "write output to inherited file descriptor", conflicts_with = "output", value_parser = parse_fd)]
+    pub output_fd: Option<i32>,
+
+    #[arg(long, help = "fail on misnamed chunk files instead of ignoring them")]
+    pub strict_chunk_names: bool,
+
+    #[arg(
+        long,
+        help = "do not verify the restored stream against the manifest digest"
+    )]
+    pub no_verify_digest: bool,
+
+    #[arg(long, help = "restore the file mode of the backup input")]
+    pub preserve_mode: bool,
+
+    #[arg(long, help = "restore the owner and group of the backup input")]
+    pub preserve_owner: bool,
+
+    #[arg(long, help = "evaluate the OpenPGP policy at RFC 3339 time instead of now", value_parser = parse_time)]
+    pub policy_time: Option<SystemTime>,
 
-    #[arg(short, long, help = "prefix path in vault", value_parser = parse_prefix)]
+    #[arg(long, help = "accept a hash, symmetric or asymmetric algorithm the OpenPGP policy rejects", action = clap::ArgAction::Append, value_parser = parse_allowed_algo)]
+    pub allow_algo: Vec<AllowedAlgorithm>,
+
+    #[arg(long, help = "preallocate output files with the input size of the backup, or with SIZE", value_name = "SIZE", num_args = 0..=1, value_parser = parse_byte_size)]
+    pub preallocate: Option<Option<u64>>,
+
+    #[arg(long, help = "open up to N chunks ahead of reading (0 to 2)", default_value_t = 0, value_parser = parse_prefetch)]
+    pub prefetch_chunks: usize,
+
+    #[arg(short, long, help = "prefix path in vault, @FILE or - to read it from a file or stdin", value_parser = parse_prefix)]
+    pub prefix: Option<PathBuf>,
+
+    #[arg(short, long, help = "vault", value_parser = parse_uuid)]
+    pub vault: uuid::Uuid,
+
+    #[arg(short, long, help = "backup ulid", required_unless_present_any = ["at", "latest"], value_parser = parse_ulid)]
+    pub ulid: Option<Ulid>,
+
+    #[arg(long, value_name = "TIME", help = "restore the latest backup of the vault and prefix created at or before RFC 3339 time, instead of --ulid", conflicts_with = "ulid", value_parser = parse_time)]
+    pub at: Option<SystemTime>,
+
+    #[arg(
+        long,
+        help = "restore the most recent backup of the vault and prefix, instead of --ulid",
+        conflicts_with_all = ["ulid", "at"]
+    )]
+    pub latest: bool,
+}
+
+#[derive(Parser, Debug)]
+#[command(about = "Not shown")]
+pub struct Rechunk {
+    #[arg(short, long, help = "prefix path in vault, @FILE or - to read it from a file or stdin", value_parser = parse_prefix)]
     pub prefix: Option<PathBuf>,
 
+    #[arg(short, long, help = "new chunk size", value_parser = parse_chunk_size, default_value_t = DEFAULT_CHUNK_SIZE)]
+    pub size: usize,
+
+    #[arg(
+        long,
+        help = "allow chunks smaller than the S3 minimum part size of 5 MiB"
+    )]
+    pub allow_small_chunks: bool,
+
+    #[arg(long, help = "sync chunks before publishing them for freeze", value_enum, default_value_t = SyncPolicy::default())]
+    pub sync_policy: SyncPolicy,
+
     #[arg(short, long, help = "vault", value_parser = parse_uuid)]
     pub vault: uuid::Uuid,
 
-    #[arg(short, long, help = "backup ulid", value_parser = parse_ulid)]
+    #[arg(short, long, help = "ulid of the queued backup", value_parser = parse_ulid)]
     pub ulid: Ulid,
+
+    #[arg(long, help = "ulid of the re-chunked backup [default: new ulid]", value_parser = parse_ulid)]
+    pub new_ulid: Option<Ulid>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Toml,
+    Json,
+}
+
+#[derive(Parser, Debug)]
+#[command(about = "Not shown")]
+pub struct Version {
+    #[arg(long, help = "print dependency versions, features and algorithms")]
+    pub verbose: bool,
+}
+
+#[derive(Parser, Debug)]
+#[command(about = "Not shown")]
+pub struct ConfigCheck {
+    #[arg(short, long, help = "output format", value_enum, default_value_t = OutputFormat::default())]
+    pub format: OutputFormat,
 }
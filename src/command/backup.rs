@@ -8,166 +8,717 @@
 // to those terms.
 
 use crate::cli::Backup;
+use crate::compression::encoder::zstd_encoder;
 use crate::compression::CompressionType;
-use crate::core::backup_id::BackupId;
-use crate::core::constants::{CHUNK_FILE_MODE, CHUNK_FILE_PREFIX, DEFAULT_BUF_SIZE};
-use crate::core::path::{CreateDirectory, Queue, SpoolPathComponents};
-use crate::core::Split;
-use crate::crypto::openpgp::{build_encryptor, openpgp_error, storage_encryption_certs, Keyring};
+use crate::core::backup_id::{monotonic_ulid, BackupId};
+use crate::core::cat::{numbered_chunks, Cat};
+use crate::core::constants::{
+    ARMOR_WARN_SIZE, BACKUP_LOCK_FILE, CHECKSUM_FILE, CHUNK_FILE_MODE, CHUNK_FILE_PREFIX,
+    DEFAULT_BUF_SIZE, EXPANSION_CHECK_SIZE, FAILED_DIR, INLINE_COMPRESSION_MAX_SIZE,
+};
+#[cfg(feature = "fec")]
+use crate::core::fec;
+use crate::core::manifest::{write_sha256sums, HashingReader, Manifest};
+use crate::core::mmap::MmapReader;
+use crate::core::path::{
+    check_fd, check_free_space, check_same_filesystem, inherited_file, inherited_metadata,
+    link_or_copy, lock_file, CreateDirectory, Queue, SpoolPathComponents,
+};
+use crate::core::watch::channel_send_error;
+use crate::core::{Split, SyncPolicy};
+#[cfg(feature = "age")]
+use crate::crypto::age::{read_passphrase, AgeEncryption};
+use crate::crypto::encryption::Encryption;
+use crate::crypto::openpgp::{
+    encryption_certs, negotiate_symmetric_algo, select_recipients, Keyring,
+};
 use crate::Config;
 
+use nix::fcntl::Flock;
 use sequoia_openpgp::policy::StandardPolicy;
-use ulid::Ulid;
+use sequoia_openpgp::Cert;
 
+use std::cell::Cell;
+use std::ffi::OsStr;
 use std::fs;
 use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
 use std::os::unix::prelude::OpenOptionsExt;
 use std::path::{Path, PathBuf};
 
-// https://github.com/rust-lang/rust-clippy/issues/11631 breaks unwrap_or_else(Ulid::new)
-#[allow(clippy::unwrap_or_default)]
 pub fn perform_backup(config: &Config, backup: &Backup) -> io::Result<()> {
+    check_input_outside_spool(backup, config.spool())?;
+    if let Some(freeze_spool) = config.freeze_spool() {
+        check_input_outside_spool(backup, freeze_spool)?;
+    }
+    // chunks of a regular input file take about its size before compression
+    let input_size = input_metadata(backup)?.map(|metadata| metadata.len());
+    let required = backup.min_free_space.max(input_size.unwrap_or(0));
+    check_free_space(config.spool(), required)?;
+    if let Some(freeze_spool) = config.freeze_spool() {
+        check_free_space(freeze_spool, required)?;
+    }
+    if let Some(input_size) = input_size.filter(|&len| len < backup.size as u64) {
+        log::info!(
+            "Input of {input_size} bytes is smaller than the chunk size {size}, \
+             the backup is likely a single chunk",
+            size = backup.size
+        );
+    }
+
     let prefix_str_maybe = backup.prefix.as_ref().and_then(|path| path.to_str());
-    let backup_id = BackupId::new(
-        backup.vault,
-        prefix_str_maybe,
-        backup.ulid.or(backup.timestamp).unwrap_or_else(Ulid::new),
-    );
+    let requested_ulid = backup.ulid.or(backup.timestamp);
+    let (spool_path_components, backup_dir, created) = loop {
+        let backup_id = BackupId::new(
+            backup.vault,
+            prefix_str_maybe,
+            requested_ulid.unwrap_or_else(monotonic_ulid),
+        );
+        let spool_path_components =
+            SpoolPathComponents::new(config.spool().to_path_buf(), backup_id)
+                .with_freeze_spool(config.freeze_spool());
+        let (backup_dir, created) =
+            spool_path_components.try_with_queue_path(Queue::Backup, CreateDirectory::Recursive)?;
+        if !created && requested_ulid.is_none() {
+            // another process picked the same ulid, do not touch its backup
+            log::warn!(
+                "Generated ulid collides with existing backup {backup_dir:?}, regenerating…"
+            );
+            continue;
+        }
+        break (spool_path_components, backup_dir, created);
+    };
+    let (freeze_dir, _) =
+        spool_path_components.try_with_queue_path(Queue::Freeze, CreateDirectory::Recursive)?;
+    // held until the backup is queued for freeze or discarded
+    let lock = lock_backup_dir(&backup_dir)?;
+    check_same_filesystem(&backup_dir, &freeze_dir)?;
+    if !created {
+        let backup_uri = spool_path_components
+            .uri()
+            .expect("cannot create backup uri");
+        if !backup.force {
+            unlock_backup_dir(&backup_dir, lock)?;
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "Backup {backup_uri} already exists in {backup_dir:?}, \
+                     use a new ulid or --force to replace it"
+                ),
+            ));
+        }
+        log::warn!("Replacing existing backup {backup_uri} in {backup_dir:?}");
+        clear_backup_dirs(&backup_dir, &freeze_dir)?;
+    }
 
-    let spool_path_components = SpoolPathComponents::new(config.cli.spool.clone(), backup_id);
-    let backup_dir =
-        spool_path_components.with_queue_path(Queue::Backup, CreateDirectory::Recursive)?;
-    let freeze_dir =
-        spool_path_components.with_queue_path(Queue::Freeze, CreateDirectory::Recursive)?;
+    let policy = StandardPolicy::new();
+    let certs = backup.certs()?;
+    let encryption = backup_encryption(backup, &certs, &policy)?;
 
-    #[cfg(feature = "age")]
-    {
-        let mut recipients: Vec<Box<dyn age::Recipient>> = vec![];
-        if backup.recipient.is_some() {
-            for recipient in backup.recipient.as_ref().expect("no recipient") {
-                recipients.push(recipient.get_recipient());
+    let backup_uri = spool_path_components
+        .uri()
+        .expect("cannot create backup uri");
+    log::debug!("Starting backup {backup_uri}");
+
+    // a failed backup must not leave chunks that look like a partial backup
+    let result = write_chunks(backup, &encryption, &backup_dir, &freeze_dir).and_then(|manifest| {
+        write_zero_file(&backup_dir, &freeze_dir, &manifest, &encryption, backup)
+    });
+    if let Err(err) = result {
+        // each queue directory stays in its spool, which may be another filesystem
+        let failed_dir = config.spool().join(FAILED_DIR);
+        let freeze_failed_dir = config
+            .freeze_spool()
+            .unwrap_or(config.spool())
+            .join(FAILED_DIR);
+        if backup.keep_failed == 0 {
+            log::error!("Backup {backup_uri} failed, removing incomplete chunks…");
+        } else {
+            log::error!("Backup {backup_uri} failed, moving incomplete chunks to {failed_dir:?}…");
+            match retain_failed_backup(&failed_dir, &freeze_failed_dir, &backup_dir, &freeze_dir) {
+                Ok(path) => {
+                    log::info!("Kept failed backup {backup_uri} in {path:?}");
+                    for dir in [&failed_dir, &freeze_failed_dir] {
+                        if let Err(prune_err) = prune_failed_backups(dir, backup.keep_failed) {
+                            log::error!("Cannot prune failed backups in {dir:?}: {prune_err}");
+                        }
+                    }
+                    return Err(err);
+                }
+                Err(keep_err) => {
+                    log::error!("Cannot keep failed backup {backup_uri}, removing it: {keep_err}")
+                }
             }
         }
-        log::debug!(
-            "Age Recipients: {recipients:?}",
-            recipients = backup.recipient
-        );
+        if let Err(clear_err) = discard_backup_dirs(&backup_dir, &freeze_dir) {
+            log::error!("Cannot remove incomplete backup {backup_uri}: {clear_err}");
+        }
+        return Err(err);
     }
 
-    if backup.keyring.is_empty() {
+    unlock_backup_dir(&backup_dir, lock)?;
+    log::info!("Queued backup {backup_uri} for freeze {freeze_dir:?}");
+    Ok(())
+}
+
+/// Encrypt with age if the backup has age recipients or a passphrase, or
+/// else with OpenPGP to the certificates of the keyrings.
+fn backup_encryption<'a>(
+    backup: &Backup,
+    certs: &'a [Cert],
+    policy: &'a StandardPolicy<'a>,
+) -> io::Result<Encryption<'a>> {
+    #[cfg(feature = "age")]
+    if backup.age_passphrase {
+        let passphrase = read_passphrase(backup.pass_fd, true)?;
+        return Ok(Encryption::Age(AgeEncryption::Passphrase(passphrase)));
+    }
+    #[cfg(feature = "age")]
+    if let Some(recipients) = backup.recipient.clone().filter(|r| !r.is_empty()) {
+        log::debug!("Age recipients: {recipients:?}");
+        return Ok(Encryption::Age(AgeEncryption::Recipients(recipients)));
+    }
+
+    let num_certs = certs.len();
+    if num_certs == 0 {
         return Err(io::Error::new(
             io::ErrorKind::InvalidInput,
             "Keyring is empty",
         ));
     }
-    log::debug!(
-        "OpenPGP keyring has {num:?} certificate(s)",
-        num = backup.keyring.len()
-    );
+    log::debug!("OpenPGP keyring has {num_certs:?} certificate(s)");
 
     // get certificates from keyring
-    let policy = StandardPolicy::new();
-    let cert_list: Keyring = storage_encryption_certs(&policy, backup.keyring.iter().flatten())?;
+    let recipients = select_recipients(certs.iter(), &backup.recipient_fingerprint)?;
+    let cert_list: Keyring = encryption_certs(
+        policy,
+        recipients.into_iter(),
+        backup.encryption_usage,
+        backup.require_all_recipients,
+    )?;
+    let symmetric_algo = negotiate_symmetric_algo(policy, &cert_list, backup.cipher);
+    Ok(Encryption::OpenPgp(cert_list, symmetric_algo))
+}
 
+/// Compress, encrypt and split the input into chunks, without the zero chunk.
+fn write_chunks(
+    backup: &Backup,
+    encryption: &Encryption,
+    backup_dir: &Path,
+    freeze_dir: &Path,
+) -> io::Result<Manifest> {
     // setup backup directory and splitter encryption sink
     // after we have some certificates for storage encryption
 
     // TODO signal handling, Ctrl+C does not finish stream https://rust-cli.github.io/book/in-depth/signals.html
-    let mut splitter = Split::new(&backup_dir, &freeze_dir, CHUNK_FILE_PREFIX, backup.size);
+    let mut splitter = Split::new(backup_dir, freeze_dir, CHUNK_FILE_PREFIX, backup.size)
+        .with_sync_policy(backup.sync_policy);
+    if let Some(max_chunks) = backup.max_chunks {
+        splitter = splitter.with_max_chunks(max_chunks);
+    }
 
-    let mut encryptor_sink = build_encryptor(cert_list, &mut splitter)?;
+    let mut encryptor_sink = encryption.writer(&mut splitter, backup.armor)?;
 
     // setup input after we created the backup directory and setup encryption to prevent
     // reading streams (or fifo files) that cannot be written later
-    let reader: Box<dyn io::Read> = build_reader(backup.input.as_ref())?;
-    let mut buffered_reader = io::BufReader::new(reader);
+    // before reading, the reader closes an inherited file descriptor when dropped
+    let metadata = input_metadata(backup)?;
+    if backup.armor
+        && metadata
+            .as_ref()
+            .is_some_and(|metadata| metadata.len() > ARMOR_WARN_SIZE)
+    {
+        log::warn!("ASCII armor inflates the backup of a large input by about a third");
+    }
+    let reader: Box<dyn io::Read> = build_reader(backup)?;
+    let mut buffered_reader = HashingReader::new(io::BufReader::new(reader));
 
-    let backup_uri = spool_path_components
-        .uri()
-        .expect("cannot create backup uri");
-    log::debug!("Starting backup {backup_uri}");
+    if backup.compression != CompressionType::Zstd
+        && (backup.zstd_long.is_some() || backup.zstd_workers > 0)
+    {
+        log::warn!(
+            "Ignoring Zstandard options for {compression:?} compression",
+            compression = backup.compression
+        );
+    }
 
     let copy_result = match backup.compression {
         CompressionType::None => {
             log::info!("Using no compression…");
             io::copy(&mut buffered_reader, &mut encryptor_sink)?
         }
-        CompressionType::Zstd => {
-            log::info!("Using Zstandard compression…");
-            thread_io::write::writer(
-                DEFAULT_BUF_SIZE,
-                1,
-                &mut encryptor_sink,
-                |writer| -> io::Result<u64> {
-                    let mut zstd_encoder = zstd::stream::Encoder::new(writer, 0)?;
-                    let result = compressor_worker(&mut buffered_reader, &mut zstd_encoder);
-                    if result.is_ok() {
-                        zstd_encoder.do_finish()?
-                    }
-                    result
-                },
-            )?
-        }
-        CompressionType::Lz4 => {
-            log::info!("Using LZ4 compression…");
-            thread_io::write::writer(
-                DEFAULT_BUF_SIZE,
-                1,
-                &mut encryptor_sink,
-                |writer| -> io::Result<u64> {
-                    let mut lz4_encoder = lz4_flex::frame::FrameEncoder::new(writer);
-                    let result = compressor_worker(&mut buffered_reader, &mut lz4_encoder);
-                    if result.is_ok() {
-                        lz4_encoder.try_finish()?
-                    }
-                    result
-                },
-            )?
+        compression => {
+            let mut compress = |writer: &mut dyn io::Write| match compression {
+                CompressionType::None => io::copy(&mut buffered_reader, writer),
+                CompressionType::Lz4 => compress_lz4(&mut buffered_reader, writer, backup),
+                CompressionType::Zstd => compress_zstd(&mut buffered_reader, writer, backup),
+            };
+            if threaded_compression(backup, metadata.as_ref()) {
+                log::debug!(
+                    "Compressing with {buffers} buffer(s) queued for the writer thread…",
+                    buffers = backup.compression_buffers
+                );
+                thread_io::write::writer(
+                    DEFAULT_BUF_SIZE,
+                    backup.compression_buffers,
+                    &mut encryptor_sink,
+                    |writer| compress(writer),
+                )?
+            } else {
+                log::debug!("Compressing without a separate writer thread…");
+                compress(&mut encryptor_sink)?
+            }
         }
     };
 
     log::debug!("Wrote total of {copy_result} bytes");
     encryptor_sink.flush()?;
-    encryptor_sink.finalize().map_err(openpgp_error)?;
+    encryptor_sink.finalize()?;
+    #[cfg(feature = "fec")]
+    let (chunks, written) = (splitter.chunks(), splitter.written());
     drop(splitter);
-    touch_zero_file(&backup_dir, &freeze_dir)?;
+    #[cfg(feature = "fec")]
+    fec::write_parity(
+        backup_dir,
+        freeze_dir,
+        CHUNK_FILE_PREFIX,
+        backup.size as u64,
+        chunks,
+        written,
+        backup.parity,
+    )?;
 
-    log::info!("Queued backup {backup_uri} for freeze {freeze_dir:?}");
+    let size = buffered_reader.bytes_read();
+    let sha256 = buffered_reader.hex_digest();
+    log::debug!("Input has {size} bytes and SHA-256 {sha256}");
+    let mut manifest = Manifest {
+        sha256: Some(sha256),
+        size: Some(size),
+        ..Default::default()
+    };
+    if let Some(metadata) = metadata {
+        log::debug!(
+            "Input has mode {mode:o}, uid {uid} and gid {gid}",
+            mode = metadata.mode() & 0o7777,
+            uid = metadata.uid(),
+            gid = metadata.gid()
+        );
+        manifest.mode = Some(metadata.mode() & 0o7777);
+        manifest.uid = Some(metadata.uid());
+        manifest.gid = Some(metadata.gid());
+    }
+    Ok(manifest)
+}
+
+/// Remove the chunks of a previous, unfinished run of the same backup.
+fn clear_backup_dirs(incoming: &Path, outgoing: &Path) -> io::Result<()> {
+    let zero_file = outgoing.join(CHUNK_FILE_PREFIX).with_extension("0");
+    if zero_file.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("Backup in {outgoing:?} is already queued for freeze, refusing to replace it"),
+        ));
+    }
+    for dir in [incoming, outgoing].into_iter().filter(|dir| dir.exists()) {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_file() && !path.ends_with(BACKUP_LOCK_FILE) {
+                log::debug!("Removing stale chunk {path:?}");
+                fs::remove_file(path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Remove the backup and freeze directories of a failed backup, skipping a
+/// directory that [`retain_failed_backup`] moved before it failed.
+pub(crate) fn discard_backup_dirs(incoming: &Path, outgoing: &Path) -> io::Result<()> {
+    clear_backup_dirs(incoming, outgoing)?;
+    if incoming.exists() {
+        fs::remove_file(incoming.join(BACKUP_LOCK_FILE))?;
+        fs::remove_dir(incoming)?;
+    }
+    if outgoing.exists() {
+        fs::remove_dir(outgoing)?;
+    }
+    Ok(())
+}
+
+/// Move the backup and freeze directories of a failed backup to a new
+/// directory in `failed_dir` and `freeze_failed_dir`, the failed directories
+/// of their spools, named after the time of failure and the ulid, so they
+/// neither look like a partial backup nor get lost.
+fn retain_failed_backup(
+    failed_dir: &Path,
+    freeze_failed_dir: &Path,
+    incoming: &Path,
+    outgoing: &Path,
+) -> io::Result<PathBuf> {
+    let ulid = incoming.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Backup directory {incoming:?} has no ulid"),
+        )
+    })?;
+    let mut name = chrono::Utc::now()
+        .format("%Y%m%dT%H%M%S%.3fZ-")
+        .to_string()
+        .into_bytes();
+    name.extend_from_slice(ulid.as_bytes());
+    let name = OsStr::from_bytes(&name);
+
+    // the freeze directory first, freeze must not find an incomplete backup
+    for (dir, failed_dir, queue) in [
+        (outgoing, freeze_failed_dir, Queue::Freeze),
+        (incoming, failed_dir, Queue::Backup),
+    ] {
+        let target = failed_dir.join(name);
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o755)
+            .create(&target)?;
+        fs::rename(dir, target.join(PathBuf::from(queue)))?;
+    }
+    Ok(failed_dir.join(name))
+}
+
+/// Remove all but the `keep` most recent failed backups in `failed_dir`.
+fn prune_failed_backups(failed_dir: &Path, keep: usize) -> io::Result<()> {
+    let mut failed = fs::read_dir(failed_dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    // names start with the time of failure
+    failed.sort_unstable();
+    let stale = failed.len().saturating_sub(keep);
+    for path in &failed[..stale] {
+        log::debug!("Removing failed backup {path:?}");
+        fs::remove_dir_all(path)?;
+    }
     Ok(())
 }
 
-fn touch_zero_file(incoming: &Path, outgoing: &Path) -> io::Result<()> {
+/// Lock the backup directory, so concurrent backups with the same backup id
+/// fail instead of writing interleaved chunks.
+pub(crate) fn lock_backup_dir(incoming: &Path) -> io::Result<Flock<fs::File>> {
+    lock_file(&incoming.join(BACKUP_LOCK_FILE), CHUNK_FILE_MODE).map_err(|err| {
+        if err.kind() == io::ErrorKind::WouldBlock {
+            io::Error::new(
+                err.kind(),
+                format!("Backup already in progress in {incoming:?}"),
+            )
+        } else {
+            err
+        }
+    })
+}
+
+/// Remove the lock file from the backup directory `incoming` before `lock` is
+/// released, so a finished backup does not keep it.
+pub(crate) fn unlock_backup_dir(incoming: &Path, lock: Flock<fs::File>) -> io::Result<()> {
+    fs::remove_file(incoming.join(BACKUP_LOCK_FILE))?;
+    drop(lock);
+    Ok(())
+}
+
+/// Write the zero chunk containing the encrypted manifest, which marks the
+/// backup as complete.
+fn write_zero_file(
+    incoming: &Path,
+    outgoing: &Path,
+    manifest: &Manifest,
+    encryption: &Encryption,
+    backup: &Backup,
+) -> io::Result<()> {
     let zero_file = incoming.join(CHUNK_FILE_PREFIX).with_extension("0");
-    log::trace!("Touch {zero_file:?}");
-    fs::OpenOptions::new()
+    log::trace!("Write manifest {zero_file:?}");
+    let file = fs::OpenOptions::new()
         .write(true)
         .create_new(true)
         .mode(CHUNK_FILE_MODE)
         .open(&zero_file)?;
+    let mut writer = io::BufWriter::new(&file);
+    let mut encryptor_sink = encryption.writer(&mut writer, backup.armor)?;
+    encryptor_sink.write_all(manifest.to_toml()?.as_bytes())?;
+    encryptor_sink.finalize()?;
+    writer.flush()?;
+    drop(writer);
+    backup.sync_policy.sync(&file)?;
+    // before the zero chunk marks the backup complete
+    if backup.checksum {
+        write_checksums(incoming, outgoing, &zero_file, backup.sync_policy)?;
+    }
     let zero_link = outgoing.join(CHUNK_FILE_PREFIX).with_extension("0");
     log::trace!("Link {zero_file:?}");
-    fs::hard_link(zero_file, zero_link)
+    link_or_copy(&zero_file, &zero_link, backup.sync_policy)
+}
+
+/// List the chunks in the freeze queue `outgoing` and the zero chunk
+/// `zero_file` in the checksum file of the backup directory `incoming`, and
+/// link it into the freeze queue to be frozen alongside the chunks.
+fn write_checksums(
+    incoming: &Path,
+    outgoing: &Path,
+    zero_file: &Path,
+    sync: SyncPolicy,
+) -> io::Result<()> {
+    let mut files = vec![zero_file.to_path_buf()];
+    for entry in fs::read_dir(outgoing)? {
+        files.push(entry?.path());
+    }
+    files[1..].sort();
+    let sums = incoming.join(CHECKSUM_FILE);
+    log::debug!(
+        "Writing checksums of {num} chunk(s) to {sums:?}",
+        num = files.len()
+    );
+    write_sha256sums(&files, &sums, sync)?;
+    let sums_link = outgoing.join(CHECKSUM_FILE);
+    log::trace!("Link {sums:?}");
+    link_or_copy(&sums, &sums_link, sync)
+}
+
+/// Writer that counts the bytes written to the inner writer.
+struct CountingWriter<'a, W: io::Write> {
+    inner: W,
+    count: &'a Cell<u64>,
+}
+
+impl<'a, W: io::Write> CountingWriter<'a, W> {
+    fn new(inner: W, count: &'a Cell<u64>) -> Self {
+        Self { inner, count }
+    }
+}
+
+impl<W: io::Write> io::Write for CountingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count.set(self.count.get() + written as u64);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn compression_ratio(input: u64, compressed: u64) -> f64 {
+    if input == 0 {
+        return 0.0;
+    }
+    compressed as f64 / input as f64
+}
+
+fn log_compression_ratio(input: u64, compressed: u64) {
+    log::info!(
+        "Compressed {input} bytes to {compressed} bytes (ratio {ratio:.3})",
+        ratio = compression_ratio(input, compressed)
+    );
+}
+
+/// Compress in a separate writer thread unless disabled, or the input is a
+/// small regular file where starting the thread dominates the backup time.
+fn threaded_compression(backup: &Backup, metadata: Option<&fs::Metadata>) -> bool {
+    if backup.no_threaded_compression {
+        return false;
+    }
+    let small_input =
+        metadata.is_some_and(|metadata| metadata.len() <= INLINE_COMPRESSION_MAX_SIZE);
+    !small_input
+}
+
+fn compress_zstd(
+    reader: &mut dyn io::Read,
+    writer: &mut dyn io::Write,
+    backup: &Backup,
+) -> io::Result<u64> {
+    log::info!("Using Zstandard compression…");
+    let compressed = Cell::new(0);
+    let counter = CountingWriter::new(writer, &compressed);
+    let mut zstd_encoder = zstd_encoder(counter, backup.zstd_long, backup.zstd_workers)?;
+    let result = compressor_worker(
+        reader,
+        &mut zstd_encoder,
+        &compressed,
+        backup.abort_if_expanding,
+    );
+    if let Ok(input) = result {
+        zstd_encoder.do_finish()?;
+        log_compression_ratio(input, compressed.get());
+    }
+    result
+}
+
+fn compress_lz4(
+    reader: &mut dyn io::Read,
+    writer: &mut dyn io::Write,
+    backup: &Backup,
+) -> io::Result<u64> {
+    log::info!("Using LZ4 compression…");
+    let compressed = Cell::new(0);
+    let counter = CountingWriter::new(writer, &compressed);
+    let mut lz4_encoder = lz4_flex::frame::FrameEncoder::new(counter);
+    let result = compressor_worker(
+        reader,
+        &mut lz4_encoder,
+        &compressed,
+        backup.abort_if_expanding,
+    );
+    if let Ok(input) = result {
+        lz4_encoder.try_finish()?;
+        log_compression_ratio(input, compressed.get());
+    }
+    result
 }
 
-fn compressor_worker(reader: &mut dyn io::Read, compressor: &mut dyn io::Write) -> io::Result<u64> {
+fn compressor_worker(
+    reader: &mut dyn io::Read,
+    compressor: &mut dyn io::Write,
+    compressed: &Cell<u64>,
+    abort_if_expanding: bool,
+) -> io::Result<u64> {
     log::trace!("Starting compressor worker…");
-    io::copy(reader, compressor)
+    if !abort_if_expanding {
+        return io::copy(reader, compressor);
+    }
+
+    let mut buf = [0u8; DEFAULT_BUF_SIZE];
+    let mut input = 0u64;
+    let mut checked = false;
+    loop {
+        let len = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        compressor.write_all(&buf[..len])?;
+        input += len as u64;
+
+        if !checked && input >= EXPANSION_CHECK_SIZE {
+            checked = true;
+            // flush buffered blocks so the compressed size is accurate
+            compressor.flush()?;
+            let ratio = compression_ratio(input, compressed.get());
+            log::debug!("Compression ratio after {input} bytes is {ratio:.3}");
+            if ratio >= 1.0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Compression expands input (ratio {ratio:.3} after {input} bytes), \
+                         input seems to be incompressible: retry with --compression none"
+                    ),
+                ));
+            }
+        }
+    }
+    Ok(input)
 }
 
-fn build_reader(path: Option<&PathBuf>) -> io::Result<Box<dyn io::Read>> {
-    let reader: Box<dyn io::Read> = match path {
-        Some(p) if p.as_path() == Path::new("-") => {
-            log::info!("Reading from stdin…");
-            Box::new(io::stdin())
+/// Concatenate a complete chunk set, the zero chunk is not part of the input.
+fn chunk_set_reader(dir: &Path) -> io::Result<Box<dyn io::Read>> {
+    let zero_chunk = dir.join(CHUNK_FILE_PREFIX).with_extension("0");
+    if !zero_chunk.is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Chunk set in {dir:?} is incomplete, {zero_chunk:?} is missing"),
+        ));
+    }
+    let (chunks, total) = numbered_chunks(dir)?;
+    let last = chunks.len() as u64;
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let index = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(CHUNK_FILE_PREFIX)?.strip_prefix('.'))
+            .and_then(|index| index.parse::<u64>().ok());
+        if index.is_some_and(|index| index > last) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Chunk set in {dir:?} has a gap after chunk.{last}, found {name:?}"),
+            ));
         }
-        None => {
-            log::info!("Reading from stdin…");
-            Box::new(io::stdin())
+    }
+    log::info!(
+        "Reading {num} chunk(s) with {total} bytes from {dir:?}…",
+        num = chunks.len()
+    );
+    let concat = Cat::new();
+    let tx = concat.tx();
+    for chunk in chunks {
+        // Cat skips chunks it cannot open, which would corrupt the input
+        fs::File::open(&chunk)?;
+        tx.send(Some(chunk)).map_err(channel_send_error)?;
+    }
+    tx.send(None).map_err(channel_send_error)?;
+    Ok(Box::new(concat))
+}
+
+/// Refuse an input inside the spool, the backup would read its own chunks.
+fn check_input_outside_spool(backup: &Backup, spool: &Path) -> io::Result<()> {
+    let input = match (backup.input_fd, backup.input.as_ref()) {
+        (Some(fd), _) => {
+            check_fd(fd, false)?;
+            // pipes and sockets link to names like pipe:[1234], not to a path
+            match fs::read_link(format!("/proc/self/fd/{fd}")) {
+                Ok(path) if path.is_absolute() => path,
+                _ => return Ok(()),
+            }
         }
-        Some(input) => {
-            log::info!("Opening {input:?}…");
-            Box::new(fs::File::open(input)?)
+        (None, Some(p)) if p.as_path() != Path::new("-") => p.clone(),
+        _ => return Ok(()),
+    };
+    // a missing input fails later when it is opened, a missing spool is created
+    let (Ok(input), Ok(spool)) = (input.canonicalize(), spool.canonicalize()) else {
+        return Ok(());
+    };
+    if input.starts_with(&spool) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Input {input:?} is inside spool {spool:?}, refusing to back up the spool"),
+        ));
+    }
+    Ok(())
+}
+
+/// Metadata of a regular input file, streams have no mode or owner to restore.
+fn input_metadata(backup: &Backup) -> io::Result<Option<fs::Metadata>> {
+    let metadata = match (backup.input_fd, backup.input.as_ref()) {
+        (Some(fd), _) => inherited_metadata(fd)?,
+        (None, Some(p)) if p.as_path() != Path::new("-") => fs::metadata(p)?,
+        _ => return Ok(None),
+    };
+    Ok(metadata.is_file().then_some(metadata))
+}
+
+fn build_reader(backup: &Backup) -> io::Result<Box<dyn io::Read>> {
+    if let Some(dir) = backup.input_chunks.as_ref() {
+        return chunk_set_reader(dir);
+    }
+    let (file, input) = match (backup.input_fd, backup.input.as_ref()) {
+        (Some(fd), _) => {
+            log::info!("Reading from file descriptor {fd}…");
+            (inherited_file(fd, false)?, format!("file descriptor {fd}"))
+        }
+        (None, Some(p)) if p.as_path() != Path::new("-") => {
+            log::info!("Opening {p:?}…");
+            (fs::File::open(p)?, format!("{p:?}"))
+        }
+        _ => {
+            log::info!("Reading from stdin…");
+            return Ok(Box::new(io::stdin()));
         }
     };
-    Ok(reader)
+    if backup.mmap {
+        match MmapReader::new(&file) {
+            Ok(Some(reader)) => {
+                log::debug!("Reading memory-mapped {input}…");
+                return Ok(Box::new(reader));
+            }
+            Ok(None) => log::debug!("Cannot map {input}, not a non-empty regular file"),
+            Err(err) => log::warn!("Cannot map {input}, reading instead: {err}"),
+        }
+    }
+    Ok(Box::new(file))
 }
@@ -0,0 +1,49 @@
+// Copyright The Cryophile Authors.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE> or
+// <http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT> or <http://opensource.org/licenses/MIT>, at your option.
+//
+// This file may not be copied, modified, or distributed except according
+// to those terms.
+
+use crate::cli::{CliError, CliResult, ConfigCheck, OutputFormat};
+use crate::config::ParseConfigError;
+use crate::Config;
+
+pub fn perform_config_check(config: &Config, config_check: &ConfigCheck) -> Result<(), CliError> {
+    log::info!("CONFIG-CHECK…");
+
+    let effective_config = match config_check.format {
+        OutputFormat::Toml => toml::to_string_pretty(&config.file).map_err(serialize_error)?,
+        OutputFormat::Json => {
+            let mut json = serde_json::to_string_pretty(&config.file).map_err(serialize_error)?;
+            json.push('\n');
+            json
+        }
+    };
+    print!("{effective_config}");
+
+    let problems = config.file.problems();
+    for problem in &problems {
+        log::error!("Configuration problem: {problem}");
+    }
+    if !problems.is_empty() {
+        return Err(CliError::ConfigurationError(
+            ParseConfigError::Invalid(format!(
+                "found {num} problem(s) in configuration",
+                num = problems.len()
+            )),
+            CliResult::ConfigError,
+        ));
+    }
+    log::info!("Configuration is valid");
+    Ok(())
+}
+
+fn serialize_error(err: impl std::error::Error) -> CliError {
+    CliError::ConfigurationError(
+        ParseConfigError::Invalid(format!("Cannot serialize configuration: {err}")),
+        CliResult::ConfigError,
+    )
+}
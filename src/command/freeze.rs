@@ -9,52 +9,219 @@
 
 use crate::cli::Freeze;
 use crate::core::aws;
+use crate::core::constants::{
+    CHUNK_FILE_MODE, CHUNK_FILE_PREFIX, FREEZE_LOCK_FILE, METRICS_INTERVAL,
+};
+use crate::core::fragment::Fragment;
+use crate::core::metrics::Metrics;
 use crate::core::notify::notify_error;
-use crate::core::path::{Queue, SpoolPathComponents};
+use crate::core::path::{lock_file, Queue, SpoolPathComponents};
 use crate::Config;
+use nix::fcntl::Flock;
 use notify::event::{AccessKind, AccessMode, CreateKind, RemoveKind};
-use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use notify::{EventKind, RecursiveMode, Watcher};
 use std::path::Path;
-use std::sync::mpsc;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError};
+use std::time::Instant;
 use std::{fs, io};
+use tokio::runtime::Runtime;
+use tokio::signal::unix::{signal, SignalKind};
 use walkdir::WalkDir;
 
-pub fn perform_freeze(config: &Config, _freeze: &Freeze) -> io::Result<()> {
+/// Messages of the freeze loop.
+enum FreezeMessage {
+    Event(notify::Result<notify::Event>),
+    Shutdown(&'static str),
+}
+
+/// Sender of the freeze loop, bounded by `--freeze-queue-depth`.
+#[derive(Clone)]
+enum FreezeSender {
+    Unbounded(Sender<FreezeMessage>),
+    Bounded(SyncSender<FreezeMessage>),
+}
+
+impl FreezeSender {
+    fn channel(depth: Option<usize>) -> (Self, Receiver<FreezeMessage>) {
+        match depth {
+            Some(depth) => {
+                let (tx, rx) = mpsc::sync_channel(depth);
+                (Self::Bounded(tx), rx)
+            }
+            None => {
+                let (tx, rx) = mpsc::channel();
+                (Self::Unbounded(tx), rx)
+            }
+        }
+    }
+
+    /// Send `message`, blocking while a bounded queue is full.
+    fn send(&self, message: FreezeMessage) -> Result<(), mpsc::SendError<FreezeMessage>> {
+        match self {
+            Self::Unbounded(tx) => tx.send(message),
+            Self::Bounded(tx) => tx.send(message),
+        }
+    }
+
+    /// Send `message` without blocking, fails if a bounded queue is full.
+    fn try_send(&self, message: FreezeMessage) -> Result<(), TrySendError<FreezeMessage>> {
+        match self {
+            Self::Unbounded(tx) => tx
+                .send(message)
+                .map_err(|mpsc::SendError(message)| TrySendError::Disconnected(message)),
+            Self::Bounded(tx) => tx.try_send(message),
+        }
+    }
+}
+
+pub fn perform_freeze(config: &Config, freeze: &Freeze) -> io::Result<()> {
     log::info!("FREEZE…");
 
-    let aws_config_future = aws::aws_config(None);
-    let aws_config = futures::executor::block_on(aws_config_future);
-    log::trace!(
-        "Using AWS config region {region:?}",
-        region = aws_config.region()
+    // held until freeze returns, a second freeze would upload the same chunks
+    let _lock = if freeze.no_lock {
+        log::warn!("Not locking spool, other freeze processes may upload the same chunks");
+        None
+    } else {
+        Some(lock_spool(config.spool())?)
+    };
+
+    // the AWS SDK needs a tokio reactor for its timers and connectors
+    log::debug!(
+        "Using {workers} freeze worker thread(s) and queue depth {depth}",
+        workers = freeze.freeze_workers,
+        depth = freeze
+            .freeze_queue_depth
+            .map_or("unbounded".to_string(), |depth| depth.to_string())
     );
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(freeze.freeze_workers)
+        .enable_all()
+        .build()?;
 
-    let aws_client_future = aws::aws_client(&aws_config);
-    let aws_client = futures::executor::block_on(aws_client_future);
+    let aws_client = runtime.block_on(aws::vault_client(config, freeze.vault))?;
     log::trace!("Using AWS client {aws_client:?}");
 
-    let (tx, rx) = mpsc::channel();
+    let (tx, rx) = FreezeSender::channel(freeze.freeze_queue_depth);
+    spawn_shutdown_handler(&runtime, tx.clone())?;
+    // never block the watcher thread, drop events if freeze falls behind
+    let handler = move |event: notify::Result<notify::Event>| {
+        if let Err(TrySendError::Full(FreezeMessage::Event(event))) =
+            tx.try_send(FreezeMessage::Event(event))
+        {
+            log::warn!("Freeze queue is full, dropping event {event:?}");
+        }
+    };
 
-    let mut watcher =
-        RecommendedWatcher::new(tx, notify::Config::default()).map_err(notify_error)?;
+    let mut watcher = config.watch_config().watcher(handler)?;
 
-    let spool_path_components = SpoolPathComponents::from_spool(config.cli.spool.clone());
+    let spool_path_components = SpoolPathComponents::from_spool(config.spool().to_path_buf())
+        .with_freeze_spool(config.freeze_spool());
     let freeze_dir = spool_path_components.to_queue_path(Queue::Freeze)?;
 
-    watch_read_dir(&mut watcher, &freeze_dir, RecursiveMode::Recursive)?;
+    watch_read_dir(
+        watcher.as_mut(),
+        &freeze_dir,
+        RecursiveMode::Recursive,
+        freeze.follow_symlinks,
+    )?;
     log::debug!("Watching spool {freeze_dir:?}");
 
-    for res in rx {
-        event_handler(res, &freeze_dir, &mut watcher).map_err(notify_error)?;
+    let deadline = freeze.max_runtime.map(|max_runtime| {
+        log::info!(
+            "Freezing for at most {max_runtime}…",
+            max_runtime = humantime::format_duration(max_runtime)
+        );
+        Instant::now() + max_runtime
+    });
+
+    let metrics = freeze.metrics_file.as_deref().map(|path| {
+        log::info!("Writing metrics to {path:?}…");
+        (path, Metrics::new())
+    });
+    let mut next_metrics = Instant::now();
+
+    let result = loop {
+        if let Some((path, metrics)) = &metrics {
+            if Instant::now() >= next_metrics {
+                write_metrics(path, metrics);
+                next_metrics = Instant::now() + METRICS_INTERVAL;
+            }
+        }
+        let timeout = [deadline, metrics.as_ref().map(|_| next_metrics)]
+            .into_iter()
+            .flatten()
+            .min();
+        let res = match timeout {
+            Some(timeout) => {
+                match rx.recv_timeout(timeout.saturating_duration_since(Instant::now())) {
+                    Ok(res) => res,
+                    Err(RecvTimeoutError::Timeout) => {
+                        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                            log::info!("Reached maximum runtime, shutting down freeze…");
+                            break Ok(());
+                        }
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break Ok(()),
+                }
+            }
+            None => match rx.recv() {
+                Ok(res) => res,
+                Err(_) => break Ok(()),
+            },
+        };
+        let res = match res {
+            FreezeMessage::Event(res) => res,
+            FreezeMessage::Shutdown(name) => {
+                log::info!("Received {name}, shutting down freeze…");
+                break Ok(());
+            }
+        };
+        if let Some((_, metrics)) = &metrics {
+            Metrics::inc(&metrics.events);
+        }
+        if let Err(err) = event_handler(res, &freeze_dir, watcher.as_mut()) {
+            if let Some((_, metrics)) = &metrics {
+                Metrics::inc(&metrics.errors);
+            }
+            break Err(notify_error(err));
+        }
+    };
+
+    if let Some((path, metrics)) = &metrics {
+        write_metrics(path, metrics);
     }
+    result
+}
 
+/// Shut down freeze gracefully on SIGTERM, e.g., from systemd or
+/// Kubernetes, and on SIGINT.
+fn spawn_shutdown_handler(runtime: &Runtime, tx: FreezeSender) -> io::Result<()> {
+    let _enter = runtime.enter();
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    runtime.spawn(async move {
+        let name = tokio::select! {
+            _ = terminate.recv() => "SIGTERM",
+            _ = interrupt.recv() => "SIGINT",
+        };
+        // the queue may be full, do not block a runtime worker
+        let _ = tokio::task::spawn_blocking(move || tx.send(FreezeMessage::Shutdown(name))).await;
+    });
     Ok(())
 }
 
+fn write_metrics(path: &Path, metrics: &Metrics) {
+    if let Err(err) = metrics.write_textfile(path) {
+        log::warn!("Cannot write metrics to {path:?}: {err}");
+    }
+}
+
 fn watch_read_dir(
-    watcher: &mut notify::RecommendedWatcher,
+    watcher: &mut dyn Watcher,
     path: &Path,
     mode: RecursiveMode,
+    follow_symlinks: bool,
 ) -> io::Result<()> {
     if !path.is_dir() {
         log::warn!("Ignoring non-directory: {path:?}");
@@ -64,15 +231,20 @@ fn watch_read_dir(
     watcher.watch(path, mode).map_err(notify_error)?;
     log::debug!("Watching path ({mode:?}): {path:?}");
 
-    for entry in WalkDir::new(path) {
+    for entry in WalkDir::new(path).follow_links(follow_symlinks) {
         if let Err(e) = &entry {
             log::warn!("Cannot walk {entry:?}, ignoring: {e}");
             continue;
         } else if let Ok(dir_entry) = &entry {
-            if !dir_entry.path_is_symlink() {
+            if follow_symlinks || !dir_entry.path_is_symlink() {
                 let dir_entry_path = dir_entry.path();
                 if dir_entry_path.is_file() {
                     log::debug!("Found {dir_entry_path:?}");
+                    if Fragment::is_misnamed(dir_entry_path, CHUNK_FILE_PREFIX) {
+                        log::warn!(
+                            "Found chunk file {dir_entry_path:?} without a valid chunk index"
+                        );
+                    }
                     // TODO found file may or may not be open for writing
                     continue;
                 }
@@ -102,7 +274,7 @@ fn watch_read_dir(
 fn event_handler(
     result: Result<notify::Event, notify::Error>,
     spool: &Path,
-    _watcher: &mut notify::RecommendedWatcher,
+    _watcher: &mut dyn Watcher,
 ) -> Result<(), notify::Error> {
     // TODO check which level in spool causes the event
     // TODO inside vault: new backup dirs arrive, add them if they are not yet uploaded, if uploaded unwatch backup_dir
@@ -194,3 +366,23 @@ fn event_handler(
     };
     Ok(())
 }
+
+/// Lock the spool, so only one freeze process uploads its chunks.
+fn lock_spool(spool: &Path) -> io::Result<Flock<fs::File>> {
+    let lock_path = spool.join(FREEZE_LOCK_FILE);
+    let lock = lock_file(&lock_path, CHUNK_FILE_MODE).map_err(|err| {
+        if err.kind() == io::ErrorKind::WouldBlock {
+            io::Error::new(
+                err.kind(),
+                format!(
+                    "Another freeze process is running on spool {spool:?}, \
+                     use --no-lock for freeze processes with disjoint vaults"
+                ),
+            )
+        } else {
+            err
+        }
+    })?;
+    log::debug!("Locked spool with {lock_path:?}");
+    Ok(lock)
+}
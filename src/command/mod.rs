@@ -8,6 +8,9 @@
 // to those terms.
 
 pub mod backup;
+pub mod config_check;
 pub mod freeze;
+pub mod rechunk;
 pub mod restore;
 pub mod thaw;
+pub mod version;
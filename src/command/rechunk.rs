@@ -0,0 +1,145 @@
+// Copyright The Cryophile Authors.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE> or
+// <http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT> or <http://opensource.org/licenses/MIT>, at your option.
+//
+// This file may not be copied, modified, or distributed except according
+// to those terms.
+
+use crate::cli::Rechunk;
+use crate::command::backup::{discard_backup_dirs, lock_backup_dir, unlock_backup_dir};
+use crate::core::backup_id::{monotonic_ulid, BackupId};
+use crate::core::cat::{numbered_chunks, Cat};
+use crate::core::constants::{CHUNK_FILE_PREFIX, PARITY_FILE_EXTENSION};
+use crate::core::path::{
+    check_same_filesystem, link_or_copy, CreateDirectory, Queue, SpoolPathComponents,
+};
+use crate::core::watch::channel_send_error;
+use crate::core::Split;
+use crate::Config;
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub fn perform_rechunk(config: &Config, rechunk: &Rechunk) -> io::Result<()> {
+    log::info!("RECHUNK…");
+
+    let prefix_str_maybe = rechunk.prefix.as_ref().and_then(|path| path.to_str());
+    let source_id = BackupId::new(rechunk.vault, prefix_str_maybe, rechunk.ulid);
+    let source = SpoolPathComponents::new(config.spool().to_path_buf(), source_id)
+        .with_freeze_spool(config.freeze_spool());
+    let source_uri = source.uri().expect("cannot create backup uri");
+    let source_dir = source.to_queue_path(Queue::Freeze)?;
+    let source_zero = source_dir.join(CHUNK_FILE_PREFIX).with_extension("0");
+    if !source_zero.is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Backup {source_uri} in {source_dir:?} is not queued for freeze, {source_zero:?} is missing"),
+        ));
+    }
+    let (chunks, total) = source_chunks(&source_dir)?;
+
+    let target_id = BackupId::new(
+        rechunk.vault,
+        prefix_str_maybe,
+        rechunk.new_ulid.unwrap_or_else(monotonic_ulid),
+    );
+    let target = SpoolPathComponents::new(config.spool().to_path_buf(), target_id)
+        .with_freeze_spool(config.freeze_spool());
+    let target_uri = target.uri().expect("cannot create backup uri");
+    let (backup_dir, created) =
+        target.try_with_queue_path(Queue::Backup, CreateDirectory::Recursive)?;
+    if !created {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("Backup {target_uri} already exists in {backup_dir:?}, use a new ulid"),
+        ));
+    }
+    let (freeze_dir, _) = target.try_with_queue_path(Queue::Freeze, CreateDirectory::Recursive)?;
+    let lock = lock_backup_dir(&backup_dir)?;
+    check_same_filesystem(&backup_dir, &freeze_dir)?;
+
+    log::info!(
+        "Re-chunking {num} chunk(s) of backup {source_uri} into chunks of {size} bytes…",
+        num = chunks.len(),
+        size = rechunk.size
+    );
+    // a failed re-chunk must not leave chunks that look like a partial backup
+    let result = write_chunks(rechunk, chunks, total, &backup_dir, &freeze_dir)
+        .and_then(|_| link_zero_file(&source_zero, &backup_dir, &freeze_dir, rechunk));
+    if let Err(err) = result {
+        log::error!("Re-chunking into {target_uri} failed, removing incomplete chunks…");
+        if let Err(clear_err) = discard_backup_dirs(&backup_dir, &freeze_dir) {
+            log::error!("Cannot remove incomplete backup {target_uri}: {clear_err}");
+        }
+        return Err(err);
+    }
+
+    unlock_backup_dir(&backup_dir, lock)?;
+    log::info!("Queued re-chunked backup {target_uri} for freeze {freeze_dir:?}");
+    Ok(())
+}
+
+/// The chunk files of a queued backup from chunk.1 on, and their total size.
+fn source_chunks(source_dir: &Path) -> io::Result<(Vec<PathBuf>, u64)> {
+    let (chunks, total) = numbered_chunks(source_dir)?;
+    let has_parity = fs::read_dir(source_dir)?.any(|entry| {
+        entry.is_ok_and(|entry| entry.path().extension() == Some(PARITY_FILE_EXTENSION.as_ref()))
+    });
+    if has_parity {
+        log::warn!("Not re-chunking parity chunks, the re-chunked backup has none");
+    }
+    Ok((chunks, total))
+}
+
+/// Concatenate the source chunks and split them into chunks of the new size.
+fn write_chunks(
+    rechunk: &Rechunk,
+    chunks: Vec<PathBuf>,
+    total: u64,
+    backup_dir: &Path,
+    freeze_dir: &Path,
+) -> io::Result<()> {
+    let mut concat = Cat::new();
+    let tx = concat.tx();
+    for chunk in chunks {
+        tx.send(Some(chunk)).map_err(channel_send_error)?;
+    }
+    tx.send(None).map_err(channel_send_error)?;
+
+    let mut splitter = Split::new(backup_dir, freeze_dir, CHUNK_FILE_PREFIX, rechunk.size)
+        .with_sync_policy(rechunk.sync_policy);
+    let copied = io::copy(&mut concat, &mut splitter)?;
+    splitter.finish()?;
+    // Cat skips chunks it cannot open, which would corrupt the stream
+    if copied != total {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("Re-chunked {copied} of {total} bytes"),
+        ));
+    }
+    log::debug!(
+        "Re-chunked {copied} bytes into {num} chunk(s)",
+        num = splitter.chunks()
+    );
+    Ok(())
+}
+
+/// Copy the zero chunk with the encrypted manifest, which marks the backup
+/// as complete.
+fn link_zero_file(
+    source_zero: &Path,
+    incoming: &Path,
+    outgoing: &Path,
+    rechunk: &Rechunk,
+) -> io::Result<()> {
+    let zero_file = incoming.join(CHUNK_FILE_PREFIX).with_extension("0");
+    log::trace!("Copy manifest {source_zero:?} to {zero_file:?}");
+    fs::copy(source_zero, &zero_file)?;
+    rechunk.sync_policy.sync(&fs::File::open(&zero_file)?)?;
+    let zero_link = outgoing.join(CHUNK_FILE_PREFIX).with_extension("0");
+    log::trace!("Link {zero_file:?}");
+    link_or_copy(&zero_file, &zero_link, rechunk.sync_policy)
+}
@@ -10,40 +10,293 @@
 use crate::cli::Restore;
 use crate::compression::decompressor::Decompressor;
 use crate::compression::CompressionType;
-use crate::core::backup_id::BackupId;
-use crate::core::cat::Cat;
+use crate::core::aws;
+use crate::core::backup_id::{ulid_at, BackupId};
+use crate::core::cat::{numbered_chunks, Cat};
+use crate::core::constants::CHUNK_FILE_PREFIX;
+#[cfg(feature = "fec")]
+use crate::core::fec;
 use crate::core::fragment::FragmentQueue;
+use crate::core::manifest::{HashingWriter, Manifest};
 use crate::core::notify::notify_error;
-use crate::core::path::{CreateDirectory, Queue, SpoolPathComponents};
-use crate::core::watch::Watch;
+use crate::core::path::{
+    check_free_space, inherited_file, CreateDirectory, Queue, SpoolPathComponents,
+};
+use crate::core::s3reader::S3Reader;
+use crate::core::tee::Tee;
+use crate::core::watch::{channel_send_error, Watch, WatchMessage};
+#[cfg(feature = "age")]
+use crate::crypto::age::{is_age_stream, AgeKeys};
 use crate::crypto::openpgp::{
-    build_decryptor, openpgp_error, read_password_fd, secret_key_store, SecretKeyStore,
+    build_decryptor, build_policy, decrypt_error, dump_packets, message_recipients,
+    read_password_fd, secret_key_ids, secret_key_store, MessageRecipient, SecretKeyStore,
 };
 use crate::Config;
-use notify::event::CreateKind;
-use notify::{EventKind, RecursiveMode, Watcher};
+use aws_sdk_s3::Client;
+use notify::event::{CreateKind, ModifyKind, RenameMode};
+use notify::{EventKind, RecursiveMode};
 use sequoia_openpgp::policy::StandardPolicy;
+use std::collections::HashMap;
 use std::convert;
-use std::os::unix::prelude::OpenOptionsExt;
+use std::ffi::OsString;
+use std::io::{self, BufRead, Read, Write};
+use std::os::fd::{AsFd, AsRawFd};
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
 use std::thread::JoinHandle;
-use std::{fs, io, thread};
+use std::{fs, thread};
+use tempfile::NamedTempFile;
+use tokio::runtime::Runtime;
+use ulid::Ulid;
 use walkdir::WalkDir;
 
+/// Restore output that only appears at its final path once the restore succeeded.
+///
+/// File outputs are written to a temporary file in the same directory as the
+/// requested output, which is atomically renamed onto the output path by
+/// [`RestoreOutput::persist`]. Dropping a file output without persisting it
+/// removes the temporary file, so an interrupted restore never leaves a
+/// truncated file behind. An existing output file is only replaced if
+/// `overwrite` is set, and then atomically as well.
+///
+/// Existing block or character devices and FIFOs are written in place, since
+/// they can neither be created nor renamed onto, as are inherited file
+/// descriptors.
+enum RestoreOutput {
+    Stdout(io::Stdout),
+    Device(fs::File),
+    File {
+        path: PathBuf,
+        temp: NamedTempFile,
+        overwrite: bool,
+    },
+}
+
+impl RestoreOutput {
+    /// Directory of a file output, which is preallocated in that directory.
+    fn dir(&self) -> Option<&Path> {
+        match self {
+            RestoreOutput::Stdout(_) | RestoreOutput::Device(_) => None,
+            RestoreOutput::File { temp, .. } => {
+                Some(temp.path().parent().unwrap_or(Path::new(".")))
+            }
+        }
+    }
+
+    /// Reserve `len` bytes for a file output to reduce fragmentation.
+    ///
+    /// Outputs that cannot be preallocated (stdout, or file systems without
+    /// `fallocate` support) are written without a sizing hint. The available
+    /// space is checked by [`preallocate_outputs`] for all outputs at once.
+    fn preallocate(&self, len: u64) -> io::Result<()> {
+        match self {
+            RestoreOutput::Stdout(_) => {
+                log::debug!("Not preallocating restore output on stdout");
+            }
+            RestoreOutput::Device(_) => {
+                log::debug!("Not preallocating restore output on device");
+            }
+            RestoreOutput::File { temp, .. } => {
+                let Ok(len) = i64::try_from(len) else {
+                    log::warn!("Cannot preallocate {len} bytes for restore output");
+                    return Ok(());
+                };
+                log::debug!(
+                    "Preallocating {len} bytes for {temp_path:?}",
+                    temp_path = temp.path()
+                );
+                if let Err(err) = nix::fcntl::posix_fallocate(temp.as_file().as_raw_fd(), 0, len) {
+                    log::warn!("Cannot preallocate {len} bytes for restore output: {err}");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Truncate a file output to the restored length, dropping any
+    /// preallocated bytes that were not written.
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        match self {
+            RestoreOutput::Stdout(_) | RestoreOutput::Device(_) => Ok(()),
+            RestoreOutput::File { temp, .. } => temp.as_file().set_len(len),
+        }
+    }
+
+    /// Apply the mode recorded in the manifest instead of the default `0o600`.
+    fn set_mode(&self, mode: u32) -> io::Result<()> {
+        match self {
+            RestoreOutput::Stdout(_) => {
+                log::warn!("Cannot set mode {mode:o} of restore output on stdout");
+                Ok(())
+            }
+            RestoreOutput::Device(_) => {
+                log::warn!("Not setting mode {mode:o} of restore output written in place");
+                Ok(())
+            }
+            RestoreOutput::File { temp, .. } => {
+                log::debug!("Setting mode {mode:o} of restore output");
+                temp.as_file()
+                    .set_permissions(fs::Permissions::from_mode(mode))
+            }
+        }
+    }
+
+    /// Apply the owner recorded in the manifest, which requires privileges.
+    fn set_owner(&self, uid: Option<u32>, gid: Option<u32>) -> io::Result<()> {
+        match self {
+            RestoreOutput::Stdout(_) => {
+                log::warn!("Cannot set owner of restore output on stdout");
+                Ok(())
+            }
+            RestoreOutput::Device(_) => {
+                log::warn!("Not setting owner of restore output written in place");
+                Ok(())
+            }
+            RestoreOutput::File { temp, .. } => {
+                log::debug!("Setting owner {uid:?} and group {gid:?} of restore output");
+                match std::os::unix::fs::fchown(temp.as_file(), uid, gid) {
+                    Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+                        log::warn!("Not permitted to set owner of restore output: {err}");
+                        Ok(())
+                    }
+                    result => result,
+                }
+            }
+        }
+    }
+
+    /// Flush and sync the output, and only then move a file output to its
+    /// final path, so a successful restore is durable once it returns.
+    fn persist(self) -> io::Result<()> {
+        match self {
+            RestoreOutput::Stdout(mut stdout) => {
+                stdout.flush()?;
+                // stdout redirected to a file can be synced, pipes and ttys not
+                let file = fs::File::from(stdout.as_fd().try_clone_to_owned()?);
+                if file.metadata()?.is_file() {
+                    log::debug!("Syncing restore output on stdout");
+                    file.sync_all()?;
+                } else {
+                    log::debug!("Not syncing restore output on stdout, not a regular file");
+                }
+                Ok(())
+            }
+            RestoreOutput::Device(mut file) => {
+                file.flush()?;
+                match file.sync_all() {
+                    // character devices and FIFOs cannot be synced
+                    Err(err) if err.raw_os_error() == Some(nix::libc::EINVAL) => {
+                        log::debug!("Not syncing restore output on device: {err}");
+                        Ok(())
+                    }
+                    result => result,
+                }
+            }
+            RestoreOutput::File {
+                path,
+                mut temp,
+                overwrite,
+            } => {
+                temp.flush()?;
+                temp.as_file().sync_all()?;
+                log::debug!(
+                    "Renaming {temp_path:?} to restore output {path:?}",
+                    temp_path = temp.path()
+                );
+                let persisted = if overwrite {
+                    temp.persist(&path)
+                } else {
+                    temp.persist_noclobber(&path)
+                };
+                persisted.map_err(|err| {
+                    io::Error::new(
+                        err.error.kind(),
+                        format!("Cannot persist restore output {path:?}: {err}"),
+                    )
+                })?;
+                // sync the directory entry of the renamed output as well
+                let dir = match path.parent() {
+                    Some(parent) if !parent.as_os_str().is_empty() => parent,
+                    _ => Path::new("."),
+                };
+                fs::File::open(dir)?.sync_all()
+            }
+        }
+    }
+}
+
+impl io::Write for RestoreOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            RestoreOutput::Stdout(stdout) => stdout.write(buf),
+            RestoreOutput::Device(file) => file.write(buf),
+            RestoreOutput::File { temp, .. } => temp.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            RestoreOutput::Stdout(stdout) => stdout.flush(),
+            RestoreOutput::Device(file) => file.flush(),
+            RestoreOutput::File { temp, .. } => temp.flush(),
+        }
+    }
+}
+
 pub fn perform_restore(config: &Config, restore: &Restore) -> io::Result<()> {
     log::info!("RESTORE…");
 
-    let output: Box<dyn io::Write> = build_writer(restore.output.as_ref())?;
-
     let prefix_str_maybe = restore.prefix.as_ref().and_then(|path| path.to_str());
-    let backup_id = BackupId::new(restore.vault, prefix_str_maybe, restore.ulid);
+    let backup_id = BackupId::new(
+        restore.vault,
+        prefix_str_maybe,
+        restore_ulid(config, restore)?,
+    );
 
-    let spool_path_components = SpoolPathComponents::new(config.cli.spool.clone(), backup_id);
+    if restore.inspect {
+        return inspect_recipients(config, restore, &backup_id);
+    }
+    if restore.dump_packets {
+        return dump_backup_packets(config, restore, &backup_id);
+    }
 
-    let concat = Cat::new();
-    let fragment_queue = FragmentQueue::new(concat.tx());
+    let policy = &build_policy(restore.policy_time, &restore.allow_algo);
+    // the scan unlocks the secret keys, so the password is only read once
+    let scanned_keys = if restore.scan_first {
+        Some(scan_restore_queue(config, restore, &backup_id, policy)?)
+    } else {
+        None
+    };
+
+    if restore.from_bucket {
+        return restore_from_bucket(config, restore, &backup_id, policy);
+    }
+
+    let spool_path_components = SpoolPathComponents::new(config.spool().to_path_buf(), backup_id)
+        .with_freeze_spool(config.freeze_spool());
+
+    let keys = match scanned_keys {
+        Some(keys) => keys,
+        None => restore_keys(restore, policy)?,
+    };
+    // the zero chunk is restored last, unless the restore queue is complete
+    let zero_chunk = spool_path_components
+        .to_queue_path(Queue::Freeze)?
+        .join(CHUNK_FILE_PREFIX)
+        .with_extension("0");
+    let (early_manifest, keys) = if restore.preallocate == Some(None) && zero_chunk.is_file() {
+        read_manifest(fs::File::open(&zero_chunk)?, keys, policy)?
+    } else {
+        (None, keys)
+    };
+    let mut outputs = build_writers(restore)?;
+    preallocate_outputs(restore, early_manifest.as_ref(), outputs.writers())?;
+
+    let concat = Cat::new().with_prefetch(restore.prefetch_chunks);
+    let fragment_queue = FragmentQueue::new(concat.tx()).with_strict(restore.strict_chunk_names);
 
-    let watch = Box::new(Watch::new(None)?);
+    let watch = Box::new(Watch::new(None, config.watch_config())?);
+    let shutdown = watch.shutdown_handle();
 
     let (freeze_dir, created) =
         spool_path_components.try_with_queue_path(Queue::Freeze, CreateDirectory::Recursive)?;
@@ -51,9 +304,14 @@ pub fn perform_restore(config: &Config, restore: &Restore) -> io::Result<()> {
     // Create and watch restore directory, or use restore directory from a previous run.
     // No need to watch once we could fully walked the downloaded restore directory (e.g., if restore was interrupted).
     let handle = if created {
-        Some(watch_restore_dir(&freeze_dir, watch, fragment_queue)?)
+        Some(watch_restore_dir(
+            &freeze_dir,
+            watch,
+            fragment_queue,
+            restore.follow_symlinks,
+        )?)
     } else {
-        walk_and_watch_restore_dir(&freeze_dir, watch, fragment_queue)?
+        walk_and_watch_restore_dir(&freeze_dir, watch, fragment_queue, restore.follow_symlinks)?
     };
 
     let restore_uri = spool_path_components
@@ -61,61 +319,469 @@ pub fn perform_restore(config: &Config, restore: &Restore) -> io::Result<()> {
         .expect("cannot create restore uri");
     log::debug!("Starting restore of {restore_uri}");
 
-    let policy = &StandardPolicy::new();
-    // TODO use optional CRYOPHILE_ASKPASS instead of terminal prompt
-    // TODO batch mode should not try to prompt for password at all
-    let password = restore.pass_fd.and_then(read_password_fd);
-    let secret_key_store = secret_key_store(policy, restore.keyring.iter().flatten(), password)?;
-
+    let mut hashing_output = HashingWriter::new(&mut outputs);
     let copy_result = fragment_worker(
         concat,
-        secret_key_store,
+        keys,
         policy,
-        restore.compression,
-        output,
-    )?;
+        restore.forced_compression(),
+        &mut hashing_output,
+    );
+    // stop watching once the restore stream ended, otherwise joining could block forever
+    shutdown.shutdown();
+    let (copy_result, keys) = copy_result?;
+    let sha256 = hashing_output.hex_digest();
     log::debug!("Received total of {copy_result} bytes");
+    for output in outputs.writers() {
+        output.set_len(copy_result)?;
+    }
 
     handle
         .map(|h| h.join().expect("could not join thread"))
-        .map_or_else(|| Ok(()), convert::identity)
-        .inspect(|_x| {
-            log::info!("Restored backup {restore_uri} from restore queue {freeze_dir:?}");
-        })
+        .map_or_else(|| Ok(()), convert::identity)?;
+
+    let manifest = match early_manifest {
+        Some(manifest) => Some(manifest),
+        None if needs_manifest(restore) => {
+            read_manifest(fs::File::open(&zero_chunk)?, keys, policy)?.0
+        }
+        None => None,
+    };
+    apply_manifest(restore, manifest.as_ref(), &sha256, outputs.writers())?;
+
+    // only move the restore outputs into place after the whole stream was restored
+    for output in outputs.into_inner() {
+        output.persist()?;
+    }
+    log::info!("Restored backup {restore_uri} from restore queue {freeze_dir:?}");
+    Ok(())
 }
 
-fn build_writer(path: Option<&PathBuf>) -> io::Result<Box<dyn io::Write>> {
-    let writer: Box<dyn io::Write> = match path {
-        Some(p) if p.as_path() == Path::new("-") => {
-            log::info!("Writing to stdout…");
-            Box::new(io::stdout())
+/// The ulid given with `--ulid`, or the latest backup of the vault and prefix
+/// created at or before `--at`, or the most recent one with `--latest`. The
+/// backups are listed in the vault bucket with `--from-bucket`, and in the
+/// restore queue otherwise.
+fn restore_ulid(config: &Config, restore: &Restore) -> io::Result<Ulid> {
+    if let Some(ulid) = restore.ulid {
+        return Ok(ulid);
+    }
+    let ulids = if restore.from_bucket {
+        bucket_backups(config, restore)?
+    } else {
+        queued_backups(config, restore)?
+    };
+    let ulid = match restore.at {
+        Some(at) => ulid_at(&ulids, at)?,
+        None => ulids.into_iter().max().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No backup found in vault {vault}", vault = restore.vault),
+            )
+        })?,
+    };
+    log::info!(
+        "Selected backup {ulid} created at {time}",
+        time = humantime::format_rfc3339_millis(ulid.datetime())
+    );
+    Ok(ulid)
+}
+
+fn restore_prefix(restore: &Restore) -> BackupId<'_> {
+    match restore.prefix.as_ref().and_then(|path| path.to_str()) {
+        Some(prefix) => BackupId::from_prefix(restore.vault, prefix),
+        None => BackupId::from_vault(restore.vault),
+    }
+}
+
+/// The ulids of the backups of the vault and prefix in the vault bucket.
+fn bucket_backups(config: &Config, restore: &Restore) -> io::Result<Vec<Ulid>> {
+    let (runtime, aws_client, bucket) = bucket_client(config, restore)?;
+    let prefix = restore_prefix(restore).to_vault_key('/');
+    runtime.block_on(aws::list_backups(&aws_client, &bucket, &prefix))
+}
+
+/// The ulids of the backups in the restore queue of the vault and prefix.
+fn queued_backups(config: &Config, restore: &Restore) -> io::Result<Vec<Ulid>> {
+    let queue_dir = SpoolPathComponents::new(config.spool().to_path_buf(), restore_prefix(restore))
+        .with_freeze_spool(config.freeze_spool())
+        .to_queue_path(Queue::Freeze)?;
+    let entries = fs::read_dir(&queue_dir).map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("Cannot list backups in restore queue {queue_dir:?}: {err}"),
+        )
+    })?;
+    let mut ulids = vec![];
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
         }
-        None => {
-            log::info!("Writing to stdout…");
-            Box::new(io::stdout())
-        }
-        Some(output) => {
-            log::info!("Creating restore output {output:?}");
-            Box::new(
-                fs::OpenOptions::new()
-                    .write(true)
-                    .create_new(true)
-                    .mode(0o600)
-                    .open(output)?,
+        if let Some(ulid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| Ulid::from_string(name).ok())
+        {
+            ulids.push(ulid);
+        }
+    }
+    log::debug!(
+        "Found {count} backups in {queue_dir:?}",
+        count = ulids.len()
+    );
+    Ok(ulids)
+}
+
+/// Runtime and client for the vault bucket, and the bucket name.
+fn bucket_client(config: &Config, restore: &Restore) -> io::Result<(Runtime, Client, String)> {
+    let bucket = config
+        .file
+        .vault
+        .iter()
+        .find(|vault| vault.id == restore.vault)
+        .and_then(|vault| vault.bucket.as_ref())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Vault {vault} has no bucket configured",
+                    vault = restore.vault
+                ),
             )
+        })?;
+
+    // the AWS SDK needs a tokio reactor for its timers and connectors
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    let aws_client = runtime.block_on(aws::vault_client(config, Some(restore.vault)))?;
+    Ok((runtime, aws_client, bucket.name.clone()))
+}
+
+/// Reader of the backup stream in the vault bucket, and the bucket name.
+fn bucket_reader(
+    config: &Config,
+    restore: &Restore,
+    backup_id: &BackupId,
+) -> io::Result<(S3Reader, String)> {
+    let (runtime, aws_client, bucket) = bucket_client(config, restore)?;
+    let vault_key = backup_id.to_vault_key('/');
+    log::debug!("Starting restore of s3://{bucket}/{vault_key}");
+    let reader = S3Reader::new(runtime, aws_client, bucket.clone(), vault_key);
+    Ok((reader, bucket))
+}
+
+fn restore_from_bucket(
+    config: &Config,
+    restore: &Restore,
+    backup_id: &BackupId,
+    policy: &StandardPolicy,
+) -> io::Result<()> {
+    let (mut reader, bucket) = bucket_reader(config, restore, backup_id)?;
+
+    let keys = restore_keys(restore, policy)?;
+    let (early_manifest, keys) = if restore.preallocate == Some(None) {
+        read_manifest(reader.zero_chunk()?.as_slice(), keys, policy)?
+    } else {
+        (None, keys)
+    };
+    let mut outputs = build_writers(restore)?;
+    preallocate_outputs(restore, early_manifest.as_ref(), outputs.writers())?;
+
+    let mut hashing_output = HashingWriter::new(&mut outputs);
+    let (copy_result, keys) = fragment_worker(
+        &mut reader,
+        keys,
+        policy,
+        restore.forced_compression(),
+        &mut hashing_output,
+    )?;
+    let sha256 = hashing_output.hex_digest();
+    log::debug!("Received total of {copy_result} bytes");
+    for output in outputs.writers() {
+        output.set_len(copy_result)?;
+    }
+
+    let manifest = match early_manifest {
+        Some(manifest) => Some(manifest),
+        None if needs_manifest(restore) => {
+            read_manifest(reader.zero_chunk()?.as_slice(), keys, policy)?.0
+        }
+        None => None,
+    };
+    apply_manifest(restore, manifest.as_ref(), &sha256, outputs.writers())?;
+
+    for output in outputs.into_inner() {
+        output.persist()?;
+    }
+    log::info!("Restored backup {backup_id} from bucket {bucket:?}");
+    Ok(())
+}
+
+/// The backup stream from the vault bucket or the restore queue, for
+/// inspecting it without restoring.
+fn backup_stream(
+    config: &Config,
+    restore: &Restore,
+    backup_id: &BackupId,
+) -> io::Result<Box<dyn io::Read + Send + Sync>> {
+    if restore.from_bucket {
+        let (reader, bucket) = bucket_reader(config, restore, backup_id)?;
+        log::info!("Inspecting backup {backup_id} in bucket {bucket:?}…");
+        return Ok(Box::new(reader));
+    }
+    let spool_path_components = SpoolPathComponents::new(config.spool().to_path_buf(), *backup_id)
+        .with_freeze_spool(config.freeze_spool());
+    let freeze_dir = spool_path_components.to_queue_path(Queue::Freeze)?;
+    log::info!("Inspecting backup {backup_id} in restore queue {freeze_dir:?}…");
+    Ok(Box::new(queued_chunks(&freeze_dir)?))
+}
+
+/// Concatenation of the chunks already in the restore queue `freeze_dir`.
+fn queued_chunks(freeze_dir: &Path) -> io::Result<Cat> {
+    let (chunks, _) = numbered_chunks(freeze_dir)?;
+    let concat = Cat::new();
+    let tx = concat.tx();
+    for chunk in chunks {
+        tx.send(Some(chunk)).map_err(channel_send_error)?;
+    }
+    tx.send(None).map_err(channel_send_error)?;
+    Ok(concat)
+}
+
+/// Decrypt, decompress and verify the complete restore queue without writing
+/// any output, and return the unlocked secret keys for the actual restore.
+fn scan_restore_queue(
+    config: &Config,
+    restore: &Restore,
+    backup_id: &BackupId,
+    policy: &StandardPolicy,
+) -> io::Result<RestoreKeys> {
+    let spool_path_components = SpoolPathComponents::new(config.spool().to_path_buf(), *backup_id)
+        .with_freeze_spool(config.freeze_spool());
+    let freeze_dir = spool_path_components.to_queue_path(Queue::Freeze)?;
+    let zero_chunk = freeze_dir.join(CHUNK_FILE_PREFIX).with_extension("0");
+    if !zero_chunk.is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "Backup {backup_id} in restore queue {freeze_dir:?} is incomplete, \
+                 {zero_chunk:?} is missing"
+            ),
+        ));
+    }
+    log::info!("Scanning backup {backup_id} in restore queue {freeze_dir:?}…");
+
+    let keys = restore_keys(restore, policy)?;
+    let mut hashing_sink = HashingWriter::new(io::sink());
+    let (scanned, mut keys) = fragment_worker(
+        queued_chunks(&freeze_dir)?,
+        keys,
+        policy,
+        restore.forced_compression(),
+        &mut hashing_sink,
+    )?;
+    let sha256 = hashing_sink.hex_digest();
+    if !restore.no_verify_digest {
+        let manifest;
+        (manifest, keys) = read_manifest(fs::File::open(zero_chunk)?, keys, policy)?;
+        check_digest(restore, manifest.as_ref(), &sha256)?;
+    }
+    log::info!("Scanned {scanned} bytes of backup {backup_id}, writing output…");
+    Ok(keys)
+}
+
+/// Print the OpenPGP packets of a backup to stderr, decrypting them if the
+/// keyrings have a secret key.
+fn dump_backup_packets(config: &Config, restore: &Restore, backup_id: &BackupId) -> io::Result<()> {
+    let input = backup_stream(config, restore, backup_id)?;
+    let certs = restore.certs()?;
+    let secret_key_store = if !certs.is_empty() {
+        let policy = &build_policy(restore.policy_time, &restore.allow_algo);
+        let password = restore.pass_fd.and_then(read_password_fd);
+        Some(secret_key_store(policy, certs.iter(), password)?)
+    } else {
+        None
+    };
+    dump_packets(input, secret_key_store, &mut io::stderr())
+}
+
+/// Print the recipients of the session keys of a backup, and whether the
+/// keyrings have their secret keys, without decrypting the backup.
+fn inspect_recipients(config: &Config, restore: &Restore, backup_id: &BackupId) -> io::Result<()> {
+    // the session keys are at the start of the stream, the parser stops
+    // reading after them
+    let recipients = message_recipients(backup_stream(config, restore, backup_id)?)?;
+
+    let secret_keys = secret_key_ids(restore.certs()?.iter());
+    let mut stdout = io::stdout().lock();
+    for recipient in recipients {
+        match recipient {
+            MessageRecipient::PublicKey(keyid) if keyid.is_wildcard() => writeln!(
+                stdout,
+                "{keyid} anonymous recipient, {n} secret key(s) to try",
+                n = secret_keys.len()
+            )?,
+            MessageRecipient::PublicKey(keyid) if secret_keys.contains(&keyid) => {
+                writeln!(stdout, "{keyid} secret key available")?
+            }
+            MessageRecipient::PublicKey(keyid) => writeln!(stdout, "{keyid} no secret key")?,
+            MessageRecipient::Password => writeln!(stdout, "password")?,
+        }
+    }
+    Ok(())
+}
+
+/// Preallocate the file outputs with the `--preallocate` size, or with the
+/// input size of the backup in `manifest`. Fails if the outputs on a file
+/// system do not fit into its available space together.
+fn preallocate_outputs(
+    restore: &Restore,
+    manifest: Option<&Manifest>,
+    outputs: &[RestoreOutput],
+) -> io::Result<()> {
+    let len = match restore.preallocate {
+        None => return Ok(()),
+        Some(Some(len)) => len,
+        Some(None) => match manifest.and_then(|manifest| manifest.size) {
+            Some(size) => size,
+            None => {
+                log::warn!("Not preallocating restore output of unknown size");
+                return Ok(());
+            }
+        },
+    };
+    // a mistyped size must not fill up the file system
+    let mut required: HashMap<u64, (&Path, u64)> = HashMap::new();
+    for dir in outputs.iter().filter_map(RestoreOutput::dir) {
+        let (_, total) = required.entry(fs::metadata(dir)?.dev()).or_insert((dir, 0));
+        *total = total.saturating_add(len);
+    }
+    for (dir, total) in required.values() {
+        check_free_space(dir, *total)?;
+    }
+    for output in outputs {
+        output.preallocate(len)?;
+    }
+    Ok(())
+}
+
+/// The restore outputs, written to together, stdout if there are none.
+fn build_writers(restore: &Restore) -> io::Result<Tee<RestoreOutput>> {
+    if let Some(fd) = restore.output_fd {
+        log::info!("Writing to file descriptor {fd}…");
+        let file = inherited_file(fd, true)?;
+        return Ok(Tee::new(vec![RestoreOutput::Device(file)]));
+    }
+    if restore.output.is_empty() {
+        log::info!("Writing to stdout…");
+        return Ok(Tee::new(vec![RestoreOutput::Stdout(io::stdout())]));
+    }
+    let mut outputs = Vec::with_capacity(restore.output.len());
+    for (pos, output) in restore.output.iter().enumerate() {
+        if restore.output[..pos].contains(output) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Restore output {output:?} is given more than once"),
+            ));
+        }
+        outputs.push(build_writer(restore, output)?);
+    }
+    Ok(Tee::new(outputs))
+}
+
+fn build_writer(restore: &Restore, output: &Path) -> io::Result<RestoreOutput> {
+    let (force, allow_device) = (restore.force, restore.allow_device);
+    let writer = match output {
+        output if output == Path::new("-") => {
+            log::info!("Writing to stdout…");
+            RestoreOutput::Stdout(io::stdout())
+        }
+        output if is_device(output) => {
+            if !allow_device {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Restore output {output:?} is a device or FIFO, use --allow-device to write to it"
+                    ),
+                ));
+            }
+            log::info!("Writing to device {output:?}…");
+            // neither create nor truncate, the device node already exists
+            let file = fs::OpenOptions::new().write(true).open(output)?;
+            RestoreOutput::Device(file)
+        }
+        output => {
+            // fail early instead of after restoring the whole stream
+            if !force && fs::symlink_metadata(output).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("Restore output {output:?} already exists"),
+                ));
+            }
+            let Some(file_name) = output.file_name() else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Restore output {output:?} is not a file name"),
+                ));
+            };
+            let dir = match output.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            let mut prefix = OsString::from(".");
+            prefix.push(file_name);
+            prefix.push(".");
+            let temp = tempfile::Builder::new()
+                .prefix(&prefix)
+                .suffix(".part")
+                .permissions(fs::Permissions::from_mode(0o600))
+                .tempfile_in(dir)?;
+            log::info!(
+                "Creating restore output {output:?} via {temp_path:?}",
+                temp_path = temp.path()
+            );
+            RestoreOutput::File {
+                path: output.to_path_buf(),
+                temp,
+                overwrite: force,
+            }
         }
     };
     Ok(writer)
 }
 
+/// Whether `path` is an existing block or character device or FIFO, following
+/// symlinks like `/dev/disk/by-id/…`.
+fn is_device(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|metadata| {
+        let file_type = metadata.file_type();
+        file_type.is_block_device() || file_type.is_char_device() || file_type.is_fifo()
+    })
+}
+
 fn walk_and_watch_restore_dir(
     path: &Path,
     watch: Box<Watch>,
     mut queue: FragmentQueue,
+    follow_symlinks: bool,
 ) -> io::Result<Option<JoinHandle<io::Result<()>>>> {
+    // reconstruct lost chunks of a completely downloaded backup
+    #[cfg(feature = "fec")]
+    if path.join(CHUNK_FILE_PREFIX).with_extension("0").exists() {
+        let repaired = fec::repair(path, CHUNK_FILE_PREFIX)?;
+        if !repaired.is_empty() {
+            log::warn!(
+                "Reconstructed {count} chunk(s) from parity",
+                count = repaired.len()
+            );
+        }
+    }
+
     // enter path, only retrieving direct children
     let walk = WalkDir::new(path)
-        .follow_root_links(false)
+        .follow_root_links(follow_symlinks)
+        .follow_links(follow_symlinks)
         .min_depth(1)
         .max_depth(1);
 
@@ -126,7 +792,7 @@ fn walk_and_watch_restore_dir(
                 continue;
             }
             Ok(dir_entry) => {
-                if dir_entry.path_is_symlink() {
+                if !follow_symlinks && dir_entry.path_is_symlink() {
                     log::debug!("Ignoring symlink {dir_entry:?}");
                     continue;
                 }
@@ -144,7 +810,7 @@ fn walk_and_watch_restore_dir(
     if queue.send_zero_maybe()? {
         Ok(None)
     } else {
-        let handle = watch_restore_dir(path, watch, queue)?;
+        let handle = watch_restore_dir(path, watch, queue, follow_symlinks)?;
         Ok(Some(handle))
     }
 }
@@ -153,6 +819,7 @@ fn watch_restore_dir(
     path: &Path,
     mut watch: Box<Watch>,
     queue: FragmentQueue,
+    follow_symlinks: bool,
 ) -> io::Result<JoinHandle<io::Result<()>>> {
     log::debug!("Monitoring restore queue at {path:?}");
     watch
@@ -160,22 +827,45 @@ fn watch_restore_dir(
         .watch(path, RecursiveMode::NonRecursive)
         .map_err(notify_error)?;
 
-    let handle = thread::spawn(move || notify_event_worker(&watch, queue));
+    let handle = thread::spawn(move || notify_event_worker(&watch, queue, follow_symlinks));
     Ok(handle)
 }
 
-fn notify_event_worker(watch: &Watch, mut queue: FragmentQueue) -> io::Result<()> {
+fn notify_event_worker(
+    watch: &Watch,
+    mut queue: FragmentQueue,
+    follow_symlinks: bool,
+) -> io::Result<()> {
     log::trace!("Starting notify_event_worker…");
     let notify_receiver = watch.rx.lock().expect("Cannot lock watch receiver");
-    for event in notify_receiver.iter() {
+    for message in notify_receiver.iter() {
+        let event = match message {
+            WatchMessage::Event(event) => event,
+            WatchMessage::Shutdown => {
+                log::debug!("Shutting down notify_event_worker…");
+                break;
+            }
+        };
         match event.map_err(notify_error)? {
             notify::Event {
-                kind: EventKind::Create(CreateKind::File),
+                // poll watchers cannot tell files from folders
+                kind: EventKind::Create(CreateKind::File | CreateKind::Any),
+                paths,
+                ..
+            }
+            // chunks copied across filesystems are renamed into the queue,
+            // the old path of a rename within the queue is no file anymore
+            | notify::Event {
+                kind: EventKind::Modify(ModifyKind::Name(RenameMode::To | RenameMode::Both)),
                 paths,
                 ..
             } => {
                 for path in paths {
-                    if path.is_symlink() {
+                    if !path.is_file() {
+                        log::trace!("Ignoring non-file {path:?}");
+                        continue;
+                    }
+                    if !follow_symlinks && path.is_symlink() {
                         log::warn!("Ignoring symlink {path:?}");
                         continue;
                     }
@@ -197,18 +887,70 @@ fn notify_event_worker(watch: &Watch, mut queue: FragmentQueue) -> io::Result<()
     Ok(())
 }
 
-fn fragment_worker(
-    concat: Cat,
+/// Keys for decrypting the backup stream and the manifest, unlocked once for
+/// the whole restore.
+struct RestoreKeys {
     secret_key_store: SecretKeyStore,
+    #[cfg(feature = "age")]
+    age_keys: AgeKeys,
+}
+
+fn restore_keys(restore: &Restore, policy: &StandardPolicy) -> io::Result<RestoreKeys> {
+    // TODO use optional CRYOPHILE_ASKPASS instead of terminal prompt
+    // TODO batch mode should not try to prompt for password at all
+    let certs = restore.certs()?;
+    let secret_key_store = if cfg!(feature = "age") && certs.is_empty() {
+        // restoring age streams needs no OpenPGP keys
+        SecretKeyStore::new(HashMap::new(), HashMap::new(), None)
+    } else {
+        let password = restore.pass_fd.and_then(read_password_fd);
+        secret_key_store(policy, certs.iter(), password)?
+    };
+    Ok(RestoreKeys {
+        secret_key_store,
+        #[cfg(feature = "age")]
+        age_keys: AgeKeys::new(
+            &restore.age_identity,
+            restore.age_passphrase,
+            restore.age_pass_fd,
+        )?,
+    })
+}
+
+fn fragment_worker<R: io::Read + Send + Sync>(
+    input: R,
+    mut keys: RestoreKeys,
     policy: &StandardPolicy,
     compression: Option<CompressionType>,
-    mut output: Box<dyn io::Write>,
-) -> io::Result<u64> {
+    output: &mut dyn io::Write,
+) -> io::Result<(u64, RestoreKeys)> {
     log::trace!("Starting fragment_worker…");
-    let reader = io::BufReader::new(concat);
-    let decryptor = build_decryptor(secret_key_store, policy, reader).map_err(openpgp_error)?;
+    #[cfg_attr(not(feature = "age"), allow(unused_mut))]
+    let mut reader = io::BufReader::new(input);
+    #[cfg(feature = "age")]
+    if is_age_stream(reader.fill_buf()?) {
+        log::info!("Decrypting age restore stream…");
+        let decryptor = keys.age_keys.decryptor(reader)?;
+        let bytes_written = decompress(decryptor, compression, output)?;
+        log::trace!("Finishing fragment_worker…");
+        return Ok((bytes_written, keys));
+    }
+    let mut decryptor =
+        build_decryptor(keys.secret_key_store, policy, reader).map_err(decrypt_error)?;
+    let bytes_written = decompress(&mut decryptor, compression, output)?;
+    log::trace!("Finishing fragment_worker…");
+    // the secret keys are unlocked now, reuse them for the manifest
+    keys.secret_key_store = decryptor.into_helper();
+    Ok((bytes_written, keys))
+}
+
+fn decompress(
+    input: impl io::Read,
+    compression: Option<CompressionType>,
+    output: &mut dyn io::Write,
+) -> io::Result<u64> {
     // guess compression algorithm by default
-    let mut decompressor = Decompressor::new(decryptor);
+    let mut decompressor = Decompressor::new(input);
     if let Some(compression_type) = compression {
         // force decompression with compression_type
         log::info!("Decompressing restore stream with {compression_type:?}…");
@@ -216,7 +958,106 @@ fn fragment_worker(
     } else {
         log::info!("Guessing decompression algorithm from restore stream…");
     }
-    let bytes_written = decompressor.copy_to(&mut output)?;
-    log::trace!("Finishing fragment_worker…");
-    Ok(bytes_written)
+    decompressor.copy_to(output)
+}
+
+/// Decrypt the manifest from the zero chunk, an empty zero chunk has none.
+/// The keys are returned for reuse.
+fn read_manifest(
+    zero_chunk: impl io::Read + Send + Sync,
+    mut keys: RestoreKeys,
+    policy: &StandardPolicy,
+) -> io::Result<(Option<Manifest>, RestoreKeys)> {
+    let mut reader = io::BufReader::new(zero_chunk);
+    if reader.fill_buf()?.is_empty() {
+        return Ok((None, keys));
+    }
+    let mut manifest = String::new();
+    #[cfg(feature = "age")]
+    if is_age_stream(reader.fill_buf()?) {
+        keys.age_keys
+            .decryptor(reader)?
+            .read_to_string(&mut manifest)?;
+        return Ok((Some(manifest.parse()?), keys));
+    }
+    let mut decryptor =
+        build_decryptor(keys.secret_key_store, policy, reader).map_err(decrypt_error)?;
+    decryptor.read_to_string(&mut manifest)?;
+    keys.secret_key_store = decryptor.into_helper();
+    Ok((Some(manifest.parse()?), keys))
+}
+
+fn needs_manifest(restore: &Restore) -> bool {
+    !restore.no_verify_digest || restore.preserve_mode || restore.preserve_owner
+}
+
+/// Verify the restored stream and apply the input metadata of the manifest
+/// to the restore output, before it is persisted.
+fn apply_manifest(
+    restore: &Restore,
+    manifest: Option<&Manifest>,
+    sha256: &str,
+    outputs: &[RestoreOutput],
+) -> io::Result<()> {
+    if restore.no_verify_digest {
+        log::warn!("Not verifying digest of restored stream");
+    } else {
+        check_digest(restore, manifest, sha256)?;
+    }
+    // changing the owner clears setuid and setgid bits, so set the mode last
+    if restore.preserve_owner {
+        match manifest {
+            Some(Manifest {
+                uid: uid @ Some(_),
+                gid,
+                ..
+            }) => {
+                for output in outputs {
+                    output.set_owner(*uid, *gid)?;
+                }
+            }
+            _ => log::warn!("Backup has no input owner, keeping owner of restore output"),
+        }
+    }
+    if restore.preserve_mode {
+        match manifest.and_then(|manifest| manifest.mode) {
+            Some(mode) => {
+                for output in outputs {
+                    output.set_mode(mode)?;
+                }
+            }
+            None => log::warn!("Backup has no input mode, keeping mode of restore output"),
+        }
+    }
+    Ok(())
+}
+
+/// Verify the restored stream, but only warn about a mismatch if it was passed
+/// through verbatim: the manifest digest is of the uncompressed input.
+fn check_digest(restore: &Restore, manifest: Option<&Manifest>, sha256: &str) -> io::Result<()> {
+    match verify_digest(manifest, sha256) {
+        Err(err) if restore.passes_through() => {
+            log::warn!("Cannot verify stream passed through without decompression: {err}");
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Compare the digest of the restored stream with the manifest digest.
+fn verify_digest(manifest: Option<&Manifest>, sha256: &str) -> io::Result<()> {
+    match manifest.and_then(|manifest| manifest.sha256.as_deref()) {
+        None => {
+            log::warn!("Backup has no manifest digest, cannot verify restored stream");
+            Ok(())
+        }
+        Some(expected) if expected == sha256 => {
+            log::info!("Verified restored stream with SHA-256 {sha256}");
+            Ok(())
+        }
+        Some(expected) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Restored stream has SHA-256 {sha256}, but the manifest expects {expected}"),
+        )),
+    }
 }
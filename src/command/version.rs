@@ -0,0 +1,100 @@
+// Copyright The Cryophile Authors.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE> or
+// <http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT> or <http://opensource.org/licenses/MIT>, at your option.
+//
+// This file may not be copied, modified, or distributed except according
+// to those terms.
+
+use crate::cli::Version;
+use crate::compression::CompressionType;
+use clap::ValueEnum;
+use sequoia_openpgp::types::{
+    AEADAlgorithm, HashAlgorithm, PublicKeyAlgorithm, SymmetricAlgorithm,
+};
+use std::fmt::Display;
+use std::io::{self, Write};
+
+/// Cargo features compiled into this binary.
+const FEATURES: &[(&str, bool)] = &[
+    ("age", cfg!(feature = "age")),
+    ("bzip2", cfg!(feature = "bzip2")),
+    ("fec", cfg!(feature = "fec")),
+    ("gnupg", cfg!(feature = "gnupg")),
+];
+
+pub fn perform_version(version: &Version) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    writeln!(
+        stdout,
+        "{name} {version}",
+        name = clap::crate_name!(),
+        version = clap::crate_version!()
+    )?;
+    if !version.verbose {
+        return Ok(());
+    }
+
+    let features: Vec<&str> = FEATURES
+        .iter()
+        .filter_map(|(feature, enabled)| enabled.then_some(*feature))
+        .collect();
+    writeln!(stdout, "features: {}", join_or_none(features))?;
+    let codecs = CompressionType::value_variants()
+        .iter()
+        .filter_map(|codec| codec.to_possible_value())
+        .map(|value| value.get_name().to_owned());
+    writeln!(stdout, "compression: {}", join_or_none(codecs))?;
+
+    writeln!(
+        stdout,
+        "aws-sdk-s3 {version}",
+        version = aws_sdk_s3::meta::PKG_VERSION
+    )?;
+    writeln!(
+        stdout,
+        "aws-types {version}",
+        version = aws_types::build_metadata::BUILD_METADATA.core_pkg_version
+    )?;
+    writeln!(
+        stdout,
+        "sequoia-openpgp {version}",
+        version = sequoia_openpgp::VERSION
+    )?;
+    writeln!(
+        stdout,
+        "public key algorithms: {}",
+        join_or_none(
+            PublicKeyAlgorithm::variants()
+                .filter(|algo| algo.is_supported())
+                // Display does not distinguish the RSA variants
+                .map(|algo| format!("{algo:?}"))
+        )
+    )?;
+    writeln!(
+        stdout,
+        "symmetric algorithms: {}",
+        join_or_none(SymmetricAlgorithm::variants().filter(|algo| algo.is_supported()))
+    )?;
+    writeln!(
+        stdout,
+        "AEAD algorithms: {}",
+        join_or_none(AEADAlgorithm::variants().filter(|algo| algo.is_supported()))
+    )?;
+    writeln!(
+        stdout,
+        "hash algorithms: {}",
+        join_or_none(HashAlgorithm::variants().filter(|algo| algo.is_supported()))
+    )?;
+    Ok(())
+}
+
+fn join_or_none<T: Display>(items: impl IntoIterator<Item = T>) -> String {
+    let items: Vec<String> = items.into_iter().map(|item| item.to_string()).collect();
+    if items.is_empty() {
+        "none".to_owned()
+    } else {
+        items.join(" ")
+    }
+}
@@ -8,9 +8,9 @@
 // to those terms.
 
 use clap::ValueEnum;
-use serde_derive::Deserialize;
+use serde_derive::{Deserialize, Serialize};
 
-#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, ValueEnum)]
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize, ValueEnum)]
 pub enum CompressionType {
     #[default]
     None,
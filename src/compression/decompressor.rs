@@ -9,6 +9,7 @@
 
 use std::io::{self, Read};
 
+use super::encoder::ZSTD_WINDOW_LOG_MAX;
 use super::CompressionType;
 
 pub struct Decompressor<'a> {
@@ -64,13 +65,19 @@ impl<'a> Decompressor<'a> {
             0xFD2FB528 => {
                 // zstd magic: https://github.com/facebook/zstd/blob/dev/doc/zstd_compression_format.md
                 log::info!("Using Zstandard decompression…");
-                Box::new(zstd::stream::Decoder::new(input)?)
+                Box::new(zstd_decoder(input)?)
             }
             0x184D2204 => {
                 // lz4 magic: https://github.com/lz4/lz4/blob/dev/doc/lz4_Frame_format.md
                 log::info!("Using LZ4 decompression…");
                 Box::new(lz4_flex::frame::FrameDecoder::new(input))
             }
+            #[cfg(feature = "bzip2")]
+            _ if is_bzip2_magic(&magic) => {
+                // bzip2 magic "BZh" followed by the block size 1-9, restore only
+                log::info!("Using bzip2 decompression…");
+                Box::new(bzip2::read::MultiBzDecoder::new(input))
+            }
             _ => {
                 log::info!("Using no decompression…");
                 Box::new(input)
@@ -92,7 +99,7 @@ impl<'a> Decompressor<'a> {
                 }
                 CompressionType::Zstd => {
                     log::info!("Using Zstandard decompression…");
-                    Box::new(zstd::stream::Decoder::new(self.input)?)
+                    Box::new(zstd_decoder(self.input)?)
                 }
             };
             io::copy(&mut decompressor, writer)
@@ -101,3 +108,19 @@ impl<'a> Decompressor<'a> {
         }
     }
 }
+
+/// Check for a bzip2 stream header, i.e., "BZh" and the block size digit.
+#[cfg(feature = "bzip2")]
+fn is_bzip2_magic(magic: &[u8; 4]) -> bool {
+    magic.starts_with(b"BZh") && (b'1'..=b'9').contains(&magic[3])
+}
+
+/// Create a Zstandard decoder accepting frames up to the largest window log,
+/// e.g., of backups compressed with long distance matching.
+fn zstd_decoder<'a, R: io::Read>(
+    input: R,
+) -> io::Result<zstd::stream::Decoder<'a, io::BufReader<R>>> {
+    let mut decoder = zstd::stream::Decoder::new(input)?;
+    decoder.window_log_max(ZSTD_WINDOW_LOG_MAX)?;
+    Ok(decoder)
+}
@@ -11,6 +11,30 @@ use std::io;
 
 use crate::core::Split;
 
+pub const ZSTD_WINDOW_LOG_MIN: u32 = 10;
+
+pub const ZSTD_WINDOW_LOG_MAX: u32 = 31;
+
+/// Create a Zstandard encoder, optionally with long distance matching using a
+/// window of `2^window_log` bytes and `workers` compression threads.
+pub fn zstd_encoder<'a, W: io::Write>(
+    writer: W,
+    window_log: Option<u32>,
+    workers: u32,
+) -> io::Result<zstd::stream::Encoder<'a, W>> {
+    let mut encoder = zstd::stream::Encoder::new(writer, 0)?;
+    if let Some(window_log) = window_log {
+        log::debug!("Using Zstandard long distance matching with window log {window_log}…");
+        encoder.long_distance_matching(true)?;
+        encoder.window_log(window_log)?;
+    }
+    if workers > 0 {
+        log::debug!("Using {workers} Zstandard worker thread(s)…");
+        encoder.multithread(workers)?;
+    }
+    Ok(encoder)
+}
+
 pub trait CompleteEncoder: io::Write {
     fn complete(&mut self) -> io::Result<()> {
         log::trace!("Complete encoder");
@@ -7,37 +7,83 @@
 // This file may not be copied, modified, or distributed except according
 // to those terms.
 
-use serde_derive::Deserialize;
+use serde_derive::{Deserialize, Serialize};
 use std::{
+    env,
     fs::File,
     io::{self, Read},
-    path::Path,
+    path::{Path, PathBuf},
     str::FromStr,
 };
 use thiserror::Error;
 
 use crate::compression::CompressionType;
+use crate::core::aws::CredentialSource;
 
-#[derive(Debug, Default, Deserialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct ConfigFile {
     pub compression: Option<CompressionType>,
+    /// Root of the freeze queue, if not below the spool
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub freeze_spool: Option<PathBuf>,
+    #[serde(default)]
     pub vault: Vec<Vault>,
 }
 
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
 pub struct Vault {
     pub id: uuid::Uuid,
     pub compression: Option<CompressionType>,
     pub profile: Option<Profile>,
     pub bucket: Option<Bucket>,
+    /// Prefix prepended to the prefix of every backup in the vault
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_prefix: Option<String>,
 }
 
-#[derive(Debug, Deserialize, PartialEq)]
+impl Vault {
+    /// Overlay `other` on top of `self`, see [`ConfigFile::merge`].
+    pub fn merge(self, other: Vault) -> Vault {
+        Vault {
+            id: self.id,
+            compression: other.compression.or(self.compression),
+            profile: match (self.profile, other.profile) {
+                (Some(profile), Some(other_profile)) => Some(profile.merge(other_profile)),
+                (profile, other_profile) => other_profile.or(profile),
+            },
+            bucket: other.bucket.or(self.bucket),
+            default_prefix: other.default_prefix.or(self.default_prefix),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
 pub struct Profile {
     pub provider: String,
+    /// Source of AWS credentials, defaults to the default credential chain
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credentials: Option<CredentialSource>,
+    /// Use the S3 Transfer Acceleration endpoint
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accelerate: Option<bool>,
+    /// Use the dual-stack (IPv4 and IPv6) endpoint
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dualstack: Option<bool>,
+}
+
+impl Profile {
+    /// Overlay `other` on top of `self` field by field, see [`ConfigFile::merge`].
+    pub fn merge(self, other: Profile) -> Profile {
+        Profile {
+            provider: other.provider,
+            credentials: other.credentials.or(self.credentials),
+            accelerate: other.accelerate.or(self.accelerate),
+            dualstack: other.dualstack.or(self.dualstack),
+        }
+    }
 }
 
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
 pub struct Bucket {
     pub name: String,
 }
@@ -48,23 +94,169 @@ pub enum ParseConfigError {
     TomlDeError(#[from] toml::de::Error),
     #[error("IoError")]
     IoError(#[from] io::Error),
+    #[error("Environment variable expansion error: {0}")]
+    EnvExpansion(String),
+    #[error("Invalid configuration: {0}")]
+    Invalid(String),
+    #[error("Duplicate vault id {0}")]
+    DuplicateVault(uuid::Uuid),
+}
+
+/// Expand `${VAR}` and `${VAR:-fallback}` in `value` using `lookup`.
+///
+/// As in the shell, the fallback is used if `VAR` is unset or empty. An unset
+/// variable without fallback is an error.
+fn expand_env_with<F>(value: &str, lookup: F) -> Result<String, ParseConfigError>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('}') else {
+            return Err(ParseConfigError::EnvExpansion(format!(
+                "unterminated variable in {value:?}"
+            )));
+        };
+        let expression = &rest[start + 2..start + end];
+        let (name, fallback) = match expression.split_once(":-") {
+            Some((name, fallback)) => (name, Some(fallback)),
+            None => (expression, None),
+        };
+        let valid_name = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid_name {
+            return Err(ParseConfigError::EnvExpansion(format!(
+                "invalid variable name {name:?} in {value:?}"
+            )));
+        }
+        match (lookup(name), fallback) {
+            (Some(var), Some(fallback)) if var.is_empty() => expanded.push_str(fallback),
+            (Some(var), _) => expanded.push_str(&var),
+            (None, Some(fallback)) => expanded.push_str(fallback),
+            (None, None) => {
+                return Err(ParseConfigError::EnvExpansion(format!(
+                    "undefined variable {name:?} in {value:?}"
+                )));
+            }
+        }
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+// https://docs.aws.amazon.com/AmazonS3/latest/userguide/bucketnamingrules.html
+fn valid_bucket_name(name: &str) -> bool {
+    (3..=63).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '.' || c == '-')
+        && name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && name.ends_with(|c: char| c.is_ascii_alphanumeric())
+}
+
+fn expand_env(value: &str) -> Result<String, ParseConfigError> {
+    expand_env_with(value, |name| env::var(name).ok())
 }
 
 impl FromStr for ConfigFile {
     type Err = ParseConfigError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let config = match toml::from_str::<ConfigFile>(s) {
+        let mut config = match toml::from_str::<ConfigFile>(s) {
             Ok(config) => config,
             Err(err) => {
                 return Err(ParseConfigError::from(err));
             }
         };
+        config.expand_env()?;
+        config.validate()?;
         Ok(config)
     }
 }
 
 impl ConfigFile {
+    /// Expand environment variables in string values, see [`expand_env_with`].
+    fn expand_env(&mut self) -> Result<(), ParseConfigError> {
+        for vault in self.vault.iter_mut() {
+            if let Some(profile) = vault.profile.as_mut() {
+                profile.provider = expand_env(&profile.provider)?;
+            }
+            if let Some(bucket) = vault.bucket.as_mut() {
+                bucket.name = expand_env(&bucket.name)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Overlay `other` on top of `self`: scalar values of `other` take
+    /// precedence, and vaults with the same id are merged field by field.
+    pub fn merge(self, other: ConfigFile) -> ConfigFile {
+        let mut vault = self.vault;
+        for other_vault in other.vault {
+            if let Some(pos) = vault.iter().position(|v| v.id == other_vault.id) {
+                let merged = vault.remove(pos).merge(other_vault);
+                vault.insert(pos, merged);
+            } else {
+                vault.push(other_vault);
+            }
+        }
+        ConfigFile {
+            compression: other.compression.or(self.compression),
+            freeze_spool: other.freeze_spool.or(self.freeze_spool),
+            vault,
+        }
+    }
+
+    /// Reject configurations that cannot be used unambiguously.
+    pub fn validate(&self) -> Result<(), ParseConfigError> {
+        for (pos, vault) in self.vault.iter().enumerate() {
+            if self.vault[..pos].iter().any(|v| v.id == vault.id) {
+                return Err(ParseConfigError::DuplicateVault(vault.id));
+            }
+        }
+        Ok(())
+    }
+
+    /// List all problems of a valid configuration. Absent optional fields are
+    /// not problems, only invalid values are.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for vault in self.vault.iter() {
+            // profile and bucket are optional, only check them if present
+            if vault
+                .profile
+                .as_ref()
+                .is_some_and(|p| p.provider.is_empty())
+            {
+                problems.push(format!("vault {id} has an empty provider", id = vault.id));
+            }
+            if let Some(bucket) = vault.bucket.as_ref() {
+                if !valid_bucket_name(&bucket.name) {
+                    problems.push(format!(
+                        "vault {id} has invalid bucket name {name:?}",
+                        id = vault.id,
+                        name = bucket.name
+                    ));
+                }
+            }
+            let accelerate = vault.profile.as_ref().and_then(|p| p.accelerate) == Some(true);
+            // acceleration needs virtual-hosted-style addressing
+            if let Some(bucket) = vault.bucket.as_ref().filter(|_| accelerate) {
+                if bucket.name.contains('.') {
+                    problems.push(format!(
+                        "vault {id} cannot accelerate bucket {name:?} with dots in its name",
+                        id = vault.id,
+                        name = bucket.name
+                    ));
+                }
+            }
+        }
+        problems
+    }
+
     pub fn new(path: &Path) -> Result<Self, ParseConfigError> {
         let mut file = File::open(path).map_err(ParseConfigError::from)?;
         let mut buf = String::new();
@@ -105,11 +297,16 @@ compression = "Lz4"
             id: uuid::Uuid::from_str("797daf41-ba2c-440e-a56a-d0a190403a0b").unwrap(),
             profile: Some(Profile {
                 provider: "s3".to_owned(),
+                // a partial user profile keeps the system credentials
+                credentials: Some(CredentialSource::WebIdentity),
+                accelerate: None,
+                dualstack: None,
             }),
             compression: None,
             bucket: Some(Bucket {
                 name: "the-bucket-name".to_owned(),
             }),
+            default_prefix: None,
         };
         assert_eq!(vaults.next().expect("1st vault missing"), &v0);
 
@@ -117,12 +314,283 @@ compression = "Lz4"
             id: uuid::Uuid::from_str("23e52b86-7293-4889-824f-50135685c9e4").unwrap(),
             profile: Some(Profile {
                 provider: "s3".to_owned(),
+                credentials: None,
+                accelerate: None,
+                dualstack: None,
             }),
             compression: Some(CompressionType::Lz4),
             bucket: None,
+            default_prefix: None,
         };
         assert_eq!(vaults.next().expect("2nd vault missing"), &v1);
 
         assert_eq!(vaults.next(), None);
     }
+
+    #[test]
+    fn expand_env_config_values() {
+        let lookup = |name: &str| match name {
+            "HOST" => Some(String::from("host-a")),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        };
+        assert_eq!(
+            expand_env_with("bucket-${HOST}", lookup).unwrap(),
+            "bucket-host-a"
+        );
+        assert_eq!(
+            expand_env_with("${HOST}/${HOST:-other}", lookup).unwrap(),
+            "host-a/host-a"
+        );
+        assert_eq!(
+            expand_env_with("bucket-${UNDEFINED:-default}", lookup).unwrap(),
+            "bucket-default"
+        );
+        assert_eq!(
+            expand_env_with("bucket-${EMPTY:-default}", lookup).unwrap(),
+            "bucket-default"
+        );
+        assert_eq!(
+            expand_env_with("bucket-${EMPTY}", lookup).unwrap(),
+            "bucket-"
+        );
+        assert_eq!(expand_env_with("$HOST", lookup).unwrap(), "$HOST");
+        assert!(matches!(
+            expand_env_with("bucket-${UNDEFINED}", lookup),
+            Err(ParseConfigError::EnvExpansion(_))
+        ));
+        assert!(matches!(
+            expand_env_with("bucket-${HOST", lookup),
+            Err(ParseConfigError::EnvExpansion(_))
+        ));
+        assert!(matches!(
+            expand_env_with("bucket-${}", lookup),
+            Err(ParseConfigError::EnvExpansion(_))
+        ));
+    }
+
+    #[test]
+    fn expand_env_config_file() {
+        env::set_var("CRYOPHILE_TEST_BUCKET", "the-bucket-name");
+        let config_str = r#"[[vault]]
+id = "797daf41-ba2c-440e-a56a-d0a190403a0b"
+    [vault.profile]
+    provider = "${CRYOPHILE_TEST_UNDEFINED_PROVIDER:-s3}"
+    [vault.bucket]
+    name = "${CRYOPHILE_TEST_BUCKET}"
+"#;
+        let config = ConfigFile::from_str(config_str).expect("should expand variables");
+        let vault = config.vault.first().expect("vault missing");
+        assert_eq!(vault.profile.as_ref().unwrap().provider, "s3");
+        assert_eq!(vault.bucket.as_ref().unwrap().name, "the-bucket-name");
+
+        let config_str = r#"[[vault]]
+id = "797daf41-ba2c-440e-a56a-d0a190403a0b"
+    [vault.bucket]
+    name = "${CRYOPHILE_TEST_UNDEFINED_BUCKET}"
+"#;
+        assert!(matches!(
+            ConfigFile::from_str(config_str),
+            Err(ParseConfigError::EnvExpansion(_))
+        ));
+    }
+
+    #[test]
+    fn credentials_config_file() {
+        let config_str = r#"[[vault]]
+id = "797daf41-ba2c-440e-a56a-d0a190403a0b"
+    [vault.profile]
+    provider = "s3"
+    credentials = "web-identity"
+"#;
+        let config = ConfigFile::from_str(config_str).expect("should parse credentials");
+        let profile = config.vault[0].profile.as_ref().expect("profile missing");
+        assert_eq!(profile.credentials, Some(CredentialSource::WebIdentity));
+
+        let config_str = r#"[[vault]]
+id = "797daf41-ba2c-440e-a56a-d0a190403a0b"
+    [vault.profile]
+    provider = "s3"
+    credentials = "environment"
+"#;
+        assert!(ConfigFile::from_str(config_str).is_err());
+    }
+
+    #[test]
+    fn endpoint_config_file() {
+        let config_str = r#"[[vault]]
+id = "797daf41-ba2c-440e-a56a-d0a190403a0b"
+    [vault.profile]
+    provider = "s3"
+    accelerate = true
+    dualstack = true
+    [vault.bucket]
+    name = "the.bucket.name"
+"#;
+        let config = ConfigFile::from_str(config_str).expect("should parse endpoint options");
+        let profile = config.vault[0].profile.as_ref().expect("profile missing");
+        assert_eq!(profile.accelerate, Some(true));
+        assert_eq!(profile.dualstack, Some(true));
+        assert_eq!(
+            config.problems(),
+            vec![
+                "vault 797daf41-ba2c-440e-a56a-d0a190403a0b cannot accelerate bucket \"the.bucket.name\" with dots in its name"
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_config_files() {
+        let system_str = r#"compression = "Zstd"
+
+[[vault]]
+id = "797daf41-ba2c-440e-a56a-d0a190403a0b"
+compression = "Lz4"
+    [vault.profile]
+    provider = "s3"
+    credentials = "web-identity"
+    [vault.bucket]
+    name = "system-bucket"
+
+[[vault]]
+id = "23e52b86-7293-4889-824f-50135685c9e4"
+"#;
+        let user_str = r#"[[vault]]
+id = "797daf41-ba2c-440e-a56a-d0a190403a0b"
+    [vault.profile]
+    provider = "s3"
+    [vault.bucket]
+    name = "user-bucket"
+
+[[vault]]
+id = "6b7a4f4e-44c5-4d57-a5a4-3a2ba8a0a6c5"
+compression = "None"
+"#;
+        let system = ConfigFile::from_str(system_str).expect("system config");
+        let user = ConfigFile::from_str(user_str).expect("user config");
+        let config = system.merge(user);
+
+        // scalars missing from the user config are kept
+        assert_eq!(config.compression, Some(CompressionType::Zstd));
+        assert_eq!(config.vault.len(), 3);
+
+        let mut vaults = config.vault.iter();
+        let v0 = Vault {
+            id: uuid::Uuid::from_str("797daf41-ba2c-440e-a56a-d0a190403a0b").unwrap(),
+            compression: Some(CompressionType::Lz4),
+            profile: Some(Profile {
+                provider: "s3".to_owned(),
+                credentials: None,
+                accelerate: None,
+                dualstack: None,
+            }),
+            bucket: Some(Bucket {
+                name: "user-bucket".to_owned(),
+            }),
+            default_prefix: None,
+        };
+        assert_eq!(vaults.next().expect("1st vault missing"), &v0);
+        let v1 = Vault {
+            id: uuid::Uuid::from_str("23e52b86-7293-4889-824f-50135685c9e4").unwrap(),
+            compression: None,
+            profile: None,
+            bucket: None,
+            default_prefix: None,
+        };
+        assert_eq!(vaults.next().expect("2nd vault missing"), &v1);
+        let v2 = Vault {
+            id: uuid::Uuid::from_str("6b7a4f4e-44c5-4d57-a5a4-3a2ba8a0a6c5").unwrap(),
+            compression: Some(CompressionType::None),
+            profile: None,
+            bucket: None,
+            default_prefix: None,
+        };
+        assert_eq!(vaults.next().expect("3rd vault missing"), &v2);
+        assert_eq!(vaults.next(), None);
+
+        // scalars of the user config take precedence
+        let user = ConfigFile::from_str(r#"compression = "Lz4""#).expect("user config");
+        let config = ConfigFile::default().merge(user);
+        assert_eq!(config.compression, Some(CompressionType::Lz4));
+    }
+
+    #[test]
+    fn merge_endpoint_config_files() {
+        let system_str = r#"[[vault]]
+id = "797daf41-ba2c-440e-a56a-d0a190403a0b"
+    [vault.profile]
+    provider = "s3"
+    accelerate = true
+    dualstack = true
+"#;
+        let user_str = r#"[[vault]]
+id = "797daf41-ba2c-440e-a56a-d0a190403a0b"
+    [vault.profile]
+    provider = "s3"
+    dualstack = false
+"#;
+        let system = ConfigFile::from_str(system_str).expect("system config");
+        let user = ConfigFile::from_str(user_str).expect("user config");
+        let config = system.merge(user);
+        let profile = config.vault[0].profile.as_ref().expect("profile missing");
+        // flags missing from the user profile are kept
+        assert_eq!(profile.accelerate, Some(true));
+        assert_eq!(profile.dualstack, Some(false));
+    }
+
+    #[test]
+    fn config_file_problems() {
+        let config_str = r#"[[vault]]
+id = "797daf41-ba2c-440e-a56a-d0a190403a0b"
+    [vault.profile]
+    provider = "s3"
+    [vault.bucket]
+    name = "the-bucket-name"
+"#;
+        let config = ConfigFile::from_str(config_str).expect("should work as is");
+        assert!(config.problems().is_empty());
+
+        let config_str = r#"[[vault]]
+id = "797daf41-ba2c-440e-a56a-d0a190403a0b"
+    [vault.profile]
+    provider = ""
+    [vault.bucket]
+    name = "The_Bucket"
+
+[[vault]]
+id = "23e52b86-7293-4889-824f-50135685c9e4"
+"#;
+        let config = ConfigFile::from_str(config_str).expect("should parse");
+        assert_eq!(
+            config.problems(),
+            vec![
+                "vault 797daf41-ba2c-440e-a56a-d0a190403a0b has an empty provider",
+                "vault 797daf41-ba2c-440e-a56a-d0a190403a0b has invalid bucket name \"The_Bucket\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_vault_config_file() {
+        let config_str = r#"[[vault]]
+id = "797daf41-ba2c-440e-a56a-d0a190403a0b"
+    [vault.bucket]
+    name = "the-bucket-name"
+
+[[vault]]
+id = "23e52b86-7293-4889-824f-50135685c9e4"
+
+[[vault]]
+id = "797daf41-ba2c-440e-a56a-d0a190403a0b"
+    [vault.bucket]
+    name = "the-other-bucket-name"
+"#;
+        match ConfigFile::from_str(config_str) {
+            Err(ParseConfigError::DuplicateVault(id)) => assert_eq!(
+                id,
+                uuid::Uuid::from_str("797daf41-ba2c-440e-a56a-d0a190403a0b").unwrap()
+            ),
+            result => panic!("expected duplicate vault error, got {result:?}"),
+        }
+    }
 }
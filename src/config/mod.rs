@@ -9,9 +9,13 @@
 
 mod configfile;
 
+use std::path::Path;
+
 use xdg::BaseDirectories;
 
-use crate::cli::Cli;
+use crate::cli::{Cli, DEFAULT_SPOOL_PATH};
+use crate::core::aws::{AssumeRole, CredentialSource, S3Endpoint};
+use crate::core::watch::WatchConfig;
 
 pub use self::configfile::ConfigFile;
 pub use self::configfile::ParseConfigError;
@@ -20,10 +24,93 @@ pub struct Config {
     pub base: xdg::BaseDirectories,
     pub cli: Cli,
     pub file: ConfigFile,
+    /// S3 client used instead of one built from the AWS configuration
+    pub aws_client: Option<aws_sdk_s3::Client>,
 }
 
 impl Config {
     pub fn new(base: BaseDirectories, cli: Cli, file: ConfigFile) -> Self {
-        Self { base, cli, file }
+        Self {
+            base,
+            cli,
+            file,
+            aws_client: None,
+        }
+    }
+
+    /// Use `client` for all S3 requests, e.g., a client of a mock S3.
+    pub fn with_aws_client(mut self, client: aws_sdk_s3::Client) -> Self {
+        self.aws_client = Some(client);
+        self
+    }
+
+    /// Spool directory from the command line, or else the system spool.
+    pub fn spool(&self) -> &Path {
+        self.cli
+            .spool
+            .as_deref()
+            .unwrap_or(Path::new(DEFAULT_SPOOL_PATH))
+    }
+
+    /// Root of the freeze queue from the command line, or else from the
+    /// config file, the spool if neither has one.
+    pub fn freeze_spool(&self) -> Option<&Path> {
+        self.cli
+            .freeze_spool
+            .as_deref()
+            .or(self.file.freeze_spool.as_deref())
+    }
+
+    /// AWS credential source from the command line, or else from the
+    /// profile of `vault`.
+    pub fn credential_source(&self, vault: Option<uuid::Uuid>) -> CredentialSource {
+        self.cli
+            .aws_credentials
+            .or_else(|| {
+                self.file
+                    .vault
+                    .iter()
+                    .find(|v| Some(v.id) == vault)
+                    .and_then(|v| v.profile.as_ref())
+                    .and_then(|profile| profile.credentials)
+            })
+            .unwrap_or_default()
+    }
+
+    /// IAM role to assume with the AWS credentials, from the command line.
+    pub fn assume_role(&self) -> Option<AssumeRole> {
+        self.cli
+            .assume_role_arn
+            .as_ref()
+            .map(|role_arn| AssumeRole {
+                role_arn: role_arn.clone(),
+                external_id: self.cli.external_id.clone(),
+                session_name: self.cli.role_session_name.clone(),
+            })
+    }
+
+    /// S3 endpoint variant from the command line, or else from the profile
+    /// of `vault`.
+    pub fn s3_endpoint(&self, vault: Option<uuid::Uuid>) -> S3Endpoint {
+        let profile = self
+            .file
+            .vault
+            .iter()
+            .find(|v| Some(v.id) == vault)
+            .and_then(|v| v.profile.as_ref());
+        S3Endpoint {
+            accelerate: self.cli.s3_accelerate
+                || profile.and_then(|p| p.accelerate).unwrap_or_default(),
+            dualstack: self.cli.s3_dualstack
+                || profile.and_then(|p| p.dualstack).unwrap_or_default(),
+        }
+    }
+
+    /// File system watcher configuration from the command line.
+    pub fn watch_config(&self) -> WatchConfig {
+        WatchConfig {
+            poll: self.cli.watch_poll,
+            debounce: self.cli.watch_debounce,
+        }
     }
 }
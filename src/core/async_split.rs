@@ -0,0 +1,134 @@
+// Copyright The Cryophile Authors.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE> or
+// <http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT> or <http://opensource.org/licenses/MIT>, at your option.
+//
+// This file may not be copied, modified, or distributed except according
+// to those terms.
+
+use std::future::Future;
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::AsyncWrite;
+use tokio::task::JoinHandle;
+
+use super::Split;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operation {
+    Write,
+    Flush,
+    Finish,
+}
+
+type Done = (Option<Split>, Operation, io::Result<()>);
+
+enum State {
+    Idle(Option<Split>),
+    Busy(JoinHandle<Done>),
+}
+
+/// [`AsyncWrite`] adapter for [`Split`].
+///
+/// Chunk files are written, synced, and linked outgoing by the wrapped
+/// `Split` on the blocking thread pool of the tokio runtime. Like
+/// `tokio::fs::File`, a write returns as soon as the data is handed to the
+/// pool, and a write error is reported by the next write, flush, or shutdown.
+/// Shut down to link the last chunk outgoing, dropping an idle `AsyncSplit`
+/// links it on the current thread.
+pub struct AsyncSplit {
+    state: State,
+}
+
+impl AsyncSplit {
+    pub fn new(split: Split) -> Self {
+        AsyncSplit {
+            state: State::Idle(Some(split)),
+        }
+    }
+
+    /// Wait for a pending operation and return its result, if any.
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> Poll<Option<(Operation, io::Result<()>)>> {
+        let State::Busy(handle) = &mut self.state else {
+            return Poll::Ready(None);
+        };
+        let (split, operation, result) = match ready!(Pin::new(handle).poll(cx)) {
+            Ok(done) => done,
+            Err(err) => (None, Operation::Finish, Err(io::Error::other(err))),
+        };
+        self.state = State::Idle(split);
+        Poll::Ready(Some((operation, result)))
+    }
+
+    fn is_finished(&self) -> bool {
+        matches!(self.state, State::Idle(None))
+    }
+
+    fn spawn<F>(&mut self, operation: Operation, op: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut Split) -> io::Result<()> + Send + 'static,
+    {
+        let State::Idle(Some(mut split)) = std::mem::replace(&mut self.state, State::Idle(None))
+        else {
+            return Err(io::Error::other("Split is already finished"));
+        };
+        self.state = State::Busy(tokio::task::spawn_blocking(move || {
+            let result = op(&mut split);
+            if operation == Operation::Finish {
+                // the last chunk is linked, dropping only closes the file
+                return (None, operation, result);
+            }
+            (Some(split), operation, result)
+        }));
+        Ok(())
+    }
+}
+
+impl AsyncWrite for AsyncSplit {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if let Some((_, Err(err))) = ready!(self.poll_idle(cx)) {
+            return Poll::Ready(Err(err));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let data = buf.to_vec();
+        self.spawn(Operation::Write, move |split| split.write_all(&data))?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            match ready!(self.poll_idle(cx)) {
+                Some((Operation::Flush, result)) => return Poll::Ready(result),
+                Some((_, Err(err))) => return Poll::Ready(Err(err)),
+                Some((_, Ok(()))) | None => {}
+            }
+            if self.is_finished() {
+                return Poll::Ready(Ok(()));
+            }
+            self.spawn(Operation::Flush, |split| split.flush())?;
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            match ready!(self.poll_idle(cx)) {
+                Some((Operation::Finish, result)) => return Poll::Ready(result),
+                Some((_, Err(err))) => return Poll::Ready(Err(err)),
+                Some((_, Ok(()))) | None => {}
+            }
+            if self.is_finished() {
+                return Poll::Ready(Ok(()));
+            }
+            self.spawn(Operation::Finish, |split| split.finish())?;
+        }
+    }
+}
@@ -7,30 +7,237 @@
 // This file may not be copied, modified, or distributed except according
 // to those terms.
 
+use crate::core::constants::CHUNK_FILE_PREFIX;
+use crate::Config;
+use aws_config::profile::ProfileFileCredentialsProvider;
+use aws_config::provider_config::ProviderConfig;
+use aws_config::sts::AssumeRoleProvider;
+use aws_config::web_identity_token::WebIdentityTokenCredentialsProvider;
 use aws_config::{meta::region::RegionProviderChain, BehaviorVersion};
-use aws_sdk_s3::{config::Region, Client};
+use aws_sdk_s3::config::{Region, SharedCredentialsProvider};
+use aws_sdk_s3::Client;
 use aws_types::SdkConfig;
-use log::log_enabled;
+use clap::ValueEnum;
+use serde_derive::{Deserialize, Serialize};
+use std::{fmt, io};
+use ulid::Ulid;
 
-pub async fn aws_config(region: Option<String>) -> SdkConfig {
+/// Where AWS credentials are loaded from.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum CredentialSource {
+    /// The default credential chain of the AWS SDK
+    #[default]
+    Default,
+    /// The shared config profile from AWS_PROFILE, e.g., an SSO profile
+    Sso,
+    /// The web identity token from AWS_WEB_IDENTITY_TOKEN_FILE and AWS_ROLE_ARN
+    WebIdentity,
+}
+
+impl fmt::Display for CredentialSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let source = match self {
+            CredentialSource::Default => "default credential chain",
+            CredentialSource::Sso => "SSO profile",
+            CredentialSource::WebIdentity => "web identity token",
+        };
+        write!(f, "{source}")
+    }
+}
+
+/// IAM role assumed with the base credentials, e.g., for a bucket in
+/// another account.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AssumeRole {
+    pub role_arn: String,
+    /// External id the trust policy of the role may require
+    pub external_id: Option<String>,
+    /// Session name recorded in CloudTrail, generated by the SDK if unset
+    pub session_name: Option<String>,
+}
+
+#[tracing::instrument(level = "debug")]
+pub async fn aws_config(
+    region: Option<String>,
+    credentials: CredentialSource,
+    assume_role: Option<AssumeRole>,
+) -> SdkConfig {
     let region_provider = RegionProviderChain::first_try(region.map(Region::new))
         .or_default_provider()
         .or_else(Region::new("ca-central-1"));
 
-    if log_enabled!(log::Level::Trace) {
-        let region = region_provider
-            .region()
-            .await
-            .expect("Region provider missing");
-        log::trace!("Using S3 region {region}")
+    let region = region_provider
+        .region()
+        .await
+        .expect("Region provider missing");
+    log::trace!("Using S3 region {region}");
+    log::debug!("Using AWS credentials from {credentials}");
+
+    // credential providers calling STS or SSO need the region as well
+    let provider_config = ProviderConfig::default().with_region(Some(region.clone()));
+    let loader = aws_config::defaults(BehaviorVersion::latest()).region(region);
+    let loader = match credentials {
+        CredentialSource::Default => loader,
+        CredentialSource::Sso => loader.credentials_provider(
+            ProfileFileCredentialsProvider::builder()
+                .configure(&provider_config)
+                .build(),
+        ),
+        CredentialSource::WebIdentity => loader.credentials_provider(
+            WebIdentityTokenCredentialsProvider::builder()
+                .configure(&provider_config)
+                .build(),
+        ),
+    };
+    let config = loader.load().await;
+    let Some(assume_role) = assume_role else {
+        return config;
+    };
+
+    log::debug!("Assuming AWS role {}", assume_role.role_arn);
+    let mut builder = AssumeRoleProvider::builder(assume_role.role_arn).configure(&config);
+    if let Some(external_id) = assume_role.external_id {
+        builder = builder.external_id(external_id);
+    }
+    if let Some(session_name) = assume_role.session_name {
+        builder = builder.session_name(session_name);
     }
+    // the identity cache of the config keeps the assumed credentials and
+    // refreshes them before they expire
+    let provider = builder.build().await;
+    config
+        .into_builder()
+        .credentials_provider(SharedCredentialsProvider::new(provider))
+        .build()
+}
 
-    aws_config::defaults(BehaviorVersion::latest())
-        .region(region_provider)
-        .load()
-        .await
+/// Which S3 endpoint variant the client talks to.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct S3Endpoint {
+    /// S3 Transfer Acceleration, for buckets far from the spool
+    pub accelerate: bool,
+    /// Dual-stack endpoint reachable over IPv4 and IPv6
+    pub dualstack: bool,
+}
+
+#[tracing::instrument(level = "debug", skip(config))]
+pub async fn aws_client(config: &SdkConfig, endpoint: S3Endpoint) -> io::Result<Client> {
+    // acceleration needs virtual-hosted-style addressing of an AWS endpoint
+    if endpoint.accelerate {
+        if let Some(url) = config.endpoint_url() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Cannot use S3 Transfer Acceleration with custom endpoint {url}"),
+            ));
+        }
+    }
+    log::debug!("Using S3 endpoint {endpoint:?}");
+    let s3_config = aws_sdk_s3::config::Builder::from(config)
+        .accelerate(endpoint.accelerate)
+        .use_dual_stack(endpoint.dualstack)
+        .build();
+    Ok(Client::from_conf(s3_config))
 }
 
-pub async fn aws_client(config: &SdkConfig) -> Client {
-    Client::new(config)
+/// S3 client for `vault`, the client of `config` if it has one, or else a
+/// client with the credentials and endpoint of the vault profile.
+#[tracing::instrument(level = "debug", skip(config))]
+pub async fn vault_client(config: &Config, vault: Option<uuid::Uuid>) -> io::Result<Client> {
+    if let Some(client) = &config.aws_client {
+        log::debug!("Using the given S3 client");
+        return Ok(client.clone());
+    }
+    let aws_config = aws_config(None, config.credential_source(vault), config.assume_role()).await;
+    log::trace!(
+        "Using AWS config region {region:?}",
+        region = aws_config.region()
+    );
+    aws_client(&aws_config, config.s3_endpoint(vault)).await
+}
+
+/// Ulids of the backups stored below the vault key `prefix` in `bucket`,
+/// taken from the common prefixes of the object keys.
+#[tracing::instrument(level = "debug", skip(client))]
+pub async fn list_backups(client: &Client, bucket: &str, prefix: &str) -> io::Result<Vec<Ulid>> {
+    let prefix = if prefix.is_empty() {
+        String::new()
+    } else {
+        format!("{prefix}/")
+    };
+    log::debug!("Listing backups in s3://{bucket}/{prefix}…");
+    let mut pages = client
+        .list_objects_v2()
+        .bucket(bucket)
+        .prefix(&prefix)
+        .delimiter("/")
+        .into_paginator()
+        .send();
+    let mut ulids = vec![];
+    while let Some(page) = pages.next().await {
+        let page = page.map_err(|err| {
+            io::Error::other(format!(
+                "Cannot list backups in s3://{bucket}/{prefix}: {err}",
+                err = err.into_service_error()
+            ))
+        })?;
+        tracing::event!(
+            name: "list_objects_v2",
+            tracing::Level::TRACE,
+            bucket,
+            prefix,
+            common_prefixes = page.common_prefixes().len(),
+        );
+        ulids.extend(
+            page.common_prefixes()
+                .iter()
+                .filter_map(|common_prefix| common_prefix.prefix()?.strip_prefix(&prefix))
+                .filter_map(|name| Ulid::from_string(name.trim_end_matches('/')).ok()),
+        );
+    }
+    Ok(ulids)
+}
+
+/// Numbers of the chunk objects stored directly below the vault key `prefix`
+/// in `bucket`, in ascending order.
+#[tracing::instrument(level = "debug", skip(client))]
+pub async fn list_chunks(client: &Client, bucket: &str, prefix: &str) -> io::Result<Vec<u64>> {
+    let prefix = if prefix.is_empty() {
+        String::new()
+    } else {
+        format!("{prefix}/")
+    };
+    log::debug!("Listing chunks in s3://{bucket}/{prefix}…");
+    let mut pages = client
+        .list_objects_v2()
+        .bucket(bucket)
+        .prefix(&prefix)
+        .delimiter("/")
+        .into_paginator()
+        .send();
+    let mut chunks = vec![];
+    while let Some(page) = pages.next().await {
+        let page = page.map_err(|err| {
+            io::Error::other(format!(
+                "Cannot list chunks in s3://{bucket}/{prefix}: {err}",
+                err = err.into_service_error()
+            ))
+        })?;
+        tracing::event!(
+            name: "list_objects_v2",
+            tracing::Level::TRACE,
+            bucket,
+            prefix,
+            contents = page.contents().len(),
+        );
+        chunks.extend(
+            page.contents()
+                .iter()
+                .filter_map(|object| object.key()?.strip_prefix(&prefix))
+                .filter_map(|name| name.strip_prefix(CHUNK_FILE_PREFIX)?.strip_prefix('.'))
+                .filter_map(|num| num.parse::<u64>().ok()),
+        );
+    }
+    chunks.sort_unstable();
+    Ok(chunks)
 }
@@ -7,12 +7,65 @@
 // This file may not be copied, modified, or distributed except according
 // to those terms.
 
+use std::io;
 use std::path::{Component, Path};
+use std::sync::{LazyLock, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
 use std::{fmt, path::PathBuf};
 
-use ulid::Ulid;
+use ulid::{Generator, MonotonicError, Ulid};
 use uuid::Uuid;
 
+static ULID_GENERATOR: LazyLock<Mutex<Generator>> = LazyLock::new(|| Mutex::new(Generator::new()));
+
+/// Generate a ulid that is strictly greater than every ulid generated
+/// before by this process.
+pub fn monotonic_ulid() -> Ulid {
+    let mut generator = ULID_GENERATOR.lock().expect("cannot lock ulid generator");
+    loop {
+        match generator.generate() {
+            Ok(ulid) => return ulid,
+            Err(MonotonicError::Overflow) => {
+                // random bits exhausted within this millisecond, wait for the next one
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+    }
+}
+
+/// The latest of `ulids` created at or before `time`. Fails if there is none,
+/// or if several were created in the same millisecond.
+pub fn ulid_at(ulids: &[Ulid], time: SystemTime) -> io::Result<Ulid> {
+    let latest = ulids
+        .iter()
+        .filter(|ulid| ulid.datetime() <= time)
+        .map(Ulid::timestamp_ms)
+        .max()
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "No backup created at or before {time}",
+                    time = humantime::format_rfc3339_millis(time)
+                ),
+            )
+        })?;
+    let candidates = ulids
+        .iter()
+        .filter(|ulid| ulid.timestamp_ms() == latest)
+        .collect::<Vec<_>>();
+    match candidates[..] {
+        [ulid] => Ok(*ulid),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Backups {candidates:?} were created at the same time, use --ulid to select one"
+            ),
+        )),
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct BackupId<'a> {
     vault: Uuid,
@@ -44,12 +97,30 @@ fn canonical_relative_path(path: &Path) -> PathBuf {
     canonical_dir_path
 }
 
-fn canonical_prefix(prefix: &str) -> String {
-    String::from(
-        canonical_relative_path(&PathBuf::from(prefix))
-            .to_str()
-            .unwrap_or_default(),
-    )
+/// Canonical form of a prefix shared by spool paths and vault keys, or `None`
+/// if the prefix is empty after canonicalization (e.g., `./` or `a/../..`).
+fn canonical_prefix(prefix: &str) -> Option<String> {
+    let canonical_path = canonical_relative_path(Path::new(prefix));
+    let components = canonical_path
+        .components()
+        .map(|component| component.as_os_str().to_str().unwrap_or_default())
+        .collect::<Vec<_>>();
+    if components.is_empty() {
+        None
+    } else {
+        Some(components.join("/"))
+    }
+}
+
+/// Returns the first segment of the canonical prefix that parses as a vault
+/// [`Uuid`] or a backup [`Ulid`], which would make spool paths and vault keys
+/// ambiguous with the vault/ulid layout.
+pub fn ambiguous_prefix_segment(prefix: &str) -> Option<String> {
+    canonical_relative_path(Path::new(prefix))
+        .components()
+        .filter_map(|component| component.as_os_str().to_str())
+        .find(|segment| Uuid::parse_str(segment).is_ok() || Ulid::from_string(segment).is_ok())
+        .map(str::to_string)
 }
 
 impl<'a> BackupId<'a> {
@@ -61,6 +132,14 @@ impl<'a> BackupId<'a> {
         }
     }
 
+    pub fn from_vault(vault: Uuid) -> Self {
+        Self {
+            vault,
+            prefix: None,
+            ulid: None,
+        }
+    }
+
     pub fn from_prefix(vault: Uuid, prefix: &'a str) -> Self {
         Self {
             vault,
@@ -96,11 +175,8 @@ impl<'a> BackupId<'a> {
     pub fn to_path_buf(&self) -> PathBuf {
         let mut path = PathBuf::new();
         path.push(self.vault.to_string());
-        if let Some(pfx) = self.prefix {
-            let canonical_prefix = canonical_relative_path(&PathBuf::from(pfx.to_string()));
-            if !canonical_prefix.as_path().as_os_str().is_empty() {
-                path.push(canonical_prefix)
-            }
+        if let Some(canonical_prefix) = self.prefix.and_then(canonical_prefix) {
+            path.push(canonical_prefix)
         };
         if let Some(ulid) = self.ulid {
             path.push(ulid.to_string())
@@ -110,13 +186,13 @@ impl<'a> BackupId<'a> {
 
     pub fn to_vault_key(&self, delimiter: char) -> String {
         let mut vault_key = String::new();
-        if let Some(prefix) = self.prefix {
-            vault_key.push_str(&canonical_prefix(prefix));
-            if let Some(ulid) = self.ulid {
+        if let Some(canonical_prefix) = self.prefix.and_then(canonical_prefix) {
+            vault_key.push_str(&canonical_prefix);
+        };
+        if let Some(ulid) = self.ulid {
+            if !vault_key.is_empty() {
                 vault_key.push(delimiter);
-                vault_key.push_str(&ulid.to_string());
-            };
-        } else if let Some(ulid) = self.ulid {
+            }
             vault_key.push_str(&ulid.to_string());
         };
         vault_key
@@ -125,9 +201,10 @@ impl<'a> BackupId<'a> {
     pub fn to_delimited_string(&self, delimiter: char) -> String {
         let mut backup_id = String::new();
         backup_id.push_str(&self.vault.to_string());
-        if self.prefix.is_some() || self.ulid.is_some() {
+        let vault_key = self.to_vault_key(delimiter);
+        if !vault_key.is_empty() {
             backup_id.push(delimiter);
-            backup_id.push_str(&self.to_vault_key(delimiter));
+            backup_id.push_str(&vault_key);
         }
         backup_id
     }
@@ -137,8 +214,9 @@ impl<'a> fmt::Display for BackupId<'a> {
     // used in to_string()
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{vault}", vault = self.vault)?;
-        if self.prefix.is_some() || self.ulid.is_some() {
-            write!(f, "/{vault_key}", vault_key = self.to_vault_key('/'))?;
+        let vault_key = self.to_vault_key('/');
+        if !vault_key.is_empty() {
+            write!(f, "/{vault_key}")?;
         };
         Ok(())
     }
@@ -148,6 +226,33 @@ impl<'a> fmt::Display for BackupId<'a> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn monotonic_ulids() {
+        let ulids: Vec<Ulid> = (0..10_000).map(|_| monotonic_ulid()).collect();
+        assert!(ulids.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn select_ulid_at_time() {
+        let time = |ms| SystemTime::UNIX_EPOCH + Duration::from_millis(ms);
+        let ulids = [
+            Ulid::from_parts(1_000, 1),
+            Ulid::from_parts(2_000, 2),
+            Ulid::from_parts(3_000, 3),
+            Ulid::from_parts(3_000, 4),
+        ];
+        assert_eq!(ulid_at(&ulids, time(2_500)).unwrap(), ulids[1]);
+        assert_eq!(ulid_at(&ulids, time(2_000)).unwrap(), ulids[1]);
+        assert_eq!(
+            ulid_at(&ulids, time(999)).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(
+            ulid_at(&ulids, time(3_000)).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
     #[test]
     fn basic_backup_id() {
         let backup_id = BackupId::new(uuid::Uuid::nil(), None, ulid::Ulid::nil());
@@ -249,4 +354,75 @@ mod tests {
         let vault_key = backup_id.to_vault_key('+');
         assert_eq!(vault_key, String::from("prefix"));
     }
+
+    #[test]
+    fn ambiguous_prefix_backup_id() {
+        assert_eq!(ambiguous_prefix_segment("some/prefix"), None);
+        assert_eq!(ambiguous_prefix_segment("./"), None);
+        assert_eq!(
+            ambiguous_prefix_segment("some/797daf41-ba2c-440e-a56a-d0a190403a0b/prefix"),
+            Some(String::from("797daf41-ba2c-440e-a56a-d0a190403a0b"))
+        );
+        assert_eq!(
+            ambiguous_prefix_segment("01J0000000000000000000000A"),
+            Some(String::from("01J0000000000000000000000A"))
+        );
+        // segments removed by canonicalization are not ambiguous
+        assert_eq!(
+            ambiguous_prefix_segment("01J0000000000000000000000A/../prefix"),
+            None
+        );
+    }
+
+    fn assert_path_matches_key(backup_id: &BackupId) {
+        let path_components = backup_id
+            .to_path_buf()
+            .components()
+            .map(|component| component.as_os_str().to_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        let key_components = backup_id
+            .to_string()
+            .split('/')
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(path_components, key_components);
+    }
+
+    #[test]
+    fn canonical_prefix_backup_id() {
+        let nil_vault = "00000000-0000-0000-0000-000000000000";
+        let nil_ulid = "00000000000000000000000000";
+
+        for prefix in ["a/../..", "./", "..", "/"] {
+            let backup_id = BackupId::new(uuid::Uuid::nil(), Some(prefix), ulid::Ulid::nil());
+            assert_path_matches_key(&backup_id);
+            assert_eq!(
+                backup_id.to_path_buf(),
+                PathBuf::from(format!("{nil_vault}/{nil_ulid}"))
+            );
+            assert_eq!(backup_id.to_string(), format!("{nil_vault}/{nil_ulid}"));
+            assert_eq!(backup_id.to_vault_key('+'), nil_ulid);
+            assert_eq!(
+                backup_id.to_delimited_string('+'),
+                format!("{nil_vault}+{nil_ulid}")
+            );
+
+            let backup_id = BackupId::from_prefix(uuid::Uuid::nil(), prefix);
+            assert_path_matches_key(&backup_id);
+            assert_eq!(backup_id.to_path_buf(), PathBuf::from(nil_vault));
+            assert_eq!(backup_id.to_string(), nil_vault);
+            assert_eq!(backup_id.to_vault_key('+'), "");
+            assert_eq!(backup_id.to_delimited_string('+'), nil_vault);
+        }
+
+        for prefix in ["foo/./bar", "./foo/bar/", "foo//bar", "foo/baz/../bar"] {
+            let backup_id = BackupId::new(uuid::Uuid::nil(), Some(prefix), ulid::Ulid::nil());
+            assert_path_matches_key(&backup_id);
+            assert_eq!(
+                backup_id.to_path_buf(),
+                PathBuf::from(format!("{nil_vault}/foo/bar/{nil_ulid}"))
+            );
+            assert_eq!(backup_id.to_vault_key('+'), format!("foo/bar+{nil_ulid}"));
+        }
+    }
 }
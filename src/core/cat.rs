@@ -7,21 +7,28 @@
 // This file may not be copied, modified, or distributed except according
 // to those terms.
 
+use std::path::{Path, PathBuf};
 use std::sync::{mpsc, Mutex};
-use std::{fmt, fs, io, path::PathBuf};
+use std::{fmt, fs, io, thread};
 
 use std::sync::mpsc::{Receiver, Sender};
 
+use super::constants::CHUNK_FILE_PREFIX;
+use super::retry::{is_transient, retry_transient};
 use super::watch::channel_recv_error;
 
+/// A chunk path together with the result of opening it.
+type OpenedChunk = (PathBuf, io::Result<fs::File>);
+
 pub struct Cat {
     tx: Sender<Option<PathBuf>>,
     rx: Mutex<Receiver<Option<PathBuf>>>,
-    pos: usize,             // written bytes of current file
-    tot: usize,             // total bytes written
-    num: u64,               // number of files concatenated
-    file: Option<fs::File>, // current input file
-    mark_failed: bool,      // Cat had an error
+    prefetch: Option<Mutex<Receiver<Option<OpenedChunk>>>>, // chunks opened ahead
+    pos: usize,                                             // written bytes of current file
+    tot: usize,                                             // total bytes written
+    num: u64,                                               // number of files concatenated
+    file: Option<fs::File>,                                 // current input file
+    mark_failed: bool,                                      // Cat had an error
     completed: bool,
 }
 
@@ -44,6 +51,7 @@ impl Cat {
         Self {
             tx,
             rx: Mutex::new(rx),
+            prefetch: None,
             pos: 0,
             num: 0,
             tot: 0,
@@ -57,6 +65,53 @@ impl Cat {
         self.tx.to_owned()
     }
 
+    /// Open up to `depth` chunks ahead in a background thread, so reading
+    /// rarely waits for `open` at a chunk boundary. A `depth` of 0 opens each
+    /// chunk only when it is read.
+    pub fn with_prefetch(mut self, depth: usize) -> Self {
+        if depth == 0 {
+            return self;
+        }
+        log::debug!("Prefetching up to {depth} chunk(s)…");
+        let (_, rx) = mpsc::channel();
+        let paths = std::mem::replace(&mut self.rx, Mutex::new(rx))
+            .into_inner()
+            .expect("Cannot take cat receiver");
+        // the thread holds one opened chunk while it blocks on the bounded
+        // channel, which holds the others, so at most depth are opened ahead
+        let (opened_tx, opened_rx) = mpsc::sync_channel(depth - 1);
+        thread::spawn(move || {
+            for opt_path in paths.iter() {
+                let opened = opt_path.map(|path| {
+                    let file = open_chunk(&path);
+                    (path, file)
+                });
+                let last = opened.is_none();
+                if opened_tx.send(opened).is_err() || last {
+                    break; // cat is gone or completed
+                }
+            }
+            log::trace!("Finished prefetching chunks");
+        });
+        self.prefetch = Some(Mutex::new(opened_rx));
+        self
+    }
+
+    /// Receive the next chunk path and open it, or take the next prefetched
+    /// chunk. Returns `None` once all chunks were received.
+    fn next_chunk(&self) -> io::Result<Option<OpenedChunk>> {
+        if let Some(prefetch) = &self.prefetch {
+            let rx = prefetch.lock().expect("Cannot lock cat prefetch receiver");
+            return rx.recv().map_err(channel_recv_error);
+        }
+        let rx = self.rx.lock().expect("Cannot lock cat receiver");
+        let opt_path = rx.recv().map_err(channel_recv_error)?;
+        Ok(opt_path.map(|path| {
+            let file = open_chunk(&path);
+            (path, file)
+        }))
+    }
+
     #[tracing::instrument(level = "trace")]
     fn ok_or_retry(&mut self, n: usize) -> io::Result<usize> {
         if n == 0 {
@@ -80,6 +135,7 @@ impl Cat {
         let (tx, rx) = mpsc::channel();
         self.tx = tx;
         self.rx = Mutex::new(rx);
+        self.prefetch = None;
         self.pos = 0;
         self.tot = 0;
         self.num = 0;
@@ -118,39 +174,16 @@ impl io::Read for Cat {
             );
             return self.ok_or_retry(n);
         }
-        let opt_path = {
-            tracing::event!(
-                tracing::Level::TRACE,
-                action = "receive",
-                total_bytes = self.tot,
-                chunks = self.num
-            );
-            let rx = self.rx.lock().expect("Cannot lock cat receiver");
-            rx.recv().map_err(channel_recv_error)?
-        };
-        if let Some(path) = opt_path {
-            loop {
-                tracing::event!(
-                    tracing::Level::TRACE,
-                    action = "open",
-                    path = format!("{path:?}", path = path),
-                    total_bytes = self.tot,
-                    chunks = self.num
-                );
-                let mut file = match fs::File::options().read(true).open(&path) {
-                    Ok(file) => file,
-                    Err(err) if err.kind() == io::ErrorKind::Interrupted => {
-                        // io::ErrorKind::Interrupted we must retry actually
-                        log::debug!("Retrying interrupted open for {path:?}: {err}");
-                        continue;
-                    }
-                    Err(err) => {
-                        log::warn!("Ignoring that we could not open {path:?}: {err}");
-                        return self.ok_or_retry(0);
-                    }
-                };
+        tracing::event!(
+            tracing::Level::TRACE,
+            action = "receive",
+            total_bytes = self.tot,
+            chunks = self.num
+        );
+        match self.next_chunk()? {
+            Some((_, Ok(mut file))) => {
                 self.num += 1;
-                break file.read(buf).and_then(|n| {
+                file.read(buf).and_then(|n| {
                     tracing::event!(
                         tracing::Level::TRACE,
                         action = "read",
@@ -160,18 +193,69 @@ impl io::Read for Cat {
                     );
                     self.file = Some(file);
                     self.ok_or_retry(n)
-                });
+                })
             }
-        } else {
-            // self.file is None and received None from channel, just shutdown
-            tracing::event!(
-                tracing::Level::TRACE,
-                action = "completed",
-                total_bytes = self.tot,
-                chunks = self.num
-            );
-            self.completed = true;
-            Ok(0)
+            Some((path, Err(err))) if is_transient(&err) => {
+                // skipping a chunk that exists would corrupt the output
+                self.mark_failed = true;
+                log::error!("Cannot open {path:?} after retrying: {err}");
+                Err(io::Error::new(
+                    err.kind(),
+                    format!("Cannot open {path:?} after retrying: {err}"),
+                ))
+            }
+            Some((path, Err(err))) => {
+                log::warn!("Ignoring that we could not open {path:?}: {err}");
+                self.ok_or_retry(0)
+            }
+            None => {
+                // self.file is None and received None from channel, just shutdown
+                tracing::event!(
+                    tracing::Level::TRACE,
+                    action = "completed",
+                    total_bytes = self.tot,
+                    chunks = self.num
+                );
+                self.completed = true;
+                Ok(0)
+            }
+        }
+    }
+}
+
+/// The chunk files `chunk.1`, `chunk.2`, … in `dir` up to the first missing
+/// index, and their total size.
+pub fn numbered_chunks(dir: &Path) -> io::Result<(Vec<PathBuf>, u64)> {
+    let mut chunks = Vec::new();
+    let mut total = 0;
+    for index in 1.. {
+        let chunk = dir
+            .join(CHUNK_FILE_PREFIX)
+            .with_extension(index.to_string());
+        match fs::metadata(&chunk) {
+            Ok(metadata) if metadata.is_file() => total += metadata.len(),
+            Ok(_) => break,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => break,
+            Err(err) => return Err(err),
         }
+        chunks.push(chunk);
+    }
+    if chunks.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{dir:?} has no first chunk"),
+        ));
     }
+    Ok((chunks, total))
+}
+
+fn open_chunk(path: &Path) -> io::Result<fs::File> {
+    tracing::event!(
+        tracing::Level::TRACE,
+        action = "open",
+        path = format!("{path:?}", path = path),
+    );
+    retry_transient(&format!("open of {path:?}"), || {
+        fs::File::options().read(true).open(path)
+    })
 }
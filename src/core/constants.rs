@@ -7,8 +7,32 @@
 // This file may not be copied, modified, or distributed except according
 // to those terms.
 
+use std::time::Duration;
+
 pub static CHUNK_FILE_PREFIX: &str = "chunk";
 
+pub static BACKUP_LOCK_FILE: &str = ".lock";
+
+pub static CHECKSUM_FILE: &str = "SHA256SUMS";
+
+pub static FAILED_DIR: &str = ".failed";
+
+pub static FREEZE_LOCK_FILE: &str = ".freeze.lock";
+
+pub static PARITY_FILE_EXTENSION: &str = "par";
+
+pub const ARMOR_WARN_SIZE: u64 = 1024 * 1024;
+
 pub const CHUNK_FILE_MODE: u32 = 0o660;
 
 pub const DEFAULT_BUF_SIZE: usize = 8192;
+
+pub const EXPANSION_CHECK_SIZE: u64 = 4 * 1024 * 1024;
+
+pub const INLINE_COMPRESSION_MAX_SIZE: u64 = 1024 * 1024;
+
+pub const METRICS_INTERVAL: Duration = Duration::from_secs(15);
+
+pub const TRANSIENT_RETRIES: u32 = 5;
+
+pub const TRANSIENT_RETRY_DELAY: Duration = Duration::from_millis(50);
@@ -0,0 +1,513 @@
+// Copyright The Cryophile Authors.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE> or
+// <http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT> or <http://opensource.org/licenses/MIT>, at your option.
+//
+// This file may not be copied, modified, or distributed except according
+// to those terms.
+
+//! Reed-Solomon parity chunks over GF(2^8).
+//!
+//! Data chunks are grouped into stripes of at most `256 - parity` chunks, and
+//! every stripe gets `parity` parity chunks computed with a Cauchy matrix, so
+//! any `parity` missing data chunks of a stripe can be reconstructed. Parity
+//! chunk `k` is stored as `{prefix}.par{k}` and starts with a
+//! [`ParityHeader`] describing the geometry of the whole backup.
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::FileExt;
+use std::os::unix::prelude::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use super::constants::{CHUNK_FILE_MODE, PARITY_FILE_EXTENSION};
+use super::path::link_or_copy;
+use super::split::SyncPolicy;
+
+const PARITY_MAGIC: &[u8; 8] = b"CRYOPAR1";
+const PARITY_HEADER_LEN: u64 = 48;
+const BLOCK_SIZE: usize = 64 * 1024;
+const FIELD_SIZE: usize = 256;
+
+const fn gf_tables() -> ([u8; 512], [u8; 256]) {
+    let mut exp = [0u8; 512];
+    let mut log = [0u8; 256];
+    let mut x: u16 = 1;
+    let mut i = 0;
+    while i < 255 {
+        exp[i] = x as u8;
+        log[x as usize] = i as u8;
+        x <<= 1;
+        if x & 0x100 != 0 {
+            x ^= 0x11d;
+        }
+        i += 1;
+    }
+    while i < 512 {
+        exp[i] = exp[i - 255];
+        i += 1;
+    }
+    (exp, log)
+}
+
+const GF_TABLES: ([u8; 512], [u8; 256]) = gf_tables();
+
+fn gf_mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+    let (exp, log) = &GF_TABLES;
+    exp[log[a as usize] as usize + log[b as usize] as usize]
+}
+
+fn gf_inv(a: u8) -> u8 {
+    debug_assert!(a != 0, "zero has no inverse");
+    let (exp, log) = &GF_TABLES;
+    exp[255 - log[a as usize] as usize]
+}
+
+/// out ^= coefficient * input
+fn gf_mul_add(out: &mut [u8], coefficient: u8, input: &[u8]) {
+    if coefficient == 0 {
+        return;
+    }
+    for (o, i) in out.iter_mut().zip(input) {
+        *o ^= gf_mul(coefficient, *i);
+    }
+}
+
+/// Cauchy matrix coefficient of parity row `row` for data column `col`.
+fn cauchy(parity: usize, row: usize, col: usize) -> u8 {
+    // rows use x = 0..parity and columns y = parity.., so x ^ y is never zero
+    gf_inv((row ^ (parity + col)) as u8)
+}
+
+/// Invert a square matrix over GF(2^8) with Gauss-Jordan elimination.
+fn invert(mut matrix: Vec<Vec<u8>>) -> Option<Vec<Vec<u8>>> {
+    let n = matrix.len();
+    let mut inverse: Vec<Vec<u8>> = (0..n)
+        .map(|i| (0..n).map(|j| u8::from(i == j)).collect())
+        .collect();
+    for col in 0..n {
+        let pivot = (col..n).find(|&row| matrix[row][col] != 0)?;
+        matrix.swap(col, pivot);
+        inverse.swap(col, pivot);
+        let scale = gf_inv(matrix[col][col]);
+        for j in 0..n {
+            matrix[col][j] = gf_mul(matrix[col][j], scale);
+            inverse[col][j] = gf_mul(inverse[col][j], scale);
+        }
+        let pivot_row = matrix[col].clone();
+        let inverse_row = inverse[col].clone();
+        for row in 0..n {
+            let factor = matrix[row][col];
+            if row == col || factor == 0 {
+                continue;
+            }
+            gf_mul_add(&mut matrix[row], factor, &pivot_row);
+            gf_mul_add(&mut inverse[row], factor, &inverse_row);
+        }
+    }
+    Some(inverse)
+}
+
+/// Geometry of a backup with parity chunks, stored in every parity chunk.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ParityHeader {
+    pub chunk_size: u64,
+    pub data_chunks: u64,
+    pub last_len: u64,
+    pub parity: u64,
+    pub group: u64,
+}
+
+impl ParityHeader {
+    fn group_size(&self) -> u64 {
+        FIELD_SIZE as u64 - self.parity
+    }
+
+    fn groups(&self) -> u64 {
+        self.data_chunks.div_ceil(self.group_size())
+    }
+
+    /// Data chunk indices (starting at 1) of `group`.
+    fn group_chunks(&self, group: u64) -> std::ops::RangeInclusive<u64> {
+        let start = group * self.group_size() + 1;
+        let end = ((group + 1) * self.group_size()).min(self.data_chunks);
+        start..=end
+    }
+
+    fn chunk_len(&self, index: u64) -> u64 {
+        if index == self.data_chunks {
+            self.last_len
+        } else {
+            self.chunk_size
+        }
+    }
+
+    fn write_to(&self, writer: &mut dyn Write) -> io::Result<()> {
+        writer.write_all(PARITY_MAGIC)?;
+        for value in [
+            self.chunk_size,
+            self.data_chunks,
+            self.last_len,
+            self.parity,
+            self.group,
+        ] {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn read_from(reader: &mut dyn Read) -> io::Result<Self> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != PARITY_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not a parity chunk",
+            ));
+        }
+        let mut values = [0u64; 5];
+        for value in values.iter_mut() {
+            let mut bytes = [0u8; 8];
+            reader.read_exact(&mut bytes)?;
+            *value = u64::from_le_bytes(bytes);
+        }
+        let [chunk_size, data_chunks, last_len, parity, group] = values;
+        if parity == 0 || parity >= FIELD_SIZE as u64 || last_len > chunk_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid parity chunk header",
+            ));
+        }
+        Ok(Self {
+            chunk_size,
+            data_chunks,
+            last_len,
+            parity,
+            group,
+        })
+    }
+}
+
+fn data_path(dir: &Path, prefix: &str, index: u64) -> PathBuf {
+    dir.join(format!("{prefix}.{index}"))
+}
+
+fn parity_path(dir: &Path, prefix: &str, index: u64) -> PathBuf {
+    dir.join(format!("{prefix}.{PARITY_FILE_EXTENSION}{index}"))
+}
+
+/// Read `buf.len()` bytes at `offset`, zero padding beyond the end of file.
+fn read_block(file: &fs::File, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read_at(&mut buf[filled..], offset + filled as u64) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    buf[filled..].fill(0);
+    Ok(())
+}
+
+fn create_chunk(path: &Path) -> io::Result<fs::File> {
+    fs::File::options()
+        .write(true)
+        .create_new(true)
+        .mode(CHUNK_FILE_MODE)
+        .open(path)
+        .map_err(|err| io::Error::new(err.kind(), format!("Cannot create {path:?}: {err}")))
+}
+
+/// Compute `parity` parity chunks for the `data_chunks` chunks in `outgoing`.
+///
+/// Parity chunks are written to `incoming` and linked to `outgoing` once they
+/// are complete, like data chunks.
+pub fn write_parity(
+    incoming: &Path,
+    outgoing: &Path,
+    prefix: &str,
+    chunk_size: u64,
+    data_chunks: u64,
+    total_bytes: u64,
+    parity: u64,
+) -> io::Result<()> {
+    if parity == 0 || data_chunks == 0 {
+        return Ok(());
+    }
+    if parity >= FIELD_SIZE as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Cannot create {parity} parity chunks, at most 255 are supported"),
+        ));
+    }
+    let mut header = ParityHeader {
+        chunk_size,
+        data_chunks,
+        last_len: total_bytes - (data_chunks - 1) * chunk_size,
+        parity,
+        group: 0,
+    };
+    let parity_len = usize::try_from(parity).expect("parity count exceeds usize");
+
+    for group in 0..header.groups() {
+        header.group = group;
+        let data = header
+            .group_chunks(group)
+            .map(|index| fs::File::open(data_path(outgoing, prefix, index)))
+            .collect::<io::Result<Vec<_>>>()?;
+        let indices: Vec<u64> = (0..parity).map(|j| group * parity + j).collect();
+        let mut outputs = indices
+            .iter()
+            .map(|&k| {
+                let mut file = create_chunk(&parity_path(incoming, prefix, k))?;
+                header.write_to(&mut file)?;
+                Ok(file)
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let mut input = vec![0u8; BLOCK_SIZE];
+        let mut blocks = vec![vec![0u8; BLOCK_SIZE]; parity_len];
+        let mut offset = 0;
+        while offset < chunk_size {
+            let len = BLOCK_SIZE.min((chunk_size - offset) as usize);
+            blocks.iter_mut().for_each(|block| block[..len].fill(0));
+            for (col, file) in data.iter().enumerate() {
+                read_block(file, offset, &mut input[..len])?;
+                for (row, block) in blocks.iter_mut().enumerate() {
+                    gf_mul_add(
+                        &mut block[..len],
+                        cauchy(parity_len, row, col),
+                        &input[..len],
+                    );
+                }
+            }
+            for (output, block) in outputs.iter_mut().zip(&blocks) {
+                output.write_all(&block[..len])?;
+            }
+            offset += len as u64;
+        }
+
+        for (output, k) in outputs.into_iter().zip(indices) {
+            output.sync_data()?;
+            let incoming_path = parity_path(incoming, prefix, k);
+            let outgoing_path = parity_path(outgoing, prefix, k);
+            link_or_copy(&incoming_path, &outgoing_path, SyncPolicy::Data)?;
+            fs::remove_file(incoming_path)?;
+        }
+    }
+    log::info!(
+        "Created {count} parity chunk(s)",
+        count = header.groups() * parity
+    );
+    Ok(())
+}
+
+fn read_parity_header(path: &Path) -> Option<ParityHeader> {
+    let mut file = fs::File::open(path).ok()?;
+    match ParityHeader::read_from(&mut file) {
+        Ok(header) => Some(header),
+        Err(err) => {
+            log::warn!("Ignoring parity chunk {path:?}: {err}");
+            None
+        }
+    }
+}
+
+/// Find the geometry of the backup in `dir` from any of its parity chunks.
+fn find_parity_header(dir: &Path, prefix: &str) -> io::Result<Option<ParityHeader>> {
+    let parity_prefix = format!("{prefix}.{PARITY_FILE_EXTENSION}");
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_parity = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with(&parity_prefix));
+        if is_parity {
+            if let Some(header) = read_parity_header(&path) {
+                return Ok(Some(header));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Reconstruct missing data chunks in `dir` from its parity chunks.
+///
+/// Returns the indices of the reconstructed data chunks.
+pub fn repair(dir: &Path, prefix: &str) -> io::Result<Vec<u64>> {
+    let Some(geometry) = find_parity_header(dir, prefix)? else {
+        return Ok(Vec::new());
+    };
+    let parity_len = usize::try_from(geometry.parity).expect("parity count exceeds usize");
+    let mut repaired = Vec::new();
+
+    for group in 0..geometry.groups() {
+        let chunks = geometry.group_chunks(group);
+        let first = *chunks.start();
+        let mut present = Vec::new(); // (row of the generator matrix, file)
+        let mut missing = Vec::new();
+        for index in chunks {
+            let path = data_path(dir, prefix, index);
+            match fs::metadata(&path) {
+                Ok(metadata) if metadata.len() == geometry.chunk_len(index) => {
+                    present.push((None, fs::File::open(&path)?, index - first));
+                }
+                _ => missing.push(index),
+            }
+        }
+        if missing.is_empty() {
+            continue;
+        }
+        for row in 0..geometry.parity {
+            if present.len() == (geometry.group_chunks(group).count()) {
+                break;
+            }
+            let path = parity_path(dir, prefix, group * geometry.parity + row);
+            let header = read_parity_header(&path);
+            let valid_len = fs::metadata(&path)
+                .is_ok_and(|metadata| metadata.len() == PARITY_HEADER_LEN + geometry.chunk_size);
+            if header.is_some_and(|h| h.group == group) && valid_len {
+                present.push((Some(row as usize), fs::File::open(&path)?, 0));
+            }
+        }
+        let n = geometry.group_chunks(group).count();
+        if present.len() < n {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "Cannot reconstruct {count} missing chunk(s) {missing:?} from parity",
+                    count = missing.len()
+                ),
+            ));
+        }
+
+        // rows of the generator matrix for the available chunks
+        let matrix = present
+            .iter()
+            .map(|(row, _, col)| match row {
+                None => (0..n).map(|j| u8::from(j as u64 == *col)).collect(),
+                Some(row) => (0..n).map(|j| cauchy(parity_len, *row, j)).collect(),
+            })
+            .collect();
+        let inverse = invert(matrix)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Singular parity matrix"))?;
+
+        let mut outputs = missing
+            .iter()
+            .map(|&index| {
+                let temp = dir.join(format!(".{prefix}.{index}.repair"));
+                Ok((index, temp.clone(), create_chunk(&temp)?))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut input = vec![0u8; BLOCK_SIZE];
+        let mut blocks = vec![vec![0u8; BLOCK_SIZE]; missing.len()];
+        let mut offset = 0;
+        while offset < geometry.chunk_size {
+            let len = BLOCK_SIZE.min((geometry.chunk_size - offset) as usize);
+            blocks.iter_mut().for_each(|block| block[..len].fill(0));
+            for (k, (row, file, _)) in present.iter().enumerate() {
+                let file_offset = if row.is_some() {
+                    PARITY_HEADER_LEN + offset
+                } else {
+                    offset
+                };
+                read_block(file, file_offset, &mut input[..len])?;
+                for (block, index) in blocks.iter_mut().zip(&missing) {
+                    let col = (index - first) as usize;
+                    gf_mul_add(&mut block[..len], inverse[col][k], &input[..len]);
+                }
+            }
+            for ((index, _, output), block) in outputs.iter_mut().zip(&blocks) {
+                let chunk_len = geometry.chunk_len(*index);
+                if offset < chunk_len {
+                    let keep = len.min((chunk_len - offset) as usize);
+                    output.write_all(&block[..keep])?;
+                }
+            }
+            offset += len as u64;
+        }
+        for (index, temp, output) in outputs {
+            output.sync_data()?;
+            fs::rename(&temp, data_path(dir, prefix, index))?;
+            log::warn!("Reconstructed chunk {index} from parity");
+            repaired.push(index);
+        }
+    }
+    Ok(repaired)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn write_chunks(dir: &Path, data: &[u8], chunk_size: usize) -> u64 {
+        let mut count = 0;
+        for (i, chunk) in data.chunks(chunk_size).enumerate() {
+            fs::write(data_path(dir, "chunk", i as u64 + 1), chunk).unwrap();
+            count += 1;
+        }
+        count
+    }
+
+    #[test]
+    fn invert_cauchy_matrix() {
+        let matrix: Vec<Vec<u8>> = (0..4)
+            .map(|row| (0..4).map(|col| cauchy(4, row, col)).collect())
+            .collect();
+        let inverse = invert(matrix.clone()).expect("cauchy matrix is invertible");
+        for (i, row) in matrix.iter().enumerate() {
+            for j in 0..4 {
+                let product = row
+                    .iter()
+                    .zip(&inverse)
+                    .fold(0, |acc, (a, inverse_row)| acc ^ gf_mul(*a, inverse_row[j]));
+                assert_eq!(product, u8::from(i == j));
+            }
+        }
+    }
+
+    #[test]
+    fn repair_missing_chunks() {
+        let incoming = TempDir::new().unwrap();
+        let outgoing = TempDir::new().unwrap();
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let chunks = write_chunks(outgoing.path(), &data, 64);
+        write_parity(
+            incoming.path(),
+            outgoing.path(),
+            "chunk",
+            64,
+            chunks,
+            data.len() as u64,
+            2,
+        )
+        .expect("cannot write parity");
+        assert!(parity_path(outgoing.path(), "chunk", 1).exists());
+        assert!(!parity_path(incoming.path(), "chunk", 1).exists());
+
+        // nothing to repair
+        assert!(repair(outgoing.path(), "chunk").unwrap().is_empty());
+
+        // lose a full chunk and the short last chunk
+        fs::remove_file(data_path(outgoing.path(), "chunk", 3)).unwrap();
+        fs::remove_file(data_path(outgoing.path(), "chunk", chunks)).unwrap();
+        assert_eq!(repair(outgoing.path(), "chunk").unwrap(), vec![3, chunks]);
+        let restored: Vec<u8> = (1..=chunks)
+            .flat_map(|i| fs::read(data_path(outgoing.path(), "chunk", i)).unwrap())
+            .collect();
+        assert_eq!(restored, data);
+
+        // three missing chunks exceed two parity chunks
+        for i in 1..=3 {
+            fs::remove_file(data_path(outgoing.path(), "chunk", i)).unwrap();
+        }
+        assert!(repair(outgoing.path(), "chunk").is_err());
+    }
+}
@@ -9,16 +9,18 @@
 
 use std::{
     cmp::{Ordering, Reverse},
-    collections::{BTreeSet, BinaryHeap},
+    collections::BinaryHeap,
     fmt, io,
-    ops::{Range, RangeBounds},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
 use std::sync::mpsc::Sender;
 
+use super::constants::{CHUNK_FILE_PREFIX, PARITY_FILE_EXTENSION};
 use super::watch::channel_send_error;
 
+pub use super::interval::{Interval, IntervalSet};
+
 #[derive(Clone, Debug, Eq)]
 pub struct Fragment {
     pub priority: Reverse<i32>,
@@ -70,6 +72,26 @@ impl Fragment {
     pub fn index(&self) -> i32 {
         self.priority.0
     }
+
+    /// Returns true if `path` is named like a chunk file of `prefix` but has no
+    /// valid chunk index, e.g., `chunk.tmp` or a swap file `chunk.1.swp`.
+    pub fn is_misnamed(path: &Path, prefix: &str) -> bool {
+        let Some(extension) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix(prefix))
+            .and_then(|name| name.strip_prefix('.'))
+        else {
+            return false;
+        };
+        if extension.parse::<i32>().is_ok() {
+            return false;
+        }
+        // parity chunks are expected next to the chunk files
+        extension
+            .strip_prefix(PARITY_FILE_EXTENSION)
+            .is_none_or(|index| index.parse::<u64>().is_err())
+    }
 }
 
 #[derive(Debug)]
@@ -78,6 +100,7 @@ pub struct FragmentQueue {
     heap: BinaryHeap<Fragment>,
     current: Reverse<i32>,
     zero: bool,
+    strict: bool,
 }
 
 impl FragmentQueue {
@@ -87,10 +110,27 @@ impl FragmentQueue {
             heap: BinaryHeap::new(),
             current: Reverse(1),
             zero: false,
+            strict: false,
         }
     }
 
+    /// Fail on misnamed chunk files instead of ignoring them.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
     pub fn send_path(&mut self, path: PathBuf) -> io::Result<bool> {
+        if Fragment::is_misnamed(&path, CHUNK_FILE_PREFIX) {
+            if self.strict {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Found chunk file {path:?} without a valid chunk index"),
+                ));
+            }
+            log::warn!("Ignoring chunk file {path:?} without a valid chunk index");
+            return Ok(false);
+        }
         Fragment::new(path)
             .map(|frag| self.send(frag))
             .unwrap_or(Ok(false))
@@ -143,235 +183,35 @@ impl FragmentQueue {
     }
 }
 
-#[derive(Clone, Copy, Eq, PartialEq)]
-pub struct Interval {
-    pub start: i32,
-    pub end: i32,
-}
-
-impl Interval {
-    pub fn new(start: i32, end: i32) -> Self {
-        if end < start {
-            Self {
-                start: end,
-                end: start,
-            }
-        } else {
-            Self { start, end }
-        }
-    }
-
-    pub fn point(p: i32) -> Self {
-        Self { start: p, end: p }
-    }
-
-    pub fn from_range(r: Range<i32>) -> Self {
-        Interval::new(r.start, r.end - 1)
-    }
-
-    pub fn envelope(&self, left: &Interval, right: &Interval) -> Self {
-        Interval::new(self.start.min(left.start), self.end.max(right.end))
-    }
-}
-
-impl fmt::Debug for Interval {
-    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(fmt, "[")?;
-        self.start.fmt(fmt)?;
-        write!(fmt, "..")?;
-        self.end.fmt(fmt)?;
-        write!(fmt, "]")?;
-        Ok(())
-    }
-}
-
-impl RangeBounds<i32> for Interval {
-    fn start_bound(&self) -> std::ops::Bound<&i32> {
-        std::ops::Bound::Included(&self.start)
-    }
-
-    fn end_bound(&self) -> std::ops::Bound<&i32> {
-        std::ops::Bound::Included(&self.end)
-    }
-}
-
-impl Ord for Interval {
-    fn cmp(&self, other: &Self) -> Ordering {
-        // self.start <= self.end && other.start <= other.end
-        if self.contains(&other.start) && self.contains(&other.end) {
-            // self.start <= other.start && other.end <= self.end
-            Ordering::Equal
-        } else if other.end < self.start {
-            Ordering::Less
-        } else if self.end < other.start {
-            Ordering::Greater
-        } else {
-            // other.start < self.start && self.end < other.end
-            Ordering::Equal
-        }
-    }
-}
-
-impl PartialOrd for Interval {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
-    }
-}
-
-#[derive(Clone, Default)]
-pub struct IntervalSet {
-    intervals: BTreeSet<Interval>,
-}
-
-impl fmt::Debug for IntervalSet {
-    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(fmt, "{{")?;
-        self.intervals.fmt(fmt)?;
-        write!(fmt, "}}")?;
-        Ok(())
-    }
-}
-
-impl IntervalSet {
-    pub fn new() -> Self {
-        Self {
-            intervals: BTreeSet::<Interval>::new(),
-        }
-    }
-
-    pub fn insert(&mut self, interval: Interval) {
-        let left_interval = Interval::point(interval.start - 1);
-        let right_interval = Interval::point(interval.end + 1);
-        let left = self.intervals.get(&left_interval);
-        let right = self.intervals.get(&right_interval);
-
-        let interval = if let (Some(l), Some(r)) = (left, right) {
-            let new_interval = interval.envelope(l, r);
-            self.intervals.remove(&left_interval);
-            self.intervals.remove(&right_interval);
-            new_interval
-        } else if let Some(l) = left {
-            let new_interval = interval.envelope(l, l);
-            self.intervals.remove(&left_interval);
-            new_interval
-        } else if let Some(r) = right {
-            let new_interval = interval.envelope(r, r);
-            self.intervals.remove(&right_interval);
-            new_interval
-        } else {
-            interval
-        };
-        let inserted = self.intervals.insert(interval);
-        assert!(inserted);
-    }
-
-    pub fn get(&self, value: &Interval) -> Option<&Interval> {
-        self.intervals.get(value)
-    }
-
-    pub fn first(&self) -> Option<&Interval> {
-        self.intervals.first()
-    }
-
-    pub fn last(&self) -> Option<&Interval> {
-        self.intervals.last()
-    }
-
-    pub fn len(&self) -> usize {
-        self.intervals.len()
-    }
-
-    pub fn is_empty(&self) -> bool {
-        self.intervals.is_empty()
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    #[test]
-    fn basic_interval_set() {
-        let mut intervals = IntervalSet::new();
-
-        // {[1..1]}
-        intervals.insert(Interval::point(1));
-        assert_eq!(intervals.len(), 1);
-        assert_eq!(intervals.last(), Some(Interval::point(1)).as_ref());
-        assert_eq!(intervals.first(), Some(Interval::point(1)).as_ref());
-
-        // {[1..1], [3..3]}
-        intervals.insert(Interval::point(3));
-        assert_eq!(intervals.len(), 2);
-        assert_eq!(intervals.last(), Some(Interval::point(1)).as_ref());
-        assert_eq!(intervals.first(), Some(Interval::point(3)).as_ref());
-
-        // {[1..1], [3..4]}
-        intervals.insert(Interval::point(4));
-        assert_eq!(intervals.len(), 2);
-        assert_eq!(intervals.last(), Some(Interval::point(1)).as_ref());
-        assert_eq!(intervals.first(), Some(Interval::new(3, 4)).as_ref());
-
-        // {[1..1], [3..4], [7..7]}
-        intervals.insert(Interval::point(7));
-        assert_eq!(intervals.len(), 3);
-        assert_eq!(intervals.last(), Some(Interval::point(1)).as_ref());
-        assert_eq!(intervals.first(), Some(Interval::point(7)).as_ref());
+    use std::sync::mpsc;
 
-        // {[1..1], [3..4], [6..7]}
-        intervals.insert(Interval::point(6));
-        assert_eq!(intervals.len(), 3);
-        assert_eq!(intervals.last(), Some(Interval::point(1)).as_ref());
-        assert_eq!(
-            intervals.get(&Interval::point(3)),
-            Some(Interval::new(3, 4)).as_ref()
-        );
-        assert_eq!(
-            intervals.get(&Interval::point(4)),
-            Some(Interval::new(3, 4)).as_ref()
-        );
-        assert_eq!(
-            intervals.get(&Interval::new(3, 4)),
-            Some(Interval::new(3, 4)).as_ref()
-        );
-        assert_eq!(
-            intervals.get(&Interval::new(4, 5)),
-            Some(Interval::new(3, 4)).as_ref()
-        );
-        assert_eq!(
-            intervals.get(&Interval::new(2, 3)),
-            Some(Interval::new(3, 4)).as_ref()
-        );
-        assert_eq!(
-            intervals.get(&Interval::new(2, 5)),
-            Some(Interval::new(3, 4)).as_ref()
-        );
-        assert_eq!(intervals.get(&Interval::point(2)), None);
-        assert_eq!(intervals.get(&Interval::point(5)), None);
-        assert_eq!(
-            intervals.get(&Interval::new(3, 6)),
-            Some(Interval::new(6, 7)).as_ref()
-        );
-        assert_eq!(
-            intervals.get(&Interval::new(4, 6)),
-            Some(Interval::new(6, 7)).as_ref()
-        );
-        assert_eq!(
-            intervals.get(&Interval::new(1, 7)),
-            Some(Interval::new(6, 7)).as_ref()
-        );
-        assert_eq!(intervals.first(), Some(Interval::new(6, 7)).as_ref());
-
-        // {[1..4], [6..7]}
-        intervals.insert(Interval::point(2));
-        assert_eq!(intervals.len(), 2);
-        assert_eq!(intervals.last(), Some(Interval::new(1, 4)).as_ref());
-        assert_eq!(intervals.first(), Some(Interval::new(6, 7)).as_ref());
+    #[test]
+    fn misnamed_chunk_files() {
+        assert!(!Fragment::is_misnamed(Path::new("dir/chunk.0"), "chunk"));
+        assert!(!Fragment::is_misnamed(Path::new("dir/chunk.42"), "chunk"));
+        assert!(!Fragment::is_misnamed(Path::new("dir/chunk.par3"), "chunk"));
+        assert!(!Fragment::is_misnamed(Path::new("dir/other.tmp"), "chunk"));
+        assert!(!Fragment::is_misnamed(Path::new("dir/chunky.1"), "chunk"));
+        assert!(Fragment::is_misnamed(Path::new("dir/chunk.tmp"), "chunk"));
+        assert!(Fragment::is_misnamed(Path::new("dir/chunk.1.swp"), "chunk"));
+        assert!(Fragment::is_misnamed(Path::new("dir/chunk."), "chunk"));
+    }
 
-        // {[1..7]}
-        intervals.insert(Interval::point(5));
-        assert_eq!(intervals.len(), 1);
-        assert_eq!(intervals.last(), Some(Interval::new(1, 7)).as_ref());
-        assert_eq!(intervals.first(), Some(Interval::new(1, 7)).as_ref());
+    #[test]
+    fn strict_fragment_queue() {
+        let (tx, rx) = mpsc::channel();
+        let mut queue = FragmentQueue::new(tx.clone());
+        assert!(!queue.send_path(PathBuf::from("chunk.tmp")).unwrap());
+        assert!(queue.send_path(PathBuf::from("chunk.1")).unwrap());
+        assert_eq!(rx.try_recv().unwrap(), Some(PathBuf::from("chunk.1")));
+
+        let mut queue = FragmentQueue::new(tx).with_strict(true);
+        let err = queue.send_path(PathBuf::from("chunk.tmp")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(rx.try_recv().is_err());
     }
 }
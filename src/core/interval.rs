@@ -0,0 +1,369 @@
+// Copyright The Cryophile Authors.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE> or
+// <http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT> or <http://opensource.org/licenses/MIT>, at your option.
+//
+// This file may not be copied, modified, or distributed except according
+// to those terms.
+
+//! Sets of closed integer intervals.
+//!
+//! An [`IntervalSet`] stores disjoint [`Interval`]s and merges adjacent
+//! intervals on insert, e.g., inserting the points 1, 3, 2 yields the single
+//! interval `[1..3]`. This tracks which chunk indices have been seen so far.
+
+use std::{
+    cmp::Ordering,
+    collections::BTreeSet,
+    fmt,
+    ops::{Range, RangeBounds},
+};
+
+/// Closed interval `[start..end]` of integers.
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub struct Interval {
+    pub start: i32,
+    pub end: i32,
+}
+
+impl Interval {
+    pub fn new(start: i32, end: i32) -> Self {
+        if end < start {
+            Self {
+                start: end,
+                end: start,
+            }
+        } else {
+            Self { start, end }
+        }
+    }
+
+    pub fn point(p: i32) -> Self {
+        Self { start: p, end: p }
+    }
+
+    pub fn from_range(r: Range<i32>) -> Self {
+        Interval::new(r.start, r.end - 1)
+    }
+
+    pub fn envelope(&self, left: &Interval, right: &Interval) -> Self {
+        Interval::new(self.start.min(left.start), self.end.max(right.end))
+    }
+}
+
+impl fmt::Debug for Interval {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "[")?;
+        self.start.fmt(fmt)?;
+        write!(fmt, "..")?;
+        self.end.fmt(fmt)?;
+        write!(fmt, "]")?;
+        Ok(())
+    }
+}
+
+impl RangeBounds<i32> for Interval {
+    fn start_bound(&self) -> std::ops::Bound<&i32> {
+        std::ops::Bound::Included(&self.start)
+    }
+
+    fn end_bound(&self) -> std::ops::Bound<&i32> {
+        std::ops::Bound::Included(&self.end)
+    }
+}
+
+impl Ord for Interval {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // self.start <= self.end && other.start <= other.end
+        if self.contains(&other.start) && self.contains(&other.end) {
+            // self.start <= other.start && other.end <= self.end
+            Ordering::Equal
+        } else if other.end < self.start {
+            Ordering::Less
+        } else if self.end < other.start {
+            Ordering::Greater
+        } else {
+            // other.start < self.start && self.end < other.end
+            Ordering::Equal
+        }
+    }
+}
+
+impl PartialOrd for Interval {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Set of disjoint, non-adjacent intervals.
+///
+/// Note that the underlying set is ordered by descending intervals, hence
+/// [`IntervalSet::first`] is the highest and [`IntervalSet::last`] the lowest
+/// interval, while [`IntervalSet::iter`] yields intervals in ascending order.
+#[derive(Clone, Default)]
+pub struct IntervalSet {
+    intervals: BTreeSet<Interval>,
+}
+
+impl fmt::Debug for IntervalSet {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "{{")?;
+        self.intervals.fmt(fmt)?;
+        write!(fmt, "}}")?;
+        Ok(())
+    }
+}
+
+impl IntervalSet {
+    pub fn new() -> Self {
+        Self {
+            intervals: BTreeSet::<Interval>::new(),
+        }
+    }
+
+    pub fn insert(&mut self, interval: Interval) {
+        let left_interval = Interval::point(interval.start - 1);
+        let right_interval = Interval::point(interval.end + 1);
+        let left = self.intervals.get(&left_interval);
+        let right = self.intervals.get(&right_interval);
+
+        let interval = if let (Some(l), Some(r)) = (left, right) {
+            let new_interval = interval.envelope(l, r);
+            self.intervals.remove(&left_interval);
+            self.intervals.remove(&right_interval);
+            new_interval
+        } else if let Some(l) = left {
+            let new_interval = interval.envelope(l, l);
+            self.intervals.remove(&left_interval);
+            new_interval
+        } else if let Some(r) = right {
+            let new_interval = interval.envelope(r, r);
+            self.intervals.remove(&right_interval);
+            new_interval
+        } else {
+            interval
+        };
+        let inserted = self.intervals.insert(interval);
+        assert!(inserted);
+    }
+
+    pub fn get(&self, value: &Interval) -> Option<&Interval> {
+        self.intervals.get(value)
+    }
+
+    pub fn first(&self) -> Option<&Interval> {
+        self.intervals.first()
+    }
+
+    pub fn last(&self) -> Option<&Interval> {
+        self.intervals.last()
+    }
+
+    pub fn len(&self) -> usize {
+        self.intervals.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.intervals.is_empty()
+    }
+
+    /// Iterate over the intervals in ascending order.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Interval> + '_ {
+        self.intervals.iter().rev()
+    }
+
+    /// Iterate over the maximal intervals of `within` not covered by this set
+    /// in ascending order.
+    pub fn gaps(&self, within: Interval) -> impl Iterator<Item = Interval> + '_ {
+        let mut next = Some(within.start);
+        self.iter()
+            .map(Some)
+            .chain(std::iter::once(None))
+            .filter_map(move |interval| {
+                let start = next?;
+                let end = match interval {
+                    Some(interval) if interval.end < start => return None,
+                    Some(interval) if interval.start > within.end => {
+                        next = None;
+                        within.end
+                    }
+                    Some(interval) => {
+                        next = interval.end.checked_add(1).filter(|n| *n <= within.end);
+                        if interval.start <= start {
+                            return None;
+                        }
+                        interval.start - 1
+                    }
+                    None => {
+                        next = None;
+                        within.end
+                    }
+                };
+                Some(Interval::new(start, end))
+            })
+    }
+}
+
+impl FromIterator<Interval> for IntervalSet {
+    fn from_iter<T: IntoIterator<Item = Interval>>(iter: T) -> Self {
+        let mut set = IntervalSet::new();
+        set.extend(iter);
+        set
+    }
+}
+
+impl Extend<Interval> for IntervalSet {
+    fn extend<T: IntoIterator<Item = Interval>>(&mut self, iter: T) {
+        for interval in iter {
+            self.insert(interval);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn basic_interval_set() {
+        let mut intervals = IntervalSet::new();
+
+        // {[1..1]}
+        intervals.insert(Interval::point(1));
+        assert_eq!(intervals.len(), 1);
+        assert_eq!(intervals.last(), Some(Interval::point(1)).as_ref());
+        assert_eq!(intervals.first(), Some(Interval::point(1)).as_ref());
+
+        // {[1..1], [3..3]}
+        intervals.insert(Interval::point(3));
+        assert_eq!(intervals.len(), 2);
+        assert_eq!(intervals.last(), Some(Interval::point(1)).as_ref());
+        assert_eq!(intervals.first(), Some(Interval::point(3)).as_ref());
+
+        // {[1..1], [3..4]}
+        intervals.insert(Interval::point(4));
+        assert_eq!(intervals.len(), 2);
+        assert_eq!(intervals.last(), Some(Interval::point(1)).as_ref());
+        assert_eq!(intervals.first(), Some(Interval::new(3, 4)).as_ref());
+
+        // {[1..1], [3..4], [7..7]}
+        intervals.insert(Interval::point(7));
+        assert_eq!(intervals.len(), 3);
+        assert_eq!(intervals.last(), Some(Interval::point(1)).as_ref());
+        assert_eq!(intervals.first(), Some(Interval::point(7)).as_ref());
+
+        // {[1..1], [3..4], [6..7]}
+        intervals.insert(Interval::point(6));
+        assert_eq!(intervals.len(), 3);
+        assert_eq!(intervals.last(), Some(Interval::point(1)).as_ref());
+        assert_eq!(
+            intervals.get(&Interval::point(3)),
+            Some(Interval::new(3, 4)).as_ref()
+        );
+        assert_eq!(
+            intervals.get(&Interval::point(4)),
+            Some(Interval::new(3, 4)).as_ref()
+        );
+        assert_eq!(
+            intervals.get(&Interval::new(3, 4)),
+            Some(Interval::new(3, 4)).as_ref()
+        );
+        assert_eq!(
+            intervals.get(&Interval::new(4, 5)),
+            Some(Interval::new(3, 4)).as_ref()
+        );
+        assert_eq!(
+            intervals.get(&Interval::new(2, 3)),
+            Some(Interval::new(3, 4)).as_ref()
+        );
+        assert_eq!(
+            intervals.get(&Interval::new(2, 5)),
+            Some(Interval::new(3, 4)).as_ref()
+        );
+        assert_eq!(intervals.get(&Interval::point(2)), None);
+        assert_eq!(intervals.get(&Interval::point(5)), None);
+        assert_eq!(
+            intervals.get(&Interval::new(3, 6)),
+            Some(Interval::new(6, 7)).as_ref()
+        );
+        assert_eq!(
+            intervals.get(&Interval::new(4, 6)),
+            Some(Interval::new(6, 7)).as_ref()
+        );
+        assert_eq!(
+            intervals.get(&Interval::new(1, 7)),
+            Some(Interval::new(6, 7)).as_ref()
+        );
+        assert_eq!(intervals.first(), Some(Interval::new(6, 7)).as_ref());
+
+        // {[1..4], [6..7]}
+        intervals.insert(Interval::point(2));
+        assert_eq!(intervals.len(), 2);
+        assert_eq!(intervals.last(), Some(Interval::new(1, 4)).as_ref());
+        assert_eq!(intervals.first(), Some(Interval::new(6, 7)).as_ref());
+
+        // {[1..7]}
+        intervals.insert(Interval::point(5));
+        assert_eq!(intervals.len(), 1);
+        assert_eq!(intervals.last(), Some(Interval::new(1, 7)).as_ref());
+        assert_eq!(intervals.first(), Some(Interval::new(1, 7)).as_ref());
+    }
+
+    #[test]
+    fn iter_interval_set() {
+        let intervals: IntervalSet = [1, 7, 3, 6, 4].map(Interval::point).into_iter().collect();
+        let ascending: Vec<Interval> = intervals.iter().copied().collect();
+        assert_eq!(
+            ascending,
+            vec![Interval::point(1), Interval::new(3, 4), Interval::new(6, 7)]
+        );
+        let descending: Vec<Interval> = intervals.iter().rev().copied().collect();
+        assert_eq!(
+            descending,
+            vec![Interval::new(6, 7), Interval::new(3, 4), Interval::point(1)]
+        );
+        assert_eq!(IntervalSet::new().iter().next(), None);
+    }
+
+    #[test]
+    fn gaps_interval_set() {
+        // {[3..4], [6..7], [10..10]}
+        let intervals: IntervalSet = [
+            Interval::new(3, 4),
+            Interval::new(6, 7),
+            Interval::point(10),
+        ]
+        .into_iter()
+        .collect();
+
+        let gaps: Vec<Interval> = intervals.gaps(Interval::new(1, 12)).collect();
+        assert_eq!(
+            gaps,
+            vec![
+                Interval::new(1, 2),
+                Interval::point(5),
+                Interval::new(8, 9),
+                Interval::new(11, 12)
+            ]
+        );
+
+        let gaps: Vec<Interval> = intervals.gaps(Interval::new(4, 8)).collect();
+        assert_eq!(gaps, vec![Interval::point(5), Interval::point(8)]);
+
+        let gaps: Vec<Interval> = intervals.gaps(Interval::new(3, 7)).collect();
+        assert_eq!(gaps, vec![Interval::point(5)]);
+
+        assert_eq!(intervals.gaps(Interval::new(6, 7)).next(), None);
+        assert_eq!(
+            intervals.gaps(Interval::new(12, 20)).collect::<Vec<_>>(),
+            vec![Interval::new(12, 20)]
+        );
+        assert_eq!(
+            IntervalSet::new()
+                .gaps(Interval::new(1, 3))
+                .collect::<Vec<_>>(),
+            vec![Interval::new(1, 3)]
+        );
+    }
+}
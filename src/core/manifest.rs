@@ -0,0 +1,209 @@
+// Copyright The Cryophile Authors.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE> or
+// <http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT> or <http://opensource.org/licenses/MIT>, at your option.
+//
+// This file may not be copied, modified, or distributed except according
+// to those terms.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::constants::CHUNK_FILE_MODE;
+use super::split::SyncPolicy;
+
+/// Backup metadata stored encrypted in the zero chunk.
+///
+/// Backups without manifest have an empty zero chunk.
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Manifest {
+    /// SHA-256 of the backup input before compression, in lowercase hex
+    pub sha256: Option<String>,
+    /// Size of the backup input before compression in bytes
+    pub size: Option<u64>,
+    /// Permission bits of a regular input file
+    pub mode: Option<u32>,
+    /// Owner of a regular input file
+    pub uid: Option<u32>,
+    /// Group of a regular input file
+    pub gid: Option<u32>,
+}
+
+impl Manifest {
+    pub fn to_toml(&self) -> io::Result<String> {
+        toml::to_string(self).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Cannot serialize manifest: {err}"),
+            )
+        })
+    }
+}
+
+impl FromStr for Manifest {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        toml::from_str(s).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Cannot parse manifest: {err}"),
+            )
+        })
+    }
+}
+
+fn hex_digest(hasher: Sha256) -> String {
+    let mut hex = String::with_capacity(64);
+    for byte in hasher.finalize() {
+        let _ = write!(hex, "{byte:02x}");
+    }
+    hex
+}
+
+/// Reader computing the SHA-256 and the number of all bytes read.
+pub struct HashingReader<R: io::Read> {
+    inner: R,
+    hasher: Sha256,
+    bytes_read: u64,
+}
+
+impl<R: io::Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            bytes_read: 0,
+        }
+    }
+
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    pub fn hex_digest(self) -> String {
+        hex_digest(self.hasher)
+    }
+}
+
+impl<R: io::Read> io::Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.bytes_read += n as u64;
+        Ok(n)
+    }
+}
+
+/// Writer computing the SHA-256 of all bytes written.
+pub struct HashingWriter<W: io::Write> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: io::Write> HashingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    pub fn hex_digest(self) -> String {
+        hex_digest(self.hasher)
+    }
+}
+
+impl<W: io::Write> io::Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Write the SHA-256 of each of `files` to `path` in the format checked by
+/// `sha256sum -c`, with file names relative to the directory of `path`.
+pub fn write_sha256sums(files: &[PathBuf], path: &Path, sync: SyncPolicy) -> io::Result<()> {
+    let mut sums = String::new();
+    for file in files {
+        let Some(name) = file.file_name().and_then(|name| name.to_str()) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Cannot list {file:?} in {path:?}"),
+            ));
+        };
+        let mut reader = HashingReader::new(fs::File::open(file)?);
+        io::copy(&mut reader, &mut io::sink())?;
+        let _ = writeln!(sums, "{digest}  {name}", digest = reader.hex_digest());
+    }
+    let mut sums_file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(CHUNK_FILE_MODE)
+        .open(path)?;
+    io::Write::write_all(&mut sums_file, sums.as_bytes())?;
+    sync.sync(&sums_file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+
+    #[test]
+    fn hashing_reader_and_writer() {
+        // sha256sum of "hello\n"
+        let expected = "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+
+        let mut reader = HashingReader::new(&b"hello\n"[..]);
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(reader.bytes_read(), 6);
+        assert_eq!(reader.hex_digest(), expected);
+
+        let mut writer = HashingWriter::new(Vec::new());
+        writer.write_all(&buf).unwrap();
+        assert_eq!(writer.hex_digest(), expected);
+    }
+
+    #[test]
+    fn sha256sums_format() {
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        let chunk = tmp_dir.path().join("chunk.1");
+        fs::write(&chunk, "hello\n").unwrap();
+        let sums = tmp_dir.path().join("SHA256SUMS");
+        write_sha256sums(&[chunk], &sums, SyncPolicy::None).unwrap();
+        assert_eq!(
+            fs::read_to_string(&sums).unwrap(),
+            "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03  chunk.1\n"
+        );
+    }
+
+    #[test]
+    fn manifest_round_trip() {
+        let manifest = Manifest {
+            sha256: Some("00ff".to_owned()),
+            size: Some(10_000),
+            mode: Some(0o640),
+            uid: Some(1000),
+            gid: None,
+        };
+        let toml = manifest.to_toml().unwrap();
+        assert_eq!(toml.parse::<Manifest>().unwrap(), manifest);
+        assert_eq!("".parse::<Manifest>().unwrap(), Manifest::default());
+    }
+}
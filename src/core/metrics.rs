@@ -0,0 +1,109 @@
+// Copyright The Cryophile Authors.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE> or
+// <http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT> or <http://opensource.org/licenses/MIT>, at your option.
+//
+// This file may not be copied, modified, or distributed except according
+// to those terms.
+
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::{fs, io};
+
+/// Freeze counters exported in the Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub events: AtomicU64,
+    pub errors: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Render all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let metrics = [
+            (
+                "cryophile_freeze_events_total",
+                "counter",
+                "Filesystem events seen by freeze.",
+                &self.events,
+            ),
+            (
+                "cryophile_freeze_errors_total",
+                "counter",
+                "Errors encountered by freeze.",
+                &self.errors,
+            ),
+        ];
+        let mut text = String::new();
+        for (name, kind, help, value) in metrics {
+            let value = value.load(Ordering::Relaxed);
+            let _ = writeln!(text, "# HELP {name} {help}");
+            let _ = writeln!(text, "# TYPE {name} {kind}");
+            let _ = writeln!(text, "{name} {value}");
+        }
+        text
+    }
+
+    /// Replace the textfile at `path` atomically, node_exporter never reads a
+    /// partially written file.
+    pub fn write_textfile(&self, path: &Path) -> io::Result<()> {
+        let dir = path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        let mut temp = tempfile::Builder::new()
+            .prefix(".cryophile-metrics")
+            .tempfile_in(dir)?;
+        temp.write_all(self.render().as_bytes())?;
+        // node_exporter usually runs as another user
+        temp.as_file()
+            .set_permissions(fs::Permissions::from_mode(0o644))?;
+        temp.as_file().sync_all()?;
+        temp.persist(path).map_err(|err| err.error)?;
+        log::trace!("Wrote metrics to {path:?}");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_metrics() {
+        let metrics = Metrics::new();
+        Metrics::inc(&metrics.events);
+        Metrics::inc(&metrics.events);
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE cryophile_freeze_events_total counter\n"));
+        assert!(text.contains("\ncryophile_freeze_events_total 2\n"));
+        assert!(text.contains("\ncryophile_freeze_errors_total 0\n"));
+    }
+
+    #[test]
+    fn write_metrics_textfile() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("cryophile.prom");
+        let metrics = Metrics::new();
+        metrics.write_textfile(&path).unwrap();
+        Metrics::inc(&metrics.errors);
+        metrics.write_textfile(&path).unwrap();
+
+        let text = fs::read_to_string(&path).unwrap();
+        assert_eq!(text, metrics.render());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
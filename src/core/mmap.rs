@@ -0,0 +1,118 @@
+// Copyright The Cryophile Authors.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE> or
+// <http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT> or <http://opensource.org/licenses/MIT>, at your option.
+//
+// This file may not be copied, modified, or distributed except according
+// to those terms.
+
+use std::ffi::c_void;
+use std::num::NonZeroUsize;
+use std::ptr::NonNull;
+use std::{fs, io};
+
+use nix::sys::mman::{self, MapFlags, MmapAdvise, ProtFlags};
+
+/// Read a regular file through a read-only memory mapping.
+///
+/// The mapping is advised for sequential access, hence the kernel reads ahead
+/// and the compressor reads straight from the page cache without a `read(2)`
+/// per buffer. The file must not be truncated while it is mapped, otherwise
+/// reading past the new end raises `SIGBUS`.
+#[derive(Debug)]
+pub struct MmapReader {
+    ptr: NonNull<c_void>,
+    len: usize,
+    pos: usize,
+}
+
+impl MmapReader {
+    /// Map `file`, returns `None` if it is not a non-empty regular file.
+    pub fn new(file: &fs::File) -> io::Result<Option<Self>> {
+        let metadata = file.metadata()?;
+        if !metadata.is_file() {
+            return Ok(None);
+        }
+        let Some(len) = usize::try_from(metadata.len())
+            .ok()
+            .and_then(NonZeroUsize::new)
+        else {
+            return Ok(None);
+        };
+        // SAFETY: we map a private read-only view of the whole file and only
+        // hand out slices within `len` bytes until the mapping is dropped
+        let ptr = unsafe {
+            mman::mmap(
+                None,
+                len,
+                ProtFlags::PROT_READ,
+                MapFlags::MAP_PRIVATE,
+                file,
+                0,
+            )
+        }?;
+        // SAFETY: ptr and len describe the mapping created above
+        if let Err(err) = unsafe { mman::madvise(ptr, len.get(), MmapAdvise::MADV_SEQUENTIAL) } {
+            log::debug!("Cannot advise sequential access: {err}");
+        }
+        Ok(Some(Self {
+            ptr,
+            len: len.get(),
+            pos: 0,
+        }))
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        // SAFETY: the mapping is readable for len bytes while self lives
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr() as *const u8, self.len) }
+    }
+}
+
+impl io::Read for MmapReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = &self.as_slice()[self.pos..];
+        let n = buf.len().min(remaining.len());
+        buf[..n].copy_from_slice(&remaining[..n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl Drop for MmapReader {
+    fn drop(&mut self) {
+        // SAFETY: ptr and len describe the mapping created in new
+        if let Err(err) = unsafe { mman::munmap(self.ptr, self.len) } {
+            log::error!("Cannot unmap input: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+
+    #[test]
+    fn read_mapped_file() {
+        let mut file = tempfile::tempfile().unwrap();
+        let input: Vec<u8> = (0..=255u8).cycle().take(10000).collect();
+        file.write_all(&input).unwrap();
+
+        let mut reader = MmapReader::new(&file).unwrap().expect("cannot map file");
+        let mut buf = [0u8; 3000];
+        assert_eq!(reader.read(&mut buf).unwrap(), 3000);
+        assert_eq!(&buf[..], &input[..3000]);
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap();
+        assert_eq!(output, &input[3000..]);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn skip_empty_file() {
+        let file = tempfile::tempfile().unwrap();
+        assert!(MmapReader::new(&file).unwrap().is_none());
+    }
+}
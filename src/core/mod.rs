@@ -7,14 +7,25 @@
 // This file may not be copied, modified, or distributed except according
 // to those terms.
 
+pub mod async_split;
 pub mod aws;
 pub mod backup_id;
 pub mod cat;
 pub mod constants;
+#[cfg(feature = "fec")]
+pub mod fec;
 pub mod fragment;
+pub mod interval;
+pub mod manifest;
+pub mod metrics;
+pub mod mmap;
 pub mod notify;
 pub mod path;
+pub mod retry;
+pub mod s3reader;
 pub mod split;
+pub mod tee;
 pub mod watch;
 
-pub use split::Split;
+pub use async_split::AsyncSplit;
+pub use split::{Split, SyncPolicy};
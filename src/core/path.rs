@@ -7,13 +7,24 @@
 // This file may not be copied, modified, or distributed except according
 // to those terms.
 
-use std::{fs, io, os::unix::fs::DirBuilderExt, path::PathBuf};
+use std::{
+    fs, io,
+    os::fd::{BorrowedFd, FromRawFd, RawFd},
+    os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+};
+
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, Flock, FlockArg, OFlag};
+use nix::sys::statvfs::statvfs;
 
 use super::backup_id::BackupId;
+use super::split::SyncPolicy;
 
 #[derive(Clone, Debug)]
 pub struct SpoolPathComponents<'a> {
     pub spool: PathBuf,
+    pub freeze_spool: Option<PathBuf>,
     pub backup_id: Option<BackupId<'a>>,
 }
 
@@ -21,6 +32,7 @@ impl<'a> SpoolPathComponents<'a> {
     pub fn new(spool: PathBuf, backup_id: BackupId<'a>) -> Self {
         Self {
             spool,
+            freeze_spool: None,
             backup_id: Some(backup_id),
         }
     }
@@ -28,14 +40,23 @@ impl<'a> SpoolPathComponents<'a> {
     pub fn from_spool(spool: PathBuf) -> Self {
         Self {
             spool,
+            freeze_spool: None,
             backup_id: None,
         }
     }
 
     pub fn with_backup_id(self, backup_id: BackupId<'a>) -> Self {
         Self {
-            spool: self.spool,
             backup_id: Some(backup_id),
+            ..self
+        }
+    }
+
+    /// Root the freeze queue at `freeze_spool` instead of the spool.
+    pub fn with_freeze_spool(self, freeze_spool: Option<&Path>) -> Self {
+        Self {
+            freeze_spool: freeze_spool.map(Path::to_path_buf),
+            ..self
         }
     }
 }
@@ -80,7 +101,10 @@ impl<'a> SpoolPathComponents<'a> {
         let mut path = PathBuf::new();
 
         // backup_dir starts with the spool directory
-        path.push(&self.spool);
+        match (queue, &self.freeze_spool) {
+            (Queue::Freeze, Some(freeze_spool)) => path.push(freeze_spool),
+            _ => path.push(&self.spool),
+        }
 
         // next up: queue path
         path.push::<PathBuf>(queue.into());
@@ -172,8 +196,196 @@ pub(crate) fn use_base_dir(base: &xdg::BaseDirectories) -> io::Result<PathBuf> {
     }
 }
 
+/// Take an exclusive advisory lock on the file at `path`, creating it with
+/// `mode` if needed. Fails with `WouldBlock` if another process holds it.
+///
+/// The holder may remove the file before releasing the lock, a lock on a
+/// removed file is retried on a new one.
+pub fn lock_file(path: &Path, mode: u32) -> io::Result<Flock<fs::File>> {
+    loop {
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(mode)
+            .open(path)?;
+        let lock = Flock::lock(file, FlockArg::LockExclusiveNonblock)
+            .map_err(|(_, errno)| lock_error(path, errno))?;
+        let locked = lock.metadata()?;
+        match fs::metadata(path) {
+            Ok(current) if current.dev() == locked.dev() && current.ino() == locked.ino() => {
+                return Ok(lock);
+            }
+            Ok(_) => log::debug!("Lock {path:?} was replaced, retrying…"),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                log::debug!("Lock {path:?} was removed, retrying…");
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+fn lock_error(path: &Path, errno: Errno) -> io::Error {
+    match errno {
+        Errno::EWOULDBLOCK => io::Error::new(
+            io::ErrorKind::WouldBlock,
+            format!("Lock {path:?} is held by another process"),
+        ),
+        errno => io::Error::new(
+            io::Error::from(errno).kind(),
+            format!("Cannot lock {path:?}: {errno}"),
+        ),
+    }
+}
+
+/// Check that the inherited file descriptor `fd` is open for reading, or for
+/// writing if `write` is set.
+pub fn check_fd(fd: RawFd, write: bool) -> io::Result<()> {
+    let flags = fcntl(fd, FcntlArg::F_GETFL).map_err(|errno| {
+        io::Error::new(
+            io::Error::from(errno).kind(),
+            format!("Cannot use file descriptor {fd}: {errno}"),
+        )
+    })?;
+    let access = OFlag::from_bits_truncate(flags) & OFlag::O_ACCMODE;
+    let usable = match access {
+        OFlag::O_RDWR => true,
+        OFlag::O_WRONLY => write,
+        _ => !write,
+    };
+    if !usable {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "File descriptor {fd} is not open for {direction}",
+                direction = if write { "writing" } else { "reading" }
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Take ownership of the inherited file descriptor `fd` after checking it
+/// with [`check_fd`], the returned file closes `fd` when dropped.
+pub fn inherited_file(fd: RawFd, write: bool) -> io::Result<fs::File> {
+    check_fd(fd, write)?;
+    // SAFETY: fd is open as checked above and inherited for this command
+    // only, nothing else in the process owns or closes it
+    Ok(unsafe { fs::File::from_raw_fd(fd) })
+}
+
+/// Metadata of the inherited file descriptor `fd`, which stays open.
+pub fn inherited_metadata(fd: RawFd) -> io::Result<fs::Metadata> {
+    check_fd(fd, false)?;
+    // SAFETY: fd is open as checked above and stays open while borrowed, the
+    // duplicate is inspected so that fd itself is left untouched
+    let file = fs::File::from(unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned()?);
+    file.metadata()
+}
+
+/// Whether `a` and `b` are on the same filesystem, so files can be
+/// hard-linked from one to the other.
+pub fn same_filesystem(a: &Path, b: &Path) -> io::Result<bool> {
+    Ok(fs::metadata(a)?.dev() == fs::metadata(b)?.dev())
+}
+
+/// Warn if chunks cannot be hard-linked from the queue `incoming` to the
+/// queue `outgoing`, since [`link_or_copy`] then copies every chunk.
+pub fn check_same_filesystem(incoming: &Path, outgoing: &Path) -> io::Result<()> {
+    if !same_filesystem(incoming, outgoing)? {
+        log::warn!(
+            "{incoming:?} and {outgoing:?} are on different filesystems, \
+             copying chunks instead of hard-linking them"
+        );
+    }
+    Ok(())
+}
+
+/// Hard-link `original` to `link`, or copy it if they are on different
+/// filesystems, see [`link_or_copy_with`].
+pub fn link_or_copy(original: &Path, link: &Path, sync: SyncPolicy) -> io::Result<()> {
+    link_or_copy_with(
+        |original, link| fs::hard_link(original, link),
+        original,
+        link,
+        sync,
+    )
+}
+
+/// Link `original` to `link` with `hard_link`, and copy it if that fails
+/// with `EXDEV`. The copy is synced with `sync` and then renamed onto
+/// `link`, so `link` never appears partially written, and like a hard link
+/// it never replaces an existing `link`.
+pub fn link_or_copy_with(
+    hard_link: impl FnOnce(&Path, &Path) -> io::Result<()>,
+    original: &Path,
+    link: &Path,
+    sync: SyncPolicy,
+) -> io::Result<()> {
+    match hard_link(original, link) {
+        Err(err) if err.raw_os_error() == Some(Errno::EXDEV as i32) => {
+            log::debug!("Cannot link {original:?} across filesystems, copying it to {link:?}…");
+        }
+        result => return result,
+    }
+    let (Some(dir), Some(file_name)) = (link.parent(), link.file_name()) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Link {link:?} is not a file name"),
+        ));
+    };
+    let mut prefix = std::ffi::OsString::from(".");
+    prefix.push(file_name);
+    prefix.push(".");
+    let mut temp = tempfile::Builder::new()
+        .prefix(&prefix)
+        .suffix(".part")
+        .tempfile_in(dir)?;
+    io::copy(&mut fs::File::open(original)?, &mut temp)?;
+    temp.as_file()
+        .set_permissions(fs::metadata(original)?.permissions())?;
+    sync.sync(temp.as_file())?;
+    temp.persist_noclobber(link).map_err(|err| err.error)?;
+    Ok(())
+}
+
+/// Bytes available to unprivileged users on the filesystem of `path`, which
+/// need not exist yet: its nearest existing ancestor is inspected instead.
+pub fn available_space(path: &Path) -> io::Result<u64> {
+    let mut errno = Errno::ENOENT;
+    for dir in path.ancestors().filter(|dir| !dir.as_os_str().is_empty()) {
+        match statvfs(dir) {
+            Ok(stat) => return Ok(stat.blocks_available().saturating_mul(stat.fragment_size())),
+            Err(Errno::ENOENT) => continue,
+            Err(err) => {
+                errno = err;
+                break;
+            }
+        }
+    }
+    Err(io::Error::new(
+        io::Error::from(errno).kind(),
+        format!("Cannot get free space of {path:?}: {errno}"),
+    ))
+}
+
+/// Fail early with `StorageFull` if fewer than `required` bytes are
+/// available on the filesystem of `path`, see [`available_space`].
+pub fn check_free_space(path: &Path, required: u64) -> io::Result<()> {
+    let available = available_space(path)?;
+    log::debug!("{available} bytes available for {path:?}, {required} required");
+    if available < required {
+        return Err(io::Error::new(
+            io::Error::from(Errno::ENOSPC).kind(),
+            format!("Only {available} bytes available for {path:?}, {required} required"),
+        ));
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
+    use std::os::unix::fs::PermissionsExt;
     use std::path::PathBuf;
 
     use super::*;
@@ -205,4 +417,85 @@ mod tests {
             )
         );
     }
+
+    #[test]
+    fn inherited_fd_direction() {
+        use std::os::fd::AsRawFd;
+
+        let file = tempfile::tempfile().unwrap();
+        let (reader, writer) = nix::unistd::pipe().unwrap();
+        check_fd(reader.as_raw_fd(), false).expect("pipe reader is not readable");
+        check_fd(writer.as_raw_fd(), true).expect("pipe writer is not writable");
+        let err = check_fd(reader.as_raw_fd(), true).expect_err("pipe reader is writable");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        check_fd(writer.as_raw_fd(), false).expect_err("pipe writer is readable");
+        check_fd(file.as_raw_fd(), true).expect("read-write file is not writable");
+    }
+
+    #[test]
+    fn exclusive_lock_file() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join(".lock");
+        let lock = lock_file(&path, 0o600).expect("cannot lock");
+        // flock conflicts between open file descriptions, even in one process
+        let err = lock_file(&path, 0o600).expect_err("locked twice");
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        drop(lock);
+        lock_file(&path, 0o600).expect("cannot lock after unlock");
+    }
+
+    #[test]
+    fn free_space_of_missing_path() {
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        let missing = tmp_dir.path().join("not/yet/created");
+        available_space(&missing).expect("cannot get free space");
+        check_free_space(&missing, 0).expect("no space required");
+        let err = check_free_space(&missing, u64::MAX).expect_err("enough space");
+        assert_eq!(err.raw_os_error(), None);
+        assert_eq!(err.kind(), io::Error::from(Errno::ENOSPC).kind());
+    }
+
+    #[test]
+    fn same_filesystem_of_queues() {
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        let (backup, freeze) = (tmp_dir.path().join("backup"), tmp_dir.path().join("freeze"));
+        fs::create_dir(&backup).unwrap();
+        fs::create_dir(&freeze).unwrap();
+        assert!(same_filesystem(&backup, &freeze).unwrap());
+        check_same_filesystem(&backup, &freeze).unwrap();
+        let missing = tmp_dir.path().join("missing");
+        let err = same_filesystem(&backup, &missing).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn copy_across_filesystems() {
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        let original = tmp_dir.path().join("incoming");
+        fs::write(&original, b"chunk").unwrap();
+        fs::set_permissions(&original, fs::Permissions::from_mode(0o640)).unwrap();
+        let cross_device = |_: &Path, _: &Path| Err(io::Error::from(Errno::EXDEV));
+
+        let link = tmp_dir.path().join("outgoing");
+        link_or_copy_with(cross_device, &original, &link, SyncPolicy::Data)
+            .expect("cannot copy across filesystems");
+        assert_eq!(fs::read(&link).unwrap(), b"chunk");
+        let mode = fs::metadata(&link).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+        assert_eq!(fs::read_dir(tmp_dir.path()).unwrap().count(), 2);
+
+        let err = link_or_copy_with(cross_device, &original, &link, SyncPolicy::Data)
+            .expect_err("replaced existing link");
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+        let permission_denied = |_: &Path, _: &Path| Err(io::Error::from(Errno::EPERM));
+        let err = link_or_copy_with(
+            permission_denied,
+            &original,
+            &tmp_dir.path().join("other"),
+            SyncPolicy::Data,
+        )
+        .expect_err("copied after another link error");
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
 }
@@ -0,0 +1,94 @@
+// Copyright The Cryophile Authors.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE> or
+// <http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT> or <http://opensource.org/licenses/MIT>, at your option.
+//
+// This file may not be copied, modified, or distributed except according
+// to those terms.
+
+use std::time::Duration;
+use std::{io, thread};
+
+use nix::errno::Errno;
+
+use super::constants::{TRANSIENT_RETRIES, TRANSIENT_RETRY_DELAY};
+
+/// Whether an operation that failed with `err` may succeed when retried,
+/// e.g., on a network filesystem that briefly returns a stale file handle.
+pub fn is_transient(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+    ) || err.raw_os_error() == Some(Errno::ESTALE as i32)
+}
+
+/// Run `op` until it succeeds or fails with an error that is not transient.
+///
+/// Interrupted operations are retried right away, other transient errors
+/// up to [`TRANSIENT_RETRIES`] times with exponential backoff.
+pub fn retry_transient<T, F>(what: &str, op: F) -> io::Result<T>
+where
+    F: FnMut() -> io::Result<T>,
+{
+    retry_transient_with_delay(what, TRANSIENT_RETRY_DELAY, op)
+}
+
+fn retry_transient_with_delay<T, F>(what: &str, delay: Duration, mut op: F) -> io::Result<T>
+where
+    F: FnMut() -> io::Result<T>,
+{
+    let mut delay = delay;
+    let mut retries = 0;
+    loop {
+        match op() {
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {
+                log::debug!("Retrying interrupted {what}: {err}");
+            }
+            Err(err) if is_transient(&err) && retries < TRANSIENT_RETRIES => {
+                retries += 1;
+                log::warn!("Retrying {what} in {delay:?} ({retries}/{TRANSIENT_RETRIES}): {err}");
+                thread::sleep(delay);
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_transient_errors() {
+        let mut attempts = 0;
+        let result = retry_transient_with_delay("test", Duration::ZERO, || {
+            attempts += 1;
+            match attempts {
+                1 => Err(io::Error::from(io::ErrorKind::Interrupted)),
+                2 => Err(io::Error::from(io::ErrorKind::WouldBlock)),
+                3 => Err(io::Error::from_raw_os_error(Errno::ESTALE as i32)),
+                _ => Ok(attempts),
+            }
+        });
+        assert_eq!(result.expect("transient errors were not retried"), 4);
+
+        let mut attempts = 0;
+        let err = retry_transient_with_delay("test", Duration::ZERO, || -> io::Result<()> {
+            attempts += 1;
+            Err(io::Error::from(io::ErrorKind::NotFound))
+        })
+        .expect_err("missing file succeeded");
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(attempts, 1);
+
+        let mut attempts = 0;
+        retry_transient_with_delay("test", Duration::ZERO, || -> io::Result<()> {
+            attempts += 1;
+            Err(io::Error::from(io::ErrorKind::WouldBlock))
+        })
+        .expect_err("persistent transient error succeeded");
+        assert_eq!(attempts, TRANSIENT_RETRIES + 1);
+    }
+}
@@ -0,0 +1,224 @@
+// Copyright The Cryophile Authors.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE> or
+// <http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT> or <http://opensource.org/licenses/MIT>, at your option.
+//
+// This file may not be copied, modified, or distributed except according
+// to those terms.
+
+use std::{fmt, io};
+
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::Client;
+use tokio::runtime::Runtime;
+
+use super::aws::list_chunks;
+use super::constants::CHUNK_FILE_PREFIX;
+
+/// Object key of chunk `num` of the backup stored at `vault_key`.
+pub fn chunk_key(vault_key: &str, num: u64) -> String {
+    if vault_key.is_empty() {
+        format!("{CHUNK_FILE_PREFIX}.{num}")
+    } else {
+        format!("{vault_key}/{CHUNK_FILE_PREFIX}.{num}")
+    }
+}
+
+/// Read the chunks of a frozen backup directly from a bucket.
+///
+/// Like [`Cat`](super::cat::Cat) for chunk files, `S3Reader` concatenates the
+/// chunk objects `chunk.1`, `chunk.2`, … below `vault_key` and advances to the
+/// next chunk key on the end of the current object. The chunks are listed
+/// before the first download, the stream ends after the last listed chunk and
+/// a chunk missing before it is an error rather than the end of the stream.
+pub struct S3Reader {
+    runtime: Runtime,
+    client: Client,
+    bucket: String,
+    vault_key: String,
+    num: u64,                 // number of the current chunk
+    last: Option<u64>,        // number of the last chunk, once listed
+    tot: u64,                 // total bytes read
+    body: Option<ByteStream>, // current chunk object
+    buf: Vec<u8>,             // bytes received but not yet read
+    pos: usize,               // read position in buf
+    completed: bool,
+}
+
+impl fmt::Debug for S3Reader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "S3Reader {{ bucket: {bucket:?}, vault_key: {vault_key:?}, total_bytes: {total_bytes}, chunk: {chunk}, completed: {completed} }}",
+            bucket = self.bucket,
+            vault_key = self.vault_key,
+            total_bytes = self.tot,
+            chunk = self.num,
+            completed = self.completed
+        )
+    }
+}
+
+impl S3Reader {
+    pub fn new(runtime: Runtime, client: Client, bucket: String, vault_key: String) -> Self {
+        Self {
+            runtime,
+            client,
+            bucket,
+            vault_key,
+            num: 0,
+            last: None,
+            tot: 0,
+            body: None,
+            buf: Vec::new(),
+            pos: 0,
+            completed: false,
+        }
+    }
+
+    /// Download the zero chunk, which marks a complete backup.
+    #[tracing::instrument(level = "debug", skip(self), fields(bucket = %self.bucket, key, size))]
+    pub fn zero_chunk(&self) -> io::Result<Vec<u8>> {
+        let key = chunk_key(&self.vault_key, 0);
+        tracing::Span::current().record("key", &key);
+        log::debug!("Downloading s3://{bucket}/{key}…", bucket = self.bucket);
+        let request = self.client.get_object().bucket(&self.bucket).key(&key);
+        let download = async {
+            let output = request.send().await.map_err(|err| {
+                io::Error::other(format!(
+                    "Cannot download s3://{bucket}/{key}: {err}",
+                    bucket = self.bucket,
+                    err = err.into_service_error()
+                ))
+            })?;
+            let body = output.body.collect().await.map_err(|err| {
+                io::Error::other(format!(
+                    "Cannot download s3://{bucket}/{key}: {err}",
+                    bucket = self.bucket
+                ))
+            })?;
+            let body = body.to_vec();
+            tracing::Span::current().record("size", body.len());
+            Ok(body)
+        };
+        self.runtime.block_on(download)
+    }
+
+    /// Number of the last chunk below `vault_key`, listed once before the
+    /// first chunk is downloaded.
+    fn last_chunk(&mut self) -> io::Result<u64> {
+        if let Some(last) = self.last {
+            return Ok(last);
+        }
+        let chunks =
+            self.runtime
+                .block_on(list_chunks(&self.client, &self.bucket, &self.vault_key))?;
+        let mut last = 0;
+        for num in chunks.into_iter().filter(|num| *num > 0) {
+            if num != last + 1 {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!(
+                        "Missing chunk s3://{bucket}/{key}",
+                        bucket = self.bucket,
+                        key = chunk_key(&self.vault_key, last + 1)
+                    ),
+                ));
+            }
+            last = num;
+        }
+        if last == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "Found no chunks in s3://{bucket}/{vault_key}",
+                    bucket = self.bucket,
+                    vault_key = self.vault_key
+                ),
+            ));
+        }
+        log::debug!("Found {last} chunk(s) to download");
+        self.last = Some(last);
+        Ok(last)
+    }
+
+    /// Open the next chunk object, returns false after the last chunk.
+    #[tracing::instrument(level = "debug", skip(self), fields(bucket = %self.bucket, key, chunk = self.num + 1, size))]
+    fn open_next(&mut self) -> io::Result<bool> {
+        if self.num >= self.last_chunk()? {
+            return Ok(false);
+        }
+        let key = chunk_key(&self.vault_key, self.num + 1);
+        tracing::Span::current().record("key", &key);
+        log::debug!("Downloading s3://{bucket}/{key}…", bucket = self.bucket);
+        let request = self.client.get_object().bucket(&self.bucket).key(&key);
+        match self.runtime.block_on(request.send()) {
+            Ok(output) => {
+                if let Some(size) = output.content_length() {
+                    tracing::Span::current().record("size", size);
+                }
+                self.num += 1;
+                self.body = Some(output.body);
+                Ok(true)
+            }
+            Err(err) => {
+                let service_error = err.into_service_error();
+                let kind = if service_error.is_no_such_key() {
+                    io::ErrorKind::NotFound
+                } else {
+                    io::ErrorKind::Other
+                };
+                Err(io::Error::new(
+                    kind,
+                    format!(
+                        "Cannot download s3://{bucket}/{key}: {service_error}",
+                        bucket = self.bucket
+                    ),
+                ))
+            }
+        }
+    }
+}
+
+impl io::Read for S3Reader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.pos < self.buf.len() {
+                let n = buf.len().min(self.buf.len() - self.pos);
+                buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+                self.pos += n;
+                self.tot += n as u64;
+                return Ok(n);
+            }
+            if self.completed {
+                return Ok(0);
+            }
+            match self.body.as_mut() {
+                Some(body) => match self.runtime.block_on(body.next()) {
+                    Some(Ok(bytes)) => {
+                        self.buf = bytes.to_vec();
+                        self.pos = 0;
+                    }
+                    Some(Err(err)) => {
+                        return Err(io::Error::other(format!(
+                            "Cannot download chunk {num}: {err}",
+                            num = self.num
+                        )));
+                    }
+                    None => self.body = None, // end of chunk, advance to the next one
+                },
+                None => {
+                    if !self.open_next()? {
+                        log::debug!(
+                            "Downloaded {num} chunk(s) with {tot} bytes",
+                            num = self.num,
+                            tot = self.tot
+                        );
+                        self.completed = true;
+                    }
+                }
+            }
+        }
+    }
+}
@@ -15,9 +15,12 @@ use std::path::Path;
 use std::path::PathBuf;
 use std::{fmt, fs, io};
 
+use clap::ValueEnum;
 use nix::fcntl::FallocateFlags;
 
 use super::constants::CHUNK_FILE_MODE;
+use super::path::link_or_copy;
+use super::retry::retry_transient;
 
 fn errno_error(e: nix::errno::Errno) -> io::Error {
     io::Error::from_raw_os_error(e as i32)
@@ -28,6 +31,28 @@ fn log_io_error(err: io::Error, error: String) -> io::Error {
     io::Error::new(err.kind(), error)
 }
 
+/// Durability of chunks before they are published outgoing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum SyncPolicy {
+    /// Sync chunk data with fdatasync
+    #[default]
+    Data,
+    /// Sync chunk data and metadata with fsync
+    Full,
+    /// Leave syncing to the kernel, chunks may be lost or corrupt after a crash
+    None,
+}
+
+impl SyncPolicy {
+    pub fn sync(self, file: &fs::File) -> io::Result<()> {
+        match self {
+            SyncPolicy::Data => file.sync_data(),
+            SyncPolicy::Full => file.sync_all(),
+            SyncPolicy::None => Ok(()),
+        }
+    }
+}
+
 pub struct Split {
     num: usize,             // maximum size of each split
     pos: usize,             // written bytes of current split
@@ -37,6 +62,8 @@ pub struct Split {
     outgoing: PathBuf,      // outgoing link prefix
     file: Option<fs::File>, // current output file
     mark_failed: bool,      // Split had an error
+    max: Option<u64>,       // maximum number of file splits
+    sync: SyncPolicy,       // sync before linking outgoing
 }
 
 impl fmt::Debug for Split {
@@ -56,6 +83,14 @@ impl fmt::Debug for Split {
 impl Drop for Split {
     #[tracing::instrument(level = "trace")]
     fn drop(&mut self) {
+        // never link the incomplete chunk of a failed split
+        if self.mark_failed {
+            log::warn!(
+                "Not linking incomplete chunk {incoming:?} of failed split",
+                incoming = self.current_incoming_path()
+            );
+            return;
+        }
         // flush data
         if let Err(err) = self.flush() {
             log::error!("Cannot flush: {err}");
@@ -79,9 +114,21 @@ impl Split {
             outgoing: outgoing.join(chunk_prefix),
             file: None,
             mark_failed: false,
+            max: None,
+            sync: SyncPolicy::default(),
         }
     }
 
+    pub fn with_max_chunks(mut self, max_chunks: u64) -> Self {
+        self.max = Some(max_chunks);
+        self
+    }
+
+    pub fn with_sync_policy(mut self, sync: SyncPolicy) -> Self {
+        self.sync = sync;
+        self
+    }
+
     pub fn clear(&mut self) -> io::Result<()> {
         let result = self.flush();
         self.pos = 0;
@@ -92,10 +139,29 @@ impl Split {
         result
     }
 
+    /// Flush and link the current chunk outgoing, like dropping the split
+    /// but reporting errors.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.mark_failed {
+            return Err(io::Error::other(format!(
+                "Split is marked failed at {total_bytes}",
+                total_bytes = self.tot
+            )));
+        }
+        self.flush()?;
+        self.outgoing_chunk()?;
+        self.file = None;
+        Ok(())
+    }
+
     pub fn written(&self) -> u64 {
         self.tot
     }
 
+    pub fn chunks(&self) -> u64 {
+        self.val
+    }
+
     fn current_incoming_path(&self) -> PathBuf {
         self.incoming.with_extension(self.val.to_string())
     }
@@ -112,7 +178,7 @@ impl Split {
         };
         let incoming = self.current_incoming_path();
         let outgoing = self.current_outgoing_path();
-        file.sync_data().map_err(|err| {
+        self.sync.sync(file).map_err(|err| {
             self.mark_failed = true;
             log_io_error(err, format!("Cannot sync incoming {incoming:?}"))
         })?;
@@ -142,7 +208,11 @@ impl Split {
             incoming = format!("{incoming:?}", incoming = incoming),
             outgoing = format!("{outgoing:?}", outgoing = outgoing)
         );
-        fs::hard_link(&incoming, &outgoing).map_err(|err| {
+        // a copy if the queues are on different filesystems
+        retry_transient(&format!("hard link of {incoming:?}"), || {
+            link_or_copy(&incoming, &outgoing, self.sync)
+        })
+        .map_err(|err| {
             self.mark_failed = true;
             log_io_error(err, format!("Cannot create new outgoing {outgoing:?}"))
         })?;
@@ -152,16 +222,38 @@ impl Split {
             action = "unlink",
             incoming = format!("{incoming:?}", incoming = incoming)
         );
-        fs::remove_file(incoming).map_err(|err| {
+        retry_transient(&format!("unlink of {incoming:?}"), || {
+            fs::remove_file(&incoming)
+        })
+        .map_err(|err| {
             self.mark_failed = true;
             log_io_error(err, format!("Cannot unlink incoming {outgoing:?}"))
         })?;
         Ok(())
     }
 
+    /// Mark the split failed after an internal invariant was violated.
+    ///
+    /// Panicking in the middle of a write could leave a corrupt chunk behind,
+    /// so invariants only panic in debug builds and fail the split otherwise.
+    fn invariant_error(&mut self, invariant: &str) -> io::Error {
+        debug_assert!(false, "{invariant}");
+        self.mark_failed = true;
+        log::error!(
+            "Split invariant violated at {total_bytes}: {invariant}",
+            total_bytes = self.tot
+        );
+        io::Error::other(format!(
+            "Split invariant violated at {total_bytes}: {invariant}",
+            total_bytes = self.tot
+        ))
+    }
+
     #[tracing::instrument(level = "trace")]
     fn use_file_or_next(&mut self) -> io::Result<usize> {
-        assert!(self.pos <= self.num, "file position exceeded max size");
+        if self.pos > self.num {
+            return Err(self.invariant_error("file position exceeded max size"));
+        }
 
         if self.mark_failed {
             log::error!(
@@ -182,6 +274,14 @@ impl Split {
         // link current incoming chunk outgoing
         self.outgoing_chunk()?;
 
+        if let Some(max) = self.max.filter(|max| self.val >= *max) {
+            self.mark_failed = true;
+            log::error!("Split exceeds maximum of {max} chunks");
+            return Err(io::Error::other(format!(
+                "Split exceeds maximum of {max} chunks"
+            )));
+        }
+
         // open next chunk
         self.val += 1;
         let incoming = self.current_incoming_path();
@@ -243,7 +343,9 @@ impl Split {
         if buf_len == 0 {
             return Ok(0);
         }
-        assert!(buf_len <= self.num, "buffer too large");
+        if buf_len > self.num {
+            return Err(self.invariant_error("buffer too large"));
+        }
 
         if self.mark_failed {
             log::error!(
@@ -267,7 +369,9 @@ impl Split {
 
         self.tot += n;
         self.pos += offset;
-        assert!(self.pos <= self.num, "Split.pos > Split.num");
+        if self.pos > self.num {
+            return Err(self.invariant_error("Split.pos > Split.num"));
+        }
 
         Ok(offset)
     }
@@ -288,6 +392,17 @@ impl io::Write for Split {
         let buf_len = buf.len();
         let mut written = 0;
 
+        // rotate a full chunk first, so that remainder cannot underflow
+        if self.pos > self.num {
+            return Err(self.invariant_error("Split.pos > Split.num"));
+        }
+        if buf_len > 0 && self.file.is_some() && self.pos == self.num {
+            let remaining_bytes = self.use_file_or_next()?;
+            if remaining_bytes == 0 {
+                return Ok(0);
+            }
+        }
+
         let remainder = self.num - self.pos;
 
         let (head, tail) = if remainder < buf_len {
@@ -309,6 +424,10 @@ impl io::Write for Split {
 
         // write left slice of length remainder or buf_len
         written += self.write_once(head)?;
+        if written < head.len() {
+            // short write (e.g., retrying fallocate), let the caller retry the rest
+            return Ok(written);
+        }
 
         if !tail.is_empty() {
             tracing::event!(
@@ -322,18 +441,31 @@ impl io::Write for Split {
 
             // write right slice in chunks of length self.num (last chunk at most self.num)
             for chunk in tail.chunks(self.num) {
-                written += self.write_once(chunk)?;
+                let n = self.write_once(chunk)?;
+                written += n;
+                if n < chunk.len() {
+                    return Ok(written);
+                }
             }
         }
 
-        assert_eq!(buf_len, written, "buf.len() != written");
+        if buf_len != written {
+            return Err(self.invariant_error("buf.len() != written"));
+        }
 
         Ok(written)
     }
 
     #[inline]
     fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
-        let total_len = bufs.iter().map(|buf| self.write(buf).unwrap()).sum();
+        let mut total_len = 0;
+        for buf in bufs {
+            let n = self.write(buf)?;
+            total_len += n;
+            if n < buf.len() {
+                break;
+            }
+        }
         Ok(total_len)
     }
 
@@ -0,0 +1,98 @@
+// Copyright The Cryophile Authors.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE> or
+// <http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT> or <http://opensource.org/licenses/MIT>, at your option.
+//
+// This file may not be copied, modified, or distributed except according
+// to those terms.
+
+use std::io;
+
+/// [`io::Write`] adapter that writes everything to all of its writers.
+///
+/// A write succeeds once the whole buffer was written to every writer, and
+/// fails with the error of the first writer that fails, leaving the writers
+/// after it behind.
+#[derive(Debug)]
+pub struct Tee<W: io::Write> {
+    writers: Vec<W>,
+}
+
+impl<W: io::Write> Tee<W> {
+    pub fn new(writers: Vec<W>) -> Self {
+        Self { writers }
+    }
+
+    pub fn writers(&self) -> &[W] {
+        &self.writers
+    }
+
+    pub fn into_inner(self) -> Vec<W> {
+        self.writers
+    }
+}
+
+impl<W: io::Write> io::Write for Tee<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for writer in self.writers.iter_mut() {
+            writer.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for writer in self.writers.iter_mut() {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    /// Accepts `capacity` bytes, then fails.
+    struct Limited {
+        written: Vec<u8>,
+        capacity: usize,
+    }
+
+    impl io::Write for Limited {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.written.len() + buf.len() > self.capacity {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "full"));
+            }
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_to_all_writers() {
+        let mut tee = Tee::new(vec![Vec::new(), Vec::new()]);
+        io::copy(&mut &b"cryophile"[..], &mut tee).unwrap();
+        tee.flush().unwrap();
+        assert_eq!(tee.into_inner(), vec![b"cryophile".to_vec(); 2]);
+    }
+
+    #[test]
+    fn fail_if_any_writer_fails() {
+        let limited = |capacity| Limited {
+            written: Vec::new(),
+            capacity,
+        };
+        let mut tee = Tee::new(vec![limited(16), limited(4)]);
+        tee.write_all(b"cryo").unwrap();
+        let err = tee.write_all(b"phile").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+        assert_eq!(tee.writers()[1].written, b"cryo");
+    }
+}
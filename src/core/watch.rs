@@ -7,12 +7,12 @@
 // This file may not be copied, modified, or distributed except according
 // to those terms.
 
-use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use notify::{Event, EventKind, PollWatcher, RecommendedWatcher, Watcher};
 use std::io;
 use std::path::PathBuf;
 use std::sync::mpsc::{Receiver, RecvError, SendError};
 use std::sync::{mpsc, Mutex};
-use tempfile::TempDir;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc::Sender;
 
 use super::notify::notify_error;
@@ -25,30 +25,116 @@ pub fn channel_recv_error(e: RecvError) -> io::Error {
     io::Error::other(format!("Channel recv error: {e}"))
 }
 
+/// File system watcher backend and event debouncing.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WatchConfig {
+    /// Poll at this interval instead of using the recommended backend, e.g.,
+    /// for spools on NFS or CIFS mounts without inotify support
+    pub poll: Option<Duration>,
+    /// Drop events identical to the previous event within this duration
+    pub debounce: Option<Duration>,
+}
+
+impl WatchConfig {
+    /// Create the configured watcher, passing events on to `handler`.
+    pub fn watcher<F>(&self, mut handler: F) -> io::Result<Box<dyn Watcher + Send>>
+    where
+        F: FnMut(notify::Result<Event>) + Send + 'static,
+    {
+        let debounce = self.debounce;
+        let mut previous: Option<(EventKind, Vec<PathBuf>, Instant)> = None;
+        let debounced = move |event: notify::Result<Event>| {
+            if let (Some(debounce), Ok(event)) = (debounce, &event) {
+                let now = Instant::now();
+                if let Some((kind, paths, seen)) = &previous {
+                    if *kind == event.kind
+                        && *paths == event.paths
+                        && now.duration_since(*seen) < debounce
+                    {
+                        log::trace!("Debouncing event {kind:?} {paths:?}");
+                        return;
+                    }
+                }
+                previous = Some((event.kind, event.paths.clone(), now));
+            }
+            handler(event)
+        };
+
+        let watcher: Box<dyn Watcher + Send> = match self.poll {
+            Some(interval) => {
+                log::info!(
+                    "Using poll watcher with interval {interval}",
+                    interval = humantime::format_duration(interval)
+                );
+                let config = notify::Config::default().with_poll_interval(interval);
+                Box::new(PollWatcher::new(debounced, config).map_err(notify_error)?)
+            }
+            None => {
+                log::info!("Using {kind:?} watcher", kind = RecommendedWatcher::kind());
+                Box::new(
+                    RecommendedWatcher::new(debounced, notify::Config::default())
+                        .map_err(notify_error)?,
+                )
+            }
+        };
+        if let Some(debounce) = self.debounce {
+            log::debug!(
+                "Debouncing watch events within {debounce}",
+                debounce = humantime::format_duration(debounce)
+            );
+        }
+        Ok(watcher)
+    }
+}
+
+/// Message received by the consumer of a [`Watch`].
+pub enum WatchMessage {
+    /// A file system event reported by the watcher
+    Event(notify::Result<Event>),
+    /// Stop consuming events
+    Shutdown,
+}
+
+/// Handle to stop the consumer of a [`Watch`] without a file system round-trip.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    tx: mpsc::Sender<WatchMessage>,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        if self.tx.send(WatchMessage::Shutdown).is_err() {
+            log::trace!("Watch consumer already finished");
+        }
+    }
+}
+
 pub struct Watch {
-    pub rx: Mutex<Receiver<notify::Result<Event>>>,
-    pub watcher: RecommendedWatcher,
-    pub shutdown: TempDir,
+    pub rx: Mutex<Receiver<WatchMessage>>,
+    pub watcher: Box<dyn Watcher + Send>,
+    shutdown: ShutdownHandle,
     _handler: Option<Sender<Option<PathBuf>>>,
 }
 
 impl Watch {
-    pub fn new(handler: Option<Sender<Option<PathBuf>>>) -> io::Result<Self> {
-        // here we can shutdown the watch
-        let shutdown = tempfile::tempdir()?;
+    pub fn new(handler: Option<Sender<Option<PathBuf>>>, config: WatchConfig) -> io::Result<Self> {
         let (tx, rx) = mpsc::channel();
-        let mut watcher =
-            RecommendedWatcher::new(tx, notify::Config::default()).map_err(notify_error)?;
-        // let watcher monitor the shutdown path
-        watcher
-            .watch(shutdown.path(), RecursiveMode::NonRecursive)
-            .map_err(notify_error)?;
+        let event_tx = tx.clone();
+        let watcher = config.watcher(move |event| {
+            // the receiver is gone once the consumer finished
+            let _ = event_tx.send(WatchMessage::Event(event));
+        })?;
 
         Ok(Self {
             rx: Mutex::new(rx),
             watcher,
-            shutdown,
+            shutdown: ShutdownHandle { tx },
             _handler: handler,
         })
     }
+
+    /// Handle to stop the consumer of this watch, e.g., from another thread.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
 }
@@ -7,10 +7,19 @@
 // This file may not be copied, modified, or distributed except according
 // to those terms.
 
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
 use std::{fmt, str::FromStr};
 
+use age::secrecy::{ExposeSecret, SecretString};
+use age::stream::{StreamReader, StreamWriter};
+use age::{Decryptor, Identity, IdentityFile, IdentityFileEntry};
 use thiserror::Error;
 
+use crate::core::path::inherited_file;
+use crate::crypto::openpgp::CryptoError;
+
 #[derive(Clone)]
 pub enum RecipientKind {
     X25519Recipient(age::x25519::Recipient),
@@ -24,7 +33,7 @@ pub struct RecipientSpec {
 }
 
 impl RecipientSpec {
-    pub fn get_recipient(&self) -> Box<dyn age::Recipient> {
+    pub fn get_recipient(&self) -> Box<dyn age::Recipient + Send> {
         match &self.recipient {
             RecipientKind::SshRecipient(r) => Box::new(r.clone()),
             RecipientKind::X25519Recipient(r) => Box::new(r.clone()),
@@ -96,3 +105,229 @@ impl FromStr for RecipientSpec {
         )))
     }
 }
+
+/// How an age stream is encrypted: to public key recipients, or symmetrically
+/// with a passphrase using age's scrypt recipient.
+pub enum AgeEncryption {
+    Recipients(Vec<RecipientSpec>),
+    Passphrase(SecretString),
+}
+
+impl AgeEncryption {
+    /// Returns `None` if there are no recipients.
+    pub fn encryptor(&self) -> Option<age::Encryptor> {
+        match self {
+            AgeEncryption::Recipients(recipients) => age::Encryptor::with_recipients(
+                recipients
+                    .iter()
+                    .map(RecipientSpec::get_recipient)
+                    .collect(),
+            ),
+            AgeEncryption::Passphrase(passphrase) => Some(age::Encryptor::with_user_passphrase(
+                SecretString::new(passphrase.expose_secret().clone()),
+            )),
+        }
+    }
+
+    /// Encrypt the age stream written to `output`, finish the returned writer
+    /// to end it.
+    pub fn wrap_output<W: io::Write>(&self, output: W) -> io::Result<StreamWriter<W>> {
+        let encryptor = self
+            .encryptor()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No age recipients"))?;
+        encryptor.wrap_output(output).map_err(encrypt_error)
+    }
+}
+
+impl fmt::Debug for AgeEncryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AgeEncryption::Recipients(recipients) => {
+                f.debug_tuple("Recipients").field(recipients).finish()
+            }
+            AgeEncryption::Passphrase(_) => f.write_str("Passphrase"),
+        }
+    }
+}
+
+/// Read the age passphrase from file descriptor `fd`, or prompt for it on the
+/// terminal, twice if `confirm` is set.
+pub fn read_passphrase(fd: Option<i32>, confirm: bool) -> io::Result<SecretString> {
+    let passphrase = if let Some(fd) = fd {
+        log::debug!("Reading age passphrase from file descriptor {fd}…");
+        let file = inherited_file(fd, false)?;
+        rpassword::read_password_from_bufread(&mut BufReader::new(file))?
+    } else {
+        let passphrase = rpassword::prompt_password("Enter age passphrase: ")?;
+        if confirm && rpassword::prompt_password("Confirm age passphrase: ")? != passphrase {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Age passphrases do not match",
+            ));
+        }
+        passphrase
+    };
+    let passphrase = SecretString::new(passphrase);
+    if passphrase.expose_secret().is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Age passphrase is empty",
+        ));
+    }
+    Ok(passphrase)
+}
+
+/// Magic bytes at the start of a binary age stream.
+pub const AGE_MAGIC: &[u8] = b"age-encryption.org/v1";
+
+pub fn is_age_stream(buf: &[u8]) -> bool {
+    buf.starts_with(AGE_MAGIC)
+}
+
+/// Asks for the passphrase of encrypted SSH identities on the terminal.
+#[derive(Clone)]
+struct TerminalCallbacks;
+
+impl age::Callbacks for TerminalCallbacks {
+    fn display_message(&self, message: &str) {
+        log::info!("{message}");
+    }
+
+    fn confirm(&self, _message: &str, _yes_string: &str, _no_string: Option<&str>) -> Option<bool> {
+        None
+    }
+
+    fn request_public_string(&self, _description: &str) -> Option<String> {
+        None
+    }
+
+    fn request_passphrase(&self, description: &str) -> Option<SecretString> {
+        rpassword::prompt_password(format!("{description}: "))
+            .map(SecretString::new)
+            .ok()
+    }
+}
+
+/// Read the X25519 identities of an age identity file, or the SSH private key
+/// in `path`.
+fn read_identities(path: &Path) -> io::Result<Vec<Box<dyn Identity>>> {
+    let filename = path.to_string_lossy().into_owned();
+    if let Ok(identity_file) = IdentityFile::from_file(filename.clone()) {
+        let identities = identity_file.into_identities();
+        if !identities.is_empty() {
+            return Ok(identities
+                .into_iter()
+                .map(|entry| match entry {
+                    IdentityFileEntry::Native(identity) => Box::new(identity) as Box<dyn Identity>,
+                })
+                .collect());
+        }
+    }
+    let reader = BufReader::new(File::open(path)?);
+    match age::ssh::Identity::from_buffer(reader, Some(filename)) {
+        Ok(age::ssh::Identity::Unsupported(key)) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unsupported SSH identity in {path:?}: {key:?}"),
+        )),
+        Ok(identity) => Ok(vec![Box::new(identity.with_callbacks(TerminalCallbacks))]),
+        Err(err) => Err(io::Error::new(
+            err.kind(),
+            format!("Cannot read age identity {path:?}: {err}"),
+        )),
+    }
+}
+
+fn encrypt_error(err: age::EncryptError) -> io::Error {
+    match err {
+        age::EncryptError::Io(err) => err,
+        err => io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Age encryption failed: {err}"),
+        ),
+    }
+}
+
+fn decrypt_error(err: age::DecryptError) -> io::Error {
+    match err {
+        age::DecryptError::Io(err) => err,
+        err => io::Error::other(CryptoError(format!("Age decryption failed: {err}"))),
+    }
+}
+
+/// Identities and passphrase for decrypting age streams, read once for all
+/// streams of a restore.
+pub struct AgeKeys {
+    identities: Vec<Box<dyn Identity>>,
+    passphrase: Option<SecretString>,
+}
+
+impl AgeKeys {
+    /// Read the identity files, and the passphrase if `passphrase` is set.
+    pub fn new(
+        identity_files: &[PathBuf],
+        passphrase: bool,
+        pass_fd: Option<i32>,
+    ) -> io::Result<Self> {
+        let mut identities = vec![];
+        for path in identity_files {
+            identities.extend(read_identities(path)?);
+        }
+        log::debug!(
+            "Age keyring has {count} identities",
+            count = identities.len()
+        );
+        let passphrase = if passphrase {
+            Some(read_passphrase(pass_fd, false)?)
+        } else {
+            None
+        };
+        Ok(Self {
+            identities,
+            passphrase,
+        })
+    }
+
+    /// Decrypt the age stream `input` with the identities, or with the
+    /// passphrase, which is prompted for if it was not read before.
+    pub fn decryptor<R: io::BufRead>(&mut self, input: R) -> io::Result<StreamReader<R>> {
+        log::trace!("Setting up age decryption…");
+        match Decryptor::new_buffered(input).map_err(decrypt_error)? {
+            Decryptor::Recipients(decryptor) => {
+                if self.identities.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Stream is encrypted to age recipients, use --age-identity to decrypt it",
+                    ));
+                }
+                decryptor
+                    .decrypt(self.identities.iter().map(|identity| identity.as_ref()))
+                    .map_err(decrypt_error)
+            }
+            Decryptor::Passphrase(decryptor) => {
+                let passphrase = match self.passphrase.take() {
+                    Some(passphrase) => passphrase,
+                    None => read_passphrase(None, false)?,
+                };
+                let reader = decryptor.decrypt(&passphrase, None).map_err(decrypt_error);
+                self.passphrase = Some(passphrase);
+                reader
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn age_encryptor() {
+        assert!(AgeEncryption::Recipients(vec![]).encryptor().is_none());
+        let recipient = age::x25519::Identity::generate().to_public().to_string();
+        let spec = recipient.parse::<RecipientSpec>().unwrap();
+        assert!(AgeEncryption::Recipients(vec![spec]).encryptor().is_some());
+        let passphrase = AgeEncryption::Passphrase(SecretString::new("cryophile".to_string()));
+        assert_eq!(format!("{passphrase:?}"), "Passphrase");
+        assert!(passphrase.encryptor().is_some());
+    }
+}
@@ -0,0 +1,82 @@
+// Copyright The Cryophile Authors.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE> or
+// <http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT> or <http://opensource.org/licenses/MIT>, at your option.
+//
+// This file may not be copied, modified, or distributed except according
+// to those terms.
+
+use std::io;
+
+use sequoia_openpgp::serialize::stream::Message;
+use sequoia_openpgp::types::SymmetricAlgorithm;
+
+#[cfg(feature = "age")]
+use super::age::AgeEncryption;
+use super::openpgp::{build_encryptor, openpgp_error, Keyring};
+
+/// Encryption of a backup: OpenPGP to a keyring, or an age stream.
+pub enum Encryption<'a> {
+    OpenPgp(Keyring<'a>, SymmetricAlgorithm),
+    #[cfg(feature = "age")]
+    Age(AgeEncryption),
+}
+
+impl<'a> Encryption<'a> {
+    /// Encrypting writer to `output`, finalize it to end the encrypted stream.
+    pub fn writer<W: 'a + io::Write + Send + Sync>(
+        &self,
+        output: W,
+        armor: bool,
+    ) -> io::Result<EncryptedWriter<'a>> {
+        match self {
+            Encryption::OpenPgp(cert_list, symmetric_algo) => {
+                build_encryptor(cert_list.clone(), output, armor, *symmetric_algo)
+                    .map(EncryptedWriter::OpenPgp)
+            }
+            #[cfg(feature = "age")]
+            Encryption::Age(encryption) => {
+                log::info!("Setting up age encryption…");
+                let output: Box<dyn io::Write + Send + Sync + 'a> = Box::new(output);
+                encryption.wrap_output(output).map(EncryptedWriter::Age)
+            }
+        }
+    }
+}
+
+/// Writer of an OpenPGP message or an age stream.
+pub enum EncryptedWriter<'a> {
+    OpenPgp(Message<'a>),
+    #[cfg(feature = "age")]
+    Age(age::stream::StreamWriter<Box<dyn io::Write + Send + Sync + 'a>>),
+}
+
+impl EncryptedWriter<'_> {
+    /// Write the end of the encrypted stream.
+    pub fn finalize(self) -> io::Result<()> {
+        match self {
+            EncryptedWriter::OpenPgp(message) => message.finalize().map_err(openpgp_error),
+            #[cfg(feature = "age")]
+            EncryptedWriter::Age(writer) => writer.finish().map(drop),
+        }
+    }
+
+    fn inner(&mut self) -> &mut dyn io::Write {
+        match self {
+            EncryptedWriter::OpenPgp(message) => message,
+            #[cfg(feature = "age")]
+            EncryptedWriter::Age(writer) => writer,
+        }
+    }
+}
+
+impl io::Write for EncryptedWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner().flush()
+    }
+}
@@ -0,0 +1,120 @@
+// Copyright The Cryophile Authors.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE> or
+// <http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT> or <http://opensource.org/licenses/MIT>, at your option.
+//
+// This file may not be copied, modified, or distributed except according
+// to those terms.
+
+use std::io;
+use std::process::{Command, Stdio};
+
+use sequoia_openpgp::cert::CertParser;
+use sequoia_openpgp::parse::Parse;
+use sequoia_openpgp::Cert;
+
+use super::openpgp::openpgp_error;
+
+const GPG_PROGRAM: &str = "gpg";
+
+/// Export the certificates of `key_id` from the local GnuPG keyring.
+pub fn export_certs(key_id: &str) -> io::Result<Vec<Cert>> {
+    export(GPG_PROGRAM, "--export", key_id)
+}
+
+/// Export the secret keys of `key_id` from the local GnuPG keyring. They stay
+/// protected by their passphrase, which is asked for again to decrypt.
+pub fn export_secret_keys(key_id: &str) -> io::Result<Vec<Cert>> {
+    export(GPG_PROGRAM, "--export-secret-keys", key_id)
+}
+
+fn export(program: &str, command: &str, key_id: &str) -> io::Result<Vec<Cert>> {
+    log::debug!("Running {program} {command} {key_id}…");
+    let output = Command::new(program)
+        .args(["--armor", command, "--", key_id])
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Cannot find {program} on PATH, --keyring-from-gpg-agent requires GnuPG"),
+            ),
+            kind => io::Error::new(kind, format!("Cannot run {program}: {err}")),
+        })?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "{program} {command} {key_id} failed: {status}",
+            status = output.status
+        )));
+    }
+    let certs = CertParser::from_bytes(&output.stdout)
+        .map_err(openpgp_error)?
+        .collect::<sequoia_openpgp::Result<Vec<Cert>>>()
+        .map_err(openpgp_error)?;
+    // gpg exports nothing, but succeeds, for unknown keys
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("GnuPG keyring has no key {key_id}"),
+        ));
+    }
+    Ok(certs)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::{Path, PathBuf};
+
+    use sequoia_openpgp::cert::CertBuilder;
+    use sequoia_openpgp::serialize::Serialize;
+
+    use super::*;
+
+    /// Stand-in for gpg printing `keyring`, written to a closed temporary file
+    /// that is renamed to `dir/gpg`, so running it cannot fail with ETXTBSY.
+    fn gpg_script(dir: &Path, keyring: &Path) -> PathBuf {
+        let mut script = tempfile::NamedTempFile::new_in(dir).unwrap();
+        write!(script, "#!/bin/sh\ncat '{}'\n", keyring.display()).unwrap();
+        script
+            .as_file()
+            .set_permissions(fs::Permissions::from_mode(0o755))
+            .unwrap();
+        let program = dir.join("gpg");
+        script.into_temp_path().persist(&program).unwrap();
+        program
+    }
+
+    #[test]
+    fn export_from_gpg() {
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        let (cert, _) = CertBuilder::general_purpose(None, Some("cryophile test"))
+            .generate()
+            .unwrap();
+        let keyring = tmp_dir.path().join("key.asc");
+        cert.armored()
+            .serialize(&mut fs::File::create(&keyring).unwrap())
+            .unwrap();
+
+        let program = gpg_script(tmp_dir.path(), &keyring);
+        let certs = export(program.to_str().unwrap(), "--export", "cryophile test").unwrap();
+        assert_eq!(certs, vec![cert]);
+
+        let empty = tmp_dir.path().join("empty.asc");
+        fs::write(&empty, b"").unwrap();
+        let program = gpg_script(tmp_dir.path(), &empty);
+        let err = export(program.to_str().unwrap(), "--export", "unknown").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn export_without_gpg() {
+        let err = export("cryophile-test-missing-gpg", "--export", "key").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("on PATH"), "{err}");
+    }
+}
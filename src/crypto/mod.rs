@@ -10,4 +10,9 @@
 #[cfg(feature = "age")]
 pub mod age;
 
+pub mod encryption;
+
+#[cfg(feature = "gnupg")]
+pub mod gnupg;
+
 pub mod openpgp;
@@ -8,28 +8,35 @@
 // to those terms.
 
 use anyhow::Context;
+use clap::ValueEnum;
 use sequoia_openpgp as openpgp;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    fmt,
     fs::File,
     io::{self, BufReader},
     os::fd::FromRawFd,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::SystemTime,
 };
 
 use openpgp::{
+    cert::amalgamation::ValidAmalgamation,
     cert::prelude::ValidKeyAmalgamation,
+    cert::Preferences,
     crypto::{Decryptor, KeyPair, Password, SessionKey},
     packet::{
         key::{PublicParts, SecretParts, UnspecifiedRole},
-        Key, PKESK, SKESK,
+        Key, Packet, PKESK, SKESK,
     },
     parse::{
         stream::{self, DecryptionHelper, DecryptorBuilder, MessageStructure, VerificationHelper},
-        Parse,
+        PacketParser, PacketParserResult, Parse,
     },
-    policy::Policy,
-    serialize::stream::{Encryptor2, LiteralWriter, Message, Recipient},
-    types::{DataFormat, SymmetricAlgorithm},
+    policy::{AsymmetricAlgorithm, Policy, StandardPolicy},
+    serialize::stream::{Armorer, Encryptor2, LiteralWriter, Message, Recipient},
+    types::{DataFormat, HashAlgorithm, KeyFlags, RevocationStatus, SymmetricAlgorithm},
     Cert, Fingerprint, KeyID,
 };
 
@@ -37,7 +44,40 @@ use crate::core::constants::DEFAULT_BUF_SIZE;
 
 pub type Keyring<'a> = Vec<ValidKeyAmalgamation<'a, PublicParts, UnspecifiedRole, bool>>;
 
+/// A failed decryption or authentication, as opposed to an I/O error of
+/// the underlying ciphertext.
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub struct CryptoError(pub(crate) String);
+
+/// Whether `error` is a [`CryptoError`].
+pub fn is_crypto_error(error: &io::Error) -> bool {
+    error
+        .get_ref()
+        .is_some_and(|inner| inner.is::<CryptoError>())
+}
+
 pub fn openpgp_error(error: anyhow::Error) -> io::Error {
+    let reason = error_reason(&error);
+    if let Ok(err) = error.downcast::<io::Error>() {
+        io::Error::new(err.kind(), reason)
+    } else {
+        io::Error::other(reason)
+    }
+}
+
+/// Like [`openpgp_error`], but an error of [`build_decryptor`] that is not
+/// an I/O error is a failed decryption and reported as [`CryptoError`].
+pub fn decrypt_error(error: anyhow::Error) -> io::Error {
+    let reason = error_reason(&error);
+    if let Ok(err) = error.downcast::<io::Error>() {
+        io::Error::new(err.kind(), reason)
+    } else {
+        io::Error::other(CryptoError(reason))
+    }
+}
+
+fn error_reason(error: &anyhow::Error) -> String {
     let mut reason = String::new();
     for cause in error.chain() {
         reason.push_str(cause.to_string().as_str());
@@ -45,58 +85,330 @@ pub fn openpgp_error(error: anyhow::Error) -> io::Error {
             reason.push_str(": ");
         }
     }
-    if let Ok(err) = error.downcast::<io::Error>() {
-        io::Error::new(err.kind(), reason)
-    } else {
-        io::Error::other(reason)
+    reason
+}
+
+/// Key usage flag an encryption subkey must carry to be used as recipient.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum EncryptionUsage {
+    /// Subkeys flagged for data-at-rest encryption
+    #[default]
+    Storage,
+    /// Subkeys flagged for communications encryption
+    Transport,
+    /// Subkeys flagged for either kind of encryption
+    Any,
+}
+
+impl EncryptionUsage {
+    fn key_flags(self) -> KeyFlags {
+        match self {
+            EncryptionUsage::Storage => KeyFlags::empty().set_storage_encryption(),
+            EncryptionUsage::Transport => KeyFlags::empty().set_transport_encryption(),
+            EncryptionUsage::Any => KeyFlags::empty()
+                .set_storage_encryption()
+                .set_transport_encryption(),
+        }
+    }
+}
+
+impl fmt::Display for EncryptionUsage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let usage = match self {
+            EncryptionUsage::Storage => "storage",
+            EncryptionUsage::Transport => "transport",
+            EncryptionUsage::Any => "any",
+        };
+        write!(f, "{usage}")
+    }
+}
+
+/// How the symmetric algorithm of the session key is chosen.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum CipherSelection {
+    /// Always use AES256
+    #[default]
+    Aes256,
+    /// Use the strongest algorithm all recipients prefer, or else AES256
+    Preferred,
+}
+
+/// Symmetric algorithms from strongest to weakest.
+const SYMMETRIC_STRENGTH: [SymmetricAlgorithm; 7] = [
+    SymmetricAlgorithm::AES256,
+    SymmetricAlgorithm::Camellia256,
+    SymmetricAlgorithm::Twofish,
+    SymmetricAlgorithm::AES192,
+    SymmetricAlgorithm::Camellia192,
+    SymmetricAlgorithm::AES128,
+    SymmetricAlgorithm::Camellia128,
+];
+
+/// The strongest supported algorithm that `policy` accepts and that is in
+/// every preference list. Recipients without preferences accept all.
+fn strongest_common_algo<'a, P>(policy: &dyn Policy, preferences: P) -> Option<SymmetricAlgorithm>
+where
+    P: Iterator<Item = Option<&'a [SymmetricAlgorithm]>> + Clone,
+{
+    SYMMETRIC_STRENGTH.into_iter().find(|algo| {
+        algo.is_supported()
+            && policy.symmetric_algorithm(*algo).is_ok()
+            && preferences
+                .clone()
+                .all(|prefs| prefs.is_none_or(|prefs| prefs.contains(algo)))
+    })
+}
+
+/// Choose the symmetric algorithm of the session key for `recipients`.
+pub fn negotiate_symmetric_algo(
+    policy: &dyn Policy,
+    recipients: &Keyring,
+    selection: CipherSelection,
+) -> SymmetricAlgorithm {
+    if selection == CipherSelection::Aes256 {
+        return SymmetricAlgorithm::AES256;
+    }
+    let preferences = recipients
+        .iter()
+        .map(|key| ValidAmalgamation::cert(key).preferred_symmetric_algorithms());
+    match strongest_common_algo(policy, preferences) {
+        Some(algo) => {
+            log::info!("Negotiated {algo} from recipient preferences…");
+            algo
+        }
+        None => {
+            log::warn!(
+                "Recipients have no common symmetric algorithm preference, using {algo}",
+                algo = SymmetricAlgorithm::AES256
+            );
+            SymmetricAlgorithm::AES256
+        }
+    }
+}
+
+/// Select the keyring certificates with a primary key or subkey fingerprint
+/// ending in one of `fingerprints`, like GnuPG key ids. Selects all
+/// certificates if `fingerprints` is empty.
+pub fn select_recipients<'a, K>(keyring: K, fingerprints: &[String]) -> io::Result<Vec<&'a Cert>>
+where
+    K: Iterator<Item = &'a Cert>,
+{
+    let certs: Vec<&Cert> = keyring.collect();
+    if fingerprints.is_empty() {
+        return Ok(certs);
+    }
+    let mut selected: Vec<&Cert> = Vec::new();
+    for fingerprint in fingerprints {
+        let matches: Vec<&Cert> = certs
+            .iter()
+            .copied()
+            .filter(|cert| {
+                cert.keys()
+                    .any(|key| key.fingerprint().to_hex().ends_with(fingerprint.as_str()))
+            })
+            .collect();
+        match matches[..] {
+            [] => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!(
+                        "Keyring does not contain a certificate with fingerprint {fingerprint}"
+                    ),
+                ))
+            }
+            [cert] => {
+                log::info!(
+                    "Selected certificate {cert_fingerprint} for fingerprint {fingerprint}",
+                    cert_fingerprint = cert.fingerprint()
+                );
+                if !selected
+                    .iter()
+                    .any(|other| other.fingerprint() == cert.fingerprint())
+                {
+                    selected.push(cert);
+                }
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Fingerprint {fingerprint} matches {n} certificates in keyring",
+                        n = matches.len()
+                    ),
+                ))
+            }
+        }
+    }
+    Ok(selected)
+}
+
+/// An algorithm the standard policy rejects that the user explicitly accepts,
+/// e.g. to restore backups made with keys or digests since deprecated.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AllowedAlgorithm {
+    Hash(HashAlgorithm),
+    Symmetric(SymmetricAlgorithm),
+    Asymmetric(AsymmetricAlgorithm),
+}
+
+impl FromStr for AllowedAlgorithm {
+    type Err = String;
+
+    /// Parse an algorithm by its sequoia name, ignoring case, e.g. `SHA1`,
+    /// `CAST5` or `RSA1024`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let matches = |name: String| name.eq_ignore_ascii_case(s);
+        if let Some(algo) = HashAlgorithm::variants().find(|a| matches(format!("{a:?}"))) {
+            return Ok(AllowedAlgorithm::Hash(algo));
+        }
+        if let Some(algo) = SymmetricAlgorithm::variants().find(|a| matches(format!("{a:?}"))) {
+            return Ok(AllowedAlgorithm::Symmetric(algo));
+        }
+        if let Some(algo) = AsymmetricAlgorithm::variants().find(|a| matches(format!("{a:?}"))) {
+            return Ok(AllowedAlgorithm::Asymmetric(algo));
+        }
+        Err(format!(
+            "unknown hash, symmetric or asymmetric algorithm {s}"
+        ))
     }
 }
 
-pub fn storage_encryption_certs<'a, K>(
+impl fmt::Display for AllowedAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AllowedAlgorithm::Hash(algo) => write!(f, "hash algorithm {algo:?}"),
+            AllowedAlgorithm::Symmetric(algo) => write!(f, "symmetric algorithm {algo:?}"),
+            AllowedAlgorithm::Asymmetric(algo) => write!(f, "asymmetric algorithm {algo:?}"),
+        }
+    }
+}
+
+/// Build the standard policy, evaluated at `time` instead of now if given,
+/// that additionally accepts the `allowed` algorithms. Every relaxation is
+/// logged since it weakens the guarantees of the standard policy.
+pub fn build_policy(
+    time: Option<SystemTime>,
+    allowed: &[AllowedAlgorithm],
+) -> StandardPolicy<'static> {
+    let mut policy = match time {
+        Some(time) => {
+            log::warn!("Using relaxed OpenPGP policy: evaluating algorithms at {time:?}");
+            StandardPolicy::at(time)
+        }
+        None => StandardPolicy::new(),
+    };
+    for algo in allowed {
+        log::warn!("Using relaxed OpenPGP policy: accepting {algo}");
+        match *algo {
+            AllowedAlgorithm::Hash(algo) => policy.accept_hash(algo),
+            AllowedAlgorithm::Symmetric(algo) => policy.accept_symmetric_algo(algo),
+            AllowedAlgorithm::Asymmetric(algo) => policy.accept_asymmetric_algo(algo),
+        }
+    }
+    policy
+}
+
+pub fn encryption_certs<'a, K>(
     policy: &'a dyn Policy,
     keyring: K,
+    usage: EncryptionUsage,
+    require_all: bool,
 ) -> io::Result<Keyring<'a>>
 where
     K: Iterator<Item = &'a Cert>,
 {
-    log::trace!("Searching certificates for data-at-rest encryption…");
+    log::trace!("Searching certificates for {usage} encryption…");
     // get certificates from keyring
     let mut cert_list: Keyring = Vec::new();
+    let mut num_certs = 0;
+    let mut rejected = 0;
     for cert in keyring {
-        for storage in cert
+        num_certs += 1;
+        let num_keys = cert_list.len();
+        for encryption in cert
             .keys()
             .with_policy(policy, None)
             .supported()
             .alive()
             .revoked(false)
-            .for_storage_encryption()
+            .key_flags(usage.key_flags())
         {
-            let storage_cert = storage.cert().fingerprint();
-            let subkey = storage.keyid();
-            let mpis = storage.mpis();
+            let encryption_cert = encryption.cert().fingerprint();
+            let subkey = encryption.keyid();
+            let mpis = encryption.mpis();
             let algo = mpis.algo().expect("cannot handle unknown algorithm");
             let size = mpis.bits().unwrap_or(0);
+            let flags = match (
+                encryption.for_storage_encryption(),
+                encryption.for_transport_encryption(),
+            ) {
+                (true, true) => "storage+transport",
+                (true, false) => "storage",
+                _ => "transport",
+            };
             log::info!(
-                "Recipient certificate {storage_cert} subkey {subkey} algo {algo}{size}",
-                storage_cert = storage_cert.to_string(),
+                "Recipient certificate {encryption_cert} subkey {subkey} algo {algo}{size} usage {flags}",
+                encryption_cert = encryption_cert.to_string(),
                 subkey = subkey.to_string(),
                 algo = algo.to_string(),
                 size = size
             );
-            cert_list.push(storage.clone());
+            cert_list.push(encryption.clone());
+        }
+        if cert_list.len() == num_keys {
+            rejected += 1;
+            log::warn!(
+                "Skipping certificate {fingerprint}: {reason}",
+                fingerprint = cert.fingerprint(),
+                reason = unusable_cert_reason(policy, cert, usage)
+            );
+        }
+    }
+
+    if rejected > 0 {
+        log::warn!("Skipped {rejected} of {num_certs} certificate(s) without usable {usage} encryption subkeys");
+        if require_all {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Keyring contains {rejected} unusable recipient certificate(s)"),
+            ));
         }
     }
 
     if cert_list.is_empty() {
         return Err(io::Error::new(
             io::ErrorKind::InvalidInput,
-            "Keyring does not contain storage encryption certificates",
+            format!("Keyring does not contain {usage} encryption certificates"),
         ));
     }
 
     Ok(cert_list)
 }
 
+/// Explain why `cert` has no subkey usable for `usage` encryption.
+fn unusable_cert_reason(policy: &dyn Policy, cert: &Cert, usage: EncryptionUsage) -> String {
+    let valid_cert = match cert.with_policy(policy, None) {
+        Ok(valid_cert) => valid_cert,
+        Err(err) => return format!("not valid under policy: {err}"),
+    };
+    if let Err(err) = valid_cert.alive() {
+        return format!("expired: {err}");
+    }
+    if let RevocationStatus::Revoked(_) = valid_cert.revocation_status() {
+        return "revoked".to_string();
+    }
+    let encryption_keys = || valid_cert.keys().key_flags(usage.key_flags());
+    if encryption_keys().next().is_none() {
+        format!("no {usage} encryption subkey")
+    } else if encryption_keys().supported().next().is_none() {
+        format!("unsupported {usage} encryption algorithm")
+    } else if encryption_keys().supported().alive().next().is_none() {
+        format!("{usage} encryption subkeys expired")
+    } else {
+        format!("{usage} encryption subkeys revoked")
+    }
+}
+
 pub trait PrivateKey {
     fn unlock(&mut self, password: Option<&Password>) -> openpgp::Result<Box<dyn Decryptor>>;
 }
@@ -161,7 +473,7 @@ pub fn secret_key_store<'a, K>(
 where
     K: Iterator<Item = &'a Cert>,
 {
-    log::trace!("Searching secret keys for data-at-rest decryption…");
+    log::trace!("Searching secret keys for decryption…");
 
     let mut keys: HashMap<KeyID, Box<dyn PrivateKey>> = HashMap::new();
     let mut identities: HashMap<KeyID, Fingerprint> = HashMap::new();
@@ -170,16 +482,21 @@ where
         for ka in tsk
             .keys()
             .with_policy(policy, None)
-            .for_storage_encryption()
+            .key_flags(EncryptionUsage::Any.key_flags())
         {
             let id: KeyID = ka.key().fingerprint().into();
+            if keys.contains_key(&id) {
+                // the same key from overlapping keyrings
+                log::debug!("Skipping duplicate secret key {id}");
+                continue;
+            }
             let key = if let Ok(private_key) = ka.key().parts_as_secret() {
                 let encryption_status = if private_key.has_unencrypted_secret() {
                     "unencrypted"
                 } else {
                     "encrypted"
                 };
-                log::info!("Using {encryption_status} secret key {id} for data-at-rest decryption");
+                log::info!("Using {encryption_status} secret key {id} for decryption");
                 Box::new(LocalPrivateKey::new(private_key.clone()))
             } else {
                 log::warn!("Cert {id} does not contain secret keys");
@@ -193,7 +510,7 @@ where
     if keys.is_empty() {
         return Err(io::Error::new(
             io::ErrorKind::InvalidInput,
-            "Keyring does not contain storage encryption keys",
+            "Keyring does not contain encryption keys",
         ));
     }
 
@@ -203,18 +520,20 @@ where
 pub fn build_encryptor<'a, R, W: 'a + io::Write + Send + Sync>(
     recipients: R,
     output: W,
+    armor: bool,
+    symmetric_algo: SymmetricAlgorithm,
 ) -> io::Result<Message<'a>>
 where
     R: IntoIterator,
     R::Item: Into<Recipient<'a>>,
 {
-    log::info!(
-        "Setting up encryption with {algo}…",
-        algo = SymmetricAlgorithm::AES256
-    );
-    let message = Message::new(output);
-    let encryptor =
-        Encryptor2::for_recipients(message, recipients).symmetric_algo(SymmetricAlgorithm::AES256);
+    log::info!("Setting up encryption with {symmetric_algo}…");
+    let mut message = Message::new(output);
+    if armor {
+        log::info!("Using ASCII armor…");
+        message = Armorer::new(message).build().map_err(openpgp_error)?;
+    }
+    let encryptor = Encryptor2::for_recipients(message, recipients).symmetric_algo(symmetric_algo);
 
     // Encrypt the message.
     log::trace!("Starting encryption…");
@@ -251,15 +570,12 @@ impl DecryptionHelper for SecretKeyStore {
     {
         for (num, pkesk) in pkesks.iter().enumerate() {
             let keyid = pkesk.recipient();
+            if keyid.is_wildcard() {
+                continue; // anonymous recipients are tried with all keys below
+            }
             log::trace!("Trying to decrypt session key {num} for recipient {keyid}…");
             if let Some(pair) = self.secret_keys.get_mut(keyid) {
-                let mut dec = pair.unlock(self.password.as_ref())?;
-                let decryptor = dec.as_mut();
-                if pkesk
-                    .decrypt(decryptor, sym_algo)
-                    .map(|(algo, session_key)| decrypt(algo, &session_key))
-                    .unwrap_or(false)
-                {
+                if try_decrypt(pair, self.password.as_ref(), pkesk, sym_algo, &mut decrypt)? {
                     let fingerprint = self.key_identities.get_mut(keyid).unwrap();
                     let recipient = Some(fingerprint.clone());
                     log::trace!("Decrypted session key {num} for recipient {keyid}");
@@ -272,6 +588,30 @@ impl DecryptionHelper for SecretKeyStore {
                 );
             }
         }
+        // like gpg --try-all-secrets, try every secret key on session keys for
+        // anonymous (wildcard key id) or unknown recipients
+        for (num, pkesk) in pkesks.iter().enumerate() {
+            let keyid = pkesk.recipient();
+            if self.secret_keys.contains_key(keyid) {
+                continue; // already tried above
+            }
+            log::debug!("Trying all secret keys on session key {num} for recipient {keyid}…");
+            for (sk_keyid, pair) in self.secret_keys.iter_mut() {
+                match try_decrypt(pair, self.password.as_ref(), pkesk, sym_algo, &mut decrypt) {
+                    Ok(true) => {
+                        log::info!(
+                            "Decrypted session key {num} for recipient {keyid} using wildcard fallback with secret key {sk_keyid}"
+                        );
+                        return Ok(self.key_identities.get(sk_keyid).cloned());
+                    }
+                    Ok(false) => {}
+                    // a locked key of another recipient must not end the search
+                    Err(err) => {
+                        log::warn!("Skipping secret key {sk_keyid} in wildcard fallback: {err}")
+                    }
+                }
+            }
+        }
         let sk_keyids = self
             .secret_keys
             .keys()
@@ -284,6 +624,127 @@ impl DecryptionHelper for SecretKeyStore {
     }
 }
 
+/// Try to decrypt the session key of `pkesk` with the secret key of `pair`.
+fn try_decrypt<D>(
+    pair: &mut Box<dyn PrivateKey>,
+    password: Option<&Password>,
+    pkesk: &PKESK,
+    sym_algo: Option<SymmetricAlgorithm>,
+    decrypt: &mut D,
+) -> openpgp::Result<bool>
+where
+    D: FnMut(SymmetricAlgorithm, &SessionKey) -> bool,
+{
+    let mut dec = pair.unlock(password)?;
+    let decryptor = dec.as_mut();
+    Ok(pkesk
+        .decrypt(decryptor, sym_algo)
+        .map(|(algo, session_key)| decrypt(algo, &session_key))
+        .unwrap_or(false))
+}
+
+/// Recipient of a session key at the start of an encrypted message.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MessageRecipient {
+    /// Session key encrypted to the key with this key id, which is the
+    /// wildcard key id for anonymous recipients
+    PublicKey(KeyID),
+    /// Session key encrypted with a password
+    Password,
+}
+
+/// List the recipients of the session keys of the encrypted message `input`
+/// without decrypting it, reading only up to the encrypted data.
+pub fn message_recipients<'a, R: 'a + io::Read + Send + Sync>(
+    input: R,
+) -> io::Result<Vec<MessageRecipient>> {
+    let mut recipients = Vec::new();
+    let mut ppr = PacketParser::from_reader(input).map_err(openpgp_error)?;
+    while let PacketParserResult::Some(pp) = ppr {
+        match &pp.packet {
+            Packet::PKESK(pkesk) => {
+                recipients.push(MessageRecipient::PublicKey(pkesk.recipient().clone()))
+            }
+            Packet::SKESK(_) => recipients.push(MessageRecipient::Password),
+            packet => {
+                log::trace!("Stopping at {tag} packet", tag = packet.tag());
+                break;
+            }
+        }
+        ppr = pp.next().map_err(openpgp_error)?.1;
+    }
+    Ok(recipients)
+}
+
+/// Print the packet tree of the message in `input` to `output`, like
+/// `sq packet dump`. The encrypted data is only dumped if a secret key of
+/// `secret_key_store` decrypts it, literal data is skipped.
+pub fn dump_packets<'a, R: 'a + io::Read + Send + Sync>(
+    input: R,
+    mut secret_key_store: Option<SecretKeyStore>,
+    output: &mut dyn io::Write,
+) -> io::Result<()> {
+    let (mut pkesks, mut skesks) = (Vec::new(), Vec::new());
+    let mut ppr = PacketParser::from_reader(input).map_err(openpgp_error)?;
+    while let PacketParserResult::Some(mut pp) = ppr {
+        let indent = "    ".repeat(pp.recursion_depth() as usize);
+        writeln!(output, "{indent}{tag}", tag = pp.packet.tag())?;
+        match &pp.packet {
+            Packet::PKESK(pkesk) => {
+                writeln!(output, "{indent}  Recipient: {}", pkesk.recipient())?;
+                writeln!(output, "{indent}  Pk algo: {}", pkesk.pk_algo())?;
+                pkesks.push(pkesk.clone());
+            }
+            Packet::SKESK(skesk) => {
+                writeln!(output, "{indent}  Version: {}", skesk.version())?;
+                skesks.push(skesk.clone());
+            }
+            Packet::SEIP(seip) => writeln!(output, "{indent}  Version: {}", seip.version())?,
+            Packet::CompressedData(compressed) => {
+                writeln!(output, "{indent}  Algorithm: {}", compressed.algo())?
+            }
+            Packet::Literal(literal) => {
+                writeln!(output, "{indent}  Format: {:?}", literal.format())?;
+                if let Some(filename) = literal.filename() {
+                    let filename = String::from_utf8_lossy(filename);
+                    writeln!(output, "{indent}  Filename: {filename:?}")?;
+                }
+                if let Some(date) = literal.date() {
+                    let date = humantime::format_rfc3339_seconds(date);
+                    writeln!(output, "{indent}  Timestamp: {date}")?;
+                }
+            }
+            _ => {}
+        }
+        if let Packet::SEIP(_) = &pp.packet {
+            let decrypted = secret_key_store.as_mut().and_then(|store| {
+                store
+                    .decrypt(&pkesks, &skesks, None, |algo, session_key| {
+                        pp.decrypt(algo, session_key).is_ok()
+                    })
+                    .ok()
+                    .flatten()
+            });
+            match decrypted {
+                Some(fingerprint) => writeln!(output, "{indent}  Decrypted with {fingerprint}")?,
+                None => writeln!(output, "{indent}  Not decrypted")?,
+            }
+        }
+        ppr = pp.recurse().map_err(openpgp_error)?.1;
+    }
+    Ok(())
+}
+
+/// Key ids of all keys with secret key material in `keyring`.
+pub fn secret_key_ids<'a, K>(keyring: K) -> HashSet<KeyID>
+where
+    K: Iterator<Item = &'a Cert>,
+{
+    keyring
+        .flat_map(|tsk| tsk.keys().secret().map(|ka| ka.key().keyid()))
+        .collect()
+}
+
 pub fn read_password_fd(fd: i32) -> Option<Password> {
     log::debug!("Reading password from file descriptor {fd}…");
     let file = unsafe { File::from_raw_fd(fd) };
@@ -297,17 +758,450 @@ pub fn read_password_fd(fd: i32) -> Option<Password> {
         .ok()
 }
 
+/// The first I/O error of the ciphertext, which the decryptor may report
+/// as a failed decryption instead.
+type InputError = Arc<Mutex<Option<io::Error>>>;
+
+fn take_input_error(input_error: &InputError) -> Option<io::Error> {
+    input_error.lock().ok().and_then(|mut err| err.take())
+}
+
+/// Stashes the I/O errors of the ciphertext, to tell them apart from the
+/// errors of the decryptor.
+struct CiphertextReader<R> {
+    inner: R,
+    error: InputError,
+}
+
+impl<R: io::Read> io::Read for CiphertextReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf).map_err(|err| {
+            if err.kind() == io::ErrorKind::Interrupted {
+                return err;
+            }
+            let reported = io::Error::new(err.kind(), err.to_string());
+            if let Ok(mut stashed) = self.error.lock() {
+                stashed.get_or_insert(err);
+            }
+            reported
+        })
+    }
+}
+
+/// Reads the plaintext of a [`build_decryptor`] message, a failed
+/// decryption or authentication is reported as [`CryptoError`].
+pub struct PlaintextReader<'a> {
+    decryptor: stream::Decryptor<'a, SecretKeyStore>,
+    input_error: InputError,
+}
+
+impl PlaintextReader<'_> {
+    pub fn into_helper(self) -> SecretKeyStore {
+        self.decryptor.into_helper()
+    }
+}
+
+impl io::Read for PlaintextReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.decryptor.read(buf).map_err(|err| {
+            if err.kind() == io::ErrorKind::Interrupted {
+                return err;
+            }
+            take_input_error(&self.input_error)
+                .unwrap_or_else(|| io::Error::other(CryptoError(err.to_string())))
+        })
+    }
+}
+
 pub fn build_decryptor<'a, R: 'a + io::Read + Send + Sync>(
     secret_key_store: SecretKeyStore,
     policy: &'a dyn Policy,
     input: R,
-) -> openpgp::Result<stream::Decryptor<'a, SecretKeyStore>> {
+) -> openpgp::Result<PlaintextReader<'a>> {
     log::trace!("Setting up decryption…");
-    let decryptor = DecryptorBuilder::from_reader(input)?
-        .buffer_size(DEFAULT_BUF_SIZE) // we do not verify, no need for a larger buffer
-        .mapping(false)
-        .with_policy(policy, None, secret_key_store)
+    let input_error = InputError::default();
+    let input = CiphertextReader {
+        inner: input,
+        error: Arc::clone(&input_error),
+    };
+    let decryptor = DecryptorBuilder::from_reader(input)
+        .and_then(|builder| {
+            builder
+                .buffer_size(DEFAULT_BUF_SIZE) // we do not verify, no need for a larger buffer
+                .mapping(false)
+                .with_policy(policy, None, secret_key_store)
+        })
+        .map_err(|err| match take_input_error(&input_error) {
+            Some(input_err) => input_err.into(),
+            None => err,
+        })
         .context("Decryption failed")?;
 
-    Ok(decryptor)
+    Ok(PlaintextReader {
+        decryptor,
+        input_error,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use openpgp::cert::CertBuilder;
+
+    #[test]
+    fn select_recipients_by_fingerprint() {
+        let certs: Vec<_> = ["alice", "bob"]
+            .into_iter()
+            .map(|userid| {
+                CertBuilder::general_purpose(None, Some(userid))
+                    .generate()
+                    .expect("cannot generate certificate")
+                    .0
+            })
+            .collect();
+        let bob = certs[1].fingerprint().to_hex();
+
+        let all = select_recipients(certs.iter(), &[]).expect("cannot select all");
+        assert_eq!(all.len(), 2);
+
+        // full fingerprint and key id suffix select the same certificate once
+        let fingerprints = [bob.clone(), bob[bob.len() - 16..].to_owned()];
+        let selected = select_recipients(certs.iter(), &fingerprints).expect("cannot select bob");
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].fingerprint(), certs[1].fingerprint());
+
+        let err = select_recipients(certs.iter(), &["0123456789ABCDEF".to_owned()])
+            .expect_err("selected unknown fingerprint");
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn negotiate_strongest_common_algo() {
+        let policy = &StandardPolicy::new();
+        let modern = [SymmetricAlgorithm::AES128, SymmetricAlgorithm::AES256];
+        let constrained = [SymmetricAlgorithm::AES128, SymmetricAlgorithm::TripleDES];
+        let legacy = [SymmetricAlgorithm::TripleDES];
+
+        let preferences = [Some(&modern[..]), None];
+        assert_eq!(
+            strongest_common_algo(policy, preferences.into_iter()),
+            Some(SymmetricAlgorithm::AES256)
+        );
+        let preferences = [Some(&modern[..]), Some(&constrained[..])];
+        assert_eq!(
+            strongest_common_algo(policy, preferences.into_iter()),
+            Some(SymmetricAlgorithm::AES128)
+        );
+        let preferences = [Some(&modern[..]), Some(&legacy[..])];
+        assert_eq!(strongest_common_algo(policy, preferences.into_iter()), None);
+    }
+
+    #[test]
+    fn secret_key_store_deduplicates_keys() {
+        let policy = &StandardPolicy::new();
+        let (alice, _) = CertBuilder::general_purpose(None, Some("alice"))
+            .generate()
+            .expect("cannot generate certificate");
+        let (bob, _) = CertBuilder::general_purpose(None, Some("bob"))
+            .generate()
+            .expect("cannot generate certificate");
+
+        // alice is in both keyrings
+        let keyrings = [vec![alice.clone()], vec![alice, bob]];
+        let store = secret_key_store(policy, keyrings.iter().flatten(), None)
+            .expect("cannot build secret key store");
+        assert_eq!(store.secret_keys.len(), 2);
+        assert_eq!(store.key_identities.len(), 2);
+    }
+
+    #[test]
+    fn decrypt_anonymous_recipient() {
+        let policy = &StandardPolicy::new();
+        let (cert, _) = CertBuilder::general_purpose(None, Some("alice"))
+            .generate()
+            .expect("cannot generate certificate");
+        let recipients: Vec<Recipient> = cert
+            .keys()
+            .with_policy(policy, None)
+            .for_storage_encryption()
+            .map(|key| Recipient::new(KeyID::wildcard(), key.key()))
+            .collect();
+
+        let mut ciphertext = Vec::new();
+        let mut message = build_encryptor(
+            recipients,
+            &mut ciphertext,
+            false,
+            SymmetricAlgorithm::AES256,
+        )
+        .expect("cannot build encryptor");
+        io::Write::write_all(&mut message, b"anonymous").expect("cannot encrypt");
+        message.finalize().expect("cannot finalize encryption");
+
+        let store = secret_key_store(policy, std::iter::once(&cert), None)
+            .expect("cannot build secret key store");
+        let mut decryptor =
+            build_decryptor(store, policy, &ciphertext[..]).expect("cannot decrypt");
+        let mut plaintext = Vec::new();
+        io::Read::read_to_end(&mut decryptor, &mut plaintext).expect("cannot read plaintext");
+        assert_eq!(plaintext, b"anonymous");
+    }
+
+    #[test]
+    fn wildcard_fallback_skips_locked_keys() {
+        let policy = &StandardPolicy::new();
+        let (locked, _) = CertBuilder::general_purpose(None, Some("alice"))
+            .set_password(Some("secret".into()))
+            .generate()
+            .expect("cannot generate certificate");
+        let (cert, _) = CertBuilder::general_purpose(None, Some("bob"))
+            .generate()
+            .expect("cannot generate certificate");
+        let recipients: Vec<Recipient> = cert
+            .keys()
+            .with_policy(policy, None)
+            .for_storage_encryption()
+            .map(|key| Recipient::new(KeyID::wildcard(), key.key()))
+            .collect();
+
+        let mut ciphertext = Vec::new();
+        let mut message = build_encryptor(
+            recipients,
+            &mut ciphertext,
+            false,
+            SymmetricAlgorithm::AES256,
+        )
+        .expect("cannot build encryptor");
+        io::Write::write_all(&mut message, b"anonymous").expect("cannot encrypt");
+        message.finalize().expect("cannot finalize encryption");
+
+        // without a password the key of alice cannot be unlocked
+        let store = secret_key_store(policy, [&locked, &cert].into_iter(), None)
+            .expect("cannot build secret key store");
+        let mut decryptor =
+            build_decryptor(store, policy, &ciphertext[..]).expect("cannot decrypt");
+        let mut plaintext = Vec::new();
+        io::Read::read_to_end(&mut decryptor, &mut plaintext).expect("cannot read plaintext");
+        assert_eq!(plaintext, b"anonymous");
+    }
+
+    #[test]
+    fn decrypt_armored_message() {
+        let policy = &StandardPolicy::new();
+        let (cert, _) = CertBuilder::general_purpose(None, Some("alice"))
+            .generate()
+            .expect("cannot generate certificate");
+        let recipients: Vec<Recipient> = cert
+            .keys()
+            .with_policy(policy, None)
+            .for_storage_encryption()
+            .map(|key| key.key().into())
+            .collect();
+
+        let mut ciphertext = Vec::new();
+        let mut message = build_encryptor(
+            recipients,
+            &mut ciphertext,
+            true,
+            SymmetricAlgorithm::AES256,
+        )
+        .expect("cannot build encryptor");
+        io::Write::write_all(&mut message, b"armored").expect("cannot encrypt");
+        message.finalize().expect("cannot finalize encryption");
+        assert!(ciphertext.starts_with(b"-----BEGIN PGP MESSAGE-----"));
+
+        let listed = message_recipients(&ciphertext[..]).expect("cannot list recipients");
+        assert_eq!(listed.len(), 1);
+
+        let store = secret_key_store(policy, std::iter::once(&cert), None)
+            .expect("cannot build secret key store");
+        let mut decryptor =
+            build_decryptor(store, policy, &ciphertext[..]).expect("cannot decrypt");
+        let mut plaintext = Vec::new();
+        io::Read::read_to_end(&mut decryptor, &mut plaintext).expect("cannot read plaintext");
+        assert_eq!(plaintext, b"armored");
+    }
+
+    #[test]
+    fn decryption_failures_are_crypto_errors() {
+        let policy = &StandardPolicy::new();
+        let (alice, _) = CertBuilder::general_purpose(None, Some("alice"))
+            .generate()
+            .expect("cannot generate certificate");
+        let (bob, _) = CertBuilder::general_purpose(None, Some("bob"))
+            .generate()
+            .expect("cannot generate certificate");
+        let recipients: Vec<Recipient> = alice
+            .keys()
+            .with_policy(policy, None)
+            .for_storage_encryption()
+            .map(|key| key.key().into())
+            .collect();
+        let mut ciphertext = Vec::new();
+        let mut message = build_encryptor(
+            recipients,
+            &mut ciphertext,
+            false,
+            SymmetricAlgorithm::AES256,
+        )
+        .expect("cannot build encryptor");
+        io::Write::write_all(&mut message, &[0; 1 << 20]).expect("cannot encrypt");
+        message.finalize().expect("cannot finalize encryption");
+
+        let wrong_key = decrypt(policy, &bob, &ciphertext[..]).expect_err("decrypted with bob");
+        assert!(is_crypto_error(&wrong_key), "{wrong_key:?}");
+
+        let mut tampered = ciphertext.clone();
+        let middle = tampered.len() / 2;
+        tampered[middle] ^= 0xff;
+        let tampered = decrypt(policy, &alice, &tampered[..]).expect_err("decrypted tampered");
+        assert!(is_crypto_error(&tampered), "{tampered:?}");
+
+        // the ciphertext breaks off with an error of the underlying reader
+        for cut in [10, middle] {
+            let broken = io::Read::chain(&ciphertext[..cut], BrokenReader);
+            let broken = decrypt(policy, &alice, broken).expect_err("decrypted broken input");
+            assert!(!is_crypto_error(&broken), "{broken:?}");
+            assert_eq!(broken.kind(), io::ErrorKind::ConnectionReset);
+        }
+
+        let plaintext = decrypt(policy, &alice, &ciphertext[..]).expect("cannot decrypt");
+        assert_eq!(plaintext.len(), 1 << 20);
+
+        // malformed input outside of decryption, e.g., a keyring, is no crypto error
+        let malformed = openpgp_error(anyhow::anyhow!("Malformed packet"));
+        assert!(!is_crypto_error(&malformed), "{malformed:?}");
+    }
+
+    fn decrypt<R: io::Read + Send + Sync>(
+        policy: &dyn Policy,
+        cert: &Cert,
+        input: R,
+    ) -> io::Result<Vec<u8>> {
+        let store = secret_key_store(policy, std::iter::once(cert), None)?;
+        let mut decryptor = build_decryptor(store, policy, input).map_err(decrypt_error)?;
+        let mut plaintext = Vec::new();
+        io::Read::read_to_end(&mut decryptor, &mut plaintext)?;
+        Ok(plaintext)
+    }
+
+    struct BrokenReader;
+
+    impl io::Read for BrokenReader {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::ConnectionReset.into())
+        }
+    }
+
+    #[test]
+    fn list_message_recipients() {
+        let policy = &StandardPolicy::new();
+        let (cert, _) = CertBuilder::general_purpose(None, Some("alice"))
+            .generate()
+            .expect("cannot generate certificate");
+        let recipients: Vec<Recipient> = cert
+            .keys()
+            .with_policy(policy, None)
+            .for_storage_encryption()
+            .map(|key| key.key().into())
+            .collect();
+        let mut ciphertext = Vec::new();
+        let mut message = build_encryptor(
+            recipients,
+            &mut ciphertext,
+            false,
+            SymmetricAlgorithm::AES256,
+        )
+        .expect("cannot build encryptor");
+        io::Write::write_all(&mut message, b"inspect").expect("cannot encrypt");
+        message.finalize().expect("cannot finalize encryption");
+
+        let listed = message_recipients(&ciphertext[..]).expect("cannot list recipients");
+        let subkey = cert
+            .keys()
+            .with_policy(policy, None)
+            .for_storage_encryption()
+            .next()
+            .expect("no encryption subkey")
+            .keyid();
+        assert_eq!(listed, vec![MessageRecipient::PublicKey(subkey.clone())]);
+
+        assert!(secret_key_ids(std::iter::once(&cert)).contains(&subkey));
+        let public = cert.clone().strip_secret_key_material();
+        assert!(secret_key_ids(std::iter::once(&public)).is_empty());
+    }
+
+    #[test]
+    fn dump_message_packets() {
+        let policy = &StandardPolicy::new();
+        let (cert, _) = CertBuilder::general_purpose(None, Some("alice"))
+            .generate()
+            .expect("cannot generate certificate");
+        let recipients: Vec<Recipient> = cert
+            .keys()
+            .with_policy(policy, None)
+            .for_storage_encryption()
+            .map(|key| key.key().into())
+            .collect();
+        let mut ciphertext = Vec::new();
+        let mut message = build_encryptor(
+            recipients,
+            &mut ciphertext,
+            false,
+            SymmetricAlgorithm::AES256,
+        )
+        .expect("cannot build encryptor");
+        io::Write::write_all(&mut message, b"dump").expect("cannot encrypt");
+        message.finalize().expect("cannot finalize encryption");
+
+        let mut dump = Vec::new();
+        dump_packets(&ciphertext[..], None, &mut dump).expect("cannot dump packets");
+        let dump = String::from_utf8(dump).expect("dump is not UTF-8");
+        assert!(dump.starts_with("Public-Key Encrypted Session Key Packet\n"));
+        assert!(dump.contains("  Not decrypted\n"));
+        assert!(!dump.contains("Literal Data Packet"));
+
+        let store = secret_key_store(policy, std::iter::once(&cert), None)
+            .expect("cannot build secret key store");
+        let mut dump = Vec::new();
+        dump_packets(&ciphertext[..], Some(store), &mut dump).expect("cannot dump packets");
+        let dump = String::from_utf8(dump).expect("dump is not UTF-8");
+        assert!(dump.contains(&format!("  Decrypted with {}\n", cert.fingerprint())));
+        assert!(dump.contains("    Literal Data Packet\n      Format: Binary\n"));
+    }
+
+    #[test]
+    fn relaxed_policy_accepts_allowed_algorithms() {
+        let sha1: AllowedAlgorithm = "sha1".parse().expect("cannot parse sha1");
+        assert_eq!(sha1, AllowedAlgorithm::Hash(HashAlgorithm::SHA1));
+        let cast5: AllowedAlgorithm = "CAST5".parse().expect("cannot parse cast5");
+        assert_eq!(
+            cast5,
+            AllowedAlgorithm::Symmetric(SymmetricAlgorithm::CAST5)
+        );
+        let rsa: AllowedAlgorithm = "rsa1024".parse().expect("cannot parse rsa1024");
+        assert_eq!(
+            rsa,
+            AllowedAlgorithm::Asymmetric(AsymmetricAlgorithm::RSA1024)
+        );
+        assert!("rot13".parse::<AllowedAlgorithm>().is_err());
+
+        // TripleDES is rejected since February 2017
+        let standard = build_policy(None, &[]);
+        assert!(standard
+            .symmetric_algorithm(SymmetricAlgorithm::TripleDES)
+            .is_err());
+        let relaxed = build_policy(
+            None,
+            &[AllowedAlgorithm::Symmetric(SymmetricAlgorithm::TripleDES)],
+        );
+        relaxed
+            .symmetric_algorithm(SymmetricAlgorithm::TripleDES)
+            .expect("TripleDES should be accepted");
+        let past = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_451_606_400);
+        build_policy(Some(past), &[])
+            .symmetric_algorithm(SymmetricAlgorithm::TripleDES)
+            .expect("TripleDES should be accepted in 2016");
+    }
 }
@@ -21,18 +21,25 @@ use cli::CliResult;
 use cli::Command;
 pub use config::Config;
 use env_logger::Builder;
+use log::LevelFilter;
 use std::env;
 use std::fs;
+use std::io;
 use std::path::Path;
 use std::path::PathBuf;
 
-use crate::cli::DEFAULT_CONFIG_PATH;
+use crate::cli::parse::{compose_prefix, read_prefix};
+use crate::cli::{DEFAULT_CONFIG_PATH, DEFAULT_SPOOL_PATH, S3_MIN_PART_SIZE, STDIN_PREFIX};
 use crate::command::backup;
+use crate::command::config_check;
 use crate::command::freeze;
+use crate::command::rechunk;
 use crate::command::restore;
 use crate::command::thaw;
+use crate::command::version;
 use crate::config::ConfigFile;
 use crate::config::ParseConfigError;
+use crate::core::backup_id::ambiguous_prefix_segment;
 
 pub fn on_clap_error(err: clap::error::Error) -> Cli {
     err.print().expect("Error writing error");
@@ -63,11 +70,23 @@ pub fn base_directory_profile(_subcommand: &Command) -> Result<xdg::BaseDirector
     }
 }
 
-pub fn setup(debug: u8, quiet: bool) -> Result<(), CliError> {
+/// The system spool for root, otherwise the per-user spool in the XDG state
+/// directory, which is created if needed.
+pub fn default_spool(base_directories: &xdg::BaseDirectories) -> io::Result<PathBuf> {
+    if nix::unistd::geteuid().is_root() {
+        return Ok(PathBuf::from(DEFAULT_SPOOL_PATH));
+    }
+    base_directories.create_state_directory("spool")
+}
+
+pub fn setup(log_level: Option<LevelFilter>, debug: u8, quiet: bool) -> Result<(), CliError> {
     // setup logger using environment:
-    // prioritize command-line args over environment variables, and quiet over debug
+    // prioritize command-line args over environment variables,
+    // and --log-level over quiet over debug
     let env = env_logger::Env::new().write_style("CRYOPHILE_LOG_STYLE");
-    let env = if quiet {
+    let env = if let Some(level) = log_level {
+        env.filter_or("", level.as_str())
+    } else if quiet {
         env.filter_or("", "error")
     } else {
         match debug {
@@ -105,45 +124,175 @@ pub fn read_config(path: &Path) -> Result<ConfigFile, CliError> {
         Err(err) => match err {
             ParseConfigError::IoError(e) => {
                 log::debug!("Cannot read config from {path:?}: {e}");
-                let path = PathBuf::from(DEFAULT_CONFIG_PATH);
-                Ok(ConfigFile::new(path.as_path()).unwrap_or_default())
+                Ok(ConfigFile::default())
             }
             _ => Err(err.into()),
         },
     }
 }
 
-pub fn run(cli: Cli) -> Result<CliResult, CliError> {
+/// Replace the prefix `-` of `command` with the first line of `stdin`.
+pub fn apply_stdin_prefix(command: &mut Command, stdin: impl io::BufRead) -> Result<(), CliError> {
+    let Some(prefix) = command.prefix_mut() else {
+        return Ok(());
+    };
+    if prefix.as_deref() != Some(Path::new(STDIN_PREFIX)) {
+        return Ok(());
+    }
+    let stdin_prefix = read_prefix(stdin).map_err(|err| {
+        CliError::PrefixError(format!("prefix from stdin: {err}"), CliResult::Usage)
+    })?;
+    log::debug!("Using prefix {stdin_prefix:?} from stdin");
+    *prefix = Some(stdin_prefix);
+    Ok(())
+}
+
+/// Prepend the default prefix of the vault of `command` to its prefix, or
+/// use the default prefix alone if the command has none.
+pub fn apply_default_prefix(command: &mut Command, file: &ConfigFile) -> Result<(), CliError> {
+    let Some(vault) = command.vault() else {
+        return Ok(());
+    };
+    let Some(default_prefix) = file
+        .vault
+        .iter()
+        .find(|v| v.id == vault)
+        .and_then(|v| v.default_prefix.as_deref())
+    else {
+        return Ok(());
+    };
+    let Some(prefix) = command.prefix_mut() else {
+        return Ok(());
+    };
+    let composed = compose_prefix(default_prefix, prefix.as_deref()).map_err(|err| {
+        CliError::PrefixError(
+            format!("default prefix {default_prefix:?} of vault {vault}: {err}"),
+            CliResult::Usage,
+        )
+    })?;
+    log::debug!("Using prefix {composed:?} with default prefix of vault {vault}");
+    *prefix = Some(composed);
+    Ok(())
+}
+
+pub fn check_prefix(prefix: &Path, allow_ambiguous: bool) -> Result<(), CliError> {
+    let Some(segment) = prefix.to_str().and_then(ambiguous_prefix_segment) else {
+        return Ok(());
+    };
+    if allow_ambiguous {
+        log::warn!(
+            "Using ambiguous prefix {prefix:?} with vault uuid or backup ulid segment {segment:?}"
+        );
+        return Ok(());
+    }
+    Err(CliError::PrefixError(
+        format!(
+            "prefix {prefix:?} contains segment {segment:?} that looks like a vault uuid or backup ulid, use --allow-ambiguous-prefix to use it anyway"
+        ),
+        CliResult::Usage,
+    ))
+}
+
+/// Freeze uploads chunks as S3 multipart parts, which must be at least
+/// 5 MiB except for the last one.
+pub fn check_chunk_size(size: usize, allow_small: bool) -> Result<(), CliError> {
+    if size >= S3_MIN_PART_SIZE {
+        return Ok(());
+    }
+    if allow_small {
+        log::warn!(
+            "Using chunk size {size} below the S3 minimum part size {S3_MIN_PART_SIZE}, \
+             freeze cannot upload the chunks as multipart parts"
+        );
+        return Ok(());
+    }
+    Err(CliError::ChunkSizeError(
+        format!(
+            "chunk size {size} is below the S3 minimum part size {S3_MIN_PART_SIZE}, use --allow-small-chunks to use it anyway"
+        ),
+        CliResult::Usage,
+    ))
+}
+
+pub fn check_spool(spool: &Path) -> Result<(), CliError> {
+    if spool.is_symlink() {
+        return Err(CliError::SpoolError(
+            format!("spool {spool:?} cannot be a symlink"),
+            CliResult::Usage,
+        ));
+    }
+    if !spool.is_dir() {
+        return Err(CliError::SpoolError(
+            format!("spool {spool:?} must be a directory"),
+            CliResult::Usage,
+        ));
+    }
+    fs::read_dir(spool)?; // PermissionDenied, NotADirectory, NotFound, etc.
+    log::debug!("Using spool directory {spool:?}");
+    Ok(())
+}
+
+pub fn run(mut cli: Cli) -> Result<CliResult, CliError> {
     log_versions();
 
+    // printing the version needs neither a spool nor a config
+    if let Command::Version(version) = &cli.command {
+        version::perform_version(version)?;
+        return Ok(CliResult::Ok);
+    }
+
     let base_directories = base_directory_profile(&cli.command)?;
 
     // setup base directory
     let config_home_path: PathBuf = core::path::use_base_dir(&base_directories)?;
     log::debug!("Using config home directory {config_home_path:?}");
 
+    if cli.spool.is_none() {
+        cli.spool = Some(default_spool(&base_directories)?);
+    }
+
     // read config file
     let config_file = if cli.config != PathBuf::from(DEFAULT_CONFIG_PATH) {
         // always fail if --config is given
         ConfigFile::new(cli.config.as_path())?
     } else {
         // do not fail if we cannot read standard config locations, unless there is a config syntax error
+        // layer the user config over the system config
+        let system_config = read_config(Path::new(DEFAULT_CONFIG_PATH))?;
         let user_config_path = base_directories.get_config_file("cryophile.toml");
-        read_config(&user_config_path)?
+        system_config.merge(read_config(&user_config_path)?)
     };
 
+    apply_stdin_prefix(&mut cli.command, io::stdin().lock())?;
+    apply_default_prefix(&mut cli.command, &config_file)?;
+    if let Some(prefix) = cli.command.prefix() {
+        check_prefix(prefix, cli.allow_ambiguous_prefix)?;
+    }
+    if let Some((size, allow_small)) = cli.command.chunk_size() {
+        check_chunk_size(size, allow_small)?;
+    }
+
     let config = Config::new(base_directories, cli, config_file);
 
-    let spool = &config.cli.spool;
-    fs::read_dir(spool)?; // PermissionDenied, NotADirectory, NotFound, etc.
-    log::debug!("Using spool directory {spool:?}");
+    // checking the configuration does not need a spool
+    if !matches!(config.cli.command, Command::ConfigCheck(_)) {
+        check_spool(config.spool())?;
+        if let Some(freeze_spool) = config.freeze_spool() {
+            check_spool(freeze_spool)?;
+        }
+    }
 
     // perform requested command
     match &config.cli.command {
         Command::Backup(backup) => backup::perform_backup(&config, backup)?,
         Command::Freeze(freeze) => freeze::perform_freeze(&config, freeze)?,
         Command::Restore(restore) => restore::perform_restore(&config, restore)?,
+        Command::Rechunk(rechunk) => rechunk::perform_rechunk(&config, rechunk)?,
         Command::Thaw(thaw) => thaw::perform_thaw(&config, thaw)?,
+        Command::ConfigCheck(config_check) => {
+            config_check::perform_config_check(&config, config_check)?
+        }
+        Command::Version(version) => version::perform_version(version)?,
     };
     Ok(CliResult::Ok)
 }
@@ -15,7 +15,7 @@ use cryophile::{
 
 fn main() -> CliResult {
     let cli = Cli::try_parse().unwrap_or_else(on_clap_error);
-    cryophile::setup(cli.debug, cli.quiet)
+    cryophile::setup(cli.log_level, cli.debug, cli.quiet)
         .and_then(|_| cryophile::run(cli))
         .map_err(Into::<CliResult>::into)
         .unwrap_or_else(std::convert::identity) // returns contained CliResult value from `Ok` or `Err`
@@ -7,11 +7,38 @@
 // This file may not be copied, modified, or distributed except according
 // to those terms.
 
-use cryophile::core::Split;
+use aws_sdk_s3::config::http::{HttpRequest, HttpResponse};
+use aws_sdk_s3::config::{BehaviorVersion, Credentials, HttpClient, Region, RuntimeComponents};
+use aws_sdk_s3::primitives::SdkBody;
+use aws_smithy_runtime_api::client::http::{
+    HttpConnector, HttpConnectorFuture, HttpConnectorSettings, SharedHttpConnector,
+};
+use clap::Parser;
+use cryophile::cli::{Cli, Command};
+use cryophile::command::backup::perform_backup;
+use cryophile::command::rechunk::perform_rechunk;
+use cryophile::command::restore::perform_restore;
+use cryophile::config::ConfigFile;
+use cryophile::core::backup_id::BackupId;
+use cryophile::core::cat::Cat;
+use cryophile::core::watch::{Watch, WatchConfig, WatchMessage};
+use cryophile::core::{AsyncSplit, Split, SyncPolicy};
+use cryophile::Config;
+use notify::RecursiveMode;
+use sequoia_openpgp::cert::CertBuilder;
+use sequoia_openpgp::serialize::Serialize;
+use std::collections::HashMap;
 use std::fs::{self, File};
 use std::io::{self, IoSlice, Read, Write};
-use std::path::PathBuf;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
+use tokio::io::AsyncWriteExt;
 
 #[test]
 fn test_split_write() {
@@ -110,3 +137,2280 @@ fn test_copy_to_split() {
     let n = io::copy(&mut slice, &mut splitter).expect("IO error");
     assert_eq!(splitter.written(), n);
 }
+
+#[tokio::test]
+async fn test_async_split() {
+    let tmp_dir = TempDir::new().unwrap();
+    let tmp_path = PathBuf::from(tmp_dir.path());
+    let out_path = tmp_path.join("out");
+    let _ = fs::create_dir(&out_path);
+    let mut splitter = AsyncSplit::new(Split::new(&tmp_path, &out_path, "chunk", 3));
+
+    let input: Vec<u8> = (0..=255u8).cycle().take(100).collect();
+    for buf in input.chunks(7) {
+        splitter
+            .write_all(buf)
+            .await
+            .expect("AsyncSplit::write_all");
+    }
+    splitter.shutdown().await.expect("AsyncSplit::shutdown");
+    splitter
+        .write_all(b"late")
+        .await
+        .expect_err("AsyncSplit accepted a write after shutdown");
+
+    let mut output = Vec::new();
+    for i in 1..=34 {
+        File::open(tmp_path.join(format!("chunk.{i}"))).expect_err("chunk is still incoming");
+        let chunk = fs::read(out_path.join(format!("chunk.{i}"))).expect("missing outgoing chunk");
+        assert!(chunk.len() <= 3);
+        output.extend(chunk);
+    }
+    assert!(!out_path.join("chunk.35").exists());
+    assert_eq!(output, input);
+}
+
+#[test]
+fn test_split_write_across_boundaries() {
+    let tmp_dir = TempDir::new().unwrap();
+    let tmp_path = PathBuf::from(tmp_dir.path());
+    let out_path = tmp_path.join("out");
+    let _ = fs::create_dir(&out_path);
+    let mut splitter = Split::new(&tmp_path, &out_path, "chunk", 3);
+
+    // buffers ending before, on and after chunk boundaries, and buffers
+    // spanning several chunks at once
+    let input: Vec<u8> = (0..=255u8).cycle().take(100).collect();
+    let mut rest = input.as_slice();
+    for len in [2, 1, 7, 3, 0, 11, 1, 4].into_iter().cycle() {
+        if rest.is_empty() {
+            break;
+        }
+        let (buf, tail) = rest.split_at(len.min(rest.len()));
+        splitter.write_all(buf).expect("Split::write_all");
+        rest = tail;
+    }
+    assert_eq!(splitter.written(), input.len() as u64);
+    drop(splitter);
+
+    let mut output = Vec::new();
+    for i in 1..=input.len().div_ceil(3) {
+        let mut chunk =
+            File::open(out_path.join(format!("chunk.{i}"))).expect("failed to open out file");
+        chunk
+            .read_to_end(&mut output)
+            .expect("failed to read out file");
+    }
+    assert_eq!(output, input);
+}
+
+#[test]
+fn test_split_write_full_chunk_then_byte() {
+    let tmp_dir = TempDir::new().unwrap();
+    let tmp_path = PathBuf::from(tmp_dir.path());
+    let out_path = tmp_path.join("out");
+    let _ = fs::create_dir(&out_path);
+    let mut splitter = Split::new(&tmp_path, &out_path, "chunk", 4);
+
+    assert_eq!(splitter.write(b"0123").expect("Split::write"), 4);
+    assert_eq!(splitter.write(b"4").expect("Split::write"), 1);
+    drop(splitter);
+
+    let chunk_1 = fs::read(out_path.join("chunk.1")).expect("failed to read chunk.1");
+    let chunk_2 = fs::read(out_path.join("chunk.2")).expect("failed to read chunk.2");
+    assert_eq!(chunk_1, b"0123");
+    assert_eq!(chunk_2, b"4");
+}
+
+#[test]
+fn test_split_max_chunks() {
+    let tmp_dir = TempDir::new().unwrap();
+    let tmp_path = PathBuf::from(tmp_dir.path());
+    let out_path = tmp_path.join("out");
+    let _ = fs::create_dir(&out_path);
+    let mut splitter = Split::new(&tmp_path, &out_path, "chunk", 3).with_max_chunks(2);
+
+    let err = splitter
+        .write_all(b"0123456789")
+        .expect_err("Split::write_all exceeded maximum chunks");
+    assert_eq!(err.kind(), io::ErrorKind::Other);
+    assert_eq!(splitter.written(), 6);
+    drop(splitter);
+
+    File::open(out_path.join("chunk.2")).expect("failed to open out file");
+    File::open(tmp_path.join("chunk.3")).expect_err("found extra chunk file");
+    File::open(out_path.join("chunk.3")).expect_err("found extra chunk file");
+}
+
+#[test]
+fn test_split_sync_policy() {
+    for sync_policy in [SyncPolicy::Data, SyncPolicy::Full, SyncPolicy::None] {
+        let tmp_dir = TempDir::new().unwrap();
+        let tmp_path = PathBuf::from(tmp_dir.path());
+        let out_path = tmp_path.join("out");
+        let _ = fs::create_dir(&out_path);
+        let mut splitter =
+            Split::new(&tmp_path, &out_path, "chunk", 4).with_sync_policy(sync_policy);
+
+        splitter.write_all(b"012345").expect("Split::write_all");
+        drop(splitter);
+
+        let chunk_1 = fs::read(out_path.join("chunk.1")).expect("failed to read chunk.1");
+        let chunk_2 = fs::read(out_path.join("chunk.2")).expect("failed to read chunk.2");
+        assert_eq!(chunk_1, b"0123", "{sync_policy:?}");
+        assert_eq!(chunk_2, b"45", "{sync_policy:?}");
+    }
+}
+
+#[test]
+fn test_failed_backup_leaves_no_chunks() {
+    let tmp_dir = TempDir::new().unwrap();
+    let (spool, keyring) = spool_fixture(&tmp_dir);
+    let input = tmp_dir.path().join("input");
+    fs::write(&input, vec![0x55; 4096]).unwrap();
+
+    // the encrypted stream needs more than one chunk, so finalizing fails
+    let cli = Cli::try_parse_from([
+        "cryophile",
+        "--spool",
+        spool.to_str().unwrap(),
+        "backup",
+        "--keyring",
+        keyring.to_str().unwrap(),
+        "--vault",
+        "797daf41-ba2c-440e-a56a-d0a190403a0b",
+        "--ulid",
+        "01J00000000000000000000611",
+        "--input",
+        input.to_str().unwrap(),
+        "--compression",
+        "none",
+        "--size",
+        "512",
+        "--max-chunks",
+        "1",
+        "--keep-failed",
+        "0",
+    ])
+    .expect("cannot parse command line");
+    let config = cli_config(cli);
+
+    perform_backup(&config, backup_command(&config)).expect_err("backup exceeded maximum chunks");
+
+    for entry in walkdir::WalkDir::new(&spool) {
+        let entry = entry.unwrap();
+        assert!(
+            !entry.file_type().is_file(),
+            "found chunk {path:?} of failed backup",
+            path = entry.path()
+        );
+        assert!(
+            !entry.path().ends_with("01J00000000000000000000611"),
+            "found directory {path:?} of failed backup",
+            path = entry.path()
+        );
+    }
+}
+
+#[test]
+fn test_failed_backup_kept() {
+    let tmp_dir = TempDir::new().unwrap();
+    let (spool, keyring) = spool_fixture(&tmp_dir);
+    let input = tmp_dir.path().join("input");
+    fs::write(&input, vec![0x55; 4096]).unwrap();
+
+    let vault = "797daf41-ba2c-440e-a56a-d0a190403a0b";
+    let ulids = [
+        "01J00000000000000000000672",
+        "01J00000000000000000000673",
+        "01J00000000000000000000674",
+    ];
+    for ulid in ulids {
+        let cli = Cli::try_parse_from([
+            "cryophile",
+            "--spool",
+            spool.to_str().unwrap(),
+            "backup",
+            "--keyring",
+            keyring.to_str().unwrap(),
+            "--vault",
+            vault,
+            "--ulid",
+            ulid,
+            "--input",
+            input.to_str().unwrap(),
+            "--compression",
+            "none",
+            "--size",
+            "512",
+            "--max-chunks",
+            "1",
+            "--keep-failed",
+            "2",
+        ])
+        .expect("cannot parse command line");
+        let config = cli_config(cli);
+        perform_backup(&config, backup_command(&config))
+            .expect_err("backup exceeded maximum chunks");
+        assert!(!spool.join("backup").join(vault).join(ulid).exists());
+        assert!(!spool.join("freeze").join(vault).join(ulid).exists());
+    }
+
+    // only the last two failed backups are kept, with their chunks
+    let mut failed = fs::read_dir(spool.join(".failed"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect::<Vec<_>>();
+    failed.sort();
+    assert_eq!(failed.len(), 2);
+    for (path, ulid) in failed.iter().zip(&ulids[1..]) {
+        let name = path.file_name().unwrap().to_str().unwrap();
+        assert!(name.ends_with(ulid), "unexpected failed backup {name}");
+        assert!(path.join("backup").is_dir());
+        assert!(path.join("freeze").join("chunk.1").is_file());
+    }
+}
+
+#[test]
+fn test_failed_backup_kept_in_freeze_spool() {
+    let tmp_dir = TempDir::new().unwrap();
+    let (spool, keyring) = spool_fixture(&tmp_dir);
+    let freeze_spool = tmp_dir.path().join("freeze-spool");
+    fs::create_dir(&freeze_spool).unwrap();
+    let input = tmp_dir.path().join("input");
+    fs::write(&input, vec![0x55; 4096]).unwrap();
+
+    let vault = "797daf41-ba2c-440e-a56a-d0a190403a0b";
+    let ulid = "01J00000000000000000000672";
+    let cli = Cli::try_parse_from([
+        "cryophile",
+        "--spool",
+        spool.to_str().unwrap(),
+        "--freeze-spool",
+        freeze_spool.to_str().unwrap(),
+        "backup",
+        "--keyring",
+        keyring.to_str().unwrap(),
+        "--vault",
+        vault,
+        "--ulid",
+        ulid,
+        "--input",
+        input.to_str().unwrap(),
+        "--compression",
+        "none",
+        "--size",
+        "512",
+        "--max-chunks",
+        "1",
+    ])
+    .expect("cannot parse command line");
+    let config = cli_config(cli);
+    perform_backup(&config, backup_command(&config)).expect_err("backup exceeded maximum chunks");
+    assert!(!spool.join("backup").join(vault).join(ulid).exists());
+    assert!(!freeze_spool.join("freeze").join(vault).join(ulid).exists());
+
+    // each queue directory is kept in the failed directory of its own spool
+    let failed = |spool: &Path| {
+        let entries = fs::read_dir(spool.join(".failed"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        assert_eq!(entries.len(), 1, "{entries:?}");
+        entries.into_iter().next().unwrap()
+    };
+    let kept = failed(&spool);
+    assert!(kept.join("backup").is_dir());
+    assert!(!kept.join("freeze").exists());
+    let frozen = failed(&freeze_spool);
+    assert_eq!(frozen.file_name(), kept.file_name());
+    assert!(frozen.join("freeze").join("chunk.1").is_file());
+    assert!(!frozen.join("backup").exists());
+}
+
+#[test]
+fn test_empty_backup_round_trip() {
+    let tmp_dir = TempDir::new().unwrap();
+    let (spool, keyring) = spool_fixture(&tmp_dir);
+    let input = tmp_dir.path().join("empty");
+    File::create(&input).unwrap();
+
+    let vault = "797daf41-ba2c-440e-a56a-d0a190403a0b";
+    for (compression, ulid) in [
+        ("none", "01J00000000000000000000635"),
+        ("zstd", "01J00000000000000000000636"),
+        ("lz4", "01J00000000000000000000637"),
+    ] {
+        let cli = Cli::try_parse_from([
+            "cryophile",
+            "--spool",
+            spool.to_str().unwrap(),
+            "backup",
+            "--keyring",
+            keyring.to_str().unwrap(),
+            "--vault",
+            vault,
+            "--ulid",
+            ulid,
+            "--input",
+            input.to_str().unwrap(),
+            "--compression",
+            compression,
+        ])
+        .expect("cannot parse command line");
+        let config = cli_config(cli);
+        perform_backup(&config, backup_command(&config)).expect("cannot back up empty input");
+
+        // inject the freeze queue into the restore queue
+        let freeze_dir = spool.join("freeze").join(vault).join(ulid);
+        let restore_dir = spool.join("restore").join(vault).join(ulid);
+        fs::create_dir_all(&restore_dir).unwrap();
+        for entry in fs::read_dir(&freeze_dir).unwrap() {
+            let path = entry.unwrap().path();
+            fs::hard_link(&path, restore_dir.join(path.file_name().unwrap())).unwrap();
+        }
+
+        let output = tmp_dir.path().join(format!("restored-{compression}"));
+        let cli = Cli::try_parse_from([
+            "cryophile",
+            "--spool",
+            spool.to_str().unwrap(),
+            "restore",
+            "--keyring",
+            keyring.to_str().unwrap(),
+            "--vault",
+            vault,
+            "--ulid",
+            ulid,
+            "--output",
+            output.to_str().unwrap(),
+        ])
+        .expect("cannot parse command line");
+        let config = cli_config(cli);
+        let Command::Restore(restore) = &config.cli.command else {
+            panic!("expected restore command");
+        };
+        perform_restore(&config, restore).expect("cannot restore empty backup");
+        assert_eq!(fs::metadata(&output).unwrap().len(), 0, "{compression}");
+    }
+}
+
+#[test]
+fn test_rechunk_round_trip() {
+    let tmp_dir = TempDir::new().unwrap();
+    let (spool, keyring) = spool_fixture(&tmp_dir);
+    let input = tmp_dir.path().join("input");
+    let data: Vec<u8> = (0..=255u8).cycle().take(40_000).collect();
+    fs::write(&input, &data).unwrap();
+
+    let vault = "797daf41-ba2c-440e-a56a-d0a190403a0b";
+    let (ulid, new_ulid) = ("01J00000000000000000000645", "01J00000000000000000000646");
+    let cli = Cli::try_parse_from([
+        "cryophile",
+        "--spool",
+        spool.to_str().unwrap(),
+        "backup",
+        "--keyring",
+        keyring.to_str().unwrap(),
+        "--vault",
+        vault,
+        "--ulid",
+        ulid,
+        "--input",
+        input.to_str().unwrap(),
+        "--size",
+        "4K",
+    ])
+    .expect("cannot parse command line");
+    let config = cli_config(cli);
+    perform_backup(&config, backup_command(&config)).expect("cannot back up input");
+
+    let cli = Cli::try_parse_from([
+        "cryophile",
+        "--spool",
+        spool.to_str().unwrap(),
+        "rechunk",
+        "--vault",
+        vault,
+        "--ulid",
+        ulid,
+        "--new-ulid",
+        new_ulid,
+        "--size",
+        "16K",
+    ])
+    .expect("cannot parse command line");
+    let config = cli_config(cli);
+    let Command::Rechunk(rechunk) = &config.cli.command else {
+        panic!("expected rechunk command");
+    };
+    perform_rechunk(&config, rechunk).expect("cannot re-chunk backup");
+
+    let freeze_dir = spool.join("freeze").join(vault);
+    let chunks = |ulid: &str| fs::read_dir(freeze_dir.join(ulid)).unwrap().count();
+    assert_eq!(chunks(ulid), 11);
+    assert_eq!(chunks(new_ulid), 4);
+    assert_eq!(
+        fs::read(freeze_dir.join(ulid).join("chunk.0")).unwrap(),
+        fs::read(freeze_dir.join(new_ulid).join("chunk.0")).unwrap()
+    );
+
+    let output = tmp_dir.path().join("restored");
+    let cli = Cli::try_parse_from([
+        "cryophile",
+        "--spool",
+        spool.to_str().unwrap(),
+        "restore",
+        "--keyring",
+        keyring.to_str().unwrap(),
+        "--vault",
+        vault,
+        "--ulid",
+        new_ulid,
+        "--output",
+        output.to_str().unwrap(),
+    ])
+    .expect("cannot parse command line");
+    let config = cli_config(cli);
+    let Command::Restore(restore) = &config.cli.command else {
+        panic!("expected restore command");
+    };
+    perform_restore(&config, restore).expect("cannot restore re-chunked backup");
+    assert_eq!(fs::read(&output).unwrap(), data);
+}
+
+#[test]
+fn test_backup_input_chunks() {
+    let tmp_dir = TempDir::new().unwrap();
+    let (spool, keyring) = spool_fixture(&tmp_dir);
+    let chunk_dir = tmp_dir.path().join("chunks");
+    fs::create_dir(&chunk_dir).unwrap();
+    fs::write(chunk_dir.join("chunk.0"), b"manifest").unwrap();
+    let mut data = Vec::new();
+    for index in 1..=3u8 {
+        let chunk: Vec<u8> = (0..=255u8).cycle().skip(index.into()).take(1000).collect();
+        fs::write(chunk_dir.join(format!("chunk.{index}")), &chunk).unwrap();
+        data.extend(chunk);
+    }
+
+    let vault = "797daf41-ba2c-440e-a56a-d0a190403a0b";
+    let backup = |ulid: &str| {
+        let cli = Cli::try_parse_from([
+            "cryophile",
+            "--spool",
+            spool.to_str().unwrap(),
+            "backup",
+            "--keyring",
+            keyring.to_str().unwrap(),
+            "--vault",
+            vault,
+            "--ulid",
+            ulid,
+            "--input-chunks",
+            chunk_dir.to_str().unwrap(),
+        ])
+        .expect("cannot parse command line");
+        let config = cli_config(cli);
+        perform_backup(&config, backup_command(&config))
+    };
+    let ulid = "01J00000000000000000000660";
+    backup(ulid).expect("cannot back up chunk set");
+
+    let output = tmp_dir.path().join("restored");
+    let cli = Cli::try_parse_from([
+        "cryophile",
+        "--spool",
+        spool.to_str().unwrap(),
+        "restore",
+        "--keyring",
+        keyring.to_str().unwrap(),
+        "--vault",
+        vault,
+        "--ulid",
+        ulid,
+        "--output",
+        output.to_str().unwrap(),
+    ])
+    .expect("cannot parse command line");
+    let config = cli_config(cli);
+    let Command::Restore(restore) = &config.cli.command else {
+        panic!("expected restore command");
+    };
+    perform_restore(&config, restore).expect("cannot restore chunk set backup");
+    assert_eq!(fs::read(&output).unwrap(), data);
+
+    fs::write(chunk_dir.join("chunk.5"), b"stray").unwrap();
+    let err = backup("01J00000000000000000000661").expect_err("backed up a chunk set with a gap");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    fs::remove_file(chunk_dir.join("chunk.5")).unwrap();
+
+    fs::remove_file(chunk_dir.join("chunk.0")).unwrap();
+    let err = backup("01J00000000000000000000662").expect_err("backed up an incomplete chunk set");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_restore_scan_first() {
+    let tmp_dir = TempDir::new().unwrap();
+    let (spool, keyring) = spool_fixture(&tmp_dir);
+    let input = tmp_dir.path().join("input");
+    let data: Vec<u8> = (0..=255u8).cycle().take(20_000).collect();
+    fs::write(&input, &data).unwrap();
+
+    let vault = "797daf41-ba2c-440e-a56a-d0a190403a0b";
+    let ulid = "01J00000000000000000000663";
+    let cli = Cli::try_parse_from([
+        "cryophile",
+        "--spool",
+        spool.to_str().unwrap(),
+        "backup",
+        "--keyring",
+        keyring.to_str().unwrap(),
+        "--vault",
+        vault,
+        "--ulid",
+        ulid,
+        "--input",
+        input.to_str().unwrap(),
+        "--compression",
+        "none",
+        "--size",
+        "4K",
+        "--allow-small-chunks",
+    ])
+    .expect("cannot parse command line");
+    let config = cli_config(cli);
+    perform_backup(&config, backup_command(&config)).expect("cannot back up input");
+
+    let output_dir = tmp_dir.path().join("output");
+    fs::create_dir(&output_dir).unwrap();
+    let output = output_dir.join("restored");
+    let restore = || {
+        let cli = Cli::try_parse_from([
+            "cryophile",
+            "--spool",
+            spool.to_str().unwrap(),
+            "restore",
+            "--keyring",
+            keyring.to_str().unwrap(),
+            "--vault",
+            vault,
+            "--ulid",
+            ulid,
+            "--output",
+            output.to_str().unwrap(),
+            "--scan-first",
+        ])
+        .expect("cannot parse command line");
+        let config = cli_config(cli);
+        let Command::Restore(restore) = &config.cli.command else {
+            panic!("expected restore command");
+        };
+        perform_restore(&config, restore)
+    };
+
+    // corrupt the end of the stream, the scan fails before any output exists
+    let last_chunk = spool.join("freeze").join(vault).join(ulid).join("chunk.5");
+    let chunk = fs::read(&last_chunk).unwrap();
+    let mut corrupted = chunk.clone();
+    *corrupted.last_mut().unwrap() ^= 0xff;
+    fs::write(&last_chunk, &corrupted).unwrap();
+    restore().expect_err("restored a corrupted backup");
+    assert_eq!(fs::read_dir(&output_dir).unwrap().count(), 0);
+
+    fs::write(&last_chunk, &chunk).unwrap();
+    restore().expect("cannot restore scanned backup");
+    assert_eq!(fs::read(&output).unwrap(), data);
+}
+
+#[test]
+fn test_restore_passthrough_compressed_backup() {
+    let tmp_dir = TempDir::new().unwrap();
+    let (spool, keyring) = spool_fixture(&tmp_dir);
+    let input = tmp_dir.path().join("input");
+    let data: Vec<u8> = (0..=255u8).cycle().take(20_000).collect();
+    fs::write(&input, &data).unwrap();
+
+    let vault = "797daf41-ba2c-440e-a56a-d0a190403a0b";
+    let ulid = "01J00000000000000000000613";
+    let cli = Cli::try_parse_from([
+        "cryophile",
+        "--spool",
+        spool.to_str().unwrap(),
+        "backup",
+        "--keyring",
+        keyring.to_str().unwrap(),
+        "--vault",
+        vault,
+        "--ulid",
+        ulid,
+        "--input",
+        input.to_str().unwrap(),
+        "--compression",
+        "zstd",
+    ])
+    .expect("cannot parse command line");
+    let config = cli_config(cli);
+    perform_backup(&config, backup_command(&config)).expect("cannot back up input");
+
+    // the manifest digest is of the uncompressed input, a mismatch of the
+    // compressed stream passed through verbatim does not fail the restore
+    let passthrough_args = [
+        vec!["--compression", "none"],
+        vec!["--no-compression-detection"],
+    ];
+    for (n, passthrough) in passthrough_args.into_iter().enumerate() {
+        let output = tmp_dir.path().join(format!("restored.{n}"));
+        let mut args = vec![
+            "cryophile",
+            "--spool",
+            spool.to_str().unwrap(),
+            "restore",
+            "--keyring",
+            keyring.to_str().unwrap(),
+            "--vault",
+            vault,
+            "--ulid",
+            ulid,
+            "--output",
+            output.to_str().unwrap(),
+        ];
+        args.extend(passthrough);
+        let cli = Cli::try_parse_from(args).expect("cannot parse command line");
+        let config = cli_config(cli);
+        let Command::Restore(restore) = &config.cli.command else {
+            panic!("expected restore command");
+        };
+        perform_restore(&config, restore).expect("cannot restore compressed stream verbatim");
+        let restored = fs::read(&output).unwrap();
+        assert_ne!(restored, data);
+        assert_eq!(zstd::decode_all(restored.as_slice()).unwrap(), data);
+    }
+}
+
+#[test]
+fn test_restore_renamed_chunks() {
+    let tmp_dir = TempDir::new().unwrap();
+    let (spool, keyring) = spool_fixture(&tmp_dir);
+    let input = tmp_dir.path().join("input");
+    let data: Vec<u8> = (0..=255u8).cycle().take(20_000).collect();
+    fs::write(&input, &data).unwrap();
+
+    let vault = "797daf41-ba2c-440e-a56a-d0a190403a0b";
+    let ulid = "01J00000000000000000000667";
+    let cli = Cli::try_parse_from([
+        "cryophile",
+        "--spool",
+        spool.to_str().unwrap(),
+        "backup",
+        "--keyring",
+        keyring.to_str().unwrap(),
+        "--vault",
+        vault,
+        "--ulid",
+        ulid,
+        "--input",
+        input.to_str().unwrap(),
+        "--size",
+        "4K",
+        "--allow-small-chunks",
+    ])
+    .expect("cannot parse command line");
+    let config = cli_config(cli);
+    perform_backup(&config, backup_command(&config)).expect("cannot back up input");
+
+    // take the chunks out of the queue, restore waits for them to come back
+    let freeze_dir = spool.join("freeze").join(vault).join(ulid);
+    let staging = tmp_dir.path().join("staging");
+    fs::rename(&freeze_dir, &staging).unwrap();
+    fs::create_dir(&freeze_dir).unwrap();
+
+    let output = tmp_dir.path().join("restored");
+    let cli = Cli::try_parse_from([
+        "cryophile",
+        "--spool",
+        spool.to_str().unwrap(),
+        "restore",
+        "--keyring",
+        keyring.to_str().unwrap(),
+        "--vault",
+        vault,
+        "--ulid",
+        ulid,
+        "--output",
+        output.to_str().unwrap(),
+    ])
+    .expect("cannot parse command line");
+    let (done_tx, done_rx) = mpsc::channel();
+    let handle = thread::spawn(move || {
+        let config = cli_config(cli);
+        let Command::Restore(restore) = &config.cli.command else {
+            panic!("expected restore command");
+        };
+        done_tx
+            .send(perform_restore(&config, restore))
+            .expect("cannot send result");
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    // like a copy across filesystems, every chunk appears by a rename only
+    let mut chunks: Vec<PathBuf> = fs::read_dir(&staging)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    // the zero chunk completes the backup, it arrives last
+    chunks.sort_by_key(|path| path.extension() == Some("0".as_ref()));
+    for chunk in chunks {
+        fs::rename(&chunk, freeze_dir.join(chunk.file_name().unwrap())).unwrap();
+    }
+
+    done_rx
+        .recv_timeout(Duration::from_secs(30))
+        .expect("restore did not see the renamed chunks")
+        .expect("cannot restore renamed chunks");
+    handle.join().expect("cannot join restore");
+    assert_eq!(fs::read(&output).unwrap(), data);
+}
+
+#[test]
+fn test_failed_restore_leaves_no_output() {
+    let tmp_dir = TempDir::new().unwrap();
+    let (spool, keyring) = spool_fixture(&tmp_dir);
+    let input = tmp_dir.path().join("input");
+    let data: Vec<u8> = (0..=255u8).cycle().take(20_000).collect();
+    fs::write(&input, &data).unwrap();
+
+    let vault = "797daf41-ba2c-440e-a56a-d0a190403a0b";
+    let ulid = "01J00000000000000000000579";
+    let cli = Cli::try_parse_from([
+        "cryophile",
+        "--spool",
+        spool.to_str().unwrap(),
+        "backup",
+        "--keyring",
+        keyring.to_str().unwrap(),
+        "--vault",
+        vault,
+        "--ulid",
+        ulid,
+        "--input",
+        input.to_str().unwrap(),
+        "--compression",
+        "none",
+        "--size",
+        "4K",
+        "--allow-small-chunks",
+    ])
+    .expect("cannot parse command line");
+    let config = cli_config(cli);
+    perform_backup(&config, backup_command(&config)).expect("cannot back up input");
+
+    let output_dir = tmp_dir.path().join("output");
+    fs::create_dir(&output_dir).unwrap();
+    let output = output_dir.join("restored");
+    let restore = || {
+        let cli = Cli::try_parse_from([
+            "cryophile",
+            "--spool",
+            spool.to_str().unwrap(),
+            "restore",
+            "--keyring",
+            keyring.to_str().unwrap(),
+            "--vault",
+            vault,
+            "--ulid",
+            ulid,
+            "--output",
+            output.to_str().unwrap(),
+        ])
+        .expect("cannot parse command line");
+        let config = cli_config(cli);
+        let Command::Restore(restore) = &config.cli.command else {
+            panic!("expected restore command");
+        };
+        perform_restore(&config, restore)
+    };
+
+    // the stream fails at its end, after most of it was written to the temp file
+    let last_chunk = spool.join("freeze").join(vault).join(ulid).join("chunk.5");
+    let chunk = fs::read(&last_chunk).unwrap();
+    let mut corrupted = chunk.clone();
+    *corrupted.last_mut().unwrap() ^= 0xff;
+    fs::write(&last_chunk, &corrupted).unwrap();
+    restore().expect_err("restored a corrupted backup");
+    assert_eq!(fs::read_dir(&output_dir).unwrap().count(), 0);
+
+    // only the renamed output remains after a successful restore
+    fs::write(&last_chunk, &chunk).unwrap();
+    restore().expect("cannot restore backup");
+    assert_eq!(fs::read(&output).unwrap(), data);
+    assert_eq!(fs::read_dir(&output_dir).unwrap().count(), 1);
+    let mode = fs::metadata(&output).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+}
+
+#[test]
+fn test_restore_preserve_mode_and_owner() {
+    let tmp_dir = TempDir::new().unwrap();
+    let (spool, keyring) = spool_fixture(&tmp_dir);
+    let input = tmp_dir.path().join("input");
+    fs::write(&input, b"preserved").unwrap();
+    fs::set_permissions(&input, fs::Permissions::from_mode(0o640)).unwrap();
+
+    let vault = "797daf41-ba2c-440e-a56a-d0a190403a0b";
+    let ulid = "01J00000000000000000000617";
+    let cli = Cli::try_parse_from([
+        "cryophile",
+        "--spool",
+        spool.to_str().unwrap(),
+        "backup",
+        "--keyring",
+        keyring.to_str().unwrap(),
+        "--vault",
+        vault,
+        "--ulid",
+        ulid,
+        "--input",
+        input.to_str().unwrap(),
+    ])
+    .expect("cannot parse command line");
+    let config = cli_config(cli);
+    perform_backup(&config, backup_command(&config)).expect("cannot back up input");
+
+    let restore = |output: &Path, preserve: &[&str]| {
+        let mut args = vec![
+            "cryophile",
+            "--spool",
+            spool.to_str().unwrap(),
+            "restore",
+            "--keyring",
+            keyring.to_str().unwrap(),
+            "--vault",
+            vault,
+            "--ulid",
+            ulid,
+            "--output",
+            output.to_str().unwrap(),
+        ];
+        args.extend(preserve);
+        let cli = Cli::try_parse_from(args).expect("cannot parse command line");
+        let config = cli_config(cli);
+        let Command::Restore(restore) = &config.cli.command else {
+            panic!("expected restore command");
+        };
+        perform_restore(&config, restore).expect("cannot restore backup");
+    };
+
+    // without the flags the output keeps the private default mode
+    let default_output = tmp_dir.path().join("default");
+    restore(&default_output, &[]);
+    let mode = fs::metadata(&default_output).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    // chown to our own uid and gid is permitted without privileges
+    let output = tmp_dir.path().join("preserved");
+    restore(&output, &["--preserve-mode", "--preserve-owner"]);
+    assert_eq!(fs::read(&output).unwrap(), b"preserved");
+    let input_metadata = fs::metadata(&input).unwrap();
+    let output_metadata = fs::metadata(&output).unwrap();
+    assert_eq!(output_metadata.permissions().mode() & 0o777, 0o640);
+    assert_eq!(output_metadata.uid(), input_metadata.uid());
+    assert_eq!(output_metadata.gid(), input_metadata.gid());
+}
+
+#[test]
+fn test_restore_preallocate() {
+    let tmp_dir = TempDir::new().unwrap();
+    let (spool, keyring) = spool_fixture(&tmp_dir);
+    let input = tmp_dir.path().join("input");
+    let data: Vec<u8> = (0..=255u8).cycle().take(10_000).collect();
+    fs::write(&input, &data).unwrap();
+
+    let vault = "797daf41-ba2c-440e-a56a-d0a190403a0b";
+    let ulid = "01J00000000000000000000588";
+    let cli = Cli::try_parse_from([
+        "cryophile",
+        "--spool",
+        spool.to_str().unwrap(),
+        "backup",
+        "--keyring",
+        keyring.to_str().unwrap(),
+        "--vault",
+        vault,
+        "--ulid",
+        ulid,
+        "--input",
+        input.to_str().unwrap(),
+    ])
+    .expect("cannot parse command line");
+    let config = cli_config(cli);
+    perform_backup(&config, backup_command(&config)).expect("cannot back up input");
+
+    let output_dir = tmp_dir.path().join("output");
+    fs::create_dir(&output_dir).unwrap();
+    let output = output_dir.join("restored");
+    let restore = |outputs: &[&Path], preallocate: Option<&str>| {
+        let mut args = vec![
+            "cryophile",
+            "--spool",
+            spool.to_str().unwrap(),
+            "restore",
+            "--keyring",
+            keyring.to_str().unwrap(),
+            "--vault",
+            vault,
+            "--ulid",
+            ulid,
+            "--force",
+            "--preallocate",
+        ];
+        args.extend(preallocate);
+        for output in outputs {
+            args.extend(["--output", output.to_str().unwrap()]);
+        }
+        let cli = Cli::try_parse_from(args).expect("cannot parse command line");
+        let config = cli_config(cli);
+        let Command::Restore(restore) = &config.cli.command else {
+            panic!("expected restore command");
+        };
+        perform_restore(&config, restore)
+    };
+
+    // a size beyond the available space fails before anything is restored
+    let err =
+        restore(&[&output], Some("8E")).expect_err("preallocated more than the available space");
+    assert!(err.to_string().contains("required"), "{err}");
+    assert_eq!(fs::read_dir(&output_dir).unwrap().count(), 0);
+
+    // outputs on the same file system must fit into the available space together
+    let available = cryophile::core::path::available_space(&output_dir).unwrap();
+    let second = output_dir.join("second");
+    let size = (available / 3 * 2).to_string();
+    let err = restore(&[&output, &second], Some(&size))
+        .expect_err("preallocated more than the available space for both outputs");
+    assert!(err.to_string().contains("required"), "{err}");
+    assert_eq!(fs::read_dir(&output_dir).unwrap().count(), 0);
+
+    // the output is truncated to the restored length after preallocating
+    restore(&[&output], Some("1M")).expect("cannot restore preallocated output");
+    assert_eq!(fs::read(&output).unwrap(), data);
+
+    // without a size, the output is preallocated with the input size in the manifest
+    restore(&[&output], None).expect("cannot restore output preallocated from manifest");
+    assert_eq!(fs::read(&output).unwrap(), data);
+}
+
+#[test]
+fn test_vault_default_prefix() {
+    let vault = "797daf41-ba2c-440e-a56a-d0a190403a0b";
+    let ulid = "01J00000000000000000000664";
+    let file = ConfigFile::from_str(&format!(
+        "[[vault]]\nid = \"{vault}\"\ndefault_prefix = \"hosts/\"\n"
+    ))
+    .expect("cannot parse config");
+    let backup_id = |prefix: Option<&str>| {
+        let mut args = vec!["cryophile", "restore", "--inspect", "--vault", vault];
+        args.extend(["--ulid", ulid]);
+        args.extend(prefix.into_iter().flat_map(|prefix| ["--prefix", prefix]));
+        let mut cli = Cli::try_parse_from(args).expect("cannot parse command line");
+        cryophile::apply_default_prefix(&mut cli.command, &file)?;
+        let Command::Restore(restore) = &cli.command else {
+            panic!("expected restore command");
+        };
+        let prefix = restore
+            .prefix
+            .as_ref()
+            .map(|p| p.to_str().unwrap().to_string());
+        let backup_id = BackupId::new(restore.vault, prefix.as_deref(), restore.ulid.unwrap());
+        Ok::<_, cryophile::cli::CliError>((backup_id.to_path_buf(), backup_id.to_vault_key('/')))
+    };
+
+    let (path, key) = backup_id(Some("web/db")).expect("cannot compose prefix");
+    assert_eq!(path, Path::new(vault).join("hosts/web/db").join(ulid));
+    assert_eq!(key, format!("hosts/web/db/{ulid}"));
+    let (path, key) = backup_id(None).expect("cannot use default prefix alone");
+    assert_eq!(path, Path::new(vault).join("hosts").join(ulid));
+    assert_eq!(key, format!("hosts/{ulid}"));
+
+    let file = ConfigFile::from_str(&format!(
+        "[[vault]]\nid = \"{vault}\"\ndefault_prefix = \"hosts#\"\n"
+    ))
+    .expect("cannot parse config");
+    let mut cli = Cli::try_parse_from([
+        "cryophile",
+        "restore",
+        "--inspect",
+        "--vault",
+        vault,
+        "--ulid",
+        ulid,
+    ])
+    .expect("cannot parse command line");
+    cryophile::apply_default_prefix(&mut cli.command, &file).expect_err("used unsafe prefix");
+}
+
+#[test]
+fn test_restore_multiple_outputs() {
+    let tmp_dir = TempDir::new().unwrap();
+    let (spool, keyring) = spool_fixture(&tmp_dir);
+    let input = tmp_dir.path().join("input");
+    let data: Vec<u8> = (0..=255u8).cycle().take(10_000).collect();
+    fs::write(&input, &data).unwrap();
+
+    let vault = "797daf41-ba2c-440e-a56a-d0a190403a0b";
+    let ulid = "01J00000000000000000000666";
+    let cli = Cli::try_parse_from([
+        "cryophile",
+        "--spool",
+        spool.to_str().unwrap(),
+        "backup",
+        "--keyring",
+        keyring.to_str().unwrap(),
+        "--vault",
+        vault,
+        "--ulid",
+        ulid,
+        "--input",
+        input.to_str().unwrap(),
+    ])
+    .expect("cannot parse command line");
+    let config = cli_config(cli);
+    perform_backup(&config, backup_command(&config)).expect("cannot back up input");
+
+    let restore = |outputs: &[&Path]| {
+        let mut args = vec![
+            "cryophile",
+            "--spool",
+            spool.to_str().unwrap(),
+            "restore",
+            "--keyring",
+            keyring.to_str().unwrap(),
+            "--vault",
+            vault,
+            "--ulid",
+            ulid,
+        ];
+        for output in outputs {
+            args.extend(["--output", output.to_str().unwrap()]);
+        }
+        let cli = Cli::try_parse_from(args).expect("cannot parse command line");
+        let config = cli_config(cli);
+        let Command::Restore(restore) = &config.cli.command else {
+            panic!("expected restore command");
+        };
+        perform_restore(&config, restore)
+    };
+
+    let (first, second) = (tmp_dir.path().join("first"), tmp_dir.path().join("second"));
+    restore(&[&first, &second]).expect("cannot restore to two outputs");
+    assert_eq!(fs::read(&first).unwrap(), data);
+    assert_eq!(fs::read(&second).unwrap(), data);
+
+    let third = tmp_dir.path().join("third");
+    let err = restore(&[&third, &third]).expect_err("restored to the same output twice");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert!(!third.exists());
+}
+
+#[test]
+fn test_freeze_spool() {
+    let tmp_dir = TempDir::new().unwrap();
+    let spool = tmp_dir.path().join("spool");
+    let freeze_spool = tmp_dir.path().join("freeze-spool");
+    fs::create_dir(&spool).unwrap();
+    fs::create_dir(&freeze_spool).unwrap();
+
+    let keyring = keyring_fixture(tmp_dir.path());
+    let input = tmp_dir.path().join("input");
+    let data: Vec<u8> = (0..=255u8).cycle().take(10_000).collect();
+    fs::write(&input, &data).unwrap();
+
+    let vault = "797daf41-ba2c-440e-a56a-d0a190403a0b";
+    let ulid = "01J00000000000000000000669";
+    let cli = Cli::try_parse_from([
+        "cryophile",
+        "--spool",
+        spool.to_str().unwrap(),
+        "--freeze-spool",
+        freeze_spool.to_str().unwrap(),
+        "backup",
+        "--keyring",
+        keyring.to_str().unwrap(),
+        "--vault",
+        vault,
+        "--ulid",
+        ulid,
+        "--input",
+        input.to_str().unwrap(),
+    ])
+    .expect("cannot parse command line");
+    let config = cli_config(cli);
+    perform_backup(&config, backup_command(&config)).expect("cannot back up input");
+    assert!(spool.join("backup").join(vault).join(ulid).is_dir());
+    assert!(!spool.join("freeze").exists());
+    let freeze_dir = freeze_spool.join("freeze").join(vault).join(ulid);
+    assert!(freeze_dir.join("chunk.0").is_file());
+    assert!(freeze_dir.join("chunk.1").is_file());
+
+    let output = tmp_dir.path().join("restored");
+    let cli = Cli::try_parse_from([
+        "cryophile",
+        "--spool",
+        spool.to_str().unwrap(),
+        "--freeze-spool",
+        freeze_spool.to_str().unwrap(),
+        "restore",
+        "--keyring",
+        keyring.to_str().unwrap(),
+        "--vault",
+        vault,
+        "--ulid",
+        ulid,
+        "--output",
+        output.to_str().unwrap(),
+    ])
+    .expect("cannot parse command line");
+    let config = cli_config(cli);
+    let Command::Restore(restore) = &config.cli.command else {
+        panic!("expected restore command");
+    };
+    perform_restore(&config, restore).expect("cannot restore from freeze spool");
+    assert_eq!(fs::read(&output).unwrap(), data);
+}
+
+#[test]
+fn test_backup_checksum_file() {
+    let tmp_dir = TempDir::new().unwrap();
+    let (spool, keyring) = spool_fixture(&tmp_dir);
+    let input = tmp_dir.path().join("input");
+    let data: Vec<u8> = (0..=255u8).cycle().take(10_000).collect();
+    fs::write(&input, &data).unwrap();
+
+    let vault = "797daf41-ba2c-440e-a56a-d0a190403a0b";
+    let ulid = "01J00000000000000000000670";
+    let cli = Cli::try_parse_from([
+        "cryophile",
+        "--spool",
+        spool.to_str().unwrap(),
+        "backup",
+        "--keyring",
+        keyring.to_str().unwrap(),
+        "--vault",
+        vault,
+        "--ulid",
+        ulid,
+        "--input",
+        input.to_str().unwrap(),
+        "--compression",
+        "none",
+        "--size",
+        "4K",
+        "--allow-small-chunks",
+        "--checksum",
+    ])
+    .expect("cannot parse command line");
+    let config = cli_config(cli);
+    perform_backup(&config, backup_command(&config)).expect("cannot back up input");
+
+    let freeze_dir = spool.join("freeze").join(vault).join(ulid);
+    let sums = fs::read_to_string(freeze_dir.join("SHA256SUMS")).unwrap();
+    let backup_dir = spool.join("backup").join(vault).join(ulid);
+    assert_eq!(
+        fs::read_to_string(backup_dir.join("SHA256SUMS")).unwrap(),
+        sums
+    );
+    let names: Vec<_> = sums.lines().map(|line| &line[66..]).collect();
+    assert_eq!(names, ["chunk.0", "chunk.1", "chunk.2", "chunk.3"]);
+    let status = std::process::Command::new("sha256sum")
+        .args(["--check", "--quiet", "SHA256SUMS"])
+        .current_dir(&freeze_dir)
+        .status()
+        .expect("cannot run sha256sum");
+    assert!(status.success());
+
+    let output = tmp_dir.path().join("restored");
+    let cli = Cli::try_parse_from([
+        "cryophile",
+        "--spool",
+        spool.to_str().unwrap(),
+        "restore",
+        "--keyring",
+        keyring.to_str().unwrap(),
+        "--vault",
+        vault,
+        "--ulid",
+        ulid,
+        "--output",
+        output.to_str().unwrap(),
+        "--strict-chunk-names",
+    ])
+    .expect("cannot parse command line");
+    let config = cli_config(cli);
+    let Command::Restore(restore) = &config.cli.command else {
+        panic!("expected restore command");
+    };
+    perform_restore(&config, restore).expect("cannot restore backup with checksums");
+    assert_eq!(fs::read(&output).unwrap(), data);
+}
+
+#[test]
+fn test_restore_at_time() {
+    let tmp_dir = TempDir::new().unwrap();
+    let (spool, keyring) = spool_fixture(&tmp_dir);
+
+    let vault = "797daf41-ba2c-440e-a56a-d0a190403a0b";
+    let backups = [
+        ("2024-06-01T15:00:00+02:00", b"yesterday".as_slice()),
+        ("2024-06-02T15:00:00+02:00", b"today".as_slice()),
+    ];
+    for (timestamp, data) in backups {
+        let input = tmp_dir.path().join("input");
+        fs::write(&input, data).unwrap();
+        let cli = Cli::try_parse_from([
+            "cryophile",
+            "--spool",
+            spool.to_str().unwrap(),
+            "backup",
+            "--keyring",
+            keyring.to_str().unwrap(),
+            "--vault",
+            vault,
+            "--prefix",
+            "hosts/db",
+            "--timestamp",
+            timestamp,
+            "--input",
+            input.to_str().unwrap(),
+        ])
+        .expect("cannot parse command line");
+        let config = cli_config(cli);
+        perform_backup(&config, backup_command(&config)).expect("cannot back up input");
+    }
+
+    let restore_at = |at: &str| {
+        let output = tmp_dir.path().join("restored");
+        let cli = Cli::try_parse_from([
+            "cryophile",
+            "--spool",
+            spool.to_str().unwrap(),
+            "restore",
+            "--keyring",
+            keyring.to_str().unwrap(),
+            "--vault",
+            vault,
+            "--prefix",
+            "hosts/db",
+            "--at",
+            at,
+            "--output",
+            output.to_str().unwrap(),
+            "--force",
+        ])
+        .expect("cannot parse command line");
+        let config = cli_config(cli);
+        let Command::Restore(restore) = &config.cli.command else {
+            panic!("expected restore command");
+        };
+        perform_restore(&config, restore).map(|_| fs::read(&output).unwrap())
+    };
+
+    assert_eq!(restore_at("2024-06-02T12:00:00Z").unwrap(), b"yesterday");
+    assert_eq!(restore_at("2024-06-02T13:00:00Z").unwrap(), b"today");
+    assert_eq!(
+        restore_at("2024-06-01T12:00:00Z").unwrap_err().kind(),
+        io::ErrorKind::NotFound
+    );
+    assert!(Cli::try_parse_from([
+        "cryophile",
+        "restore",
+        "--vault",
+        vault,
+        "--ulid",
+        "01J00000000000000000000675",
+        "--at",
+        "2024-06-02T12:00:00Z",
+    ])
+    .is_err());
+}
+
+#[test]
+fn test_restore_follow_symlinks() {
+    let tmp_dir = TempDir::new().unwrap();
+    let (spool, keyring) = spool_fixture(&tmp_dir);
+    let input = tmp_dir.path().join("input");
+    let data: Vec<u8> = (0..=255u8).cycle().take(20_000).collect();
+    fs::write(&input, &data).unwrap();
+
+    let vault = "797daf41-ba2c-440e-a56a-d0a190403a0b";
+    let ulid = "01J00000000000000000000671";
+    let cli = Cli::try_parse_from([
+        "cryophile",
+        "--spool",
+        spool.to_str().unwrap(),
+        "backup",
+        "--keyring",
+        keyring.to_str().unwrap(),
+        "--vault",
+        vault,
+        "--ulid",
+        ulid,
+        "--input",
+        input.to_str().unwrap(),
+        "--compression",
+        "none",
+        "--size",
+        "4K",
+        "--allow-small-chunks",
+    ])
+    .expect("cannot parse command line");
+    let config = cli_config(cli);
+    perform_backup(&config, backup_command(&config)).expect("cannot back up input");
+
+    // move the chunks to other storage and leave symlinks in the restore queue
+    let freeze_dir = spool.join("freeze").join(vault).join(ulid);
+    let storage = tmp_dir.path().join("storage");
+    fs::create_dir(&storage).unwrap();
+    for entry in fs::read_dir(&freeze_dir).unwrap() {
+        let chunk = entry.unwrap().path();
+        let target = storage.join(chunk.file_name().unwrap());
+        fs::rename(&chunk, &target).unwrap();
+        std::os::unix::fs::symlink(&target, &chunk).unwrap();
+    }
+
+    let output = tmp_dir.path().join("restored");
+    let cli = Cli::try_parse_from([
+        "cryophile",
+        "--spool",
+        spool.to_str().unwrap(),
+        "restore",
+        "--keyring",
+        keyring.to_str().unwrap(),
+        "--vault",
+        vault,
+        "--ulid",
+        ulid,
+        "--output",
+        output.to_str().unwrap(),
+        "--follow-symlinks",
+    ])
+    .expect("cannot parse command line");
+    let config = cli_config(cli);
+    let Command::Restore(restore) = &config.cli.command else {
+        panic!("expected restore command");
+    };
+    perform_restore(&config, restore).expect("cannot restore symlinked chunks");
+    assert_eq!(fs::read(&output).unwrap(), data);
+}
+
+#[cfg(feature = "age")]
+#[test]
+fn test_age_backup_round_trip() {
+    use age::secrecy::ExposeSecret;
+    use std::os::fd::IntoRawFd;
+
+    let tmp_dir = TempDir::new().unwrap();
+    let spool = tmp_dir.path().join("spool");
+    fs::create_dir(&spool).unwrap();
+
+    let identity = age::x25519::Identity::generate();
+    let identity_file = tmp_dir.path().join("identity.txt");
+    fs::write(&identity_file, identity.to_string().expose_secret()).unwrap();
+    let passphrase_file = tmp_dir.path().join("passphrase.txt");
+    fs::write(&passphrase_file, "cryophile\n").unwrap();
+    let passphrase_fd = || {
+        File::open(&passphrase_file)
+            .unwrap()
+            .into_raw_fd()
+            .to_string()
+    };
+    let input = tmp_dir.path().join("input");
+    let data: Vec<u8> = (0..=255u8).cycle().take(20_000).collect();
+    fs::write(&input, &data).unwrap();
+
+    let vault = "797daf41-ba2c-440e-a56a-d0a190403a0b";
+    for (ulid, backup_args, restore_args) in [
+        (
+            "01J00000000000000000000673",
+            vec!["--recipient".to_string(), identity.to_public().to_string()],
+            vec![
+                "--age-identity".to_string(),
+                identity_file.to_str().unwrap().to_string(),
+            ],
+        ),
+        (
+            "01J00000000000000000000674",
+            vec![
+                "--age-passphrase".to_string(),
+                "--pass-fd".to_string(),
+                passphrase_fd(),
+            ],
+            vec![
+                "--age-passphrase".to_string(),
+                "--age-pass-fd".to_string(),
+                passphrase_fd(),
+            ],
+        ),
+    ] {
+        let mut args = vec![
+            "cryophile".to_string(),
+            "--spool".to_string(),
+            spool.to_str().unwrap().to_string(),
+            "backup".to_string(),
+            "--vault".to_string(),
+            vault.to_string(),
+            "--ulid".to_string(),
+            ulid.to_string(),
+            "--input".to_string(),
+            input.to_str().unwrap().to_string(),
+            "--size".to_string(),
+            "4K".to_string(),
+        ];
+        args.extend(backup_args);
+        let cli = Cli::try_parse_from(args).expect("cannot parse command line");
+        let config = cli_config(cli);
+        perform_backup(&config, backup_command(&config)).expect("cannot back up with age");
+
+        // both the chunks and the manifest in the zero chunk are age streams
+        let freeze_dir = spool.join("freeze").join(vault).join(ulid);
+        for chunk in ["chunk.0", "chunk.1"] {
+            let stream = fs::read(freeze_dir.join(chunk)).unwrap();
+            assert!(stream.starts_with(b"age-encryption.org/v1"), "{chunk}");
+        }
+
+        // inject the freeze queue into the restore queue
+        let restore_dir = spool.join("restore").join(vault).join(ulid);
+        fs::create_dir_all(&restore_dir).unwrap();
+        for entry in fs::read_dir(&freeze_dir).unwrap() {
+            let path = entry.unwrap().path();
+            fs::hard_link(&path, restore_dir.join(path.file_name().unwrap())).unwrap();
+        }
+
+        let output = tmp_dir.path().join(ulid);
+        let mut args = vec![
+            "cryophile".to_string(),
+            "--spool".to_string(),
+            spool.to_str().unwrap().to_string(),
+            "restore".to_string(),
+            "--vault".to_string(),
+            vault.to_string(),
+            "--ulid".to_string(),
+            ulid.to_string(),
+            "--output".to_string(),
+            output.to_str().unwrap().to_string(),
+        ];
+        args.extend(restore_args);
+        let cli = Cli::try_parse_from(args).expect("cannot parse command line");
+        let config = cli_config(cli);
+        let Command::Restore(restore) = &config.cli.command else {
+            panic!("expected restore command");
+        };
+        perform_restore(&config, restore).expect("cannot restore age backup");
+        assert_eq!(fs::read(&output).unwrap(), data);
+    }
+}
+
+#[cfg(feature = "age")]
+#[test]
+fn test_age_wrong_identity_exit_code() {
+    use age::secrecy::ExposeSecret;
+
+    let tmp_dir = TempDir::new().unwrap();
+    let spool = tmp_dir.path().join("spool");
+    fs::create_dir(&spool).unwrap();
+    let recipient = age::x25519::Identity::generate().to_public();
+    let wrong_identity = age::x25519::Identity::generate();
+    let identity_file = tmp_dir.path().join("identity.txt");
+    fs::write(&identity_file, wrong_identity.to_string().expose_secret()).unwrap();
+    let input = tmp_dir.path().join("input");
+    fs::write(&input, b"age").unwrap();
+
+    let vault = "797daf41-ba2c-440e-a56a-d0a190403a0b";
+    let ulid = "01J00000000000000000000675";
+    let cli = Cli::try_parse_from([
+        "cryophile",
+        "--spool",
+        spool.to_str().unwrap(),
+        "backup",
+        "--vault",
+        vault,
+        "--ulid",
+        ulid,
+        "--input",
+        input.to_str().unwrap(),
+        "--recipient",
+        &recipient.to_string(),
+    ])
+    .expect("cannot parse command line");
+    let config = cli_config(cli);
+    perform_backup(&config, backup_command(&config)).expect("cannot back up with age");
+
+    let output = tmp_dir.path().join("restored");
+    let cli = Cli::try_parse_from([
+        "cryophile",
+        "--spool",
+        spool.to_str().unwrap(),
+        "restore",
+        "--vault",
+        vault,
+        "--ulid",
+        ulid,
+        "--output",
+        output.to_str().unwrap(),
+        "--age-identity",
+        identity_file.to_str().unwrap(),
+    ])
+    .expect("cannot parse command line");
+    let config = cli_config(cli);
+    let Command::Restore(restore) = &config.cli.command else {
+        panic!("expected restore command");
+    };
+    let err = perform_restore(&config, restore).expect_err("restored with the wrong identity");
+    let code = cryophile::cli::CliResult::from(cryophile::cli::CliError::from(err));
+    assert_eq!(code as u8, 43, "{code:?}");
+}
+
+#[test]
+fn test_assume_role_options() {
+    let role_arn = "arn:aws:iam::123456789012:role/cryophile/vault-reader";
+    let cli = Cli::try_parse_from([
+        "cryophile",
+        "--assume-role-arn",
+        role_arn,
+        "--external-id",
+        "the-external-id",
+        "version",
+    ])
+    .unwrap();
+    let assume_role = cli_config(cli).assume_role().expect("role missing");
+    assert_eq!(assume_role.role_arn, role_arn);
+    assert_eq!(assume_role.external_id.as_deref(), Some("the-external-id"));
+    assert_eq!(assume_role.session_name, None);
+
+    let cli = Cli::try_parse_from(["cryophile", "version"]).unwrap();
+    assert_eq!(cli_config(cli).assume_role(), None);
+
+    for invalid in [
+        "arn:aws:iam::123456789012:user/someone",
+        "arn:aws:iam::12345:role/short-account",
+        "arn:aws:s3:::the-bucket-name",
+        "the-role-name",
+    ] {
+        assert!(
+            Cli::try_parse_from(["cryophile", "--assume-role-arn", invalid, "version"]).is_err(),
+            "{invalid} should not parse as role ARN"
+        );
+    }
+    assert!(
+        Cli::try_parse_from(["cryophile", "--external-id", "the-external-id", "version"]).is_err()
+    );
+}
+
+#[test]
+fn test_keyring_directory() {
+    let tmp_dir = TempDir::new().unwrap();
+    let keyrings = tmp_dir.path().join("keyrings");
+    fs::create_dir(&keyrings).unwrap();
+    let certs: Vec<_> = ["alice", "bob"]
+        .into_iter()
+        .map(|name| {
+            let (cert, _) = CertBuilder::general_purpose(None, Some(name))
+                .generate()
+                .expect("cannot generate certificate");
+            cert
+        })
+        .collect();
+    for (name, certs) in [("a.pgp", &certs[..1]), ("b.pgp", &certs[..])] {
+        let mut file = File::create(keyrings.join(name)).unwrap();
+        for cert in certs {
+            cert.as_tsk()
+                .serialize(&mut file)
+                .expect("cannot write key");
+        }
+    }
+    // subdirectories are not keyrings
+    fs::create_dir(keyrings.join("private")).unwrap();
+
+    let cli = Cli::try_parse_from([
+        "cryophile",
+        "restore",
+        "--keyring",
+        keyrings.to_str().unwrap(),
+        "--vault",
+        "797daf41-ba2c-440e-a56a-d0a190403a0b",
+        "--ulid",
+        "01J00000000000000000000648",
+    ])
+    .expect("cannot parse command line");
+    let Command::Restore(restore) = &cli.command else {
+        panic!("expected restore command");
+    };
+    let fingerprints: Vec<_> = restore
+        .certs()
+        .expect("cannot read keyrings")
+        .iter()
+        .map(|cert| cert.fingerprint())
+        .collect();
+    assert_eq!(
+        fingerprints,
+        [
+            certs[0].fingerprint(),
+            certs[0].fingerprint(),
+            certs[1].fingerprint()
+        ]
+    );
+
+    let empty = tmp_dir.path().join("empty");
+    fs::create_dir(&empty).unwrap();
+    Cli::try_parse_from([
+        "cryophile",
+        "restore",
+        "--keyring",
+        empty.to_str().unwrap(),
+        "--vault",
+        "797daf41-ba2c-440e-a56a-d0a190403a0b",
+        "--ulid",
+        "01J00000000000000000000648",
+    ])
+    .expect_err("accepted an empty keyring directory");
+}
+
+#[test]
+fn test_prefix_from_file() {
+    let tmp_dir = TempDir::new().unwrap();
+    let parse = |prefix: &str| {
+        Cli::try_parse_from([
+            "cryophile",
+            "rechunk",
+            "--prefix",
+            prefix,
+            "--vault",
+            "797daf41-ba2c-440e-a56a-d0a190403a0b",
+            "--ulid",
+            "01J00000000000000000000653",
+        ])
+    };
+
+    let prefix_file = tmp_dir.path().join("prefix");
+    fs::write(&prefix_file, "hosts/db01\nignored\n").unwrap();
+    let cli = parse(&format!("@{}", prefix_file.display())).expect("cannot parse command line");
+    let Command::Rechunk(rechunk) = &cli.command else {
+        panic!("expected rechunk command");
+    };
+    assert_eq!(rechunk.prefix.as_deref(), Some(Path::new("hosts/db01")));
+
+    // a prefix from a file is validated like an inline prefix
+    for invalid in ["hosts/db 01\n", "/hosts\n", "\n"] {
+        fs::write(&prefix_file, invalid).unwrap();
+        parse(&format!("@{}", prefix_file.display())).expect_err("accepted invalid prefix");
+    }
+    let missing = tmp_dir.path().join("missing");
+    parse(&format!("@{}", missing.display())).expect_err("accepted missing prefix file");
+
+    // stdin is only read when the command runs, not while parsing
+    let mut cli = parse("-").expect("cannot parse command line");
+    cryophile::apply_stdin_prefix(&mut cli.command, &b"hosts/db02\nignored\n"[..])
+        .expect("cannot read prefix from stdin");
+    assert_eq!(
+        cli.command.prefix().map(PathBuf::as_path),
+        Some(Path::new("hosts/db02"))
+    );
+    let mut cli = parse("-").expect("cannot parse command line");
+    cryophile::apply_stdin_prefix(&mut cli.command, &b"/hosts\n"[..])
+        .expect_err("accepted invalid prefix from stdin");
+}
+
+#[test]
+fn test_backup_refuses_input_inside_spool() {
+    let tmp_dir = TempDir::new().unwrap();
+    let (spool, keyring) = spool_fixture(&tmp_dir);
+    let input = spool.join("input");
+    fs::write(&input, b"0123456789abcdef").unwrap();
+
+    // refer to the spool via a symlink, the input is still inside
+    let link = tmp_dir.path().join("link");
+    std::os::unix::fs::symlink(&spool, &link).unwrap();
+    let cli = Cli::try_parse_from([
+        "cryophile",
+        "--spool",
+        spool.to_str().unwrap(),
+        "backup",
+        "--keyring",
+        keyring.to_str().unwrap(),
+        "--vault",
+        "797daf41-ba2c-440e-a56a-d0a190403a0b",
+        "--input",
+        link.join("input").to_str().unwrap(),
+    ])
+    .expect("cannot parse command line");
+    let config = cli_config(cli);
+
+    let err =
+        perform_backup(&config, backup_command(&config)).expect_err("backed up input inside spool");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    let entries: Vec<_> = fs::read_dir(&spool).unwrap().collect();
+    assert_eq!(entries.len(), 1, "backup created queue directories");
+}
+
+#[test]
+fn test_backup_refuses_unreadable_input_fd() {
+    let tmp_dir = TempDir::new().unwrap();
+    let (spool, keyring) = spool_fixture(&tmp_dir);
+    // open for writing only, backup cannot read from it
+    let input = File::create(tmp_dir.path().join("input")).unwrap();
+    let fd = input.as_raw_fd().to_string();
+
+    let cli = Cli::try_parse_from([
+        "cryophile",
+        "--spool",
+        spool.to_str().unwrap(),
+        "backup",
+        "--keyring",
+        keyring.to_str().unwrap(),
+        "--vault",
+        "797daf41-ba2c-440e-a56a-d0a190403a0b",
+        "--input-fd",
+        &fd,
+    ])
+    .expect("cannot parse command line");
+    let config = cli_config(cli);
+
+    let err = perform_backup(&config, backup_command(&config))
+        .expect_err("backed up from an unreadable file descriptor");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("not open for reading"), "{err}");
+    assert_eq!(
+        fs::read_dir(&spool).unwrap().count(),
+        0,
+        "backup created queue directories"
+    );
+}
+
+#[test]
+fn test_backup_checks_free_space() {
+    let tmp_dir = TempDir::new().unwrap();
+    let spool = tmp_dir.path().join("spool");
+
+    let keyring = keyring_fixture(tmp_dir.path());
+    let input = tmp_dir.path().join("input");
+    fs::write(&input, b"0123456789abcdef").unwrap();
+
+    let cli = Cli::try_parse_from([
+        "cryophile",
+        "--spool",
+        spool.to_str().unwrap(),
+        "backup",
+        "--keyring",
+        keyring.to_str().unwrap(),
+        "--vault",
+        "797daf41-ba2c-440e-a56a-d0a190403a0b",
+        "--input",
+        input.to_str().unwrap(),
+        "--min-free-space",
+        "8E",
+    ])
+    .expect("cannot parse command line");
+    let config = cli_config(cli);
+
+    let err = perform_backup(&config, backup_command(&config))
+        .expect_err("backed up without enough free space");
+    assert!(err.to_string().contains("required"), "{err}");
+    assert!(!spool.exists(), "backup created the spool");
+}
+
+#[test]
+fn test_concurrent_backup_fails() {
+    let tmp_dir = TempDir::new().unwrap();
+    let (spool, keyring) = spool_fixture(&tmp_dir);
+    let fifo = tmp_dir.path().join("input.fifo");
+    nix::unistd::mkfifo(&fifo, nix::sys::stat::Mode::S_IRWXU).expect("cannot create fifo");
+
+    let args = [
+        "cryophile",
+        "--spool",
+        spool.to_str().unwrap(),
+        "backup",
+        "--keyring",
+        keyring.to_str().unwrap(),
+        "--vault",
+        "797daf41-ba2c-440e-a56a-d0a190403a0b",
+        "--ulid",
+        "01J00000000000000000000622",
+        "--input",
+        fifo.to_str().unwrap(),
+    ];
+    let config = cli_config(Cli::try_parse_from(args).expect("cannot parse command line"));
+    let first = thread::spawn(move || perform_backup(&config, backup_command(&config)));
+
+    // the first backup opens its fifo input only after locking the backup
+    let mut input = File::options()
+        .write(true)
+        .open(&fifo)
+        .expect("cannot open fifo");
+    let config = cli_config(Cli::try_parse_from(args).expect("cannot parse command line"));
+    let err = perform_backup(&config, backup_command(&config))
+        .expect_err("concurrent backup with the same ulid");
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+    input.write_all(b"0123456789abcdef").unwrap();
+    drop(input);
+    first
+        .join()
+        .expect("cannot join backup thread")
+        .expect("first backup failed");
+    // the lock file is removed once the backup is queued for freeze
+    let backup_dir = spool
+        .join("backup")
+        .join("797daf41-ba2c-440e-a56a-d0a190403a0b")
+        .join("01J00000000000000000000622");
+    assert!(backup_dir.join("chunk.0").is_file());
+    assert!(!backup_dir.join(".lock").exists());
+}
+
+#[test]
+fn test_cat_prefetch() {
+    let tmp_dir = TempDir::new().unwrap();
+    for depth in 0..=2 {
+        let mut cat = Cat::new().with_prefetch(depth);
+        let tx = cat.tx();
+        let mut expected = Vec::new();
+        for i in 1..=5 {
+            let path = tmp_dir.path().join(format!("chunk.{i}"));
+            fs::write(&path, format!("chunk {i} ")).expect("cannot write chunk");
+            expected.extend_from_slice(format!("chunk {i} ").as_bytes());
+            tx.send(Some(path)).expect("cannot send chunk path");
+        }
+        tx.send(None).expect("cannot send end of chunks");
+
+        let mut buf = Vec::new();
+        cat.read_to_end(&mut buf).expect("cannot read chunks");
+        assert_eq!(buf, expected, "prefetch depth {depth}");
+    }
+}
+
+#[test]
+fn test_watch_shutdown() {
+    let tempdir = TempDir::new().expect("cannot create temporary directory");
+    let mut watch = Watch::new(None, WatchConfig::default()).expect("cannot create watch");
+    watch
+        .watcher
+        .watch(tempdir.path(), RecursiveMode::NonRecursive)
+        .expect("cannot watch temporary directory");
+    let shutdown = watch.shutdown_handle();
+
+    let (done_tx, done_rx) = mpsc::channel();
+    let handle = thread::spawn(move || {
+        let receiver = watch.rx.lock().expect("cannot lock watch receiver");
+        let mut events = 0;
+        for message in receiver.iter() {
+            match message {
+                WatchMessage::Event(_) => events += 1,
+                WatchMessage::Shutdown => break,
+            }
+        }
+        done_tx.send(events).expect("cannot send result");
+    });
+
+    File::create(tempdir.path().join("chunk.1")).expect("cannot create file");
+    shutdown.shutdown();
+
+    // the consumer must finish without seeing any shutdown file
+    done_rx
+        .recv_timeout(Duration::from_secs(10))
+        .expect("watch consumer did not shut down");
+    handle.join().expect("cannot join watch consumer");
+}
+
+#[test]
+fn test_watch_poll() {
+    let tempdir = TempDir::new().expect("cannot create temporary directory");
+    let config = WatchConfig {
+        poll: Some(Duration::from_millis(50)),
+        debounce: Some(Duration::from_secs(10)),
+    };
+    let mut watch = Watch::new(None, config).expect("cannot create watch");
+    watch
+        .watcher
+        .watch(tempdir.path(), RecursiveMode::NonRecursive)
+        .expect("cannot watch temporary directory");
+
+    let chunk = tempdir.path().join("chunk.1");
+    File::create(&chunk).expect("cannot create file");
+    let receiver = watch.rx.lock().expect("cannot lock watch receiver");
+    match receiver
+        .recv_timeout(Duration::from_secs(10))
+        .expect("poll watcher did not report file")
+    {
+        WatchMessage::Event(Ok(event)) => {
+            assert!(event.kind.is_create());
+            assert_eq!(event.paths, vec![chunk]);
+        }
+        _ => panic!("unexpected watch message"),
+    }
+}
+
+#[cfg(feature = "bzip2")]
+#[test]
+fn test_decompress_bzip2() {
+    use cryophile::compression::decompressor::Decompressor;
+
+    let data = b"legacy archive ".repeat(1000);
+    let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::best());
+    encoder.write_all(&data).unwrap();
+    let mut compressed = encoder.finish().unwrap();
+    // concatenated streams as written by pbzip2
+    compressed.extend_from_slice(&compressed.clone());
+
+    let mut output = Vec::new();
+    let bytes = Decompressor::new(compressed.as_slice())
+        .copy_to(&mut output)
+        .unwrap();
+    assert_eq!(bytes, 2 * data.len() as u64);
+    assert_eq!(output, [data.as_slice(), data.as_slice()].concat());
+}
+
+/// Back up `data` into `spool` and return the chunk files of its freeze queue
+/// as the objects of a mock bucket, keyed like freeze would upload them.
+fn mock_bucket_fixture(
+    spool: &Path,
+    keyring: &Path,
+    vault: &str,
+    ulid: &str,
+    data: &[u8],
+) -> HashMap<String, Vec<u8>> {
+    let input = spool.with_file_name("input");
+    fs::write(&input, data).unwrap();
+    let cli = Cli::try_parse_from([
+        "cryophile",
+        "--spool",
+        spool.to_str().unwrap(),
+        "backup",
+        "--keyring",
+        keyring.to_str().unwrap(),
+        "--vault",
+        vault,
+        "--prefix",
+        "hosts/db01",
+        "--ulid",
+        ulid,
+        "--input",
+        input.to_str().unwrap(),
+        "--size",
+        "4K",
+    ])
+    .expect("cannot parse command line");
+    let config = cli_config(cli);
+    perform_backup(&config, backup_command(&config)).expect("cannot back up input");
+
+    let freeze_dir = spool
+        .join("freeze")
+        .join(vault)
+        .join("hosts/db01")
+        .join(ulid);
+    fs::read_dir(&freeze_dir)
+        .unwrap()
+        .map(|entry| {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_str().unwrap();
+            let key = format!("/vault-bucket/hosts/db01/{ulid}/{name}");
+            (key, fs::read(&path).unwrap())
+        })
+        .collect()
+}
+
+/// Restore backup `ulid` from a mock bucket serving `objects` into `output`.
+fn restore_from_mock_bucket(
+    spool: &Path,
+    keyring: &Path,
+    vault: &str,
+    ulid: &str,
+    objects: HashMap<String, Vec<u8>>,
+    output: &Path,
+) -> io::Result<()> {
+    let cli = Cli::try_parse_from([
+        "cryophile",
+        "--spool",
+        spool.to_str().unwrap(),
+        "restore",
+        "--from-bucket",
+        "--keyring",
+        keyring.to_str().unwrap(),
+        "--vault",
+        vault,
+        "--prefix",
+        "hosts/db01",
+        "--ulid",
+        ulid,
+        "--output",
+        output.to_str().unwrap(),
+    ])
+    .expect("cannot parse command line");
+    let file = format!("[[vault]]\nid = \"{vault}\"\n[vault.bucket]\nname = \"vault-bucket\"\n")
+        .parse()
+        .expect("cannot parse config");
+    let base = xdg::BaseDirectories::new().expect("cannot get base directories");
+    let config = Config::new(base, cli, file).with_aws_client(MockS3::client(objects));
+    let Command::Restore(restore) = &config.cli.command else {
+        panic!("expected restore command");
+    };
+    perform_restore(&config, restore)
+}
+
+#[test]
+fn test_restore_from_mock_bucket() {
+    let tmp_dir = TempDir::new().unwrap();
+    let (spool, keyring) = spool_fixture(&tmp_dir);
+    let data: Vec<u8> = (0..=255u8).cycle().take(40_000).collect();
+
+    let vault = "797daf41-ba2c-440e-a56a-d0a190403a0b";
+    let ulid = "01J00000000000000000000657";
+    let objects = mock_bucket_fixture(&spool, &keyring, vault, ulid, &data);
+
+    let output = tmp_dir.path().join("restored");
+    restore_from_mock_bucket(&spool, &keyring, vault, ulid, objects, &output)
+        .expect("cannot restore from mock bucket");
+    assert_eq!(fs::read(&output).unwrap(), data);
+}
+
+#[test]
+fn test_restore_missing_chunk_from_mock_bucket() {
+    let tmp_dir = TempDir::new().unwrap();
+    let (spool, keyring) = spool_fixture(&tmp_dir);
+    let data: Vec<u8> = (0..=255u8).cycle().take(40_000).collect();
+
+    let vault = "797daf41-ba2c-440e-a56a-d0a190403a0b";
+    let ulid = "01J00000000000000000000597";
+    let mut objects = mock_bucket_fixture(&spool, &keyring, vault, ulid, &data);
+    let missing = format!("/vault-bucket/hosts/db01/{ulid}/chunk.2");
+    assert!(
+        objects.remove(&missing).is_some(),
+        "expected chunk.2 to exist"
+    );
+
+    let output = tmp_dir.path().join("restored");
+    restore_from_mock_bucket(&spool, &keyring, vault, ulid, objects, &output)
+        .expect_err("restore with a missing chunk succeeded");
+    assert!(!output.exists(), "truncated restore left an output file");
+}
+
+#[test]
+fn test_restore_latest() {
+    let tmp_dir = TempDir::new().unwrap();
+    let (spool, keyring) = spool_fixture(&tmp_dir);
+
+    let vault = "797daf41-ba2c-440e-a56a-d0a190403a0b";
+    let backups = [
+        ("01J00000000000000000000676", b"older".as_slice()),
+        ("01J00000000000000000000677", b"newer".as_slice()),
+    ];
+    for (ulid, data) in backups {
+        let input = tmp_dir.path().join("input");
+        fs::write(&input, data).unwrap();
+        let cli = Cli::try_parse_from([
+            "cryophile",
+            "--spool",
+            spool.to_str().unwrap(),
+            "backup",
+            "--keyring",
+            keyring.to_str().unwrap(),
+            "--vault",
+            vault,
+            "--prefix",
+            "hosts/db01",
+            "--ulid",
+            ulid,
+            "--input",
+            input.to_str().unwrap(),
+        ])
+        .expect("cannot parse command line");
+        let config = cli_config(cli);
+        perform_backup(&config, backup_command(&config)).expect("cannot back up input");
+    }
+
+    // only the older backup is frozen to the mock bucket
+    let (ulid, _) = backups[0];
+    let freeze_dir = spool
+        .join("freeze")
+        .join(vault)
+        .join("hosts/db01")
+        .join(ulid);
+    let objects = fs::read_dir(&freeze_dir)
+        .unwrap()
+        .map(|entry| {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_str().unwrap();
+            let key = format!("/vault-bucket/hosts/db01/{ulid}/{name}");
+            (key, fs::read(&path).unwrap())
+        })
+        .collect();
+    let client = MockS3::client(objects);
+
+    let restore_latest = |from_bucket: bool| {
+        let output = tmp_dir.path().join("restored");
+        let mut args = vec![
+            "cryophile",
+            "--spool",
+            spool.to_str().unwrap(),
+            "restore",
+            "--keyring",
+            keyring.to_str().unwrap(),
+            "--vault",
+            vault,
+            "--prefix",
+            "hosts/db01",
+            "--latest",
+            "--output",
+            output.to_str().unwrap(),
+            "--force",
+        ];
+        if from_bucket {
+            args.push("--from-bucket");
+        }
+        let cli = Cli::try_parse_from(args).expect("cannot parse command line");
+        let file =
+            format!("[[vault]]\nid = \"{vault}\"\n[vault.bucket]\nname = \"vault-bucket\"\n")
+                .parse()
+                .expect("cannot parse config");
+        let base = xdg::BaseDirectories::new().expect("cannot get base directories");
+        let config = Config::new(base, cli, file).with_aws_client(client.clone());
+        let Command::Restore(restore) = &config.cli.command else {
+            panic!("expected restore command");
+        };
+        perform_restore(&config, restore).expect("cannot restore latest backup");
+        fs::read(&output).unwrap()
+    };
+
+    assert_eq!(restore_latest(false), b"newer");
+    assert_eq!(restore_latest(true), b"older");
+    assert!(Cli::try_parse_from([
+        "cryophile",
+        "restore",
+        "--vault",
+        vault,
+        "--ulid",
+        ulid,
+        "--latest",
+    ])
+    .is_err());
+}
+
+/// S3 serving GetObject and ListObjectsV2 requests from path-style object keys.
+#[derive(Clone, Debug)]
+struct MockS3 {
+    objects: Arc<HashMap<String, Vec<u8>>>,
+}
+
+impl MockS3 {
+    fn client(objects: HashMap<String, Vec<u8>>) -> aws_sdk_s3::Client {
+        let mock = MockS3 {
+            objects: Arc::new(objects),
+        };
+        let config = aws_sdk_s3::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .credentials_provider(Credentials::new("mock", "mock", None, None, "mock"))
+            .endpoint_url("http://mock-s3")
+            .force_path_style(true)
+            .http_client(mock)
+            .build();
+        aws_sdk_s3::Client::from_conf(config)
+    }
+}
+
+impl HttpClient for MockS3 {
+    fn http_connector(
+        &self,
+        _settings: &HttpConnectorSettings,
+        _components: &RuntimeComponents,
+    ) -> SharedHttpConnector {
+        SharedHttpConnector::new(self.clone())
+    }
+}
+
+impl MockS3 {
+    /// ListObjectsV2 response with the keys of the objects directly below
+    /// `prefix` and the common prefixes of the others, up to the next `/`.
+    fn list(&self, bucket: &str, query: &str) -> HttpResponse {
+        let prefix = query
+            .split('&')
+            .find_map(|param| param.strip_prefix("prefix="))
+            .unwrap_or_default()
+            .replace("%2F", "/");
+        let below = self
+            .objects
+            .keys()
+            .filter_map(|key| key.strip_prefix(&format!("{bucket}/{prefix}")));
+        let mut contents = below
+            .clone()
+            .filter(|rest| !rest.contains('/'))
+            .map(|name| format!("<Contents><Key>{prefix}{name}</Key></Contents>"))
+            .collect::<Vec<_>>();
+        contents.sort();
+        let mut common_prefixes = below
+            .filter_map(|rest| rest.split_once('/'))
+            .map(|(name, _)| {
+                format!("<CommonPrefixes><Prefix>{prefix}{name}/</Prefix></CommonPrefixes>")
+            })
+            .collect::<Vec<_>>();
+        common_prefixes.sort();
+        common_prefixes.dedup();
+        let body = format!(
+            "<ListBucketResult><Name>{bucket}</Name><Prefix>{prefix}</Prefix><Delimiter>/</Delimiter>\
+             <KeyCount>{count}</KeyCount><IsTruncated>false</IsTruncated>{contents}{common_prefixes}</ListBucketResult>",
+            bucket = bucket.trim_start_matches('/'),
+            count = contents.len() + common_prefixes.len(),
+            contents = contents.concat(),
+            common_prefixes = common_prefixes.concat()
+        );
+        HttpResponse::new(200.try_into().unwrap(), SdkBody::from(body))
+    }
+}
+
+impl HttpConnector for MockS3 {
+    fn call(&self, request: HttpRequest) -> HttpConnectorFuture {
+        let path = request.uri().trim_start_matches("http://mock-s3");
+        let (key, query) = path.split_once('?').unwrap_or((path, ""));
+        if query.split('&').any(|param| param == "list-type=2") {
+            let bucket = key.trim_end_matches('/');
+            return HttpConnectorFuture::ready(Ok(self.list(bucket, query)));
+        }
+        let response = match self.objects.get(key) {
+            Some(object) => HttpResponse::new(200.try_into().unwrap(), SdkBody::from(object.clone())),
+            None => HttpResponse::new(
+                404.try_into().unwrap(),
+                SdkBody::from("<Error><Code>NoSuchKey</Code><Message>The specified key does not exist.</Message></Error>"),
+            ),
+        };
+        HttpConnectorFuture::ready(Ok(response))
+    }
+}
+
+fn cli_config(cli: Cli) -> Config {
+    let base = xdg::BaseDirectories::new().expect("cannot get base directories");
+    Config::new(base, cli, ConfigFile::default())
+}
+
+/// Spool directory and keyring with a new secret key in `tmp_dir`.
+fn spool_fixture(tmp_dir: &TempDir) -> (PathBuf, PathBuf) {
+    let spool = tmp_dir.path().join("spool");
+    fs::create_dir(&spool).unwrap();
+    (spool, keyring_fixture(tmp_dir.path()))
+}
+
+/// Keyring file in `dir` with the secret key of a new certificate.
+fn keyring_fixture(dir: &Path) -> PathBuf {
+    let (cert, _) = CertBuilder::general_purpose(None, Some("cryophile test"))
+        .generate()
+        .expect("cannot generate certificate");
+    let keyring = dir.join("key.pgp");
+    cert.as_tsk()
+        .serialize(&mut File::create(&keyring).unwrap())
+        .expect("cannot write key");
+    keyring
+}
+
+fn backup_command(config: &Config) -> &cryophile::cli::Backup {
+    let Command::Backup(backup) = &config.cli.command else {
+        panic!("expected backup command");
+    };
+    backup
+}